@@ -1,3 +1,6 @@
 fn main() {
+    println!("cargo:rerun-if-changed=src/dlopen.c");
+    println!("cargo:rerun-if-changed=src/crash.c");
     cc::Build::new().file("src/dlopen.c").compile("dlopen");
+    cc::Build::new().file("src/crash.c").compile("crash");
 }
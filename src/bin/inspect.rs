@@ -0,0 +1,41 @@
+//! Usage: ckb-x64-inspect <tx> [mock_info] [--setup setup.json]
+//!
+//! Prints the inputs, outputs, cell deps, header deps, script groups and
+//! witnesses of the transaction, decoding the molecule types bound in the
+//! setup.
+use ckb_x64_simulator::{inspect::inspect, tx_loader, RunningSetup};
+use std::process::exit;
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let mut setup = RunningSetup::default();
+    if let Some(i) = args.iter().position(|arg| arg == "--setup") {
+        let Some(path) = args.get(i + 1).cloned() else {
+            usage(&args[0]);
+        };
+        let setup_content = std::fs::read_to_string(path).unwrap_or_else(|e| fail(e));
+        setup = serde_json::from_str(&setup_content).unwrap_or_else(|e| fail(e));
+        args.drain(i..i + 2);
+    }
+    if args.len() < 2 || args.len() > 3 {
+        usage(&args[0]);
+    }
+    let mock_info = args.get(2).map(|info| {
+        tx_loader::TxSource::from(info.as_str())
+            .read()
+            .unwrap_or_else(|e| fail(e))
+    });
+    let tx = tx_loader::load_mock_tx(&args[1].as_str().into(), mock_info.as_deref())
+        .unwrap_or_else(|e| fail(e));
+    print!("{}", inspect(&tx, &setup.molecule));
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} <tx> [mock_info] [--setup setup.json]", program);
+    exit(2);
+}
+
+fn fail<E: std::fmt::Display>(e: E) -> ! {
+    eprintln!("error: {}", e);
+    exit(2);
+}
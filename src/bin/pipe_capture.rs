@@ -0,0 +1,25 @@
+//! Usage: ckb-x64-pipe-capture <capture.json> [framing]
+//!
+//! Prints the traffic of every pipe in a capture written by the
+//! `pipe_capture` setup option. `framing` splits the bytes written into
+//! length-prefixed frames: u8, u16le, u16be, u32le, u32be, u64le or u64be.
+use ckb_x64_simulator::capture::{Capture, Framing};
+use std::process::exit;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("usage: {} <capture.json> [framing]", args[0]);
+        exit(2);
+    }
+    let capture = Capture::load(&args[1]).unwrap_or_else(|e| fail(e));
+    let framing = args
+        .get(2)
+        .map(|f| f.parse::<Framing>().unwrap_or_else(|e| fail(e)));
+    print!("{}", capture.view(framing.as_ref()));
+}
+
+fn fail<E: std::fmt::Display>(e: E) -> ! {
+    eprintln!("error: {}", e);
+    exit(2);
+}
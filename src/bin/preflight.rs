@@ -0,0 +1,34 @@
+//! Usage: ckb-x64-preflight <tx> <setup.json> [mock_info]
+//!
+//! Prints how every script group and native binary of the transaction
+//! resolves, and exits with 1 when there are issues.
+use ckb_x64_simulator::{preflight::preflight, tx_loader, RunningSetup};
+use std::process::exit;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!("usage: {} <tx> <setup.json> [mock_info]", args[0]);
+        exit(2);
+    }
+    let mock_info = args.get(3).map(|info| {
+        tx_loader::TxSource::from(info.as_str())
+            .read()
+            .unwrap_or_else(|e| fail(e))
+    });
+    let tx = tx_loader::load_mock_tx(&args[1].as_str().into(), mock_info.as_deref())
+        .unwrap_or_else(|e| fail(e));
+    let setup_content = std::fs::read_to_string(&args[2]).unwrap_or_else(|e| fail(e));
+    let setup: RunningSetup = serde_json::from_str(&setup_content).unwrap_or_else(|e| fail(e));
+
+    let report = preflight(&tx, &setup);
+    println!("{}", report);
+    if !report.is_ok() {
+        exit(1);
+    }
+}
+
+fn fail<E: std::fmt::Display>(e: E) -> ! {
+    eprintln!("error: {}", e);
+    exit(2);
+}
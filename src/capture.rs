@@ -0,0 +1,379 @@
+//! Pipe traffic of a run: what every process wrote to, read from and
+//! closed on its pipes, in order. Bytes are recorded as they move from a
+//! writer to a reader, so a write the reader stops short of only shows
+//! the part that was read. Reads and writes served by faults or syscall
+//! handlers never reach a pipe and are not recorded. Setting
+//! `pipe_capture` in the running setup writes it to that file when the
+//! root VM returns or exits, and `ckb-x64-pipe-capture` shows it per pipe:
+//!
+//! ```text
+//! pipe 2/3, created by pid 0
+//!   pid 0 write fd 3, 8 bytes: 0x0400000001020304
+//!   pid 1 read fd 2, 8 bytes: 0x0400000001020304
+//!   pid 0 close fd 3
+//!   frames written:
+//!     4 bytes: 0x01020304
+//! ```
+//!
+//! Frames are decoded with a length-prefix `Framing` such as `u32le`. A
+//! capture of a child process turns into a scripted mock child with
+//! `MockChild::from_capture`. VMs replaced by an exec in dynamic library
+//! mode run in a context of their own and are not captured.
+use ckb_jsonrpc_types::JsonBytes;
+use serde_derive::{Deserialize, Serialize};
+use std::{fmt::Write, path::Path, str::FromStr};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PipeOp {
+    Write,
+    Read,
+    Close,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PipeEvent {
+    pub pid: u64,
+    pub fd: u64,
+    pub op: PipeOp,
+    /// Bytes written or read, empty for closes.
+    #[serde(default)]
+    pub data: JsonBytes,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedPipe {
+    /// Process creating the pipe.
+    pub pid: u64,
+    pub read_fd: u64,
+    pub write_fd: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedProcess {
+    pub pid: u64,
+    pub inherited_fds: Vec<u64>,
+    /// Absent when the process was never waited for.
+    #[serde(default)]
+    pub exit_code: Option<i8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capture {
+    #[serde(default)]
+    pub pipes: Vec<CapturedPipe>,
+    #[serde(default)]
+    pub processes: Vec<CapturedProcess>,
+    #[serde(default)]
+    pub events: Vec<PipeEvent>,
+}
+impl Capture {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CaptureError> {
+        let name = path.as_ref().display().to_string();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| CaptureError::Read(name.clone(), e.to_string()))?;
+        serde_json::from_str(&content).map_err(|e| CaptureError::Parse(name, e.to_string()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), CaptureError> {
+        let name = path.as_ref().display().to_string();
+        let content = serde_json::to_string_pretty(self).expect("serialize capture");
+        std::fs::write(path, content).map_err(|e| CaptureError::Write(name, e.to_string()))
+    }
+
+    pub fn process(&self, pid: u64) -> Option<&CapturedProcess> {
+        self.processes.iter().find(|p| p.pid == pid)
+    }
+
+    /// The events of every pipe in order, with the bytes written to it
+    /// split into frames when `framing` is given.
+    pub fn view(&self, framing: Option<&Framing>) -> String {
+        let mut out = String::new();
+        for pipe in &self.pipes {
+            writeln!(
+                out,
+                "pipe {}/{}, created by pid {}",
+                pipe.read_fd, pipe.write_fd, pipe.pid
+            )
+            .unwrap();
+            let mut written = vec![];
+            let events = self
+                .events
+                .iter()
+                .filter(|e| e.fd == pipe.read_fd || e.fd == pipe.write_fd);
+            for event in events {
+                let data = event.data.as_bytes();
+                let op = match event.op {
+                    PipeOp::Close => {
+                        writeln!(out, "  pid {} close fd {}", event.pid, event.fd).unwrap();
+                        continue;
+                    }
+                    PipeOp::Write => "write",
+                    PipeOp::Read => "read",
+                };
+                let hex = faster_hex::hex_string(data);
+                writeln!(
+                    out,
+                    "  pid {} {} fd {}, {} bytes: 0x{}",
+                    event.pid,
+                    op,
+                    event.fd,
+                    data.len(),
+                    hex
+                )
+                .unwrap();
+                if event.op == PipeOp::Write {
+                    written.extend_from_slice(data);
+                }
+            }
+            if let Some(framing) = framing {
+                writeln!(out, "  frames written:").unwrap();
+                let (frames, rest) = framing.split(&written);
+                for frame in frames {
+                    let hex = faster_hex::hex_string(frame);
+                    writeln!(out, "    {} bytes: 0x{}", frame.len(), hex).unwrap();
+                }
+                if !rest.is_empty() {
+                    let hex = faster_hex::hex_string(rest);
+                    writeln!(out, "    incomplete, {} bytes: 0x{}", rest.len(), hex).unwrap();
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Frames of a byte stream, each an unsigned length and that many bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Framing {
+    /// Size of the length, 1, 2, 4 or 8 bytes.
+    pub prefix: usize,
+    pub big_endian: bool,
+}
+impl Framing {
+    /// The payloads of the complete frames of `data`, and the bytes left.
+    pub fn split<'a>(&self, mut data: &'a [u8]) -> (Vec<&'a [u8]>, &'a [u8]) {
+        let mut frames = vec![];
+        while self.prefix > 0 && data.len() >= self.prefix {
+            let mut len = [0u8; 8];
+            if self.big_endian {
+                len[8 - self.prefix..].copy_from_slice(&data[..self.prefix]);
+            } else {
+                len[..self.prefix].copy_from_slice(&data[..self.prefix]);
+            }
+            let len = match self.big_endian {
+                true => u64::from_be_bytes(len),
+                false => u64::from_le_bytes(len),
+            };
+            let Some(end) = (len as usize).checked_add(self.prefix) else {
+                break;
+            };
+            if data.len() < end {
+                break;
+            }
+            frames.push(&data[self.prefix..end]);
+            data = &data[end..];
+        }
+        (frames, data)
+    }
+}
+impl FromStr for Framing {
+    type Err = String;
+
+    /// `u8`, or `u16`, `u32` and `u64` followed by `le` or `be`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, big_endian) = match s {
+            "u8" => (1, false),
+            "u16le" => (2, false),
+            "u16be" => (2, true),
+            "u32le" => (4, false),
+            "u32be" => (4, true),
+            "u64le" => (8, false),
+            "u64be" => (8, true),
+            _ => return Err(format!("unknown framing {}", s)),
+        };
+        Ok(Self { prefix, big_endian })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CaptureError {
+    /// File, error message.
+    Read(String, String),
+    /// File, error message.
+    Write(String, String),
+    /// File, error message.
+    Parse(String, String),
+}
+impl std::fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read(file, msg) => write!(f, "read {}: {}", file, msg),
+            Self::Write(file, msg) => write!(f, "write {}: {}", file, msg),
+            Self::Parse(file, msg) => write!(f, "parse {}: {}", file, msg),
+        }
+    }
+}
+impl std::error::Error for CaptureError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ckb_pipe, ckb_spawn_cell, ckb_wait, ckb_write, constants::CKB_SUCCESS, test_utils,
+        RunningSetup, Simulation,
+    };
+    use std::ffi::c_void;
+
+    /// Writes 4 bytes to a mock child reading 2 of them, with the capture
+    /// saved to `path`. Returns the capture and the parent's exit code.
+    fn write_to_short_reader(path: String) -> (Capture, i8) {
+        let code_hash = [1u8; 32];
+        let child = test_utils::temp_file(
+            "capture_child.json",
+            br#"{ "steps": [{ "read": { "fd": 0, "data": "0x0102" } }], "exit_code": 4 }"#,
+        );
+        let setup = RunningSetup {
+            native_binaries: [(test_utils::cell_key(&code_hash, 1), child)].into(),
+            pipe_capture: Some(path),
+            ..Default::default()
+        };
+        let sim = Simulation::new(Default::default(), setup);
+        test_utils::with_timeout(move || {
+            let code = sim.run_root("parent".to_string(), move |_, _| {
+                let mut fds = [0u64; 2];
+                assert_eq!(ckb_pipe(fds.as_mut_ptr()), CKB_SUCCESS);
+                let inherited_fds = [fds[0], 0];
+                let mut pid = 0;
+                let ret = ckb_spawn_cell(
+                    code_hash.as_ptr(),
+                    1,
+                    0,
+                    0,
+                    0,
+                    std::ptr::null(),
+                    inherited_fds.as_ptr(),
+                    &mut pid,
+                );
+                assert_eq!(ret, CKB_SUCCESS);
+                let data = [1u8, 2, 3, 4];
+                let mut len = data.len();
+                ckb_write(fds[1], data.as_ptr() as *const c_void, &mut len);
+                let mut code = 0;
+                assert_eq!(ckb_wait(pid, &mut code), CKB_SUCCESS);
+                code
+            });
+            (sim.pipe_capture(), code)
+        })
+    }
+
+    fn event(pid: u64, fd: u64, op: PipeOp, data: &[u8]) -> PipeEvent {
+        PipeEvent {
+            pid,
+            fd,
+            op,
+            data: JsonBytes::from_vec(data.to_vec()),
+        }
+    }
+
+    #[test]
+    fn only_transferred_bytes_are_recorded() {
+        let path = test_utils::temp_path("capture.json").display().to_string();
+        let (capture, code) = write_to_short_reader(path.clone());
+        assert_eq!(code, 4);
+        assert_eq!(
+            capture.events,
+            vec![
+                event(0, 3, PipeOp::Write, &[1, 2]),
+                event(1, 2, PipeOp::Read, &[1, 2]),
+            ]
+        );
+        assert_eq!(
+            capture.process(1),
+            Some(&CapturedProcess {
+                pid: 1,
+                inherited_fds: vec![2],
+                exit_code: Some(4),
+            })
+        );
+        assert_eq!(Capture::load(&path), Ok(capture));
+    }
+
+    #[test]
+    fn unsaved_captures_do_not_fail_the_run() {
+        let path = "/nonexistent/capture.json".to_string();
+        let (capture, code) = write_to_short_reader(path.clone());
+        assert_eq!(code, 4);
+        assert_eq!(capture.events.len(), 2);
+        assert!(matches!(Capture::load(&path), Err(CaptureError::Read(..))));
+        let e = capture.save(&path).unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("write /nonexistent/capture.json: "));
+    }
+
+    #[test]
+    fn framings_split_length_prefixed_frames() {
+        let u32le: Framing = "u32le".parse().unwrap();
+        let data = [2, 0, 0, 0, 7, 8, 0, 0, 0, 0, 1, 0, 0, 0];
+        assert_eq!(
+            u32le.split(&data),
+            (vec![&[7, 8][..], &[]], &[1, 0, 0, 0][..])
+        );
+        let u16be: Framing = "u16be".parse().unwrap();
+        assert_eq!(u16be.split(&[0, 1, 9, 0]), (vec![&[9][..]], &[0][..]));
+        let u8: Framing = "u8".parse().unwrap();
+        assert_eq!(u8.split(&[1, 5, 2, 6]), (vec![&[5][..]], &[2, 6][..]));
+        // Lengths past the address space are left as they are.
+        let u64le: Framing = "u64le".parse().unwrap();
+        let huge = [0xff; 9];
+        assert_eq!(u64le.split(&huge), (vec![], &huge[..]));
+        assert_eq!(
+            "u24le".parse::<Framing>(),
+            Err("unknown framing u24le".to_string())
+        );
+    }
+
+    #[test]
+    fn views_list_events_by_pipe() {
+        let capture = Capture {
+            pipes: vec![
+                CapturedPipe {
+                    pid: 0,
+                    read_fd: 2,
+                    write_fd: 3,
+                },
+                CapturedPipe {
+                    pid: 1,
+                    read_fd: 4,
+                    write_fd: 5,
+                },
+            ],
+            processes: vec![],
+            events: vec![
+                event(0, 3, PipeOp::Write, &[1, 0, 7]),
+                event(1, 5, PipeOp::Write, &[9]),
+                event(1, 2, PipeOp::Read, &[1, 0, 7]),
+                event(0, 3, PipeOp::Write, &[0, 8]),
+                event(0, 3, PipeOp::Close, &[]),
+            ],
+        };
+        let u16le = "u16le".parse().unwrap();
+        assert_eq!(
+            capture.view(Some(&u16le)),
+            "pipe 2/3, created by pid 0\n\
+             \x20 pid 0 write fd 3, 3 bytes: 0x010007\n\
+             \x20 pid 1 read fd 2, 3 bytes: 0x010007\n\
+             \x20 pid 0 write fd 3, 2 bytes: 0x0008\n\
+             \x20 pid 0 close fd 3\n\
+             \x20 frames written:\n\
+             \x20   1 bytes: 0x07\n\
+             \x20   incomplete, 2 bytes: 0x0008\n\
+             pipe 4/5, created by pid 1\n\
+             \x20 pid 1 write fd 5, 1 bytes: 0x09\n\
+             \x20 frames written:\n\
+             \x20   incomplete, 1 bytes: 0x09\n"
+        );
+        assert!(!capture.view(None).contains("frames"));
+    }
+}
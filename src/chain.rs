@@ -0,0 +1,353 @@
+//! A small in-memory chain for multi-step scenarios: transactions whose
+//! scripts pass natively are committed in new blocks, their outputs become
+//! live cells, and the next transaction is resolved against them.
+use crate::{
+    headers::{DaoField, MockHeader},
+    verify::{verify_transaction, VerifyError},
+    RunningSetup,
+};
+use ckb_hash::blake2b_256;
+use ckb_mock_tx_types::{MockCellDep, MockInfo, MockInput, MockTransaction};
+use ckb_types::{
+    bytes::Bytes,
+    core::{DepType, EpochNumberWithFraction, HeaderView, TransactionView},
+    packed::{Byte32, CellDep, CellOutput, OutPoint, OutPointVec},
+    prelude::*,
+};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct LiveCell {
+    pub output: CellOutput,
+    pub data: Bytes,
+    /// Hash of the block committing the cell.
+    pub block_hash: Byte32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChainError {
+    /// The out point is unknown or already spent.
+    DeadCell(OutPoint),
+    /// A header dep is not a block of this chain.
+    UnknownHeader(Byte32),
+    /// The data of a dep group cell is not an `OutPointVec`.
+    InvalidDepGroup(OutPoint),
+    Verify(VerifyError),
+}
+impl std::fmt::Display for ChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DeadCell(out_point) => write!(
+                f,
+                "cell {:#x}:{} is dead or unknown",
+                out_point.tx_hash(),
+                Unpack::<u32>::unpack(&out_point.index())
+            ),
+            Self::UnknownHeader(hash) => write!(f, "header {:#x} is not on chain", hash),
+            Self::InvalidDepGroup(out_point) => write!(
+                f,
+                "cell {:#x}:{} is not a dep group",
+                out_point.tx_hash(),
+                Unpack::<u32>::unpack(&out_point.index())
+            ),
+            Self::Verify(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for ChainError {}
+impl From<VerifyError> for ChainError {
+    fn from(e: VerifyError) -> Self {
+        Self::Verify(e)
+    }
+}
+
+pub struct Chain {
+    /// Setup used to verify transactions, its `native_binaries` map every
+    /// script the scenario runs.
+    pub setup: RunningSetup,
+    /// Blocks per epoch of synthesized headers.
+    pub epoch_length: u64,
+    /// Milliseconds between synthesized headers.
+    pub block_interval: u64,
+    headers: Vec<HeaderView>,
+    cells: HashMap<OutPoint, LiveCell>,
+    created_cells: u64,
+}
+impl Chain {
+    /// A chain holding only a genesis block.
+    pub fn new(setup: RunningSetup) -> Self {
+        let mut chain = Self {
+            setup,
+            epoch_length: 1000,
+            block_interval: 8000,
+            headers: vec![],
+            cells: HashMap::new(),
+            created_cells: 0,
+        };
+        let genesis = chain.next_header();
+        chain.headers.push(genesis);
+        chain
+    }
+
+    pub fn tip(&self) -> &HeaderView {
+        self.headers.last().expect("genesis")
+    }
+
+    pub fn header(&self, number: u64) -> Option<&HeaderView> {
+        self.headers.get(number as usize)
+    }
+
+    pub fn header_by_hash(&self, hash: &Byte32) -> Option<&HeaderView> {
+        self.headers.iter().find(|h| &h.hash() == hash)
+    }
+
+    pub fn get_cell(&self, out_point: &OutPoint) -> Option<&LiveCell> {
+        self.cells.get(out_point)
+    }
+
+    pub fn live_cells(&self) -> impl Iterator<Item = (&OutPoint, &LiveCell)> {
+        self.cells.iter()
+    }
+
+    /// Adds a live cell to the tip block without a transaction, to seed the
+    /// scenario with contracts and funds.
+    pub fn create_cell(&mut self, output: CellOutput, data: Bytes) -> OutPoint {
+        let mut seed = b"chain-created-cell".to_vec();
+        seed.extend_from_slice(&self.created_cells.to_le_bytes());
+        self.created_cells += 1;
+        let out_point = OutPoint::new(blake2b_256(&seed).pack(), 0);
+        let block_hash = self.tip().hash();
+        self.cells.insert(
+            out_point.clone(),
+            LiveCell {
+                output,
+                data,
+                block_hash,
+            },
+        );
+        out_point
+    }
+
+    /// Appends `count` empty blocks.
+    pub fn mine(&mut self, count: u64) -> &HeaderView {
+        for _ in 0..count {
+            let header = self.next_header();
+            self.headers.push(header);
+        }
+        self.tip()
+    }
+
+    /// Mines until the tip reaches the start of the epoch `epochs` later.
+    pub fn advance_epochs(&mut self, epochs: u64) -> &HeaderView {
+        let target = (self.tip().epoch().number() + epochs) * self.epoch_length;
+        self.mine(target.saturating_sub(self.tip().number()))
+    }
+
+    fn next_header(&self) -> HeaderView {
+        let (number, parent_hash, dao) = match self.headers.last() {
+            Some(parent) => (
+                parent.number() + 1,
+                parent.hash(),
+                DaoField::unpack(&parent.dao()),
+            ),
+            None => (0, Byte32::zero(), Default::default()),
+        };
+        MockHeader {
+            number,
+            epoch: EpochNumberWithFraction::new(
+                number / self.epoch_length,
+                number % self.epoch_length,
+                self.epoch_length,
+            ),
+            timestamp: number * self.block_interval,
+            dao,
+            parent_hash,
+        }
+        .build()
+    }
+
+    fn mock_cell_dep(
+        &self,
+        cell_dep: CellDep,
+        out_point: &OutPoint,
+    ) -> Result<MockCellDep, ChainError> {
+        let cell = self
+            .get_cell(out_point)
+            .ok_or_else(|| ChainError::DeadCell(out_point.clone()))?;
+        Ok(MockCellDep {
+            cell_dep,
+            output: cell.output.clone(),
+            data: cell.data.clone(),
+            header: Some(cell.block_hash.clone()),
+        })
+    }
+
+    /// Resolves `tx` against the live cells. Dep groups are listed followed
+    /// by their member cells, like ckb-cli dumps them, and the header deps
+    /// of the mock info end with the blocks committing the inputs.
+    pub fn build_mock_tx(&self, tx: &TransactionView) -> Result<MockTransaction, ChainError> {
+        let mut inputs = vec![];
+        for input in tx.inputs() {
+            let out_point = input.previous_output();
+            let cell = self
+                .get_cell(&out_point)
+                .ok_or_else(|| ChainError::DeadCell(out_point.clone()))?;
+            inputs.push(MockInput {
+                input,
+                output: cell.output.clone(),
+                data: cell.data.clone(),
+                header: Some(cell.block_hash.clone()),
+            });
+        }
+
+        let mut cell_deps = vec![];
+        for cell_dep in tx.cell_deps() {
+            let out_point = cell_dep.out_point();
+            let mock_cell_dep = self.mock_cell_dep(cell_dep.clone(), &out_point)?;
+            let is_dep_group = cell_dep.dep_type() == DepType::DepGroup.into();
+            let members = if is_dep_group {
+                OutPointVec::from_slice(&mock_cell_dep.data)
+                    .map_err(|_| ChainError::InvalidDepGroup(out_point.clone()))?
+                    .into_iter()
+                    .collect()
+            } else {
+                vec![]
+            };
+            cell_deps.push(mock_cell_dep);
+            for member in members {
+                let member_dep = CellDep::new_builder()
+                    .out_point(member.clone())
+                    .dep_type(DepType::Code.into())
+                    .build();
+                cell_deps.push(self.mock_cell_dep(member_dep, &member)?);
+            }
+        }
+
+        let mut header_deps = vec![];
+        for hash in tx.header_deps() {
+            header_deps.push(
+                self.header_by_hash(&hash)
+                    .cloned()
+                    .ok_or(ChainError::UnknownHeader(hash))?,
+            );
+        }
+        // Relative since is checked against the blocks committing the
+        // inputs. Their headers are only looked up by hash, scripts index
+        // the header deps of the transaction.
+        for input in &inputs {
+            let hash = input.header.as_ref().expect("committed input");
+            if header_deps.iter().all(|h| &h.hash() != hash) {
+                header_deps.push(self.header_by_hash(hash).cloned().expect("chain block"));
+            }
+        }
+
+        Ok(MockTransaction {
+            mock_info: MockInfo {
+                inputs,
+                cell_deps,
+                header_deps,
+                extensions: vec![],
+            },
+            tx: tx.data(),
+        })
+    }
+
+    /// Runs every script group of `tx` natively against the live cells.
+    pub fn verify(&self, tx: &TransactionView) -> Result<MockTransaction, ChainError> {
+        let mock_tx = self.build_mock_tx(tx)?;
+        // The transaction goes in the next block.
+        let mut setup = self.setup.clone();
+        setup
+            .tip_header
+            .get_or_insert_with(|| self.next_header().into());
+        verify_transaction(&mock_tx, &setup)?;
+        Ok(mock_tx)
+    }
+
+    /// Verifies `tx` and commits it in a new block: its inputs are spent and
+    /// its outputs become live. Returns the new tip.
+    pub fn apply(&mut self, tx: &TransactionView) -> Result<HeaderView, ChainError> {
+        self.verify(tx)?;
+        let header = self.mine(1).clone();
+        for input in tx.inputs() {
+            self.cells.remove(&input.previous_output());
+        }
+        for (i, (output, data)) in tx.outputs_with_data_iter().enumerate() {
+            self.cells.insert(
+                OutPoint::new(tx.hash(), i as u32),
+                LiveCell {
+                    output,
+                    data,
+                    block_hash: header.hash(),
+                },
+            );
+        }
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{since::SINCE_RELATIVE_FLAG, test_utils, tx_verifier::TxVerifyError};
+    use ckb_types::{
+        core::{error::TransactionError, ScriptHashType},
+        packed::{CellInput, Script},
+    };
+
+    #[test]
+    fn relative_since_counts_from_the_committing_block() {
+        let lock = Script::new_builder()
+            .code_hash([1u8; 32].pack())
+            .hash_type(ScriptHashType::Data1.into())
+            .build();
+        let setup = RunningSetup {
+            native_binaries: [(
+                test_utils::cell_key(&[1u8; 32], 0xFF),
+                test_utils::argv_dylib(),
+            )]
+            .into(),
+            check_since: true,
+            ..Default::default()
+        };
+        let mut chain = Chain::new(setup);
+        let output = CellOutput::new_builder()
+            .capacity(100_000_000_000u64.pack())
+            .lock(lock)
+            .build();
+        chain.mine(5);
+        let funds = chain.create_cell(output.clone(), Bytes::new());
+        chain.mine(20);
+
+        let spend = |out_point: OutPoint, blocks: u64| {
+            TransactionView::new_advanced_builder()
+                .input(CellInput::new(out_point, SINCE_RELATIVE_FLAG | blocks))
+                .output(output.clone())
+                .output_data(Bytes::new().pack())
+                .build()
+        };
+        // Committed in block 5, the transaction goes in block 26.
+        let mock_tx = chain.verify(&spend(funds.clone(), 21)).unwrap();
+        assert_eq!(
+            mock_tx.mock_info.header_deps,
+            vec![chain.header(5).unwrap().clone()]
+        );
+        assert!(mock_tx.tx.raw().header_deps().is_empty());
+        let immature = Err(ChainError::Verify(VerifyError::Invalid(
+            TxVerifyError::Transaction(TransactionError::Immature { index: 0 }),
+        )));
+        assert_eq!(
+            chain.verify(&spend(funds.clone(), 22)).map(|_| ()),
+            immature
+        );
+
+        let tx = spend(funds, 21);
+        let header = chain.apply(&tx).unwrap();
+        let spent = OutPoint::new(tx.hash(), 0);
+        assert_eq!(chain.get_cell(&spent).unwrap().block_hash, header.hash());
+        assert!(chain.verify(&spend(spent.clone(), 1)).is_ok());
+        assert_eq!(chain.verify(&spend(spent.clone(), 2)).map(|_| ()), immature);
+        chain.mine(1);
+        assert!(chain.verify(&spend(spent, 2)).is_ok());
+    }
+}
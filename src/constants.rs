@@ -15,18 +15,47 @@ pub const SYS_LOAD_HEADER_BY_FIELD: u64 = 2082;
 pub const SYS_LOAD_INPUT_BY_FIELD: u64 = 2083;
 pub const SYS_LOAD_CELL_DATA_AS_CODE: u64 = 2091;
 pub const SYS_LOAD_CELL_DATA: u64 = 2092;
+pub const SYS_LOAD_BLOCK_EXTENSION: u64 = 2104;
+pub const SYS_SPAWN: u64 = 2601;
+pub const SYS_WAIT: u64 = 2602;
+pub const SYS_PROCESS_ID: u64 = 2603;
+pub const SYS_PIPE: u64 = 2604;
+pub const SYS_WRITE: u64 = 2605;
+pub const SYS_READ: u64 = 2606;
+pub const SYS_INHERITED_FDS: u64 = 2607;
+pub const SYS_CLOSE: u64 = 2608;
 pub const SYS_DEBUG: u64 = 2177;
 
 // https://github.com/nervosnetwork/ckb-c-stdlib/blob/744c62e5259a5ab826e1a02ca36a811c9905f010/ckb_consts.h#L32
 pub const CKB_SUCCESS: i32 = 0;
 pub const CKB_INDEX_OUT_OF_BOUND: i32 = 1;
 pub const CKB_ITEM_MISSING: i32 = 2;
+pub const CKB_SLICE_OUT_OF_BOUND: i32 = 3;
 pub const CKB_WAIT_FAILURE: i32 = 5;
 pub const CKB_INVALID_FD: i32 = 6;
 pub const CKB_OTHER_END_CLOSED: i32 = 7;
 pub const CKB_MAX_VMS_SPAWNED: i32 = 8;
 pub const CKB_MAX_FDS_CREATED: i32 = 9;
 
+// Exit code of a simulated VM that crashed with a signal, see `crash` module.
+pub const SIMULATOR_CRASH_EXIT_CODE: i8 = -128;
+
+// Exit code of a mock child whose parent's pipe traffic does not match its
+// steps, and of the root VM of its run, see `mock_child` module.
+pub const SIMULATOR_MOCK_MISMATCH_EXIT_CODE: i8 = -127;
+
+// Exit code of native simulators whose transaction fails the checks of the
+// setup loaded from CKB_RUNNING_SETUP, see `tx_verifier::precheck`.
+pub const SIMULATOR_PRECHECK_EXIT_CODE: i8 = -126;
+
+// Exit code of native simulators starting code that has no native binary in
+// the setup, a mistake of the setup scripts must not be able to handle.
+pub const SIMULATOR_MISSING_BINARY_EXIT_CODE: i8 = -125;
+
+// Returned by ckb_syscall for numbers no syscall serves, and for
+// ckb_load_cell_data_as_code, where CKB-VM fails the script instead.
+pub const SIMULATOR_UNSUPPORTED_SYSCALL: i32 = -1;
+
 pub const SOURCE_INPUT: u64 = 1;
 pub const SOURCE_OUTPUT: u64 = 2;
 pub const SOURCE_CELL_DEP: u64 = 3;
@@ -50,3 +79,13 @@ pub const HEADER_FIELD_EPOCH_LENGTH: u64 = 2;
 
 pub const INPUT_FIELD_OUT_POINT: u64 = 0;
 pub const INPUT_FIELD_SINCE: u64 = 1;
+
+// Stack of CKB-VM, the argv strings of ckb_exec and ckb_spawn and their
+// pointers must fit in it. DEFAULT_STACK_SIZE of ckb-vm-definitions, which
+// ckb-script passes to initialize_stack for exec (src/syscalls/exec.rs) and
+// spawn (load_program_with_metadata in src/scheduler.rs).
+pub const MAX_ARGV_SIZE: usize = 1024 * 1024;
+
+// Where ckb_exec and ckb_spawn take code from.
+pub const PLACE_CELL_DATA: u64 = 0;
+pub const PLACE_WITNESS: u64 = 1;
@@ -0,0 +1,163 @@
+#include <execinfo.h>
+#include <setjmp.h>
+#include <signal.h>
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+#define CRASH_ALT_STACK_SIZE (256 * 1024)
+#define CRASH_MAX_FRAMES 64
+
+static const int crash_signals[] = {SIGSEGV, SIGBUS, SIGABRT, SIGILL, SIGFPE};
+#define CRASH_SIGNALS_COUNT (sizeof(crash_signals) / sizeof(crash_signals[0]))
+
+/* What the handler records of a crash, the report is built from it once the
+ * handler is left. */
+struct crash_info {
+  int32_t signal;
+  int32_t frames_count;
+  void* address;
+  void* frames[CRASH_MAX_FRAMES];
+};
+
+/* Every native binary links its own copy of the simulator, so previous
+ * handlers are chained: a crash is handled by the copy that protected the
+ * faulting thread. */
+static struct sigaction previous_actions[CRASH_SIGNALS_COUNT];
+static int crash_handler_installed = 0;
+
+/* Crashes outside of a protected call are written here before the process
+ * dies, for the supervising process to report them. */
+static int crash_report_fd = -1;
+
+static __thread sigjmp_buf* current_jmp = NULL;
+static __thread struct crash_info* current_crash = NULL;
+static __thread void* current_alt_stack = NULL;
+
+static struct sigaction* previous_action(int sig) {
+  for (size_t i = 0; i < CRASH_SIGNALS_COUNT; i++) {
+    if (crash_signals[i] == sig) {
+      return &previous_actions[i];
+    }
+  }
+  return NULL;
+}
+
+/* Only async-signal-safe calls from here on: backtrace() is, once loaded by
+ * simulator_internal_install_crash_handler. */
+static void record_crash(struct crash_info* crash, int sig, siginfo_t* info) {
+  crash->signal = sig;
+  crash->address = info->si_addr;
+  crash->frames_count = backtrace(crash->frames, CRASH_MAX_FRAMES);
+}
+
+static void crash_handler(int sig, siginfo_t* info, void* ucontext) {
+  if (current_jmp != NULL) {
+    record_crash(current_crash, sig, info);
+    siglongjmp(*current_jmp, sig);
+  }
+  struct sigaction* prev = previous_action(sig);
+  if (prev != NULL && (prev->sa_flags & SA_SIGINFO) &&
+      prev->sa_sigaction != NULL) {
+    prev->sa_sigaction(sig, info, ucontext);
+    return;
+  }
+  if (prev != NULL && !(prev->sa_flags & SA_SIGINFO) &&
+      prev->sa_handler != SIG_DFL && prev->sa_handler != SIG_IGN) {
+    prev->sa_handler(sig);
+    return;
+  }
+  if (crash_report_fd >= 0) {
+    struct crash_info crash;
+    record_crash(&crash, sig, info);
+    ssize_t written = write(crash_report_fd, &crash, sizeof(crash));
+    (void)written;
+  }
+  signal(sig, SIG_DFL);
+  raise(sig);
+}
+
+int simulator_internal_install_crash_handler(void) {
+  if (crash_handler_installed) {
+    return 0;
+  }
+  /* The first call loads the unwinder, which is not signal safe. */
+  void* frames[1];
+  backtrace(frames, 1);
+
+  struct sigaction action;
+  memset(&action, 0, sizeof(action));
+  action.sa_sigaction = crash_handler;
+  action.sa_flags = SA_SIGINFO | SA_ONSTACK | SA_NODEFER;
+  sigemptyset(&action.sa_mask);
+  for (size_t i = 0; i < CRASH_SIGNALS_COUNT; i++) {
+    if (sigaction(crash_signals[i], &action, &previous_actions[i]) != 0) {
+      return -1;
+    }
+  }
+  crash_handler_installed = 1;
+  return 0;
+}
+
+void simulator_internal_set_crash_report_fd(int fd) { crash_report_fd = fd; }
+
+/* Gives the current thread an alternate signal stack, for the handler to run
+ * on stack overflows. Returns 1 when one was set up, 0 when the thread has
+ * one already and -1 on failure. */
+static int setup_alt_stack(void) {
+  if (current_alt_stack != NULL) {
+    return 0;
+  }
+  stack_t ss;
+  memset(&ss, 0, sizeof(ss));
+  ss.ss_sp = malloc(CRASH_ALT_STACK_SIZE);
+  if (ss.ss_sp == NULL) {
+    return -1;
+  }
+  ss.ss_size = CRASH_ALT_STACK_SIZE;
+  ss.ss_flags = 0;
+  if (sigaltstack(&ss, NULL) != 0) {
+    free(ss.ss_sp);
+    return -1;
+  }
+  current_alt_stack = ss.ss_sp;
+  return 1;
+}
+
+/* Threads of VMs come and go, their alternate stack is released once the
+ * outermost protected call returns. */
+static void release_alt_stack(void) {
+  stack_t ss;
+  memset(&ss, 0, sizeof(ss));
+  ss.ss_flags = SS_DISABLE;
+  sigaltstack(&ss, NULL);
+  free(current_alt_stack);
+  current_alt_stack = NULL;
+}
+
+/* Runs func(arg) and returns 0 with its result stored in *result, or the
+ * number of the signal that crashed it, recorded in *crash. */
+int simulator_internal_protected_call(int8_t (*func)(void*), void* arg,
+                                      int8_t* result,
+                                      struct crash_info* crash) {
+  sigjmp_buf buf;
+  sigjmp_buf* prev = current_jmp;
+  struct crash_info* prev_crash = current_crash;
+  volatile int owns_alt_stack = setup_alt_stack();
+  if (owns_alt_stack < 0) {
+    return -1;
+  }
+  int sig = sigsetjmp(buf, 1);
+  if (sig == 0) {
+    current_crash = crash;
+    current_jmp = &buf;
+    *result = func(arg);
+  }
+  current_jmp = prev;
+  current_crash = prev_crash;
+  if (owns_alt_stack) {
+    release_alt_stack();
+  }
+  return sig;
+}
@@ -0,0 +1,359 @@
+//! Crash containment for simulated VMs, enabled by `crash_handler` in the
+//! running setup.
+//!
+//! The signal handler only records the signal, the faulting address and the
+//! raw frames of the crashed thread, then jumps back to the entry of its VM,
+//! where the report is built and the VM ends with
+//! `SIMULATOR_CRASH_EXIT_CODE`. The jump skips every frame of the VM without
+//! running its destructors: what it allocated leaks and locks it held stay
+//! held, a VM crashing inside a syscall can leave the simulation stuck. Only
+//! the report of a crashed run can be trusted, not its state.
+//!
+//! Executable contracts have no entry the simulator runs, their process is
+//! forked instead: the child runs the contract and the parent waits for it,
+//! reporting its crash and exiting with its code.
+use crate::{
+    constants::SIMULATOR_CRASH_EXIT_CODE, global_data::GlobalData, simulator_context::SimContext,
+};
+use std::{
+    ffi::{c_int, c_void, CStr},
+    fmt::Write,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+};
+
+const CRASH_MAX_FRAMES: usize = 64;
+
+extern "C" {
+    fn simulator_internal_install_crash_handler() -> c_int;
+    fn simulator_internal_set_crash_report_fd(fd: c_int);
+    fn simulator_internal_protected_call(
+        func: extern "C" fn(*mut c_void) -> i8,
+        arg: *mut c_void,
+        result: *mut i8,
+        crash: *mut CrashInfo,
+    ) -> c_int;
+}
+
+/// What the signal handler records, `struct crash_info` in crash.c.
+#[repr(C)]
+struct CrashInfo {
+    signal: i32,
+    frames_count: i32,
+    address: *mut c_void,
+    frames: [*mut c_void; CRASH_MAX_FRAMES],
+}
+impl Default for CrashInfo {
+    fn default() -> Self {
+        Self {
+            signal: 0,
+            frames_count: 0,
+            address: std::ptr::null_mut(),
+            frames: [std::ptr::null_mut(); CRASH_MAX_FRAMES],
+        }
+    }
+}
+impl CrashInfo {
+    /// The frames symbolized, with the symbol and the library of each.
+    fn backtrace(&self) -> String {
+        let mut out = String::new();
+        let count = (self.frames_count.max(0) as usize).min(CRASH_MAX_FRAMES);
+        for (i, frame) in self.frames[..count].iter().enumerate() {
+            let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+            // Frames are return addresses, look up the call before them.
+            let found =
+                unsafe { libc::dladdr((*frame as *const u8).wrapping_sub(1).cast(), &mut info) };
+            let name = |ptr: *const libc::c_char| match ptr.is_null() {
+                true => None,
+                false => Some(unsafe { CStr::from_ptr(ptr) }.to_string_lossy()),
+            };
+            write!(out, "  {:2}: {:p}", i, *frame).unwrap();
+            if found != 0 {
+                if let Some(symbol) = name(info.dli_sname) {
+                    let offset = *frame as usize - info.dli_saddr as usize;
+                    write!(out, " {}+0x{:x}", symbol, offset).unwrap();
+                }
+                if let Some(file) = name(info.dli_fname) {
+                    write!(out, " ({})", file).unwrap();
+                }
+            }
+            writeln!(out).unwrap();
+        }
+        out
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CrashReport {
+    pub signal: i32,
+    pub address: u64,
+    pub sim_id: u64,
+    pub process_id: u64,
+    pub script: String,
+    pub backtrace: String,
+}
+impl std::fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "[crash] {} at 0x{:x} in VM (sim: {}, pid: {}, script: {})",
+            signal_name(self.signal),
+            self.address,
+            self.sim_id,
+            self.process_id,
+            self.script
+        )?;
+        write!(f, "{}", self.backtrace)
+    }
+}
+
+/// Installs the SIGSEGV/SIGBUS/SIGABRT/SIGILL/SIGFPE handler. Crashes inside a
+/// simulated VM are then reported and the VM exits with
+/// `SIMULATOR_CRASH_EXIT_CODE` instead of taking down the whole process.
+pub fn install_crash_handler() {
+    if unsafe { simulator_internal_install_crash_handler() } != 0 {
+        panic!("cannot install crash handler");
+    }
+}
+
+/// Returns every crash reported so far by every simulation, in the order
+/// they happened. `Simulation::crash_reports` has the ones of a simulation.
+pub fn crash_reports() -> Vec<CrashReport> {
+    GlobalData::locked().crash_reports().to_vec()
+}
+
+struct ProtectedCall<F> {
+    func: Option<F>,
+    panic: Option<Box<dyn std::any::Any + Send>>,
+}
+
+extern "C" fn protected_trampoline<F: FnOnce() -> i8>(arg: *mut c_void) -> i8 {
+    let call = unsafe { &mut *(arg as *mut ProtectedCall<F>) };
+    let func = call.func.take().expect("protected call");
+    match catch_unwind(AssertUnwindSafe(func)) {
+        Ok(code) => code,
+        Err(panic) => {
+            call.panic = Some(panic);
+            0
+        }
+    }
+}
+
+/// Runs a VM entry on the current thread. When the crash handler is enabled,
+/// a crash is contained and turned into `SIMULATOR_CRASH_EXIT_CODE`.
+pub fn run_protected<F: FnOnce() -> i8>(enabled: bool, func: F) -> i8 {
+    if !enabled {
+        return func();
+    }
+    install_crash_handler();
+
+    let mut call = ProtectedCall {
+        func: Some(func),
+        panic: None,
+    };
+    let mut result = 0;
+    let mut crash = CrashInfo::default();
+    let sig = unsafe {
+        simulator_internal_protected_call(
+            protected_trampoline::<F>,
+            &mut call as *mut ProtectedCall<F> as *mut c_void,
+            &mut result,
+            &mut crash,
+        )
+    };
+    if let Some(panic) = call.panic {
+        resume_unwind(panic);
+    }
+    match sig {
+        0 => result,
+        -1 => panic!("cannot setup alternate signal stack"),
+        _ => {
+            report_crash(&crash, None);
+            SIMULATOR_CRASH_EXIT_CODE
+        }
+    }
+}
+
+/// Forks the process running an executable contract. The child returns and
+/// runs the contract, the parent never returns: it waits for the child,
+/// reports its crash if any, and exits with its exit code.
+pub(crate) fn supervise() {
+    install_crash_handler();
+    let mut fds = [0 as c_int; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        panic!("cannot create crash report pipe");
+    }
+    // Both processes would write what is still buffered.
+    let _ = std::io::Write::flush(&mut std::io::stdout());
+    unsafe { libc::fflush(std::ptr::null_mut()) };
+
+    let child = unsafe { libc::fork() };
+    if child == -1 {
+        panic!("cannot fork crash supervisor");
+    }
+    if child == 0 {
+        unsafe {
+            libc::close(fds[0]);
+            simulator_internal_set_crash_report_fd(fds[1]);
+        }
+        return;
+    }
+
+    unsafe { libc::close(fds[1]) };
+    let mut crash = CrashInfo::default();
+    let size = std::mem::size_of::<CrashInfo>();
+    let read = unsafe { libc::read(fds[0], &mut crash as *mut CrashInfo as *mut c_void, size) };
+    let mut status = 0;
+    while unsafe { libc::waitpid(child, &mut status, 0) } == -1 {}
+    let code = if libc::WIFSIGNALED(status) {
+        // Killed before the handler wrote anything.
+        if read != size as isize {
+            crash = CrashInfo {
+                signal: libc::WTERMSIG(status),
+                ..Default::default()
+            };
+        }
+        let script = std::env::current_exe().map(|p| p.display().to_string());
+        report_crash(&crash, script.ok());
+        SIMULATOR_CRASH_EXIT_CODE
+    } else {
+        libc::WEXITSTATUS(status) as i8
+    };
+    unsafe { libc::_exit(code as u8 as c_int) };
+}
+
+fn signal_name(sig: i32) -> String {
+    match sig {
+        libc::SIGSEGV => "SIGSEGV".to_string(),
+        libc::SIGBUS => "SIGBUS".to_string(),
+        libc::SIGABRT => "SIGABRT".to_string(),
+        libc::SIGILL => "SIGILL".to_string(),
+        libc::SIGFPE => "SIGFPE".to_string(),
+        _ => format!("signal {}", sig),
+    }
+}
+
+/// Reports a crash of the VM of the current thread.
+fn report_crash(crash: &CrashInfo, script: Option<String>) {
+    let sim_id = SimContext::ctx_id();
+    let pid = SimContext::pid();
+
+    let mut report = CrashReport {
+        signal: crash.signal,
+        address: crash.address as u64,
+        sim_id: sim_id.clone().into(),
+        process_id: pid.clone().into(),
+        script: script.unwrap_or_else(|| "<unknown>".to_string()),
+        backtrace: crash.backtrace(),
+    };
+    // The crashed VM may have left the lock held, never block on it.
+    if let Ok(mut global_data) = GlobalData::get().try_lock() {
+        if let Some(script) = global_data.try_get_tx(&sim_id).and_then(|t| t.script(&pid)) {
+            report.script = script;
+        }
+        global_data.push_crash_report(report.clone());
+        if let Some(sim_ctx) = global_data.try_get_tx_mut(&sim_id) {
+            sim_ctx.push_crash_report(report.clone());
+        }
+    }
+    eprintln!("{}", report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RunningSetup, Simulation};
+
+    fn crashing_simulation() -> Simulation {
+        let setup = RunningSetup {
+            crash_handler: true,
+            ..Default::default()
+        };
+        Simulation::new(Default::default(), setup)
+    }
+
+    #[test]
+    fn crashes_are_contained_and_reported() {
+        let sim = crashing_simulation();
+        let code = sim.run_root("segfaulting".to_string(), |_, _| {
+            unsafe { libc::raise(libc::SIGSEGV) };
+            0
+        });
+        assert_eq!(code, SIMULATOR_CRASH_EXIT_CODE);
+        let code = sim.run_root("aborting".to_string(), |_, _| std::process::abort());
+        assert_eq!(code, SIMULATOR_CRASH_EXIT_CODE);
+
+        let reports = sim.crash_reports();
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|r| r.sim_id == sim.id()));
+        assert_eq!(reports[0].signal, libc::SIGSEGV);
+        assert_eq!(reports[0].script, "segfaulting");
+        assert_eq!(reports[1].signal, libc::SIGABRT);
+        assert_eq!(reports[1].script, "aborting");
+        assert!(reports.iter().all(|r| r.process_id == 0));
+        assert!(reports[1].backtrace.contains("abort"));
+    }
+
+    #[test]
+    fn runs_without_crash_return_their_code() {
+        let sim = crashing_simulation();
+        assert_eq!(sim.run_root("fine".to_string(), |_, _| 7), 7);
+        assert!(sim.crash_reports().is_empty());
+    }
+
+    fn alt_stack_disabled() -> bool {
+        let mut ss: libc::stack_t = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::sigaltstack(std::ptr::null(), &mut ss) }, 0);
+        ss.ss_flags & libc::SS_DISABLE != 0
+    }
+
+    #[test]
+    fn alternate_stacks_are_released_after_each_call() {
+        for i in 0..512 {
+            let thread = std::thread::spawn(move || {
+                let code = run_protected(true, || {
+                    assert!(!alt_stack_disabled());
+                    // Nested calls share the stack of the outermost one.
+                    assert_eq!(run_protected(true, || 3), 3);
+                    assert!(!alt_stack_disabled());
+                    if i % 2 == 0 {
+                        unsafe { libc::raise(libc::SIGSEGV) };
+                    }
+                    5
+                });
+                (code, alt_stack_disabled())
+            });
+            let expected = if i % 2 == 0 {
+                SIMULATOR_CRASH_EXIT_CODE
+            } else {
+                5
+            };
+            assert_eq!(thread.join().unwrap(), (expected, true));
+        }
+    }
+
+    /// Exit status of a process running `contract` supervised.
+    fn supervised_status(contract: fn() -> !) -> c_int {
+        let child = unsafe { libc::fork() };
+        if child == 0 {
+            supervise();
+            contract();
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+        status
+    }
+
+    #[test]
+    fn supervisor_reports_crash_of_executable() {
+        let status = supervised_status(|| std::process::abort());
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(
+            libc::WEXITSTATUS(status),
+            SIMULATOR_CRASH_EXIT_CODE as u8 as c_int
+        );
+
+        let status = supervised_status(|| unsafe { libc::_exit(3) });
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 3);
+    }
+}
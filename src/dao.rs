@@ -0,0 +1,479 @@
+//! Native Nervos DAO type script, following dao.c of ckb-system-scripts:
+//!
+//! * deposit: an output with 8 zero bytes of data;
+//! * withdraw phase 1: a deposited input turns into an output at the same
+//!   index, same capacity, whose data is the deposit block number;
+//! * withdraw phase 2: a withdrawing input is unlocked once its since reaches
+//!   the end of the lock period, and may release up to its maximum withdraw
+//!   capacity computed from the accumulated rates of both headers.
+use crate::{
+    constants::{
+        CELL_FIELD_CAPACITY, CELL_FIELD_OCCUPIED_CAPACITY, CELL_FIELD_TYPE_HASH, CKB_ITEM_MISSING,
+        INPUT_FIELD_SINCE, SOURCE_HEADER_DEP, SOURCE_INPUT, SOURCE_OUTPUT,
+    },
+    headers::DaoField,
+    system_scripts::{count_cells, exit_code, load, load_witness},
+};
+use ckb_types::{
+    core::{Capacity, EpochNumberWithFraction, HeaderView},
+    h256,
+    packed::{self, Byte32, WitnessArgs},
+    prelude::*,
+    H256,
+};
+
+pub const DAO_TYPE_HASH: H256 =
+    h256!("0x82d76d1b75fe2fd9a27dfbaa65a039221a380d76c926f378d3f81cf3e7e13f2e");
+
+/// Deposits are locked by periods of 180 epochs, about 30 days.
+pub const LOCK_PERIOD_EPOCHS: u64 = 180;
+pub const MAX_OUTPUT_CELLS: u64 = 64;
+
+const SINCE_ABSOLUTE_EPOCH_FLAG: u64 = 0x20;
+
+const ERROR_SYSCALL: i8 = -4;
+const ERROR_ENCODING: i8 = -11;
+const ERROR_OVERFLOW: i8 = -13;
+const ERROR_INVALID_WITHDRAW_BLOCK: i8 = -14;
+const ERROR_INCORRECT_CAPACITY: i8 = -15;
+const ERROR_INCORRECT_EPOCH: i8 = -16;
+const ERROR_INCORRECT_SINCE: i8 = -17;
+const ERROR_TOO_MANY_OUTPUT_CELLS: i8 = -18;
+const ERROR_INVALID_WITHDRAWING_CELL: i8 = -20;
+
+/// Capacity a withdrawing cell can release: its occupied capacity plus the
+/// rest grown by the accumulated rate between the deposit and withdrawing
+/// blocks.
+pub fn calculate_maximum_withdraw(
+    capacity: Capacity,
+    occupied_capacity: Capacity,
+    deposit_header_dao: &Byte32,
+    withdrawing_header_dao: &Byte32,
+) -> Option<Capacity> {
+    let deposit_ar = DaoField::unpack(deposit_header_dao).ar;
+    let withdrawing_ar = DaoField::unpack(withdrawing_header_dao).ar;
+    let counted_capacity = capacity.safe_sub(occupied_capacity).ok()?;
+    let withdraw_counted_capacity =
+        u128::from(counted_capacity.as_u64()) * u128::from(withdrawing_ar) / u128::from(deposit_ar);
+    Capacity::shannons(u64::try_from(withdraw_counted_capacity).ok()?)
+        .safe_add(occupied_capacity)
+        .ok()
+}
+
+/// The earliest epoch a withdrawing cell can be spent at: the deposit epoch
+/// plus enough whole lock periods to cover the time until withdrawing. None
+/// when withdrawing comes before the deposit.
+pub fn minimal_since_epoch(
+    deposit_epoch: EpochNumberWithFraction,
+    withdrawing_epoch: EpochNumberWithFraction,
+) -> Option<EpochNumberWithFraction> {
+    let mut deposited_epochs = withdrawing_epoch
+        .number()
+        .checked_sub(deposit_epoch.number())?;
+    if withdrawing_epoch.index() * deposit_epoch.length()
+        > deposit_epoch.index() * withdrawing_epoch.length()
+    {
+        deposited_epochs += 1;
+    }
+    let lock_epochs = deposited_epochs.div_ceil(LOCK_PERIOD_EPOCHS) * LOCK_PERIOD_EPOCHS;
+    Some(EpochNumberWithFraction::new(
+        deposit_epoch.number() + lock_epochs,
+        deposit_epoch.index(),
+        deposit_epoch.length(),
+    ))
+}
+
+fn load_u64(index: u64, source: u64, field: u64) -> Result<u64, i8> {
+    let data = load(|ptr, len, offset| {
+        crate::ckb_load_cell_by_field(ptr, len, offset, index, source, field)
+    })
+    .map_err(|_| ERROR_SYSCALL)?;
+    Ok(u64::from_le_bytes(
+        data.try_into().map_err(|_| ERROR_ENCODING)?,
+    ))
+}
+
+fn load_type_hash(index: u64, source: u64) -> Result<Option<Vec<u8>>, i8> {
+    match load(|ptr, len, offset| {
+        crate::ckb_load_cell_by_field(ptr, len, offset, index, source, CELL_FIELD_TYPE_HASH)
+    }) {
+        Ok(hash) => Ok(Some(hash)),
+        Err(CKB_ITEM_MISSING) => Ok(None),
+        Err(_) => Err(ERROR_SYSCALL),
+    }
+}
+
+/// Returns the block number stored in a DAO cell's data.
+fn load_dao_data(index: u64, source: u64) -> Result<u64, i8> {
+    let data = load(|ptr, len, offset| crate::ckb_load_cell_data(ptr, len, offset, index, source))
+        .map_err(|_| ERROR_SYSCALL)?;
+    Ok(u64::from_le_bytes(
+        data.try_into().map_err(|_| ERROR_ENCODING)?,
+    ))
+}
+
+fn load_header(index: u64, source: u64) -> Result<HeaderView, i8> {
+    let header = load(|ptr, len, offset| crate::ckb_load_header(ptr, len, offset, index, source))
+        .map_err(|_| ERROR_SYSCALL)?;
+    Ok(packed::Header::from_slice(&header)
+        .map_err(|_| ERROR_ENCODING)?
+        .into_view())
+}
+
+/// Validates phase 2 of input `index` and returns its maximum withdraw.
+fn withdraw(index: u64, deposit_block_number: u64) -> Result<Capacity, i8> {
+    let witness = load_witness(index, SOURCE_INPUT)
+        .map_err(|_| ERROR_SYSCALL)?
+        .ok_or(ERROR_INVALID_WITHDRAW_BLOCK)?;
+    let header_dep_index: [u8; 8] = WitnessArgs::from_slice(&witness)
+        .map_err(|_| ERROR_ENCODING)?
+        .input_type()
+        .to_opt()
+        .ok_or(ERROR_INVALID_WITHDRAW_BLOCK)?
+        .raw_data()
+        .as_ref()
+        .try_into()
+        .map_err(|_| ERROR_INVALID_WITHDRAW_BLOCK)?;
+    let deposit_header = load_header(u64::from_le_bytes(header_dep_index), SOURCE_HEADER_DEP)?;
+    if deposit_header.number() != deposit_block_number {
+        return Err(ERROR_INVALID_WITHDRAW_BLOCK);
+    }
+    let withdrawing_header = load_header(index, SOURCE_INPUT)?;
+
+    let since = load(|ptr, len, offset| {
+        crate::ckb_load_input_by_field(ptr, len, offset, index, SOURCE_INPUT, INPUT_FIELD_SINCE)
+    })
+    .map_err(|_| ERROR_SYSCALL)?;
+    let since = u64::from_le_bytes(since.try_into().map_err(|_| ERROR_ENCODING)?);
+    if since >> 56 != SINCE_ABSOLUTE_EPOCH_FLAG {
+        return Err(ERROR_INCORRECT_SINCE);
+    }
+    let since_epoch = EpochNumberWithFraction::from_full_value(since & 0x00ff_ffff_ffff_ffff);
+    let minimal = minimal_since_epoch(deposit_header.epoch(), withdrawing_header.epoch())
+        .ok_or(ERROR_INCORRECT_EPOCH)?;
+    if since_epoch.to_rational() < minimal.to_rational() {
+        return Err(ERROR_INCORRECT_EPOCH);
+    }
+
+    calculate_maximum_withdraw(
+        Capacity::shannons(load_u64(index, SOURCE_INPUT, CELL_FIELD_CAPACITY)?),
+        Capacity::shannons(load_u64(index, SOURCE_INPUT, CELL_FIELD_OCCUPIED_CAPACITY)?),
+        &deposit_header.dao(),
+        &withdrawing_header.dao(),
+    )
+    .ok_or(ERROR_OVERFLOW)
+}
+
+/// Validates phase 1: output `index` must come from a deposited input at the
+/// same index, keep its capacity and record its block number.
+fn check_withdrawing_cell(
+    index: u64,
+    capacity: u64,
+    deposit_block_number: u64,
+    script_hash: &[u8],
+) -> Result<(), i8> {
+    let input_type_hash =
+        load_type_hash(index, SOURCE_INPUT).map_err(|_| ERROR_INVALID_WITHDRAWING_CELL)?;
+    if input_type_hash.as_deref() != Some(script_hash) || load_dao_data(index, SOURCE_INPUT)? != 0 {
+        return Err(ERROR_INVALID_WITHDRAWING_CELL);
+    }
+    if load_u64(index, SOURCE_INPUT, CELL_FIELD_CAPACITY)? != capacity {
+        return Err(ERROR_INCORRECT_CAPACITY);
+    }
+    if load_header(index, SOURCE_INPUT)?.number() != deposit_block_number {
+        return Err(ERROR_INVALID_WITHDRAW_BLOCK);
+    }
+    Ok(())
+}
+
+pub(crate) fn dao() -> i8 {
+    exit_code((|| {
+        let script_hash = load(|ptr, len, offset| crate::ckb_load_script_hash(ptr, len, offset))
+            .map_err(|_| ERROR_SYSCALL)?;
+
+        let mut input_capacities = Capacity::zero();
+        for index in 0..count_cells(SOURCE_INPUT).map_err(|_| ERROR_SYSCALL)? as u64 {
+            let mut capacity =
+                Capacity::shannons(load_u64(index, SOURCE_INPUT, CELL_FIELD_CAPACITY)?);
+            if load_type_hash(index, SOURCE_INPUT)?.as_ref() == Some(&script_hash) {
+                let deposit_block_number = load_dao_data(index, SOURCE_INPUT)?;
+                if deposit_block_number != 0 {
+                    capacity = withdraw(index, deposit_block_number)?;
+                }
+            }
+            input_capacities = input_capacities
+                .safe_add(capacity)
+                .map_err(|_| ERROR_OVERFLOW)?;
+        }
+
+        let outputs = count_cells(SOURCE_OUTPUT).map_err(|_| ERROR_SYSCALL)? as u64;
+        if outputs > MAX_OUTPUT_CELLS {
+            return Err(ERROR_TOO_MANY_OUTPUT_CELLS);
+        }
+        let mut output_capacities = Capacity::zero();
+        for index in 0..outputs {
+            let capacity = load_u64(index, SOURCE_OUTPUT, CELL_FIELD_CAPACITY)?;
+            if load_type_hash(index, SOURCE_OUTPUT)?.as_ref() == Some(&script_hash) {
+                let deposit_block_number = load_dao_data(index, SOURCE_OUTPUT)?;
+                if deposit_block_number != 0 {
+                    check_withdrawing_cell(index, capacity, deposit_block_number, &script_hash)?;
+                }
+            }
+            output_capacities = output_capacities
+                .safe_add(Capacity::shannons(capacity))
+                .map_err(|_| ERROR_OVERFLOW)?;
+        }
+
+        if output_capacities > input_capacities {
+            return Err(ERROR_INCORRECT_CAPACITY);
+        }
+        Ok(())
+    })())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        headers::{add_header_dep, set_input_header, MockHeader},
+        test_utils,
+        verify::{run_group, script_groups},
+        RunningSetup,
+    };
+    use ckb_mock_tx_types::MockTransaction;
+    use ckb_types::{
+        bytes::Bytes,
+        core::ScriptHashType,
+        packed::{CellInput, CellOutput, OutPoint, Script},
+    };
+
+    const DEPOSIT_AR: u64 = 10_000_000_000_123_456;
+    const WITHDRAWING_AR: u64 = 10_000_000_001_123_456;
+    const DEPOSIT_CAPACITY: u64 = 100_000_000_000;
+
+    fn header(number: u64, epoch: EpochNumberWithFraction, ar: u64) -> HeaderView {
+        MockHeader {
+            number,
+            epoch,
+            dao: DaoField {
+                ar,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .build()
+    }
+
+    fn deposit_header() -> HeaderView {
+        header(100, EpochNumberWithFraction::new(5, 100, 1000), DEPOSIT_AR)
+    }
+
+    fn withdrawing_header() -> HeaderView {
+        header(
+            200,
+            EpochNumberWithFraction::new(100, 200, 1000),
+            WITHDRAWING_AR,
+        )
+    }
+
+    fn cell(capacity: u64, dao: bool) -> CellOutput {
+        let dao_type = Script::new_builder()
+            .code_hash(DAO_TYPE_HASH.pack())
+            .hash_type(ScriptHashType::Type.into())
+            .build();
+        let lock = test_utils::sample_tx().mock_info.inputs[0].output.lock();
+        CellOutput::new_builder()
+            .capacity(capacity.pack())
+            .lock(lock)
+            .type_(dao.then_some(dao_type).pack())
+            .build()
+    }
+
+    /// A transaction spending a DAO cell holding `input_data`, committed in
+    /// `input_header`, into `output` holding `output_data`.
+    fn dao_tx(
+        input_data: u64,
+        input_header: &HeaderView,
+        since: u64,
+        output: CellOutput,
+        output_data: u64,
+    ) -> MockTransaction {
+        let mut tx = test_utils::sample_tx();
+        let input = CellInput::new(OutPoint::new([3u8; 32].pack(), 0), since);
+        tx.mock_info.inputs[0].input = input.clone();
+        tx.mock_info.inputs[0].output = cell(DEPOSIT_CAPACITY, true);
+        tx.mock_info.inputs[0].data = Bytes::copy_from_slice(&input_data.to_le_bytes());
+        tx.mock_info.header_deps.clear();
+        let raw = tx
+            .tx
+            .raw()
+            .as_builder()
+            .inputs(vec![input].pack())
+            .outputs(vec![output].pack())
+            .outputs_data(vec![Bytes::copy_from_slice(&output_data.to_le_bytes()).pack()].pack())
+            .header_deps(Default::default())
+            .build();
+        tx.tx = tx
+            .tx
+            .as_builder()
+            .raw(raw)
+            .witnesses(Default::default())
+            .build();
+        set_input_header(&mut tx, 0, input_header);
+        tx
+    }
+
+    /// Phase 2 of a deposit of `deposit_header()`, the witness pointing at
+    /// header dep `deposit_index`.
+    fn withdraw_tx(since: u64, output_capacity: u64, deposit_index: u64) -> MockTransaction {
+        withdraw_tx_from(&deposit_header(), since, output_capacity, deposit_index)
+    }
+
+    fn withdraw_tx_from(
+        deposit: &HeaderView,
+        since: u64,
+        output_capacity: u64,
+        deposit_index: u64,
+    ) -> MockTransaction {
+        let output = cell(output_capacity, false);
+        let mut tx = dao_tx(100, &withdrawing_header(), since, output, 0);
+        add_header_dep(&mut tx, deposit);
+        let witness = WitnessArgs::new_builder()
+            .input_type(Some(Bytes::copy_from_slice(&deposit_index.to_le_bytes())).pack())
+            .build();
+        tx.tx = tx
+            .tx
+            .as_builder()
+            .witnesses(vec![witness.as_bytes().pack()].pack())
+            .build();
+        tx
+    }
+
+    fn run(tx: &MockTransaction) -> i8 {
+        let group = script_groups(tx)
+            .into_iter()
+            .find(|g| g.script.code_hash() == DAO_TYPE_HASH.pack())
+            .expect("dao group");
+        run_group(tx, &RunningSetup::default(), &group).unwrap()
+    }
+
+    fn epoch_since(epoch: EpochNumberWithFraction) -> u64 {
+        (SINCE_ABSOLUTE_EPOCH_FLAG << 56) | epoch.full_value()
+    }
+
+    /// `check_withdraw_calculation` of ckb's DaoCalculator tests.
+    #[test]
+    fn maximum_withdraw_matches_ckb() {
+        let dao = |ar| {
+            DaoField {
+                ar,
+                ..Default::default()
+            }
+            .pack()
+        };
+        // 1,000,000 CKB in a cell with the default lock and 10 bytes of data.
+        let withdraw = calculate_maximum_withdraw(
+            Capacity::shannons(100_000_000_000_000),
+            Capacity::bytes(51).unwrap(),
+            &dao(DEPOSIT_AR),
+            &dao(WITHDRAWING_AR),
+        );
+        assert_eq!(withdraw, Some(Capacity::shannons(100_000_000_009_999)));
+
+        // `check_withdraw_calculation_overflows`.
+        let withdraw = calculate_maximum_withdraw(
+            Capacity::shannons(18_446_744_073_709_550_000),
+            Capacity::bytes(41).unwrap(),
+            &dao(DEPOSIT_AR),
+            &dao(WITHDRAWING_AR),
+        );
+        assert_eq!(withdraw, None);
+    }
+
+    #[test]
+    fn minimal_since_covers_whole_lock_periods() {
+        let epoch = EpochNumberWithFraction::new;
+        let deposit = epoch(5, 100, 1000);
+        assert_eq!(
+            minimal_since_epoch(deposit, epoch(100, 200, 1000)),
+            Some(epoch(185, 100, 1000))
+        );
+        assert_eq!(
+            minimal_since_epoch(deposit, epoch(185, 100, 1000)),
+            Some(epoch(185, 100, 1000)),
+            "a withdraw at the end of a period needs no other"
+        );
+        assert_eq!(
+            minimal_since_epoch(deposit, epoch(185, 101, 1000)),
+            Some(epoch(365, 100, 1000))
+        );
+        assert_eq!(
+            minimal_since_epoch(deposit, epoch(185, 51, 500)),
+            Some(epoch(365, 100, 1000)),
+            "fractions of different lengths are compared"
+        );
+        assert_eq!(minimal_since_epoch(deposit, epoch(4, 900, 1000)), None);
+    }
+
+    #[test]
+    fn withdrawing_keeps_the_deposit() {
+        let deposit = deposit_header();
+        let withdrawing = |capacity, block_number| {
+            let output = cell(capacity, true);
+            run(&dao_tx(0, &deposit, 0, output, block_number))
+        };
+        assert_eq!(withdrawing(DEPOSIT_CAPACITY, 100), 0);
+        assert_eq!(
+            withdrawing(DEPOSIT_CAPACITY - 1, 100),
+            ERROR_INCORRECT_CAPACITY
+        );
+        assert_eq!(
+            withdrawing(DEPOSIT_CAPACITY, 101),
+            ERROR_INVALID_WITHDRAW_BLOCK
+        );
+    }
+
+    #[test]
+    fn withdraw_releases_up_to_the_maximum_once_unlocked() {
+        let occupied = cell(DEPOSIT_CAPACITY, true)
+            .occupied_capacity(Capacity::bytes(8).unwrap())
+            .unwrap();
+        let maximum = calculate_maximum_withdraw(
+            Capacity::shannons(DEPOSIT_CAPACITY),
+            occupied,
+            &deposit_header().dao(),
+            &withdrawing_header().dao(),
+        )
+        .unwrap()
+        .as_u64();
+        assert!(maximum > DEPOSIT_CAPACITY);
+
+        let unlocked = epoch_since(EpochNumberWithFraction::new(185, 100, 1000));
+        assert_eq!(run(&withdraw_tx(unlocked, maximum, 1)), 0);
+        assert_eq!(
+            run(&withdraw_tx(unlocked, maximum + 1, 1)),
+            ERROR_INCORRECT_CAPACITY
+        );
+        assert_eq!(
+            run(&withdraw_tx(unlocked, maximum, 0)),
+            ERROR_INVALID_WITHDRAW_BLOCK,
+            "the witness points at the withdrawing header"
+        );
+        let early = epoch_since(EpochNumberWithFraction::new(185, 99, 1000));
+        assert_eq!(run(&withdraw_tx(early, maximum, 1)), ERROR_INCORRECT_EPOCH);
+        let relative = unlocked | (1 << 63);
+        assert_eq!(
+            run(&withdraw_tx(relative, maximum, 1)),
+            ERROR_INCORRECT_SINCE
+        );
+    }
+
+    #[test]
+    fn withdraw_before_the_deposit_is_rejected() {
+        // A deposit header with the expected number, in a later epoch.
+        let deposit = header(100, EpochNumberWithFraction::new(150, 0, 1000), DEPOSIT_AR);
+        let unlocked = epoch_since(EpochNumberWithFraction::new(185, 100, 1000));
+        let tx = withdraw_tx_from(&deposit, unlocked, DEPOSIT_CAPACITY, 1);
+        assert_eq!(run(&tx), ERROR_INCORRECT_EPOCH);
+    }
+}
@@ -0,0 +1,269 @@
+//! Fault injection: syscalls matching a fault of the setup fail or return
+//! altered data, so contracts can be tested against results that are legal
+//! on chain but rare, like short reads or the spawn limit.
+//!
+//! ```json
+//! "faults": [
+//!     { "syscall": "ckb_load_witness", "args": { "index": 1 }, "return_code": 1 },
+//!     { "syscall": "ckb_spawn_cell", "occurrence": 2, "return_code": 8 },
+//!     { "syscall": "ckb_read", "pid": 1, "length": 3 }
+//! ]
+//! ```
+//!
+//! Every injected fault is written to the debug output.
+use crate::{current_setup, get_cur_tx, get_cur_tx_mut, global_data::GlobalData, SimContext};
+use ckb_jsonrpc_types::JsonBytes;
+use ckb_types::bytes::Bytes;
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, os::raw::c_int};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Fault {
+    /// Name of the exported syscall, `ckb_load_witness` for example.
+    pub syscall: String,
+    /// Arguments the call must have, by name: `index`, `source`, `offset`,
+    /// `field`, `fd`, `length`, `hash_type`, `pid`. A name the syscall does
+    /// not take never matches.
+    #[serde(default)]
+    pub args: HashMap<String, u64>,
+    /// Process making the call, any when absent.
+    #[serde(default)]
+    pub pid: Option<u64>,
+    /// Only the nth matching call fails, 1 for the first, every matching
+    /// call when absent.
+    #[serde(default)]
+    pub occurrence: Option<u64>,
+    /// Returned without running the syscall.
+    #[serde(default)]
+    pub return_code: Option<i32>,
+    /// Loaded instead of the real data, or read instead of pipe data.
+    #[serde(default)]
+    pub data: Option<JsonBytes>,
+    /// Loaded data is cut to this length, `ckb_read` and `ckb_write` move
+    /// at most this many bytes.
+    #[serde(default)]
+    pub length: Option<u64>,
+}
+impl Fault {
+    fn matches(&self, syscall: &str, args: &[(&str, u64)], pid: u64) -> bool {
+        self.syscall == syscall
+            && self.pid.is_none_or(|p| p == pid)
+            && self
+                .args
+                .iter()
+                .all(|(name, value)| args.contains(&(name.as_str(), *value)))
+    }
+
+    /// `data` as the faulted syscall returns it.
+    pub fn apply(&self, data: Bytes) -> Bytes {
+        let mut data = match &self.data {
+            Some(bytes) => bytes.clone().into_bytes(),
+            None => data,
+        };
+        if let Some(length) = self.length {
+            data.truncate(length as usize);
+        }
+        data
+    }
+}
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut actions = vec![];
+        if let Some(code) = self.return_code {
+            actions.push(format!("return {}", code));
+        }
+        if let Some(data) = &self.data {
+            actions.push(format!("data {}", serde_json::to_string(data).unwrap()));
+        }
+        if let Some(length) = self.length {
+            actions.push(format!("length {}", length));
+        }
+        if actions.is_empty() {
+            actions.push("none".to_string());
+        }
+        write!(f, "{}", actions.join(", "))
+    }
+}
+
+/// `data` as returned under `fault`, unchanged without one.
+pub fn apply(fault: &Option<Fault>, data: Bytes) -> Bytes {
+    match fault {
+        Some(fault) => fault.apply(data),
+        None => data,
+    }
+}
+
+/// Counts the call of `syscall` against the faults of the current setup.
+/// The first fault firing is logged and returned, or its return code when
+/// it has one.
+pub(crate) fn inject(syscall: &str, args: &[(&str, u64)]) -> Result<Option<Fault>, c_int> {
+    let setup = current_setup();
+    if setup.faults.is_empty() {
+        return Ok(None);
+    }
+    let pid: u64 = SimContext::pid().into();
+    let mut fired = None;
+    for (i, fault) in setup.faults.iter().enumerate() {
+        if !fault.matches(syscall, args, pid) {
+            continue;
+        }
+        let count = get_cur_tx_mut!().count_fault(i);
+        if fired.is_none() && fault.occurrence.is_none_or(|n| n == count) {
+            fired = Some((fault, count));
+        }
+    }
+    let Some((fault, count)) = fired else {
+        return Ok(None);
+    };
+
+    let args: Vec<String> = args
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    let message = format!(
+        "{}({}) pid {}, call {}: {}",
+        syscall,
+        args.join(", "),
+        pid,
+        count,
+        fault
+    );
+    match get_cur_tx!().debug_sink() {
+        Some(sink) => sink(&message),
+        None => println!("[fault] {}", message),
+    }
+    match fault.return_code {
+        Some(code) => Err(code),
+        None => Ok(Some(fault.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ckb_load_witness,
+        constants::{CKB_SUCCESS, SOURCE_INPUT},
+        test_utils, RunningSetup, Simulation,
+    };
+    use std::sync::{Arc, Mutex};
+
+    fn load_witness(index: u64) -> Result<Vec<u8>, c_int> {
+        let mut data = [0u8; 16];
+        let mut len = data.len() as u64;
+        match ckb_load_witness(data.as_mut_ptr().cast(), &mut len, 0, index, SOURCE_INPUT) {
+            CKB_SUCCESS => Ok(data[..len.min(16) as usize].to_vec()),
+            code => Err(code),
+        }
+    }
+
+    /// Loads witness 0 of `sample_tx` four times under `faults`, returns the
+    /// results and the debug messages.
+    fn run_faults(faults: Vec<Fault>) -> (Vec<Result<Vec<u8>, c_int>>, Vec<String>) {
+        let setup = RunningSetup {
+            faults,
+            ..Default::default()
+        };
+        let sim = Simulation::new(test_utils::sample_tx(), setup);
+        let messages = Arc::new(Mutex::new(vec![]));
+        let sink = messages.clone();
+        sim.set_debug_sink(move |m| sink.lock().unwrap().push(m.to_string()));
+        let results = Arc::new(Mutex::new(vec![]));
+        let loaded = results.clone();
+        sim.run_root("faults".to_string(), move |_, _| {
+            let mut loaded = loaded.lock().unwrap();
+            loaded.extend((0..4).map(|_| load_witness(0)));
+            0
+        });
+        let results = results.lock().unwrap().clone();
+        let messages = messages.lock().unwrap().clone();
+        (results, messages)
+    }
+
+    fn witness_fault(args: &[(&str, u64)]) -> Fault {
+        Fault {
+            syscall: "ckb_load_witness".to_string(),
+            args: args.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matching_calls_fail_with_the_return_code() {
+        let fault = Fault {
+            return_code: Some(1),
+            ..witness_fault(&[("index", 0), ("source", SOURCE_INPUT)])
+        };
+        let (results, messages) = run_faults(vec![fault]);
+        assert_eq!(results, vec![Err(1); 4]);
+        assert_eq!(
+            messages[0],
+            "ckb_load_witness(index=0, source=1, offset=0) pid 0, call 1: return 1"
+        );
+        assert_eq!(messages.len(), 4);
+    }
+
+    #[test]
+    fn other_calls_are_not_faulted() {
+        let faults = vec![
+            Fault {
+                return_code: Some(1),
+                ..witness_fault(&[("index", 1)])
+            },
+            Fault {
+                return_code: Some(2),
+                ..witness_fault(&[("fd", 0)])
+            },
+            Fault {
+                pid: Some(1),
+                return_code: Some(3),
+                ..witness_fault(&[])
+            },
+        ];
+        let (results, messages) = run_faults(faults);
+        assert_eq!(results, vec![Ok(vec![9]); 4]);
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn occurrences_count_matching_calls() {
+        let faults = vec![
+            Fault {
+                occurrence: Some(2),
+                return_code: Some(1),
+                ..witness_fault(&[])
+            },
+            Fault {
+                occurrence: Some(3),
+                data: Some(JsonBytes::from_vec(vec![1, 2, 3])),
+                length: Some(2),
+                ..witness_fault(&[])
+            },
+        ];
+        let (results, messages) = run_faults(faults);
+        assert_eq!(
+            results,
+            vec![Ok(vec![9]), Err(1), Ok(vec![1, 2]), Ok(vec![9])]
+        );
+        assert_eq!(messages.len(), 2);
+        assert!(messages[1].ends_with("call 3: data \"0x010203\", length 2"));
+    }
+
+    #[test]
+    fn faults_alter_loaded_data() {
+        let data = Bytes::from(vec![1u8, 2, 3]);
+        let cut = Fault {
+            length: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(cut.apply(data.clone()), Bytes::from(vec![1u8]));
+        assert_eq!(apply(&None, data.clone()), data);
+        let longer = Fault {
+            length: Some(8),
+            data: Some(JsonBytes::from_vec(vec![4, 5])),
+            ..Default::default()
+        };
+        assert_eq!(apply(&Some(longer), data), Bytes::from(vec![4u8, 5]));
+        assert_eq!(Fault::default().to_string(), "none");
+    }
+}
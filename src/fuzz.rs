@@ -0,0 +1,511 @@
+//! In-process fuzzing of one script group: the fuzzer's bytes replace parts
+//! of a base transaction, then the group runs natively in its own
+//! simulation, so every iteration sees fresh data.
+//!
+//! A libFuzzer or AFL++ target only needs a harness built once:
+//!
+//! ```ignore
+//! fuzz_target!(|data: &[u8]| HARNESS.fuzz(data));
+//! ```
+//!
+//! Whole transactions can be fuzzed too, with `fuzz_tx_input` and the
+//! structured mutator of the `mutator` module:
+//!
+//! ```ignore
+//! fuzz_mutator!(|data: &mut [u8], size: usize, max_size: usize, seed: u32| {
+//!     TxMutator::default().mutate_input(data, size, max_size, seed)
+//! });
+//! ```
+//!
+//! Crashes, i.e. panics and signals contained by the crash handler, abort
+//! the process after the mutated transaction is saved as mock tx JSON, in
+//! `fuzz-crashes/` unless the harness says otherwise.
+use crate::{
+    constants::SIMULATOR_CRASH_EXIT_CODE,
+    system_scripts::builtin_script,
+    tx_loader::{decode_mock_tx, encode_mock_tx},
+    tx_verifier::precheck,
+    verify::{native_binary, run_group_in, script_groups, ScriptGroup, VerifyError},
+    RunningSetup, Simulation,
+};
+use ckb_hash::blake2b_256;
+use ckb_mock_tx_types::{MockTransaction, ReprMockTransaction};
+use ckb_types::{
+    bytes::Bytes,
+    packed::{CellOutput, Script},
+    prelude::*,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
+
+/// Where `FuzzHarness` saves crashing transactions by default.
+pub const DEFAULT_CRASH_DIR: &str = "fuzz-crashes";
+
+/// Part of the transaction replaced by fuzzer bytes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Region {
+    Witness(usize),
+    InputData(usize),
+    OutputData(usize),
+    InputLockArgs(usize),
+    InputTypeArgs(usize),
+    OutputLockArgs(usize),
+    OutputTypeArgs(usize),
+}
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Witness(i) => write!(f, "witnesses[{}]", i),
+            Self::InputData(i) => write!(f, "inputs[{}].data", i),
+            Self::OutputData(i) => write!(f, "outputs_data[{}]", i),
+            Self::InputLockArgs(i) => write!(f, "inputs[{}].lock.args", i),
+            Self::InputTypeArgs(i) => write!(f, "inputs[{}].type.args", i),
+            Self::OutputLockArgs(i) => write!(f, "outputs[{}].lock.args", i),
+            Self::OutputTypeArgs(i) => write!(f, "outputs[{}].type.args", i),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FuzzError {
+    /// The region is not in the base transaction.
+    MissingRegion(Region),
+    /// The setup does not point to a script of the base transaction.
+    MissingScript,
+    /// The script group cannot run, like when it has no native binary, or
+    /// the mutated transaction fails a check enabled in the setup.
+    Run(VerifyError),
+    /// A crashing transaction could not be saved, with the file.
+    Save(PathBuf, String),
+}
+impl std::fmt::Display for FuzzError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingRegion(region) => write!(f, "{} is not in the transaction", region),
+            Self::MissingScript => write!(f, "the setup script is not in the transaction"),
+            Self::Run(e) => write!(f, "{}", e),
+            Self::Save(path, e) => write!(f, "save {}: {}", path.display(), e),
+        }
+    }
+}
+impl std::error::Error for FuzzError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FuzzOutcome {
+    /// The script exited, or the mutated transaction no longer has the
+    /// script, with its exit code.
+    Exit(i8),
+    /// The script panicked or crashed, with the panic message or signal.
+    Crash(String),
+}
+
+/// Splits fuzzer bytes between `count` regions: every region but the last
+/// takes a 2-byte little endian length and that many bytes, the last one
+/// takes the rest.
+pub fn split_input(mut data: &[u8], count: usize) -> Vec<&[u8]> {
+    let mut parts = vec![];
+    for _ in 1..count {
+        let len = match data {
+            [a, b, rest @ ..] => {
+                data = rest;
+                u16::from_le_bytes([*a, *b]) as usize
+            }
+            _ => {
+                data = &[];
+                0
+            }
+        };
+        let (part, rest) = data.split_at(len.min(data.len()));
+        parts.push(part);
+        data = rest;
+    }
+    if count > 0 {
+        parts.push(data);
+    }
+    parts
+}
+
+fn with_args(script: Script, args: &[u8]) -> Script {
+    script
+        .as_builder()
+        .args(Bytes::from(args.to_vec()).pack())
+        .build()
+}
+
+fn with_type_args(cell: CellOutput, args: &[u8]) -> Option<CellOutput> {
+    let type_script = with_args(cell.type_().to_opt()?, args);
+    Some(cell.as_builder().type_(Some(type_script).pack()).build())
+}
+
+/// Returns `tx` with `region` replaced by `bytes`, `None` when the region
+/// is not in `tx`.
+pub fn apply_region(
+    tx: &MockTransaction,
+    region: &Region,
+    bytes: &[u8],
+) -> Option<MockTransaction> {
+    let mut tx = tx.clone();
+    let view = tx.tx.clone().into_view();
+    let data = Bytes::from(bytes.to_vec());
+    match region {
+        Region::Witness(i) => {
+            let mut witnesses: Vec<_> = view.witnesses().into_iter().collect();
+            *witnesses.get_mut(*i)? = data.pack();
+            tx.tx = view
+                .as_advanced_builder()
+                .set_witnesses(witnesses)
+                .build()
+                .data();
+        }
+        Region::InputData(i) => tx.mock_info.inputs.get_mut(*i)?.data = data,
+        Region::OutputData(i) => {
+            let mut outputs_data: Vec<_> = view.outputs_data().into_iter().collect();
+            *outputs_data.get_mut(*i)? = data.pack();
+            tx.tx = view
+                .as_advanced_builder()
+                .set_outputs_data(outputs_data)
+                .build()
+                .data();
+        }
+        Region::InputLockArgs(i) => {
+            let input = tx.mock_info.inputs.get_mut(*i)?;
+            let lock = with_args(input.output.lock(), bytes);
+            input.output = input.output.clone().as_builder().lock(lock).build();
+        }
+        Region::InputTypeArgs(i) => {
+            let input = tx.mock_info.inputs.get_mut(*i)?;
+            input.output = with_type_args(input.output.clone(), bytes)?;
+        }
+        Region::OutputLockArgs(i) | Region::OutputTypeArgs(i) => {
+            let mut outputs: Vec<_> = view.outputs().into_iter().collect();
+            let output = outputs.get_mut(*i)?;
+            *output = if matches!(region, Region::OutputLockArgs(_)) {
+                let lock = with_args(output.lock(), bytes);
+                output.clone().as_builder().lock(lock).build()
+            } else {
+                with_type_args(output.clone(), bytes)?
+            };
+            tx.tx = view
+                .as_advanced_builder()
+                .set_outputs(outputs)
+                .build()
+                .data();
+        }
+    }
+    Some(tx)
+}
+
+pub struct FuzzHarness {
+    pub base: MockTransaction,
+    /// Setup of the fuzzed script group, crashes are always contained.
+    pub setup: RunningSetup,
+    pub regions: Vec<Region>,
+    /// Where crashing transactions are saved, as `crash-{hash}.json`,
+    /// `DEFAULT_CRASH_DIR` unless changed.
+    pub crash_dir: PathBuf,
+}
+impl FuzzHarness {
+    pub fn new(
+        base: MockTransaction,
+        setup: RunningSetup,
+        regions: Vec<Region>,
+    ) -> Result<Self, FuzzError> {
+        for region in &regions {
+            if apply_region(&base, region, &[]).is_none() {
+                return Err(FuzzError::MissingRegion(region.clone()));
+            }
+        }
+        let harness = Self {
+            base,
+            setup: RunningSetup {
+                crash_handler: true,
+                ..setup
+            },
+            regions,
+            crash_dir: PathBuf::from(DEFAULT_CRASH_DIR),
+        };
+        let group = harness
+            .target(&harness.base)
+            .ok_or(FuzzError::MissingScript)?;
+        if native_binary(&harness.setup, &group.script).is_none()
+            && builtin_script(&group.script).is_none()
+        {
+            return Err(FuzzError::Run(VerifyError::MissingNativeBinary(
+                group.source(),
+                group.script_hash(),
+            )));
+        }
+        Ok(harness)
+    }
+
+    /// The base transaction with the regions replaced by `data`.
+    pub fn mutate(&self, data: &[u8]) -> MockTransaction {
+        let mut tx = self.base.clone();
+        for (region, bytes) in self
+            .regions
+            .iter()
+            .zip(split_input(data, self.regions.len()))
+        {
+            tx = apply_region(&tx, region, bytes).expect("validated region");
+        }
+        tx
+    }
+
+    fn target(&self, tx: &MockTransaction) -> Option<ScriptGroup> {
+        let cell = if self.setup.is_output {
+            tx.tx
+                .raw()
+                .outputs()
+                .get(self.setup.script_index as usize)?
+        } else {
+            tx.mock_info
+                .inputs
+                .get(self.setup.script_index as usize)?
+                .output
+                .clone()
+        };
+        let script = if self.setup.is_lock_script {
+            cell.lock()
+        } else {
+            cell.type_().to_opt()?
+        };
+        script_groups(tx)
+            .into_iter()
+            .find(|g| g.is_lock_script == self.setup.is_lock_script && g.script == script)
+    }
+
+    /// Runs the script group on the transaction mutated by `data`.
+    pub fn run(&self, data: &[u8]) -> Result<FuzzOutcome, FuzzError> {
+        self.run_tx(self.mutate(data), data)
+    }
+
+    /// The base transaction as a fuzzer input of `run_tx_input`.
+    pub fn seed_input(&self) -> Vec<u8> {
+        encode_mock_tx(&self.base)
+    }
+
+    /// Runs the script group on a whole transaction encoded with
+    /// `encode_mock_tx`, as `TxMutator` mutates them. Inputs that do not
+    /// decode are skipped.
+    pub fn run_tx_input(&self, data: &[u8]) -> Option<Result<FuzzOutcome, FuzzError>> {
+        let tx = decode_mock_tx(data).ok()?;
+        Some(self.run_tx(tx, data))
+    }
+
+    /// Runs the script group on `tx`, naming saved crashes after `data`.
+    pub fn run_tx(&self, tx: MockTransaction, data: &[u8]) -> Result<FuzzOutcome, FuzzError> {
+        let Some(group) = self.target(&tx) else {
+            return Ok(FuzzOutcome::Exit(0));
+        };
+        precheck(&tx, &self.setup).map_err(|e| FuzzError::Run(VerifyError::Invalid(e)))?;
+        let sim = Simulation::new(tx.clone(), group.setup(&self.setup));
+        let run = catch_unwind(AssertUnwindSafe(|| run_group_in(&sim, &self.setup, &group)));
+        let outcome = match run {
+            Ok(Ok(SIMULATOR_CRASH_EXIT_CODE)) if !sim.crash_reports().is_empty() => {
+                // The backtrace is printed when the crash is reported.
+                let report = sim.crash_reports().pop().expect("crash report").to_string();
+                FuzzOutcome::Crash(report.lines().next().unwrap_or_default().to_string())
+            }
+            Ok(Ok(code)) => FuzzOutcome::Exit(code),
+            Ok(Err(e)) => return Err(FuzzError::Run(e)),
+            Err(panic) => FuzzOutcome::Crash(
+                panic
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "panic".to_string()),
+            ),
+        };
+        if let FuzzOutcome::Crash(_) = outcome {
+            self.save(&self.crash_dir, data, &tx)?;
+        }
+        Ok(outcome)
+    }
+
+    fn save(&self, dir: &Path, data: &[u8], tx: &MockTransaction) -> Result<(), FuzzError> {
+        let path = dir.join(format!(
+            "crash-{}.json",
+            faster_hex::hex_string(&blake2b_256(data)[..8])
+        ));
+        let repr: ReprMockTransaction = tx.clone().into();
+        let content = serde_json::to_string_pretty(&repr).expect("serialize mock tx");
+        std::fs::create_dir_all(dir)
+            .and_then(|_| std::fs::write(&path, content))
+            .map_err(|e| FuzzError::Save(path, e.to_string()))
+    }
+
+    /// Fuzzer entry: aborts the process on crashes, so libFuzzer and AFL++
+    /// record the input. Mutations failing a check of the setup are
+    /// skipped, as ckb rejects them before any script runs.
+    pub fn fuzz(&self, data: &[u8]) {
+        abort_on_crash(self.run(data));
+    }
+
+    /// Fuzzer entry for whole transactions, see `run_tx_input`.
+    pub fn fuzz_tx_input(&self, data: &[u8]) {
+        if let Some(result) = self.run_tx_input(data) {
+            abort_on_crash(result);
+        }
+    }
+}
+
+fn abort_on_crash(result: Result<FuzzOutcome, FuzzError>) {
+    match result {
+        Ok(FuzzOutcome::Crash(message)) => eprintln!("[fuzz] crash: {}", message),
+        // The input crashed, only its copy is lost.
+        Err(e @ FuzzError::Save(..)) => eprintln!("[fuzz] crash, {}", e),
+        Ok(FuzzOutcome::Exit(_)) | Err(_) => return,
+    }
+    std::process::abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use ckb_types::core::ScriptHashType;
+
+    /// A harness of the `sample_tx` lock fuzzing its witness and args,
+    /// run by `binary`.
+    fn harness(binary: String) -> FuzzHarness {
+        let setup = RunningSetup {
+            is_lock_script: true,
+            native_binaries: [(
+                test_utils::cell_key(&[1; 32], ScriptHashType::Type.into()),
+                binary,
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let regions = vec![Region::Witness(0), Region::InputLockArgs(0)];
+        FuzzHarness::new(test_utils::sample_tx(), setup, regions).unwrap()
+    }
+
+    #[test]
+    fn splits_input_between_regions() {
+        let data = [2, 0, 1, 2, 3, 4];
+        assert_eq!(split_input(&data, 0), Vec::<&[u8]>::new());
+        assert_eq!(split_input(&data, 1), vec![&data[..]]);
+        assert_eq!(split_input(&data, 2), vec![&[1, 2][..], &[3, 4]]);
+        // Lengths past the end take what is left.
+        assert_eq!(split_input(&[9, 0, 1], 2), vec![&[1][..], &[]]);
+        assert_eq!(split_input(&[9], 3), vec![&[0u8; 0][..], &[], &[]]);
+    }
+
+    #[test]
+    fn replaces_regions() {
+        let tx = test_utils::sample_tx();
+        let witness = apply_region(&tx, &Region::Witness(0), &[5]).unwrap();
+        assert_eq!(witness.tx.witnesses().get(0).unwrap().raw_data(), vec![5]);
+        let args = apply_region(&tx, &Region::InputLockArgs(0), &[6]).unwrap();
+        assert_eq!(
+            args.mock_info.inputs[0].output.lock().args().raw_data(),
+            vec![6]
+        );
+        let data = apply_region(&tx, &Region::OutputData(0), &[]).unwrap();
+        assert!(data.tx.raw().outputs_data().get(0).unwrap().is_empty());
+        assert!(apply_region(&tx, &Region::Witness(1), &[]).is_none());
+        // The sample cells have no type script.
+        assert!(apply_region(&tx, &Region::InputTypeArgs(0), &[]).is_none());
+    }
+
+    #[test]
+    fn harnesses_check_the_base_transaction() {
+        let tx = test_utils::sample_tx();
+        let lock = RunningSetup {
+            is_lock_script: true,
+            ..Default::default()
+        };
+        let missing = |setup: &RunningSetup, regions| {
+            FuzzHarness::new(tx.clone(), setup.clone(), regions).err()
+        };
+        assert_eq!(
+            missing(&lock, vec![Region::OutputData(1)]),
+            Some(FuzzError::MissingRegion(Region::OutputData(1)))
+        );
+        assert_eq!(
+            missing(&RunningSetup::default(), vec![]),
+            Some(FuzzError::MissingScript)
+        );
+        let group = script_groups(&tx).remove(0);
+        assert_eq!(
+            missing(&lock, vec![]),
+            Some(FuzzError::Run(VerifyError::MissingNativeBinary(
+                group.source(),
+                group.script_hash()
+            )))
+        );
+    }
+
+    #[test]
+    fn runs_mutated_transactions() {
+        let harness = harness(test_utils::argv_dylib());
+        assert_eq!(
+            harness
+                .mutate(&[1, 0, 7, 8])
+                .tx
+                .witnesses()
+                .get(0)
+                .unwrap()
+                .raw_data(),
+            vec![7]
+        );
+        assert_eq!(harness.run(&[1, 0, 7, 8]), Ok(FuzzOutcome::Exit(0)));
+        let seed = harness.seed_input();
+        assert_eq!(harness.run_tx_input(&seed), Some(Ok(FuzzOutcome::Exit(0))));
+        assert_eq!(harness.run_tx_input(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn mutated_transactions_are_prechecked() {
+        let mut harness = harness(test_utils::argv_dylib());
+        assert_eq!(harness.run(&[]), Ok(FuzzOutcome::Exit(0)));
+        // The sample output has less capacity than it occupies.
+        harness.setup.check_structure = true;
+        assert!(matches!(
+            harness.run(&[]),
+            Err(FuzzError::Run(VerifyError::Invalid(_)))
+        ));
+    }
+
+    #[test]
+    fn crash_codes_are_crashes_only_with_a_report() {
+        let harness = harness(test_utils::crash_code_dylib());
+        // Another simulation crashing does not make the run a crash.
+        let crashing = Simulation::new(
+            Default::default(),
+            RunningSetup {
+                crash_handler: true,
+                ..Default::default()
+            },
+        );
+        let code = crashing.run_root("aborting".to_string(), |_, _| std::process::abort());
+        assert_eq!(code, SIMULATOR_CRASH_EXIT_CODE);
+        assert_eq!(
+            harness.run(&[]),
+            Ok(FuzzOutcome::Exit(SIMULATOR_CRASH_EXIT_CODE))
+        );
+    }
+
+    #[test]
+    fn crashes_are_saved() {
+        let mut harness = harness(test_utils::abort_dylib());
+        harness.crash_dir = test_utils::temp_path("fuzz-crashes");
+        let outcome = harness.run(&[1, 0, 7]).unwrap();
+        assert!(matches!(outcome, FuzzOutcome::Crash(_)), "{:?}", outcome);
+        let name = format!(
+            "crash-{}.json",
+            faster_hex::hex_string(&blake2b_256([1, 0, 7])[..8])
+        );
+        let saved = std::fs::read_to_string(harness.crash_dir.join(name)).unwrap();
+        let repr: ReprMockTransaction = serde_json::from_str(&saved).unwrap();
+        let tx = MockTransaction::from(repr);
+        assert_eq!(tx.tx.witnesses().get(0).unwrap().raw_data(), vec![7]);
+
+        harness.crash_dir = PathBuf::from(test_utils::temp_file("fuzz-not-a-dir", b""));
+        assert!(matches!(harness.run(&[1, 0, 7]), Err(FuzzError::Save(..))));
+    }
+}
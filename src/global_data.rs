@@ -1,4 +1,4 @@
-use crate::{simulator_context::SimContext, utils::SimID};
+use crate::{crash::CrashReport, simulator_context::SimContext, utils::SimID};
 use std::{
     collections::HashMap,
     ffi::c_void,
@@ -14,6 +14,8 @@ static mut GLOBAL_DATA_PTR: *mut Mutex<GlobalData> = std::ptr::null_mut();
 pub struct GlobalData {
     tx_ctx: HashMap<SimID, SimContext>,
     tx_ctx_id_count: SimID,
+
+    crash_reports: Vec<CrashReport>,
 }
 impl Default for GlobalData {
     fn default() -> Self {
@@ -21,6 +23,7 @@ impl Default for GlobalData {
         Self {
             tx_ctx: [(0.into(), SimContext::default())].into(),
             tx_ctx_id_count: 1.into(),
+            crash_reports: Default::default(),
         }
     }
 }
@@ -68,11 +71,27 @@ impl GlobalData {
             .get(id)
             .unwrap_or_else(|| panic!("unknow tx context: {:?}", id))
     }
+    pub fn remove_tx(&mut self, id: &SimID) -> Option<SimContext> {
+        self.tx_ctx.remove(id)
+    }
+    pub fn try_get_tx(&self, id: &SimID) -> Option<&SimContext> {
+        self.tx_ctx.get(id)
+    }
+    pub fn try_get_tx_mut(&mut self, id: &SimID) -> Option<&mut SimContext> {
+        self.tx_ctx.get_mut(id)
+    }
     pub fn get_tx_mut(&mut self, id: &SimID) -> &mut SimContext {
         self.tx_ctx
             .get_mut(id)
             .unwrap_or_else(|| panic!("unknow mut tx context: {:?}", id))
     }
+
+    pub fn crash_reports(&self) -> &[CrashReport] {
+        &self.crash_reports
+    }
+    pub fn push_crash_report(&mut self, report: CrashReport) {
+        self.crash_reports.push(report);
+    }
 }
 
 #[macro_export]
@@ -0,0 +1,161 @@
+//! Synthesized block headers for mock transactions, with chosen numbers,
+//! epochs, timestamps and DAO fields.
+use ckb_mock_tx_types::MockTransaction;
+use ckb_types::{
+    core::{Capacity, EpochNumberWithFraction, HeaderBuilder, HeaderView},
+    packed::Byte32,
+    prelude::*,
+};
+
+/// Accumulated rate of the genesis block, `AR_0` in the DAO RFC.
+pub const GENESIS_ACCUMULATED_RATE: u64 = 10_000_000_000_000_000;
+
+/// The `dao` field of a header: total issuance `C`, accumulated rate `AR`,
+/// secondary issuance `S` and occupied capacity `U`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DaoField {
+    pub c: Capacity,
+    pub ar: u64,
+    pub s: Capacity,
+    pub u: Capacity,
+}
+impl Default for DaoField {
+    fn default() -> Self {
+        Self {
+            c: Capacity::zero(),
+            ar: GENESIS_ACCUMULATED_RATE,
+            s: Capacity::zero(),
+            u: Capacity::zero(),
+        }
+    }
+}
+impl DaoField {
+    pub fn pack(&self) -> Byte32 {
+        let mut buf = [0u8; 32];
+        buf[0..8].copy_from_slice(&self.c.as_u64().to_le_bytes());
+        buf[8..16].copy_from_slice(&self.ar.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.s.as_u64().to_le_bytes());
+        buf[24..32].copy_from_slice(&self.u.as_u64().to_le_bytes());
+        buf.pack()
+    }
+
+    pub fn unpack(dao: &Byte32) -> Self {
+        let data = dao.raw_data();
+        let field =
+            |i: usize| u64::from_le_bytes(data[i * 8..(i + 1) * 8].try_into().expect("u64"));
+        Self {
+            c: Capacity::shannons(field(0)),
+            ar: field(1),
+            s: Capacity::shannons(field(2)),
+            u: Capacity::shannons(field(3)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MockHeader {
+    pub number: u64,
+    pub epoch: EpochNumberWithFraction,
+    /// Milliseconds since unix epoch.
+    pub timestamp: u64,
+    pub dao: DaoField,
+    pub parent_hash: Byte32,
+}
+impl Default for MockHeader {
+    fn default() -> Self {
+        Self {
+            number: 0,
+            epoch: EpochNumberWithFraction::new(0, 0, 1000),
+            timestamp: 0,
+            dao: DaoField::default(),
+            parent_hash: Byte32::zero(),
+        }
+    }
+}
+impl MockHeader {
+    pub fn build(&self) -> HeaderView {
+        HeaderBuilder::default()
+            .number(self.number.pack())
+            .epoch(self.epoch.pack())
+            .timestamp(self.timestamp.pack())
+            .dao(self.dao.pack())
+            .parent_hash(self.parent_hash.clone())
+            .compact_target(0x2000_0000u32.pack())
+            .build()
+    }
+}
+
+/// Adds `header` to both the mock info and the transaction's header deps.
+pub fn add_header_dep(mock_tx: &mut MockTransaction, header: &HeaderView) {
+    if mock_tx
+        .mock_info
+        .header_deps
+        .iter()
+        .all(|h| h.hash() != header.hash())
+    {
+        mock_tx.mock_info.header_deps.push(header.clone());
+    }
+    if mock_tx
+        .tx
+        .raw()
+        .header_deps()
+        .into_iter()
+        .all(|h| h != header.hash())
+    {
+        let raw = mock_tx.tx.raw();
+        let header_deps = raw.header_deps().as_builder().push(header.hash()).build();
+        mock_tx.tx = mock_tx
+            .tx
+            .clone()
+            .as_builder()
+            .raw(raw.as_builder().header_deps(header_deps).build())
+            .build();
+    }
+}
+
+/// Records `header` as the block committing input `index`, and adds it to
+/// the header deps so the input's header can be loaded.
+pub fn set_input_header(mock_tx: &mut MockTransaction, index: usize, header: &HeaderView) {
+    mock_tx.mock_info.inputs[index].header = Some(header.hash());
+    add_header_dep(mock_tx, header);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dao_field_layout_matches_ckb() {
+        let dao = DaoField {
+            c: Capacity::shannons(1),
+            ar: 2,
+            s: Capacity::shannons(3),
+            u: Capacity::shannons(4),
+        };
+        // `pack_dao_data` of ckb-dao-utils: c, ar, s and u in little endian.
+        let mut expected = [0u8; 32];
+        for (i, value) in [1u64, 2, 3, 4].iter().enumerate() {
+            expected[i * 8..(i + 1) * 8].copy_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(dao.pack().raw_data().as_ref(), expected);
+        assert_eq!(DaoField::unpack(&dao.pack()), dao);
+        assert_eq!(DaoField::default().ar, GENESIS_ACCUMULATED_RATE);
+    }
+
+    #[test]
+    fn input_headers_are_header_deps() {
+        let mut tx = crate::test_utils::sample_tx();
+        let header = MockHeader {
+            number: 9,
+            ..Default::default()
+        }
+        .build();
+        set_input_header(&mut tx, 0, &header);
+        set_input_header(&mut tx, 0, &header);
+        assert_eq!(tx.mock_info.inputs[0].header, Some(header.hash()));
+        assert_eq!(tx.mock_info.header_deps.len(), 2);
+        let header_deps: Vec<_> = tx.tx.raw().header_deps().into_iter().collect();
+        assert_eq!(header_deps.len(), 2);
+        assert_eq!(header_deps[1], header.hash());
+    }
+}
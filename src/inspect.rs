@@ -0,0 +1,304 @@
+//! Pretty-prints a mock transaction the way scripts see it: hashes and
+//! occupied capacities as `ckb_load_cell_by_field` returns them, cell deps
+//! in `SOURCE_CELL_DEP` order and the cells of every script group. Witnesses
+//! and cell data with a bound molecule type are decoded.
+use crate::{molecule::MoleculeBindings, tx_verifier::resolve_cell_deps, verify::script_groups};
+use ckb_mock_tx_types::MockTransaction;
+use ckb_types::{
+    bytes::Bytes,
+    core::{cell::CellMetaBuilder, Capacity, DepType, HeaderView},
+    packed::{Byte32, CellOutput, OutPoint, OutPointVec, Script},
+    prelude::*,
+};
+use std::fmt::Write;
+
+/// Data longer than this is shortened.
+const DATA_PREVIEW_BYTES: usize = 32;
+
+fn hex(data: &[u8]) -> String {
+    if data.len() > DATA_PREVIEW_BYTES {
+        format!(
+            "0x{}... ({} bytes)",
+            faster_hex::hex_string(&data[..DATA_PREVIEW_BYTES]),
+            data.len()
+        )
+    } else {
+        format!("0x{}", faster_hex::hex_string(data))
+    }
+}
+
+fn out_point(out_point: &OutPoint) -> String {
+    format!(
+        "{:#x}:{}",
+        out_point.tx_hash(),
+        Unpack::<u32>::unpack(&out_point.index())
+    )
+}
+
+fn script(script: &Script) -> String {
+    format!(
+        "{:#x} (code_hash {:#x}, hash_type {}, args {})",
+        script.calc_script_hash(),
+        script.code_hash(),
+        Into::<u8>::into(script.hash_type()),
+        hex(&script.args().raw_data())
+    )
+}
+
+/// Writes a decoded value below the line describing its bytes.
+fn write_decoded(out: &mut String, indent: &str, rendered: &str) {
+    let rendered = rendered.replace('\n', &format!("\n{}  ", indent));
+    let _ = writeln!(out, "{}  {}", indent, rendered);
+}
+
+fn write_cell(out: &mut String, cell: &CellOutput, data: &Bytes, molecule: &MoleculeBindings) {
+    let capacity: Capacity = cell.capacity().unpack();
+    let occupied = CellMetaBuilder::from_cell_output(cell.clone(), data.clone())
+        .build()
+        .occupied_capacity()
+        .map(|c| c.as_u64().to_string())
+        .unwrap_or_else(|_| "overflow".to_string());
+    let _ = writeln!(
+        out,
+        "      capacity {} shannons, occupied {}",
+        capacity.as_u64(),
+        occupied
+    );
+    let _ = writeln!(out, "      lock {}", script(&cell.lock()));
+    match cell.type_().to_opt() {
+        Some(type_) => {
+            let _ = writeln!(out, "      type {}", script(&type_));
+        }
+        None => {
+            let _ = writeln!(out, "      type none");
+        }
+    }
+    let _ = writeln!(
+        out,
+        "      data {}, hash {:#x}",
+        hex(data),
+        CellOutput::calc_data_hash(data)
+    );
+    if let Some(type_name) = molecule.cell_data_type(cell) {
+        write_decoded(out, "      ", &molecule.render(type_name, data));
+    }
+}
+
+fn write_header(out: &mut String, index: usize, header: &HeaderView) {
+    let _ = writeln!(
+        out,
+        "  [{}] {:#x} number {}, epoch {} {}/{}, timestamp {}, dao {:#x}",
+        index,
+        header.hash(),
+        header.number(),
+        header.epoch().number(),
+        header.epoch().index(),
+        header.epoch().length(),
+        header.timestamp(),
+        header.dao()
+    );
+}
+
+fn block_hash(header: &Option<Byte32>) -> String {
+    match header {
+        Some(hash) => format!("{:#x}", hash),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Describes every input, output, cell dep, header dep, script group and
+/// witness of `tx`.
+pub fn inspect(tx: &MockTransaction, molecule: &MoleculeBindings) -> String {
+    let mut out = String::new();
+    let raw = tx.tx.raw();
+    let witnesses = tx.tx.witnesses();
+    let _ = writeln!(out, "tx hash {:#x}", tx.tx.calc_tx_hash());
+
+    let _ = writeln!(out, "inputs:");
+    for (i, input) in raw.inputs().into_iter().enumerate() {
+        let since: u64 = input.since().unpack();
+        let _ = writeln!(
+            out,
+            "  [{}] {} since {:#x}",
+            i,
+            out_point(&input.previous_output()),
+            since
+        );
+        match tx.mock_info.inputs.get(i) {
+            Some(mock_input) => {
+                let _ = writeln!(out, "      block {}", block_hash(&mock_input.header));
+                write_cell(&mut out, &mock_input.output, &mock_input.data, molecule);
+            }
+            None => {
+                let _ = writeln!(out, "      <missing from mock info>");
+            }
+        }
+    }
+
+    let _ = writeln!(out, "outputs:");
+    for (i, output) in raw.outputs().into_iter().enumerate() {
+        let _ = writeln!(out, "  [{}]", i);
+        let data = raw
+            .outputs_data()
+            .get(i)
+            .map(|d| d.raw_data())
+            .unwrap_or_default();
+        write_cell(&mut out, &output, &data, molecule);
+    }
+
+    let _ = writeln!(out, "cell deps:");
+    match resolve_cell_deps(tx) {
+        Ok(indices) => {
+            let mut resolved = indices.into_iter().enumerate();
+            for cell_dep in raw.cell_deps() {
+                let mut members = 1;
+                if cell_dep.dep_type() == DepType::DepGroup.into() {
+                    let group = tx
+                        .mock_info
+                        .cell_deps
+                        .iter()
+                        .find(|dep| dep.cell_dep.out_point() == cell_dep.out_point())
+                        .expect("resolved");
+                    members = OutPointVec::from_slice(&group.data)
+                        .expect("resolved")
+                        .len();
+                    let _ = writeln!(
+                        out,
+                        "  dep group {}, block {}",
+                        out_point(&cell_dep.out_point()),
+                        block_hash(&group.header)
+                    );
+                }
+                for (i, index) in resolved.by_ref().take(members) {
+                    let member = &tx.mock_info.cell_deps[index];
+                    let _ = writeln!(
+                        out,
+                        "  [{}] {}, block {}",
+                        i,
+                        out_point(&member.cell_dep.out_point()),
+                        block_hash(&member.header)
+                    );
+                    write_cell(&mut out, &member.output, &member.data, molecule);
+                }
+            }
+        }
+        Err(e) => {
+            let _ = writeln!(out, "  <unresolved: {}>", e);
+        }
+    }
+
+    let _ = writeln!(out, "header deps:");
+    for (i, hash) in raw.header_deps().into_iter().enumerate() {
+        match tx.mock_info.header_deps.iter().find(|h| h.hash() == hash) {
+            Some(header) => write_header(&mut out, i, header),
+            None => {
+                let _ = writeln!(out, "  [{}] {:#x} <missing from mock info>", i, hash);
+            }
+        }
+    }
+
+    let _ = writeln!(out, "script groups:");
+    for group in script_groups(tx) {
+        let kind = if group.is_lock_script { "lock" } else { "type" };
+        let _ = writeln!(
+            out,
+            "  {} {:#x} ({}): inputs {:?}, outputs {:?}",
+            kind,
+            group.script_hash(),
+            group.source(),
+            group.input_indices,
+            group.output_indices
+        );
+    }
+
+    let _ = writeln!(out, "witnesses:");
+    let count = witnesses.len().max(raw.inputs().len());
+    for i in 0..count {
+        match witnesses.get(i) {
+            Some(witness) => {
+                let data = witness.raw_data();
+                let _ = writeln!(out, "  [{}] {} bytes", i, data.len());
+                if let Some(type_name) = molecule.witness_type(i) {
+                    write_decoded(&mut out, "  ", &molecule.render(type_name, &data));
+                }
+            }
+            None => {
+                let _ = writeln!(out, "  [{}] none", i);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use ckb_types::packed::WitnessArgs;
+
+    #[test]
+    fn describes_cells_as_scripts_see_them() {
+        let tx = test_utils::sample_tx();
+        let out = inspect(&tx, &MoleculeBindings::default());
+        let lock = tx.mock_info.inputs[0].output.lock();
+        let header = &tx.mock_info.header_deps[0];
+        for expected in [
+            format!("tx hash {:#x}", tx.tx.calc_tx_hash()),
+            format!("  [0] 0x{}:0 since 0x0", "03".repeat(32)),
+            format!("      block {:#x}", header.hash()),
+            // 8 bytes of capacity, 53 of lock and 3 of data.
+            "      capacity 1000 shannons, occupied 6400000000".to_string(),
+            "      data 0x010203, hash".to_string(),
+            format!("  [0] 0x{}:1, block unknown", "04".repeat(32)),
+            format!(
+                "  [0] {:#x} number 5, epoch 0 5/1000, timestamp 0",
+                header.hash()
+            ),
+            format!(
+                "  lock {:#x} (Inputs[0].Lock): inputs [0], outputs []",
+                lock.calc_script_hash()
+            ),
+            "witnesses:\n  [0] 1 bytes".to_string(),
+        ] {
+            assert!(out.contains(&expected), "{} not in\n{}", expected, out);
+        }
+    }
+
+    #[test]
+    fn decodes_bound_witnesses() {
+        let mut tx = test_utils::sample_tx();
+        let witness = WitnessArgs::new_builder()
+            .lock(Some(Bytes::from(vec![0xabu8; 2])).pack())
+            .build();
+        tx.tx = tx
+            .tx
+            .as_builder()
+            .witnesses(vec![witness.as_bytes().pack()].pack())
+            .build();
+        let molecule = MoleculeBindings {
+            witnesses: [(0, "WitnessArgs".to_string())].into(),
+            ..Default::default()
+        };
+        let out = inspect(&tx, &molecule);
+        let rendered = molecule.render("WitnessArgs", &witness.as_bytes());
+        assert!(rendered.contains("abab"), "{}", rendered);
+        assert!(out.contains(&rendered.replace('\n', "\n    ")), "{}", out);
+    }
+
+    #[test]
+    fn reports_what_the_mock_info_lacks() {
+        let mut tx = test_utils::sample_tx();
+        tx.mock_info.inputs.clear();
+        tx.mock_info.cell_deps.clear();
+        tx.mock_info.header_deps.clear();
+        let out = inspect(&tx, &MoleculeBindings::default());
+        assert!(out.contains("      <missing from mock info>"), "{}", out);
+        assert!(out.contains("  <unresolved: "), "{}", out);
+        let hash = tx.tx.raw().header_deps().get(0).unwrap();
+        assert!(
+            out.contains(&format!("  [0] {:#x} <missing from mock info>", hash)),
+            "{}",
+            out
+        );
+    }
+}
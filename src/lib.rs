@@ -1,10 +1,43 @@
+pub mod capture;
+pub mod chain;
 pub mod constants;
 
+pub mod crash;
+pub use crash::{crash_reports, install_crash_handler, CrashReport};
+
+pub mod dao;
+pub mod faults;
+pub mod fuzz;
+pub mod headers;
+pub mod inspect;
+pub mod mock_child;
+pub mod molecule;
+pub mod mutator;
+
 pub mod spawn;
 pub use spawn::*;
 
+pub mod signer;
+pub mod since;
+
+pub mod preflight;
+pub mod rpc;
+
+pub mod simulation;
+pub use simulation::Simulation;
+
+pub mod syscalls;
+pub mod system_scripts;
+#[cfg(feature = "ckb-testtool")]
+pub mod testtool;
+pub mod tx_loader;
+pub mod tx_verifier;
+pub mod verify;
+
 mod global_data;
 mod simulator_context;
+#[cfg(test)]
+mod test_utils;
 mod utils;
 
 use global_data::GlobalData;
@@ -13,25 +46,30 @@ use simulator_context::SimContext;
 #[macro_use]
 extern crate lazy_static;
 
-use ckb_mock_tx_types::{MockTransaction, ReprMockTransaction};
+use ckb_hash::blake2b_256;
+use ckb_mock_tx_types::{MockCellDep, MockTransaction};
 use ckb_types::{
     bytes::Bytes,
-    core::{cell::CellMetaBuilder, Capacity, HeaderView},
-    packed::{self, Byte32, CellInput, CellOutput, Script},
+    core::{cell::CellMetaBuilder, Capacity, HeaderView, ScriptHashType},
+    packed::{Byte32, CellInput, CellOutput, Script},
     prelude::*,
 };
 use constants::{
     CELL_FIELD_CAPACITY, CELL_FIELD_DATA_HASH, CELL_FIELD_LOCK, CELL_FIELD_LOCK_HASH,
     CELL_FIELD_OCCUPIED_CAPACITY, CELL_FIELD_TYPE, CELL_FIELD_TYPE_HASH, CKB_INDEX_OUT_OF_BOUND,
-    CKB_ITEM_MISSING, CKB_SUCCESS, HEADER_FIELD_EPOCH_LENGTH, HEADER_FIELD_EPOCH_NUMBER,
-    HEADER_FIELD_EPOCH_START_BLOCK_NUMBER, INPUT_FIELD_OUT_POINT, INPUT_FIELD_SINCE,
-    SOURCE_CELL_DEP, SOURCE_GROUP_CELL_DEP, SOURCE_GROUP_HEADER_DEP, SOURCE_GROUP_INPUT,
-    SOURCE_GROUP_OUTPUT, SOURCE_HEADER_DEP, SOURCE_INPUT, SOURCE_OUTPUT,
+    CKB_ITEM_MISSING, CKB_SLICE_OUT_OF_BOUND, CKB_SUCCESS, HEADER_FIELD_EPOCH_LENGTH,
+    HEADER_FIELD_EPOCH_NUMBER, HEADER_FIELD_EPOCH_START_BLOCK_NUMBER, INPUT_FIELD_OUT_POINT,
+    INPUT_FIELD_SINCE, PLACE_CELL_DATA, PLACE_WITNESS, SIMULATOR_MISSING_BINARY_EXIT_CODE,
+    SIMULATOR_MOCK_MISMATCH_EXIT_CODE, SIMULATOR_PRECHECK_EXIT_CODE, SOURCE_CELL_DEP,
+    SOURCE_GROUP_CELL_DEP, SOURCE_GROUP_HEADER_DEP, SOURCE_GROUP_INPUT, SOURCE_GROUP_OUTPUT,
+    SOURCE_HEADER_DEP, SOURCE_INPUT, SOURCE_OUTPUT,
 };
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::ffi::CString;
 use std::os::raw::{c_char, c_int, c_void};
+use std::sync::Arc;
+use syscalls::SyscallResult;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub enum RunningType {
@@ -39,57 +77,136 @@ pub enum RunningType {
     DynamicLib,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct RunningSetup {
     pub is_lock_script: bool,
     pub is_output: bool,
     pub script_index: u64,
     pub vm_version: i32,
+    /// Native binaries by the `0x` prefixed hex key of the code they run:
+    /// - 41 bytes, `code_hash + hash_type + offset + length` with big endian
+    ///   offset and length, for script groups, `ckb_spawn_cell` and
+    ///   `ckb_exec_cell`. Hash type 0xFF matches any.
+    /// - 33 bytes, `dep_cell_hash + hash_type`, for `ckb_dlopen2`.
+    /// - 32 bytes, `blake2b(code)` of the code `ckb_spawn` and `ckb_exec`
+    ///   select by index, source, place and bounds, code in witnesses must
+    ///   use it.
     pub native_binaries: HashMap<String, String>,
     pub run_type: Option<RunningType>,
+    /// Contain SIGSEGV/SIGABRT crashes of simulated VMs, see `crash` module.
+    #[serde(default)]
+    pub crash_handler: bool,
+    /// Check the transaction like ckb's non-script verifiers before running,
+    /// see `tx_verifier` module. `verify_transaction` and
+    /// `Simulation::run_native` check it, a setup loaded from
+    /// `CKB_RUNNING_SETUP` is checked once loaded and the process exits with
+    /// `SIMULATOR_PRECHECK_EXIT_CODE` when the check fails.
+    #[serde(default)]
+    pub check_structure: bool,
+    /// Check every input's since before running, see `since` module. Checked
+    /// like `check_structure`.
+    #[serde(default)]
+    pub check_since: bool,
+    /// The block since is checked at, defaults to the latest header dep.
+    #[serde(default)]
+    pub tip_header: Option<ckb_jsonrpc_types::HeaderView>,
+    /// Molecule types to decode witnesses, cell data and syscall buffers
+    /// with in debug output, see `molecule` module.
+    #[serde(default)]
+    pub molecule: molecule::MoleculeBindings,
+    /// Syscall failures to inject, see `faults` module.
+    #[serde(default)]
+    pub faults: Vec<faults::Fault>,
+    /// File the pipe traffic of the run is written to, see `capture`
+    /// module.
+    #[serde(default)]
+    pub pipe_capture: Option<String>,
 }
 
 lazy_static! {
-    static ref TRANSACTION: MockTransaction = {
-        let tx_filename = std::env::var("CKB_TX_FILE").expect("environment variable");
-        let tx_content = std::fs::read_to_string(tx_filename).expect("read tx file");
-        let repr_mock_tx: ReprMockTransaction =
-            serde_json::from_str(&tx_content).expect("parse tx file");
-        let mock_tx: MockTransaction = repr_mock_tx.into();
-        mock_tx
-    };
-    static ref SETUP: RunningSetup = {
+    static ref TRANSACTION: Arc<MockTransaction> = Arc::new(
+        tx_loader::load_from_env().unwrap_or_else(|e| panic!("load tx: {}", e))
+    );
+    static ref SETUP: Arc<RunningSetup> = {
         let setup_filename = std::env::var("CKB_RUNNING_SETUP").expect("environment variable");
         let setup_content = std::fs::read_to_string(setup_filename).expect("read setup file");
-        serde_json::from_str(&setup_content).expect("parse setup file")
+        let setup: RunningSetup = serde_json::from_str(&setup_content).expect("parse setup file");
+        if let Err(e) = tx_verifier::precheck(&TRANSACTION, &setup) {
+            eprintln!("[precheck] {}", e);
+            std::process::exit(SIMULATOR_PRECHECK_EXIT_CODE.into());
+        }
+        // Executable contracts have no protected entry, a supervising
+        // process reports their crashes.
+        if setup.crash_handler {
+            match setup.run_type {
+                Some(RunningType::DynamicLib) => install_crash_handler(),
+                _ => crash::supervise(),
+            }
+        }
+        Arc::new(setup)
     };
 }
 
+// Every simulation owns its transaction and setup, the environment variables
+// only provide the defaults.
+fn current_tx() -> Arc<MockTransaction> {
+    get_cur_tx!().transaction()
+}
+
+fn current_setup() -> Arc<RunningSetup> {
+    get_cur_tx!().setup()
+}
+
 fn assert_vm_version() {
-    if SETUP.vm_version == 0 {
+    let setup = current_setup();
+
+    if setup.vm_version == 0 {
         panic!(
             "Currently running setup vm_version({}) not support this syscall",
-            SETUP.vm_version
+            setup.vm_version
         );
     }
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_exit(code: i8) -> i32 {
-    std::process::exit(code.into());
+    syscalls::handle("ckb_exit", &syscall_args!(code), |_| -> c_int {
+        let pid = SimContext::pid();
+        get_cur_tx_mut!().capture_exit(&pid, code);
+        if let Err(e) = get_cur_tx!().save_capture() {
+            eprintln!("[pipe capture] {}", e);
+        }
+        let code = match get_cur_tx!().mock_child_failed() {
+            true => SIMULATOR_MOCK_MISMATCH_EXIT_CODE,
+            false => code,
+        };
+        std::process::exit(code.into())
+    })
+    .ret as i32
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_vm_version() -> c_int {
-    assert_vm_version();
-    SETUP.vm_version
+    syscalls::handle("ckb_vm_version", &[], |_| {
+        let setup = current_setup();
+
+        assert_vm_version();
+        setup.vm_version
+    })
+    .ret as c_int
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_current_cycles() -> u64 {
-    assert_vm_version();
-    // NOTE: return a fake number since this value is meaningless in simulator
-    333
+    syscalls::handle("ckb_current_cycles", &[], |_| {
+        assert_vm_version();
+        // NOTE: return a fake number since this value is meaningless in simulator
+        SyscallResult {
+            ret: 333,
+            data: None,
+        }
+    })
+    .ret as u64
 }
 
 /// The binary key string is 0x{code_hash + hash_type + offset.to_be_bytes() + length.to_be_bytes()}
@@ -102,74 +219,238 @@ pub extern "C" fn ckb_exec_cell(
     argc: i32,
     argv: *const *const u8,
 ) -> c_int {
-    assert_vm_version();
+    let args = syscall_args!(hash_type, offset, length, argc);
+    syscalls::handle("ckb_exec_cell", &args, |_| {
+        assert_vm_version();
 
-    let sim_path =
-        utils::get_simulator_path(utils::to_array(code_hash, 32), hash_type, offset, length);
-    let sim_path = sim_path.expect("cannot locate native binary for ckb_exec syscall!");
+        let code_hash = utils::to_array(code_hash, 32);
+        let sim_path = utils::get_simulator_path(code_hash, hash_type, offset, length)
+            .unwrap_or_else(|| {
+                let key = utils::simulator_key(code_hash, hash_type, offset, length);
+                missing_native_binary(format!("ckb_exec_cell of {}", key))
+            });
+        exec_native(sim_path, argc, argv)
+    })
+    .ret as c_int
+}
 
-    match SETUP.run_type.as_ref().unwrap_or(&RunningType::Executable) {
+/// Runs the code `place` and `bounds` select in the cell at `index` and
+/// `source`, see `locate_code`.
+#[no_mangle]
+pub extern "C" fn ckb_exec(
+    index: u64,
+    source: u64,
+    place: u64,
+    bounds: u64,
+    argc: i32,
+    argv: *const *const u8,
+) -> c_int {
+    let args = syscall_args!(index, source, place, bounds, argc);
+    syscalls::handle("ckb_exec", &args, |_| {
+        assert_vm_version();
+
+        match locate_code(index, source, place, bounds) {
+            Ok(sim_path) => exec_native(sim_path, argc, argv),
+            Err(code) => code,
+        }
+    })
+    .ret as c_int
+}
+
+/// Replaces the current process with the native binary at `sim_path`.
+fn exec_native(sim_path: String, argc: i32, argv: *const *const u8) -> c_int {
+    let args = utils::to_vec_args(argc, argv as *const *const i8);
+    let setup = current_setup();
+    match setup.run_type.as_ref().unwrap_or(&RunningType::Executable) {
         RunningType::Executable => {
             let filename_cstring = CString::new(sim_path.as_bytes().to_vec()).unwrap();
-            unsafe {
-                let args = argv as *const *const i8;
-                libc::execvp(filename_cstring.as_ptr(), args)
-            }
+            // argv has argc entries on chain, execvp needs a null one after.
+            let args: Vec<CString> = args
+                .into_iter()
+                .map(|a| CString::new(a).expect("argv entry"))
+                .collect();
+            let mut argv: Vec<*const i8> = args.iter().map(|a| a.as_ptr()).collect();
+            argv.push(std::ptr::null());
+            unsafe { libc::execvp(filename_cstring.as_ptr(), argv.as_ptr()) }
         }
         RunningType::DynamicLib => {
             use utils::CkbNativeSimulator;
 
-            let tx_ctx_id = GlobalData::locked().set_tx(simulator_context::SimContext::default());
+            let sim_ctx = get_cur_tx!().fork();
+            let parent_ctx_id = SimContext::ctx_id();
+            let tx_ctx_id = GlobalData::locked().set_tx(sim_ctx);
             SimContext::update_ctx_id(tx_ctx_id.clone(), None);
 
-            let sim = CkbNativeSimulator::new_by_hash(code_hash, hash_type, offset, length);
-            let args = utils::to_vec_args(argc, argv as *const *const i8);
+            let sim = CkbNativeSimulator::new(&sim_path.into());
 
             let join_handle = {
                 let mut global_data = GlobalData::locked();
                 let sim_ctx = global_data.get_tx_mut(&tx_ctx_id);
-                let child_pid: utils::ProcID = sim_ctx.start_process(&[], move |sim_id, pid| {
-                    sim.update_script_info(sim_id, pid);
-                    sim.ckb_std_main(args)
-                });
+                let script = sim.path();
+                let child_pid: utils::ProcID =
+                    sim_ctx.start_process(&[], script, move |sim_id, pid| {
+                        sim.update_script_info(sim_id, pid);
+                        sim.ckb_std_main(args)
+                    });
                 sim_ctx.exit(&child_pid).unwrap()
             };
-            join_handle.join().expect("exec dylib") as c_int
+            let code = join_handle.join().expect("exec dylib") as c_int;
+            // The context lives as long as the exec'd VM.
+            SimContext::update_ctx_id(parent_ctx_id.clone(), None);
+            let mut global_data = GlobalData::locked();
+            let exec_ctx = global_data.remove_tx(&tx_ctx_id).expect("exec context");
+            global_data.get_tx_mut(&parent_ctx_id).end_exec(exec_ctx);
+            code
         }
     }
 }
 
+/// The native binary running the code `place` and `bounds` select in the
+/// cell or witness at `index` and `source`, like the raw exec and spawn
+/// syscalls do. `bounds` is the offset in its high 32 bits and the length,
+/// 0 for the rest, in its low 32 bits.
+///
+/// The binary is mapped by `0x{blake2b(code)}`. Code in cells may also use
+/// the spawn key of the cell data or type hash with the offset and length.
+/// Unknown places are `CKB_ITEM_MISSING`, unmapped code ends the process,
+/// see `missing_native_binary`.
+fn locate_code(index: u64, source: u64, place: u64, bounds: u64) -> Result<String, c_int> {
+    let (offset, length) = ((bounds >> 32) as u32, bounds as u32);
+    let (cell, data) = match place {
+        PLACE_CELL_DATA => {
+            let (cell, data) = fetch_cell(index, source)?;
+            (Some(cell), data)
+        }
+        PLACE_WITNESS => {
+            let actual_index = witness_index(index, source).ok_or(CKB_INDEX_OUT_OF_BOUND)?;
+            match current_tx().tx.witnesses().get(actual_index) {
+                Some(witness) => (None, witness.raw_data()),
+                None => return Err(CKB_INDEX_OUT_OF_BOUND),
+            }
+        }
+        _ => return Err(CKB_ITEM_MISSING),
+    };
+    let start = offset as usize;
+    let end = match length {
+        0 => data.len(),
+        _ => start + length as usize,
+    };
+    if start > data.len() || end > data.len() {
+        return Err(CKB_SLICE_OUT_OF_BOUND);
+    }
+
+    let code_hash = blake2b_256(&data[start..end]);
+    let setup = current_setup();
+    let key = format!("0x{}", faster_hex::hex_string(&code_hash));
+    if let Some(path) = setup.native_binaries.get(&key) {
+        return Ok(path.clone());
+    }
+    let Some(cell) = cell else {
+        missing_native_binary(format!(
+            "code {} in witness {} of source {:#x}, it must be mapped in native_binaries",
+            key, index, source
+        ));
+    };
+    let data_hash = CellOutput::calc_data_hash(&data);
+    let by_data = [
+        ScriptHashType::Data,
+        ScriptHashType::Data1,
+        ScriptHashType::Data2,
+    ]
+    .into_iter()
+    .find_map(|t| utils::get_simulator_path(data_hash.as_slice(), t as u8, offset, length));
+    let by_type = || {
+        let type_hash = cell.type_().to_opt()?.calc_script_hash();
+        let hash_type = ScriptHashType::Type as u8;
+        utils::get_simulator_path(type_hash.as_slice(), hash_type, offset, length)
+    };
+    Ok(by_data.or_else(by_type).unwrap_or_else(|| {
+        missing_native_binary(format!(
+            "code {} in cell {} of source {:#x}",
+            key, index, source
+        ))
+    }))
+}
+
+/// Ends the process with `SIMULATOR_MISSING_BINARY_EXIT_CODE` when a VM
+/// spawns or execs code the setup has no native binary for. On chain the
+/// code would run, so no return code of the syscall is right.
+pub(crate) fn missing_native_binary(code: String) -> ! {
+    eprintln!("[simulator] no native binary for {}", code);
+    std::process::exit(SIMULATOR_MISSING_BINARY_EXIT_CODE.into())
+}
+
 #[no_mangle]
 pub extern "C" fn ckb_load_tx_hash(ptr: *mut c_void, len: *mut u64, offset: u64) -> c_int {
-    let view = TRANSACTION.tx.clone().into_view();
-    store_data(ptr, len, offset, view.hash().as_slice());
-    CKB_SUCCESS
+    let args = syscall_args!(offset);
+    load_syscall("ckb_load_tx_hash", &args, ptr, len, offset, || {
+        let tx = current_tx();
+
+        let view = tx.tx.clone().into_view();
+        Ok(view.hash().as_bytes())
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_load_transaction(ptr: *mut c_void, len: *mut u64, offset: u64) -> c_int {
-    store_data(ptr, len, offset, TRANSACTION.tx.as_slice());
-    CKB_SUCCESS
+    let args = syscall_args!(offset);
+    load_syscall("ckb_load_transaction", &args, ptr, len, offset, || {
+        let tx = current_tx();
+
+        debug_molecule("ckb_load_transaction", None, offset, len, tx.tx.as_slice());
+        Ok(tx.tx.as_bytes())
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_load_script_hash(ptr: *mut c_void, len: *mut u64, offset: u64) -> c_int {
-    let hash = fetch_current_script().calc_script_hash();
-    store_data(ptr, len, offset, hash.as_slice());
-    CKB_SUCCESS
+    let args = syscall_args!(offset);
+    load_syscall("ckb_load_script_hash", &args, ptr, len, offset, || {
+        Ok(fetch_current_script().calc_script_hash().as_bytes())
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_load_script(ptr: *mut c_void, len: *mut u64, offset: u64) -> c_int {
-    store_data(ptr, len, offset, fetch_current_script().as_slice());
-    CKB_SUCCESS
+    let args = syscall_args!(offset);
+    load_syscall("ckb_load_script", &args, ptr, len, offset, || {
+        let script = fetch_current_script();
+        debug_molecule("ckb_load_script", None, offset, len, script.as_slice());
+        Ok(script.as_bytes())
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_debug(s: *const c_char) {
-    let message = utils::to_c_str(s).to_str().expect("UTF8 error!");
-    // println!("Debug message: {}", message);
-    println!("[contract debug] {}", message);
+    syscalls::handle("ckb_debug", &[], |_| {
+        let message = utils::to_c_str(s).to_str().expect("UTF8 error!");
+        match get_cur_tx!().debug_sink() {
+            Some(sink) => sink(message),
+            None => println!("[contract debug] {}", message),
+        }
+        CKB_SUCCESS
+    });
+}
+
+/// Sends a buffer returned by `syscall` to the debug sink, decoded as its
+/// bound molecule type, `type_name` or the type bound to the syscall. Only
+/// whole reads are shown, not length queries or reads at an offset.
+fn debug_molecule(syscall: &str, type_name: Option<&str>, offset: u64, len: *mut u64, data: &[u8]) {
+    let setup = current_setup();
+    let Some(type_name) = type_name.or(setup.molecule.syscall_type(syscall)) else {
+        return;
+    };
+    if offset != 0 || unsafe { len.as_ref() }.is_none_or(|len| *len == 0) {
+        return;
+    }
+    let Some(sink) = get_cur_tx!().debug_sink() else {
+        return;
+    };
+    sink(&format!(
+        "{}: {}",
+        syscall,
+        setup.molecule.render(type_name, data)
+    ));
 }
 
 #[no_mangle]
@@ -180,12 +461,12 @@ pub extern "C" fn ckb_load_cell(
     index: u64,
     source: u64,
 ) -> c_int {
-    let (cell, _) = match fetch_cell(index, source) {
-        Ok(cell) => cell,
-        Err(code) => return code,
-    };
-    store_data(ptr, len, offset, cell.as_slice());
-    CKB_SUCCESS
+    let args = syscall_args!(index, source, offset);
+    load_syscall("ckb_load_cell", &args, ptr, len, offset, || {
+        let (cell, _) = fetch_cell(index, source)?;
+        debug_molecule("ckb_load_cell", None, offset, len, cell.as_slice());
+        Ok(cell.as_bytes())
+    })
 }
 
 #[no_mangle]
@@ -196,12 +477,12 @@ pub extern "C" fn ckb_load_input(
     index: u64,
     source: u64,
 ) -> c_int {
-    let input = match fetch_input(index, source) {
-        Ok(input) => input,
-        Err(code) => return code,
-    };
-    store_data(ptr, len, offset, input.as_slice());
-    CKB_SUCCESS
+    let args = syscall_args!(index, source, offset);
+    load_syscall("ckb_load_input", &args, ptr, len, offset, || {
+        let input = fetch_input(index, source)?;
+        debug_molecule("ckb_load_input", None, offset, len, input.as_slice());
+        Ok(input.as_bytes())
+    })
 }
 
 #[no_mangle]
@@ -212,12 +493,12 @@ pub extern "C" fn ckb_load_header(
     index: u64,
     source: u64,
 ) -> c_int {
-    let header = match fetch_header(index, source) {
-        Ok(input) => input,
-        Err(code) => return code,
-    };
-    store_data(ptr, len, offset, header.data().as_slice());
-    CKB_SUCCESS
+    let args = syscall_args!(index, source, offset);
+    load_syscall("ckb_load_header", &args, ptr, len, offset, || {
+        let header = fetch_header(index, source)?.data();
+        debug_molecule("ckb_load_header", None, offset, len, header.as_slice());
+        Ok(header.as_bytes())
+    })
 }
 
 #[no_mangle]
@@ -228,12 +509,18 @@ pub extern "C" fn ckb_load_witness(
     index: u64,
     source: u64,
 ) -> c_int {
-    let witness = match fetch_witness(index, source) {
-        Some(witness) => witness,
-        None => return CKB_INDEX_OUT_OF_BOUND,
-    };
-    store_data(ptr, len, offset, &witness.raw_data());
-    CKB_SUCCESS
+    let args = syscall_args!(index, source, offset);
+    load_syscall("ckb_load_witness", &args, ptr, len, offset, || {
+        let actual_index = witness_index(index, source).ok_or(CKB_INDEX_OUT_OF_BOUND)?;
+        let witness = match current_tx().tx.witnesses().get(actual_index) {
+            Some(witness) => witness.raw_data(),
+            None => return Err(CKB_INDEX_OUT_OF_BOUND),
+        };
+        let setup = current_setup();
+        let type_name = setup.molecule.witness_type(actual_index);
+        debug_molecule("ckb_load_witness", type_name, offset, len, &witness);
+        Ok(witness)
+    })
 }
 
 #[no_mangle]
@@ -245,57 +532,42 @@ pub extern "C" fn ckb_load_cell_by_field(
     source: u64,
     field: u64,
 ) -> c_int {
-    let (cell, cell_data) = match fetch_cell(index, source) {
-        Ok(cell) => cell,
-        Err(code) => return code,
-    };
-    let cell_meta = CellMetaBuilder::from_cell_output(cell.clone(), cell_data.clone()).build();
-    match field {
-        CELL_FIELD_CAPACITY => {
-            let capacity: Capacity = cell.capacity().unpack();
-            let data = capacity.as_u64().to_le_bytes();
-            store_data(ptr, len, offset, &data[..]);
-        }
-        CELL_FIELD_DATA_HASH => {
-            let hash = CellOutput::calc_data_hash(&cell_data);
-            store_data(ptr, len, offset, hash.as_slice());
-        }
-        CELL_FIELD_OCCUPIED_CAPACITY => {
-            let data = cell_meta
-                .occupied_capacity()
-                .expect("capacity error")
-                .as_u64()
-                .to_le_bytes();
-            store_data(ptr, len, offset, &data[..]);
-        }
-        CELL_FIELD_LOCK => {
-            let lock = cell.lock();
-            store_data(ptr, len, offset, lock.as_slice());
-        }
-        CELL_FIELD_LOCK_HASH => {
-            let hash = cell.calc_lock_hash();
-            store_data(ptr, len, offset, &hash.as_bytes());
-        }
-        CELL_FIELD_TYPE => match cell.type_().to_opt() {
-            Some(type_) => {
-                store_data(ptr, len, offset, type_.as_slice());
-            }
-            None => {
-                return CKB_ITEM_MISSING;
+    let args = syscall_args!(index, source, field, offset);
+    load_syscall("ckb_load_cell_by_field", &args, ptr, len, offset, || {
+        let (cell, cell_data) = fetch_cell(index, source)?;
+        let cell_meta = CellMetaBuilder::from_cell_output(cell.clone(), cell_data.clone()).build();
+        let data = match field {
+            CELL_FIELD_CAPACITY => {
+                let capacity: Capacity = cell.capacity().unpack();
+                Bytes::from(capacity.as_u64().to_le_bytes().to_vec())
             }
-        },
-        CELL_FIELD_TYPE_HASH => match cell.type_().to_opt() {
-            Some(type_) => {
-                let hash = type_.calc_script_hash();
-                store_data(ptr, len, offset, &hash.as_bytes());
+            CELL_FIELD_DATA_HASH => CellOutput::calc_data_hash(&cell_data).as_bytes(),
+            CELL_FIELD_OCCUPIED_CAPACITY => {
+                let data = cell_meta
+                    .occupied_capacity()
+                    .expect("capacity error")
+                    .as_u64()
+                    .to_le_bytes();
+                Bytes::from(data.to_vec())
             }
-            None => {
-                return CKB_ITEM_MISSING;
-            }
-        },
-        _ => panic!("Invalid field: {}", field),
-    };
-    CKB_SUCCESS
+            CELL_FIELD_LOCK => cell.lock().as_bytes(),
+            CELL_FIELD_LOCK_HASH => cell.calc_lock_hash().as_bytes(),
+            CELL_FIELD_TYPE => match cell.type_().to_opt() {
+                Some(type_) => type_.as_bytes(),
+                None => {
+                    return Err(CKB_ITEM_MISSING);
+                }
+            },
+            CELL_FIELD_TYPE_HASH => match cell.type_().to_opt() {
+                Some(type_) => type_.calc_script_hash().as_bytes(),
+                None => {
+                    return Err(CKB_ITEM_MISSING);
+                }
+            },
+            _ => panic!("Invalid field: {}", field),
+        };
+        Ok(data)
+    })
 }
 
 #[no_mangle]
@@ -307,23 +579,21 @@ pub extern "C" fn ckb_load_header_by_field(
     source: u64,
     field: u64,
 ) -> c_int {
-    let header = match fetch_header(index, source) {
-        Ok(input) => input,
-        Err(code) => return code,
-    };
-    let epoch = header.epoch();
-    let value = match field {
-        HEADER_FIELD_EPOCH_NUMBER => epoch.number(),
-        HEADER_FIELD_EPOCH_START_BLOCK_NUMBER => header
-            .number()
-            .checked_sub(epoch.index())
-            .expect("Overflow!"),
-        HEADER_FIELD_EPOCH_LENGTH => epoch.length(),
-        _ => panic!("Invalid field: {}", field),
-    };
-    let data = value.to_le_bytes();
-    store_data(ptr, len, offset, &data[..]);
-    CKB_SUCCESS
+    let args = syscall_args!(index, source, field, offset);
+    load_syscall("ckb_load_header_by_field", &args, ptr, len, offset, || {
+        let header = fetch_header(index, source)?;
+        let epoch = header.epoch();
+        let value = match field {
+            HEADER_FIELD_EPOCH_NUMBER => epoch.number(),
+            HEADER_FIELD_EPOCH_START_BLOCK_NUMBER => header
+                .number()
+                .checked_sub(epoch.index())
+                .expect("Overflow!"),
+            HEADER_FIELD_EPOCH_LENGTH => epoch.length(),
+            _ => panic!("Invalid field: {}", field),
+        };
+        Ok(Bytes::from(value.to_le_bytes().to_vec()))
+    })
 }
 
 #[no_mangle]
@@ -335,22 +605,19 @@ pub extern "C" fn ckb_load_input_by_field(
     source: u64,
     field: u64,
 ) -> c_int {
-    let input = match fetch_input(index, source) {
-        Ok(input) => input,
-        Err(code) => return code,
-    };
-    match field {
-        INPUT_FIELD_OUT_POINT => {
-            store_data(ptr, len, offset, input.previous_output().as_slice());
-        }
-        INPUT_FIELD_SINCE => {
-            let since: u64 = input.since().unpack();
-            let data = since.to_le_bytes();
-            store_data(ptr, len, offset, &data[..]);
-        }
-        _ => panic!("Invalid field: {}", field),
-    };
-    CKB_SUCCESS
+    let args = syscall_args!(index, source, field, offset);
+    load_syscall("ckb_load_input_by_field", &args, ptr, len, offset, || {
+        let input = fetch_input(index, source)?;
+        let data = match field {
+            INPUT_FIELD_OUT_POINT => input.previous_output().as_bytes(),
+            INPUT_FIELD_SINCE => {
+                let since: u64 = input.since().unpack();
+                Bytes::from(since.to_le_bytes().to_vec())
+            }
+            _ => panic!("Invalid field: {}", field),
+        };
+        Ok(data)
+    })
 }
 
 #[no_mangle]
@@ -361,12 +628,14 @@ pub extern "C" fn ckb_load_cell_data(
     index: u64,
     source: u64,
 ) -> c_int {
-    let (_, cell_data) = match fetch_cell(index, source) {
-        Ok(cell) => cell,
-        Err(code) => return code,
-    };
-    store_data(ptr, len, offset, &cell_data);
-    CKB_SUCCESS
+    let args = syscall_args!(index, source, offset);
+    load_syscall("ckb_load_cell_data", &args, ptr, len, offset, || {
+        let (cell, cell_data) = fetch_cell(index, source)?;
+        let setup = current_setup();
+        let type_name = setup.molecule.cell_data_type(&cell);
+        debug_molecule("ckb_load_cell_data", type_name, offset, len, &cell_data);
+        Ok(cell_data)
+    })
 }
 
 extern "C" {
@@ -413,42 +682,49 @@ pub extern "C" fn ckb_dlopen2(
     handle: *mut *mut c_void,
     consumed_size: *mut u64,
 ) -> c_int {
-    let dep_cell_hash = utils::to_array(dep_cell_hash, 32);
-    let mut buffer = vec![];
-    buffer.extend_from_slice(dep_cell_hash);
-    buffer.push(hash_type);
-    let key = format!("0x{}", faster_hex::hex_string(&buffer));
-    let filename = SETUP
-        .native_binaries
-        .get(&key)
-        .expect("cannot locate native binary!");
-    let cell_dep = TRANSACTION
-        .mock_info
-        .cell_deps
-        .iter()
-        .find(|cell_dep| {
-            if hash_type == 1 {
-                cell_dep
-                    .output
-                    .type_()
-                    .to_opt()
-                    .map(|t| t.calc_script_hash().as_slice() == dep_cell_hash)
-                    .unwrap_or(false)
-            } else {
-                CellOutput::calc_data_hash(&cell_dep.data).as_slice() == dep_cell_hash
-            }
-        })
-        .expect("cannot locate cell dep");
-    let cell_data = cell_dep.data.as_ref();
-    rs_simulator_internal_dlopen2(
-        filename.as_str().as_ptr(),
-        cell_data.as_ptr(),
-        cell_data.len() as u64,
-        aligned_addr,
-        aligned_size,
-        handle,
-        consumed_size,
-    )
+    syscalls::handle("ckb_dlopen2", &syscall_args!(hash_type), |_| {
+        let dep_cell_hash = utils::to_array(dep_cell_hash, 32);
+        let mut buffer = vec![];
+        buffer.extend_from_slice(dep_cell_hash);
+        buffer.push(hash_type);
+        let key = format!("0x{}", faster_hex::hex_string(&buffer));
+        let tx = current_tx();
+        let setup = current_setup();
+        let filename = setup
+            .native_binaries
+            .get(&key)
+            .expect("cannot locate native binary!");
+        let cell_deps = match resolved_cell_deps(&tx) {
+            Ok(cell_deps) => cell_deps,
+            Err(code) => return code,
+        };
+        let cell_dep = cell_deps
+            .into_iter()
+            .find(|cell_dep| {
+                if hash_type == 1 {
+                    cell_dep
+                        .output
+                        .type_()
+                        .to_opt()
+                        .map(|t| t.calc_script_hash().as_slice() == dep_cell_hash)
+                        .unwrap_or(false)
+                } else {
+                    CellOutput::calc_data_hash(&cell_dep.data).as_slice() == dep_cell_hash
+                }
+            })
+            .expect("cannot locate cell dep");
+        let cell_data = cell_dep.data.as_ref();
+        rs_simulator_internal_dlopen2(
+            filename.as_str().as_ptr(),
+            cell_data.as_ptr(),
+            cell_data.len() as u64,
+            aligned_addr,
+            aligned_size,
+            handle,
+            consumed_size,
+        )
+    })
+    .ret as c_int
 }
 
 #[no_mangle]
@@ -461,37 +737,37 @@ pub extern "C" fn set_script_info(ptr: *const std::ffi::c_void, tx_ctx_id: u64,
     }
 }
 
+/// Cell deps with dep groups expanded, in the order `SOURCE_CELL_DEP`
+/// indexes them on chain. Deps that do not resolve are missing.
+fn resolved_cell_deps(tx: &MockTransaction) -> Result<Vec<&MockCellDep>, c_int> {
+    let indices = get_cur_tx_mut!()
+        .resolved_cell_deps()
+        .map_err(|_| CKB_ITEM_MISSING)?;
+    Ok(indices
+        .iter()
+        .map(|i| &tx.mock_info.cell_deps[*i])
+        .collect())
+}
+
+fn fetch_cell_dep(tx: &MockTransaction, index: u64) -> Result<&MockCellDep, c_int> {
+    resolved_cell_deps(tx)?
+        .get(index as usize)
+        .copied()
+        .ok_or(CKB_INDEX_OUT_OF_BOUND)
+}
+
 fn fetch_cell(index: u64, source: u64) -> Result<(CellOutput, Bytes), c_int> {
+    let tx = current_tx();
+
     match source {
-        SOURCE_INPUT => TRANSACTION
+        SOURCE_INPUT => tx
             .mock_info
             .inputs
             .get(index as usize)
             .ok_or(CKB_INDEX_OUT_OF_BOUND)
             .map(|input| (input.output.clone(), input.data.clone())),
-        SOURCE_OUTPUT => TRANSACTION
-            .tx
-            .raw()
-            .outputs()
-            .get(index as usize)
-            .ok_or(CKB_INDEX_OUT_OF_BOUND)
-            .map(|output| {
-                (
-                    output,
-                    TRANSACTION
-                        .tx
-                        .raw()
-                        .outputs_data()
-                        .get(index as usize)
-                        .expect("cell data mismatch")
-                        .unpack(),
-                )
-            }),
-        SOURCE_CELL_DEP => TRANSACTION
-            .mock_info
-            .cell_deps
-            .get(index as usize)
-            .ok_or(CKB_INDEX_OUT_OF_BOUND)
+        SOURCE_OUTPUT => fetch_output(&tx, index as usize),
+        SOURCE_CELL_DEP => fetch_cell_dep(&tx, index)
             .map(|cell_dep| (cell_dep.output.clone(), cell_dep.data.clone())),
         SOURCE_HEADER_DEP => Err(CKB_INDEX_OUT_OF_BOUND),
         SOURCE_GROUP_INPUT => {
@@ -500,8 +776,7 @@ fn fetch_cell(index: u64, source: u64) -> Result<(CellOutput, Bytes), c_int> {
                 .get(index as usize)
                 .ok_or(CKB_INDEX_OUT_OF_BOUND)
                 .and_then(|actual_index| {
-                    TRANSACTION
-                        .mock_info
+                    tx.mock_info
                         .inputs
                         .get(*actual_index)
                         .ok_or(CKB_INDEX_OUT_OF_BOUND)
@@ -513,26 +788,7 @@ fn fetch_cell(index: u64, source: u64) -> Result<(CellOutput, Bytes), c_int> {
             indices
                 .get(index as usize)
                 .ok_or(CKB_INDEX_OUT_OF_BOUND)
-                .and_then(|actual_index| {
-                    TRANSACTION
-                        .tx
-                        .raw()
-                        .outputs()
-                        .get(*actual_index)
-                        .ok_or(CKB_INDEX_OUT_OF_BOUND)
-                        .map(|output| {
-                            (
-                                output,
-                                TRANSACTION
-                                    .tx
-                                    .raw()
-                                    .outputs_data()
-                                    .get(index as usize)
-                                    .expect("cell data mismatch")
-                                    .unpack(),
-                            )
-                        })
-                })
+                .and_then(|actual_index| fetch_output(&tx, *actual_index))
         }
         SOURCE_GROUP_CELL_DEP => Err(CKB_INDEX_OUT_OF_BOUND),
         SOURCE_GROUP_HEADER_DEP => Err(CKB_INDEX_OUT_OF_BOUND),
@@ -540,9 +796,21 @@ fn fetch_cell(index: u64, source: u64) -> Result<(CellOutput, Bytes), c_int> {
     }
 }
 
+/// Output `index` with its data. A transaction with fewer outputs data
+/// than outputs is malformed, the output is out of bound then.
+fn fetch_output(tx: &MockTransaction, index: usize) -> Result<(CellOutput, Bytes), c_int> {
+    let raw = tx.tx.raw();
+    match (raw.outputs().get(index), raw.outputs_data().get(index)) {
+        (Some(output), Some(data)) => Ok((output, data.unpack())),
+        _ => Err(CKB_INDEX_OUT_OF_BOUND),
+    }
+}
+
 fn fetch_input(index: u64, source: u64) -> Result<CellInput, c_int> {
+    let tx = current_tx();
+
     match source {
-        SOURCE_INPUT => TRANSACTION
+        SOURCE_INPUT => tx
             .tx
             .raw()
             .inputs()
@@ -557,8 +825,7 @@ fn fetch_input(index: u64, source: u64) -> Result<CellInput, c_int> {
                 .get(index as usize)
                 .ok_or(CKB_INDEX_OUT_OF_BOUND)
                 .and_then(|actual_index| {
-                    TRANSACTION
-                        .tx
+                    tx.tx
                         .raw()
                         .inputs()
                         .get(*actual_index)
@@ -573,8 +840,9 @@ fn fetch_input(index: u64, source: u64) -> Result<CellInput, c_int> {
 }
 
 fn find_header(hash: Byte32) -> Option<HeaderView> {
-    TRANSACTION
-        .mock_info
+    let tx = current_tx();
+
+    tx.mock_info
         .header_deps
         .iter()
         .find(|header| header.hash() == hash)
@@ -582,8 +850,10 @@ fn find_header(hash: Byte32) -> Option<HeaderView> {
 }
 
 fn fetch_header(index: u64, source: u64) -> Result<HeaderView, c_int> {
+    let tx = current_tx();
+
     match source {
-        SOURCE_INPUT => TRANSACTION
+        SOURCE_INPUT => tx
             .mock_info
             .inputs
             .get(index as usize)
@@ -591,27 +861,25 @@ fn fetch_header(index: u64, source: u64) -> Result<HeaderView, c_int> {
             .ok_or(CKB_INDEX_OUT_OF_BOUND)
             .and_then(|header_hash| find_header(header_hash).ok_or(CKB_ITEM_MISSING)),
         SOURCE_OUTPUT => Err(CKB_INDEX_OUT_OF_BOUND),
-        SOURCE_CELL_DEP => TRANSACTION
-            .mock_info
-            .cell_deps
-            .get(index as usize)
-            .and_then(|cell_dep| cell_dep.header.as_ref().cloned())
+        SOURCE_CELL_DEP => fetch_cell_dep(&tx, index)?
+            .header
+            .clone()
             .ok_or(CKB_INDEX_OUT_OF_BOUND)
             .and_then(|header_hash| find_header(header_hash).ok_or(CKB_ITEM_MISSING)),
-        SOURCE_HEADER_DEP => TRANSACTION
-            .mock_info
-            .header_deps
+        SOURCE_HEADER_DEP => tx
+            .tx
+            .raw()
+            .header_deps()
             .get(index as usize)
-            .cloned()
-            .ok_or(CKB_INDEX_OUT_OF_BOUND),
+            .ok_or(CKB_INDEX_OUT_OF_BOUND)
+            .and_then(|header_hash| find_header(header_hash).ok_or(CKB_ITEM_MISSING)),
         SOURCE_GROUP_INPUT => {
             let (indices, _) = fetch_group_indices();
             indices
                 .get(index as usize)
                 .ok_or(CKB_INDEX_OUT_OF_BOUND)
                 .and_then(|actual_index| {
-                    TRANSACTION
-                        .mock_info
+                    tx.mock_info
                         .inputs
                         .get(*actual_index)
                         .and_then(|input| input.header.as_ref().cloned())
@@ -626,21 +894,18 @@ fn fetch_header(index: u64, source: u64) -> Result<HeaderView, c_int> {
     }
 }
 
-fn fetch_witness(index: u64, source: u64) -> Option<packed::Bytes> {
+/// Index in the transaction witnesses of the witness at `index` of `source`.
+fn witness_index(index: u64, source: u64) -> Option<usize> {
     match source {
-        SOURCE_INPUT => TRANSACTION.tx.witnesses().get(index as usize),
-        SOURCE_OUTPUT => TRANSACTION.tx.witnesses().get(index as usize),
+        SOURCE_INPUT => Some(index as usize),
+        SOURCE_OUTPUT => Some(index as usize),
         SOURCE_GROUP_INPUT => {
             let (indices, _) = fetch_group_indices();
-            indices
-                .get(index as usize)
-                .and_then(|actual_index| TRANSACTION.tx.witnesses().get(*actual_index))
+            indices.get(index as usize).copied()
         }
         SOURCE_GROUP_OUTPUT => {
             let (_, indices) = fetch_group_indices();
-            indices
-                .get(index as usize)
-                .and_then(|actual_index| TRANSACTION.tx.witnesses().get(*actual_index))
+            indices.get(index as usize).copied()
         }
         SOURCE_CELL_DEP => None,
         SOURCE_HEADER_DEP => None,
@@ -651,24 +916,34 @@ fn fetch_witness(index: u64, source: u64) -> Option<packed::Bytes> {
 }
 
 fn fetch_group_indices() -> (Vec<usize>, Vec<usize>) {
+    let tx = current_tx();
+    let setup = current_setup();
+    group_indices(&tx, &fetch_current_script(), setup.is_lock_script)
+}
+
+/// Input and output indices of the cells in `script`'s group.
+pub(crate) fn group_indices(
+    tx: &MockTransaction,
+    script: &Script,
+    is_lock_script: bool,
+) -> (Vec<usize>, Vec<usize>) {
     let mut input_indices: Vec<usize> = vec![];
     let mut output_indices: Vec<usize> = vec![];
-    let current_script = fetch_current_script();
 
-    for (i, input) in TRANSACTION.mock_info.inputs.iter().enumerate() {
-        if SETUP.is_lock_script {
-            if input.output.lock() == current_script {
+    for (i, input) in tx.mock_info.inputs.iter().enumerate() {
+        if is_lock_script {
+            if &input.output.lock() == script {
                 input_indices.push(i);
             }
         } else if let Some(t) = input.output.type_().to_opt() {
-            if t == current_script {
+            if &t == script {
                 input_indices.push(i);
             }
         }
     }
-    for (i, output) in TRANSACTION.tx.raw().outputs().into_iter().enumerate() {
+    for (i, output) in tx.tx.raw().outputs().into_iter().enumerate() {
         if let Some(t) = output.type_().to_opt() {
-            if t == current_script {
+            if &t == script {
                 output_indices.push(i);
             }
         }
@@ -677,29 +952,50 @@ fn fetch_group_indices() -> (Vec<usize>, Vec<usize>) {
 }
 
 fn fetch_current_script() -> Script {
-    let cell = if SETUP.is_output {
-        TRANSACTION
-            .tx
+    let tx = current_tx();
+    let setup = current_setup();
+
+    let cell = if setup.is_output {
+        tx.tx
             .raw()
             .outputs()
-            .get(SETUP.script_index as usize)
+            .get(setup.script_index as usize)
             .expect("running script index out of bound!")
     } else {
-        TRANSACTION
-            .mock_info
+        tx.mock_info
             .inputs
-            .get(SETUP.script_index as usize)
+            .get(setup.script_index as usize)
             .expect("running script index out of bound!")
             .output
             .clone()
     };
-    if SETUP.is_lock_script {
+    if setup.is_lock_script {
         cell.lock()
     } else {
         cell.type_().to_opt().unwrap()
     }
 }
 
+/// Runs a load syscall through `syscalls::handle` and stores the data it
+/// returns, altered by the injected fault if any.
+fn load_syscall(
+    name: &str,
+    args: &[(&str, u64)],
+    ptr: *mut c_void,
+    len: *mut u64,
+    offset: u64,
+    load: impl FnOnce() -> Result<Bytes, c_int>,
+) -> c_int {
+    let result = syscalls::handle(name, args, |fault| match load() {
+        Ok(data) => SyscallResult::data(faults::apply(&fault, data)),
+        Err(code) => code.into(),
+    });
+    if let Some(data) = &result.data {
+        store_data(ptr, len, offset, data);
+    }
+    result.ret as c_int
+}
+
 fn store_data(ptr: *mut c_void, len: *mut u64, offset: u64, data: &[u8]) {
     let size_ptr = unsafe { len.as_mut().expect("casting pointer") };
     let size = *size_ptr;
@@ -712,3 +1008,466 @@ fn store_data(ptr: *mut c_void, len: *mut u64, offset: u64, data: &[u8]) {
     buffer[..real_size as usize]
         .copy_from_slice(&data[offset as usize..(offset + real_size) as usize]);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::molecule::Schema;
+    use ckb_types::{
+        core::{DepType, EpochNumberWithFraction, ScriptHashType},
+        packed::{CellDep, CellOutput, Header, OutPoint},
+    };
+
+    fn load_cell_data(index: u64, source: u64) -> Result<Vec<u8>, c_int> {
+        let mut data = [0u8; 16];
+        let mut len = data.len() as u64;
+        match ckb_load_cell_data(data.as_mut_ptr().cast(), &mut len, 0, index, source) {
+            CKB_SUCCESS => Ok(data[..len as usize].to_vec()),
+            code => Err(code),
+        }
+    }
+
+    #[test]
+    fn group_output_data_follows_the_group_index() {
+        let mut tx = test_utils::sample_tx();
+        let type_script = Script::new_builder()
+            .code_hash([5u8; 32].pack())
+            .hash_type(ScriptHashType::Data1.into())
+            .build();
+        let plain = tx.tx.raw().outputs().get(0).unwrap();
+        let typed = CellOutput::new_builder()
+            .type_(Some(type_script).pack())
+            .build();
+        let raw = tx
+            .tx
+            .raw()
+            .as_builder()
+            .outputs(vec![plain, typed].pack())
+            .outputs_data(
+                vec![
+                    Bytes::from(vec![0u8]).pack(),
+                    Bytes::from(vec![1u8, 2]).pack(),
+                ]
+                .pack(),
+            )
+            .build();
+        tx.tx = tx.tx.as_builder().raw(raw).build();
+        let setup = RunningSetup {
+            is_output: true,
+            script_index: 1,
+            ..Default::default()
+        };
+
+        let sim = Simulation::new(tx, setup);
+        let code = sim.run_root("group output".to_string(), |_, _| {
+            assert_eq!(load_cell_data(0, SOURCE_GROUP_OUTPUT), Ok(vec![1, 2]));
+            assert_eq!(
+                load_cell_data(1, SOURCE_GROUP_OUTPUT),
+                Err(CKB_INDEX_OUT_OF_BOUND)
+            );
+            0
+        });
+        assert_eq!(code, 0);
+    }
+
+    fn load_header_number(index: u64, source: u64) -> Result<u64, c_int> {
+        let mut data = [0u8; 256];
+        let mut len = data.len() as u64;
+        match ckb_load_header(data.as_mut_ptr().cast(), &mut len, 0, index, source) {
+            CKB_SUCCESS => {
+                let header = Header::from_slice(&data[..len as usize]);
+                Ok(header.unwrap().raw().number().unpack())
+            }
+            code => Err(code),
+        }
+    }
+
+    #[test]
+    fn header_deps_follow_the_transaction() {
+        let mut tx = test_utils::sample_tx();
+        // Known by hash only, as the header of the block committing input 0.
+        let committing = HeaderView::new_advanced_builder()
+            .number(7u64.pack())
+            .epoch(EpochNumberWithFraction::new(0, 7, 1000).pack())
+            .build();
+        tx.mock_info.header_deps.insert(0, committing.clone());
+        tx.mock_info.inputs[0].header = Some(committing.hash());
+        let raw = tx.tx.raw();
+        let header_deps = raw
+            .header_deps()
+            .as_builder()
+            .push([7u8; 32].pack())
+            .build();
+        tx.tx = tx
+            .tx
+            .as_builder()
+            .raw(raw.as_builder().header_deps(header_deps).build())
+            .build();
+        let setup = RunningSetup {
+            is_lock_script: true,
+            ..Default::default()
+        };
+
+        let sim = Simulation::new(tx, setup);
+        let code = sim.run_root("header deps".to_string(), |_, _| {
+            assert_eq!(load_header_number(0, SOURCE_HEADER_DEP), Ok(5));
+            assert_eq!(
+                load_header_number(1, SOURCE_HEADER_DEP),
+                Err(CKB_ITEM_MISSING)
+            );
+            assert_eq!(
+                load_header_number(2, SOURCE_HEADER_DEP),
+                Err(CKB_INDEX_OUT_OF_BOUND)
+            );
+            assert_eq!(load_header_number(0, SOURCE_INPUT), Ok(7));
+            0
+        });
+        assert_eq!(code, 0);
+    }
+
+    /// Exit status of a process loading `tx` and `setup` from the
+    /// environment.
+    fn env_setup_status(name: &str, tx: &MockTransaction, setup: &RunningSetup) -> c_int {
+        let tx_file = test_utils::temp_file(
+            &format!("{}_tx.json", name),
+            &serde_json::to_vec(&test_utils::tx_json(tx)).unwrap(),
+        );
+        let setup_file = test_utils::temp_file(
+            &format!("{}_setup.json", name),
+            &serde_json::to_vec(setup).unwrap(),
+        );
+        let child = unsafe { libc::fork() };
+        if child == 0 {
+            std::env::set_var(tx_loader::TX_FILE_ENV, tx_file);
+            std::env::set_var("CKB_RUNNING_SETUP", setup_file);
+            let _ = SETUP.clone();
+            unsafe { libc::_exit(0) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+        assert!(libc::WIFEXITED(status));
+        libc::WEXITSTATUS(status)
+    }
+
+    #[test]
+    fn env_setups_are_prechecked() {
+        let mut tx = test_utils::sample_tx();
+        let raw = tx
+            .tx
+            .raw()
+            .as_builder()
+            .outputs(Default::default())
+            .outputs_data(Default::default())
+            .build();
+        tx.tx = tx.tx.as_builder().raw(raw).build();
+
+        let status = env_setup_status("unchecked", &tx, &RunningSetup::default());
+        assert_eq!(status, 0);
+        let setup = RunningSetup {
+            check_structure: true,
+            ..Default::default()
+        };
+        let status = env_setup_status("prechecked", &tx, &setup);
+        assert_eq!(status, SIMULATOR_PRECHECK_EXIT_CODE as u8 as c_int);
+    }
+
+    #[test]
+    fn bound_loads_are_decoded_to_the_debug_sink() {
+        let tx = test_utils::sample_tx();
+        let script = tx.mock_info.inputs[0].output.lock();
+        let mut setup = RunningSetup {
+            is_lock_script: true,
+            ..Default::default()
+        };
+        setup
+            .molecule
+            .syscalls
+            .insert("ckb_load_script".to_string(), "Script".to_string());
+        let sim = Simulation::new(tx, setup);
+        let messages = Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = messages.clone();
+        sim.set_debug_sink(move |m| sink.lock().unwrap().push(m.to_string()));
+        let code = sim.run_root("molecule".to_string(), |_, _| {
+            let mut data = [0u8; 128];
+            for (size, offset) in [(0, 0), (128, 1), (128, 0)] {
+                let mut len = size;
+                let ret = ckb_load_script(data.as_mut_ptr().cast(), &mut len, offset);
+                assert_eq!(ret, CKB_SUCCESS);
+            }
+            0
+        });
+        assert_eq!(code, 0);
+        let rendered = Schema::default().render("Script", script.as_slice());
+        assert_eq!(
+            *messages.lock().unwrap(),
+            vec![format!("ckb_load_script: {}", rendered)]
+        );
+    }
+
+    /// Spawns the code `place` and `bounds` select in input 0 or witness 0
+    /// with argv `ab`, returns the spawn result and the child's exit code.
+    fn spawn_code(place: u64, bounds: u64) -> Result<i8, c_int> {
+        let argv = [c"ab".as_ptr()];
+        let inherited_fds = [0u64];
+        let mut pid = 0;
+        let spawn_args = SpawnArgs {
+            argc: 1,
+            argv: argv.as_ptr(),
+            process_id: &mut pid,
+            inherited_fds: inherited_fds.as_ptr(),
+        };
+        match ckb_spawn(0, SOURCE_INPUT, place, bounds, &spawn_args) {
+            CKB_SUCCESS => {
+                let mut code = 0;
+                assert_eq!(ckb_wait(pid, &mut code), CKB_SUCCESS);
+                Ok(code)
+            }
+            code => Err(code),
+        }
+    }
+
+    #[test]
+    fn spawned_code_is_located_by_bounds() {
+        let code = b"code";
+        let data = Bytes::from([&b"xx"[..], code, b"yyy"].concat());
+        let witness = Bytes::from(&b"witness code"[..]);
+        let mut tx = test_utils::sample_tx();
+        tx.mock_info.inputs[0].data = data.clone();
+        tx.tx = tx
+            .tx
+            .as_builder()
+            .witnesses(vec![witness.pack()].pack())
+            .build();
+        let hash_key = |code: &[u8]| format!("0x{}", faster_hex::hex_string(&blake2b_256(code)));
+        let data_hash = CellOutput::calc_data_hash(&data);
+        let spawn_key = utils::simulator_key(data_hash.as_slice(), 0xFF, 3, 2);
+        let setup = RunningSetup {
+            native_binaries: [
+                (hash_key(code), test_utils::argv_dylib()),
+                (hash_key(&witness[8..]), test_utils::argv_dylib()),
+                (spawn_key, test_utils::argv_dylib()),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let sim = Simulation::new(tx, setup);
+        let code = test_utils::with_timeout(move || {
+            sim.run_root("spawner".to_string(), |_, _| {
+                let ab = Ok(test_utils::argv_code(&[b"ab"]));
+                let bounds = |offset: u64, length: u64| offset << 32 | length;
+                assert_eq!(spawn_code(PLACE_CELL_DATA, bounds(2, 4)), ab);
+                assert_eq!(spawn_code(PLACE_CELL_DATA, bounds(3, 2)), ab);
+                assert_eq!(spawn_code(PLACE_WITNESS, bounds(8, 0)), ab);
+                assert_eq!(spawn_code(2, 0), Err(CKB_ITEM_MISSING));
+                0
+            })
+        });
+        assert_eq!(code, 0);
+    }
+
+    /// Exit code of the process running `start` in the root VM of a
+    /// simulation of `sample_tx` mapping no native binary.
+    fn unmapped_code_exit(test: &str, start: fn() -> c_int) -> Option<i32> {
+        let status = test_utils::exit_status_of(test, || {
+            let setup = RunningSetup {
+                vm_version: 2,
+                ..Default::default()
+            };
+            let sim = Simulation::new(test_utils::sample_tx(), setup);
+            sim.run_root("starter".to_string(), move |_, _| {
+                start();
+                0
+            });
+        });
+        status.code()
+    }
+
+    const MISSING_BINARY_EXIT: Option<i32> = Some(SIMULATOR_MISSING_BINARY_EXIT_CODE as u8 as i32);
+
+    #[test]
+    fn unmapped_spawned_cells_end_the_process() {
+        let exit = unmapped_code_exit("tests::unmapped_spawned_cells_end_the_process", || {
+            let mut pid = 0;
+            let inherited_fds = [0u64];
+            let code_hash = [1u8; 32];
+            ckb_spawn_cell(
+                code_hash.as_ptr(),
+                1,
+                0,
+                0,
+                0,
+                std::ptr::null(),
+                inherited_fds.as_ptr(),
+                &mut pid,
+            )
+        });
+        assert_eq!(exit, MISSING_BINARY_EXIT);
+    }
+
+    #[test]
+    fn unmapped_exec_cells_end_the_process() {
+        let exit = unmapped_code_exit("tests::unmapped_exec_cells_end_the_process", || {
+            let code_hash = [1u8; 32];
+            ckb_exec_cell(code_hash.as_ptr(), 1, 0, 0, 0, std::ptr::null())
+        });
+        assert_eq!(exit, MISSING_BINARY_EXIT);
+    }
+
+    #[test]
+    fn unmapped_spawned_code_ends_the_process() {
+        let exit = unmapped_code_exit("tests::unmapped_spawned_code_ends_the_process", || {
+            spawn_code(PLACE_CELL_DATA, 0).err().unwrap_or(CKB_SUCCESS)
+        });
+        assert_eq!(exit, MISSING_BINARY_EXIT);
+    }
+
+    #[test]
+    fn unmapped_exec_code_ends_the_process() {
+        let exit = unmapped_code_exit("tests::unmapped_exec_code_ends_the_process", || {
+            ckb_exec(0, SOURCE_INPUT, PLACE_WITNESS, 0, 0, std::ptr::null())
+        });
+        assert_eq!(exit, MISSING_BINARY_EXIT);
+    }
+
+    #[test]
+    fn spawned_code_must_be_in_the_cell() {
+        let sim = Simulation::new(test_utils::sample_tx(), RunningSetup::default());
+        let code = sim.run_root("spawner".to_string(), |_, _| {
+            let bounds = |offset: u64, length: u64| offset << 32 | length;
+            // Input 0 has 3 bytes of data.
+            for (offset, length) in [(2, 2), (4, 0), (0, 4), (3, 1)] {
+                assert_eq!(
+                    spawn_code(PLACE_CELL_DATA, bounds(offset, length)),
+                    Err(CKB_SLICE_OUT_OF_BOUND),
+                    "offset {}, length {}",
+                    offset,
+                    length
+                );
+            }
+            assert_eq!(
+                spawn_code(PLACE_WITNESS, bounds(2, 0)),
+                Err(CKB_SLICE_OUT_OF_BOUND)
+            );
+            0
+        });
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    fn witness_code_runs_with_exec_and_spawn() {
+        let code = Bytes::from(&b"native code"[..]);
+        let mut tx = test_utils::sample_tx();
+        tx.tx = tx
+            .tx
+            .as_builder()
+            .witnesses(vec![code.pack()].pack())
+            .build();
+        let key = format!("0x{}", faster_hex::hex_string(&blake2b_256(&code)));
+        let setup = RunningSetup {
+            is_lock_script: true,
+            vm_version: 2,
+            run_type: Some(RunningType::DynamicLib),
+            native_binaries: [(key, test_utils::argv_dylib())].into(),
+            ..Default::default()
+        };
+        let sim = Simulation::new(tx, setup);
+        let code = test_utils::with_timeout(move || {
+            sim.run_root("witness code".to_string(), |_, _| {
+                let argv = [c"ab".as_ptr() as *const u8];
+                let exec =
+                    |index, source| ckb_exec(index, source, PLACE_WITNESS, 0, 1, argv.as_ptr());
+                let ab = test_utils::argv_code(&[b"ab"]);
+                assert_eq!(exec(0, SOURCE_INPUT), ab as c_int);
+                assert_eq!(exec(0, SOURCE_GROUP_INPUT), ab as c_int);
+                assert_eq!(exec(1, SOURCE_INPUT), CKB_INDEX_OUT_OF_BOUND);
+                assert_eq!(exec(1, SOURCE_GROUP_INPUT), CKB_INDEX_OUT_OF_BOUND);
+                assert_eq!(spawn_code(PLACE_WITNESS, 0), Ok(ab));
+                0
+            })
+        });
+        assert_eq!(code, 0);
+    }
+
+    /// `sample_tx` whose only cell dep is a dep group of `members`, the
+    /// mock info having cells at out points [10; 32] and [11; 32].
+    fn dep_group_tx(members: &[[u8; 32]]) -> MockTransaction {
+        let mut tx = test_utils::sample_tx();
+        let group = tx.mock_info.cell_deps[0].clone();
+        let members: Vec<_> = members.iter().map(|m| OutPoint::new(m.pack(), 0)).collect();
+        let group_dep = group
+            .cell_dep
+            .clone()
+            .as_builder()
+            .dep_type(DepType::DepGroup.into())
+            .build();
+        let mut cell_deps = vec![MockCellDep {
+            cell_dep: group_dep.clone(),
+            data: members.pack().as_bytes(),
+            ..group.clone()
+        }];
+        for byte in [10u8, 11] {
+            let out_point = OutPoint::new([byte; 32].pack(), 0);
+            cell_deps.push(MockCellDep {
+                cell_dep: CellDep::new_builder().out_point(out_point).build(),
+                data: Bytes::from(vec![byte]),
+                ..group.clone()
+            });
+        }
+        tx.mock_info.cell_deps = cell_deps;
+        let raw = tx
+            .tx
+            .raw()
+            .as_builder()
+            .cell_deps(vec![group_dep].pack())
+            .build();
+        tx.tx = tx.tx.as_builder().raw(raw).build();
+        tx
+    }
+
+    #[test]
+    fn dep_groups_expand_to_their_members() {
+        let sim = Simulation::new(dep_group_tx(&[[11; 32], [10; 32]]), Default::default());
+        let code = sim.run_root("dep group".to_string(), |_, _| {
+            assert_eq!(load_cell_data(0, SOURCE_CELL_DEP), Ok(vec![11]));
+            assert_eq!(load_cell_data(1, SOURCE_CELL_DEP), Ok(vec![10]));
+            assert_eq!(
+                load_cell_data(2, SOURCE_CELL_DEP),
+                Err(CKB_INDEX_OUT_OF_BOUND)
+            );
+            let resolved = get_cur_tx_mut!().resolved_cell_deps().unwrap();
+            let cached = get_cur_tx_mut!().resolved_cell_deps().unwrap();
+            assert!(Arc::ptr_eq(&resolved, &cached), "resolved once");
+            0
+        });
+        assert_eq!(code, 0);
+
+        let sim = Simulation::new(dep_group_tx(&[[10; 32], [12; 32]]), Default::default());
+        let code = sim.run_root("unknown member".to_string(), |_, _| {
+            assert_eq!(load_cell_data(0, SOURCE_CELL_DEP), Err(CKB_ITEM_MISSING));
+            0
+        });
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    fn outputs_without_data_are_out_of_bound() {
+        let mut tx = test_utils::sample_tx();
+        let raw = tx
+            .tx
+            .raw()
+            .as_builder()
+            .outputs_data(Default::default())
+            .build();
+        tx.tx = tx.tx.as_builder().raw(raw).build();
+
+        let sim = Simulation::new(tx, RunningSetup::default());
+        let code = sim.run_root("no output data".to_string(), |_, _| {
+            assert_eq!(
+                load_cell_data(0, SOURCE_OUTPUT),
+                Err(CKB_INDEX_OUT_OF_BOUND)
+            );
+            0
+        });
+        assert_eq!(code, 0);
+    }
+}
@@ -0,0 +1,521 @@
+//! Scripted mock child processes: a spawn target mapped in
+//! `native_binaries` to a `.json` file runs its steps over the pipes it
+//! inherits instead of a native binary, so the IPC protocol of a parent
+//! can be tested without building a child.
+//!
+//! ```json
+//! {
+//!     "steps": [
+//!         { "read": { "fd": 0, "data": "0x0100????" } },
+//!         { "write": { "fd": 1, "data": "0x02000000" } },
+//!         { "close": { "fd": 1 } }
+//!     ],
+//!     "exit_code": 0
+//! }
+//! ```
+//!
+//! `fd` is an index into the inherited fds, `??` matches any byte. Traffic
+//! of the parent that does not match ends the child with
+//! `SIMULATOR_MOCK_MISMATCH_EXIT_CODE`, the diff goes to the debug sink of
+//! the simulation or stderr. The root VM then ends with the same code, even
+//! when the parent ignores the child's.
+//!
+//! A recorded `Capture` of a real child turns into a script with
+//! `MockChild::from_capture`.
+use crate::{
+    capture::{Capture, PipeOp},
+    constants::{CKB_SUCCESS, SIMULATOR_MOCK_MISMATCH_EXIT_CODE},
+    get_cur_tx, get_cur_tx_mut,
+    global_data::GlobalData,
+    spawn::{ckb_close, ckb_inherited_fds, ckb_read, ckb_write},
+    SimContext,
+};
+use ckb_jsonrpc_types::JsonBytes;
+use serde_derive::{Deserialize, Serialize};
+use std::{os::raw::c_void, path::Path};
+
+/// Expected bytes, `None` matching any byte.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pattern(pub Vec<Option<u8>>);
+impl Pattern {
+    pub fn exact(data: &[u8]) -> Self {
+        Self(data.iter().map(|b| Some(*b)).collect())
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        self.0.len() == data.len()
+            && self
+                .0
+                .iter()
+                .zip(data)
+                .all(|(expected, b)| expected.is_none_or(|e| e == *b))
+    }
+
+    /// Expected and actual bytes, with the ones differing marked below.
+    pub fn diff(&self, data: &[u8]) -> String {
+        let marks: String = (0..self.0.len().max(data.len()))
+            .map(|i| match (self.0.get(i), data.get(i)) {
+                (Some(None), Some(_)) => "  ",
+                (Some(Some(e)), Some(b)) if e == b => "  ",
+                _ => "^^",
+            })
+            .collect();
+        format!(
+            "expected {}\n  got      0x{}\n             {}",
+            self,
+            faster_hex::hex_string(data),
+            marks.trim_end()
+        )
+    }
+}
+impl TryFrom<String> for Pattern {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let hex = s.strip_prefix("0x").unwrap_or(&s);
+        if !hex.len().is_multiple_of(2) {
+            return Err(format!("odd length pattern {}", s));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| match &hex[i..i + 2] {
+                "??" => Ok(None),
+                byte => u8::from_str_radix(byte, 16)
+                    .map(Some)
+                    .map_err(|_| format!("invalid byte {} in pattern {}", byte, s)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self(bytes))
+    }
+}
+impl From<Pattern> for String {
+    fn from(pattern: Pattern) -> Self {
+        pattern.to_string()
+    }
+}
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x")?;
+        for byte in &self.0 {
+            match byte {
+                Some(b) => write!(f, "{:02x}", b)?,
+                None => write!(f, "??")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    /// Reads as many bytes as `data` has, they must match it.
+    Read {
+        fd: usize,
+        data: Pattern,
+    },
+    Write {
+        fd: usize,
+        data: JsonBytes,
+    },
+    Close {
+        fd: usize,
+    },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MockChild {
+    pub steps: Vec<Step>,
+    #[serde(default)]
+    pub exit_code: i8,
+}
+impl MockChild {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MockChildError> {
+        let name = path.as_ref().display().to_string();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| MockChildError::Io(name.clone(), e.to_string()))?;
+        serde_json::from_str(&content).map_err(|e| MockChildError::Parse(name, e.to_string()))
+    }
+
+    /// The child `pid` of `capture` replayed: its reads expect the same
+    /// bytes, its writes and closes are repeated, and it exits the same.
+    pub fn from_capture(capture: &Capture, pid: u64) -> Result<Self, MockChildError> {
+        let process = capture
+            .process(pid)
+            .ok_or(MockChildError::UnknownProcess(pid))?;
+        let mut steps: Vec<Step> = vec![];
+        for event in capture.events.iter().filter(|e| e.pid == pid) {
+            let fd = process
+                .inherited_fds
+                .iter()
+                .position(|fd| *fd == event.fd)
+                .ok_or(MockChildError::UninheritedFd(pid, event.fd))?;
+            let step = match event.op {
+                PipeOp::Read => Step::Read {
+                    fd,
+                    data: Pattern::exact(event.data.as_bytes()),
+                },
+                PipeOp::Write => Step::Write {
+                    fd,
+                    data: event.data.clone(),
+                },
+                PipeOp::Close => Step::Close { fd },
+            };
+            // Reads and writes are split however the syscalls returned,
+            // consecutive ones on a fd are one step.
+            match (steps.last_mut(), step) {
+                (Some(Step::Read { fd: a, data }), Step::Read { fd: b, data: more }) if *a == b => {
+                    data.0.extend(more.0)
+                }
+                (Some(Step::Write { fd: a, data }), Step::Write { fd: b, data: more })
+                    if *a == b =>
+                {
+                    *data = JsonBytes::from_vec([data.as_bytes(), more.as_bytes()].concat())
+                }
+                (_, step) => steps.push(step),
+            }
+        }
+        Ok(Self {
+            steps,
+            exit_code: process.exit_code.unwrap_or_default(),
+        })
+    }
+
+    /// Runs the steps as the current process and returns the exit code,
+    /// or the first step the parent does not match. `name` identifies the
+    /// child.
+    pub fn run(&self, name: &str) -> Result<i8, MockChildError> {
+        let mut fds = [0u64; 64];
+        let mut len = fds.len();
+        ckb_inherited_fds(fds.as_mut_ptr(), &mut len);
+
+        for (i, step) in self.steps.iter().enumerate() {
+            run_step(step, &fds[..len])
+                .map_err(|message| MockChildError::Mismatch(name.to_string(), i, message))?;
+        }
+        Ok(self.exit_code)
+    }
+
+    /// `run` as a spawned process: a mismatch is reported and ends it with
+    /// `SIMULATOR_MOCK_MISMATCH_EXIT_CODE`, failing the run.
+    pub(crate) fn run_spawned(&self, name: &str) -> i8 {
+        self.run(name).unwrap_or_else(|e| {
+            fail_run(&e.to_string());
+            SIMULATOR_MOCK_MISMATCH_EXIT_CODE
+        })
+    }
+}
+
+/// Reports `message` to the debug sink or stderr and fails the run: its
+/// root VM ends with `SIMULATOR_MOCK_MISMATCH_EXIT_CODE`.
+pub(crate) fn fail_run(message: &str) {
+    match get_cur_tx!().debug_sink() {
+        Some(sink) => sink(message),
+        None => eprintln!("[mock child] {}", message),
+    }
+    get_cur_tx_mut!().fail_mock_child();
+}
+
+fn run_step(step: &Step, fds: &[u64]) -> Result<(), String> {
+    let index = match step {
+        Step::Read { fd, .. } | Step::Write { fd, .. } | Step::Close { fd } => *fd,
+    };
+    let fd = *fds
+        .get(index)
+        .ok_or_else(|| format!("no inherited fd {}, there are {}", index, fds.len()))?;
+    match step {
+        Step::Read { data, .. } => {
+            let read = read_exact(fd, data.0.len());
+            if !data.matches(&read) {
+                return Err(format!(
+                    "read from fd {} does not match\n  {}",
+                    fd,
+                    data.diff(&read)
+                ));
+            }
+        }
+        Step::Write { data, .. } => {
+            let mut data = data.as_bytes();
+            while !data.is_empty() {
+                let mut len = data.len();
+                let ret = ckb_write(fd, data.as_ptr() as *const c_void, &mut len);
+                if ret != CKB_SUCCESS {
+                    return Err(format!("write to fd {} returns {}", fd, ret));
+                }
+                data = &data[len..];
+            }
+        }
+        Step::Close { .. } => {
+            let ret = ckb_close(fd);
+            if ret != CKB_SUCCESS {
+                return Err(format!("close of fd {} returns {}", fd, ret));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Up to `length` bytes from `fd`, fewer when the other end is closed.
+fn read_exact(fd: u64, length: usize) -> Vec<u8> {
+    let mut data = vec![0u8; length];
+    let mut read = 0;
+    while read < length {
+        let mut len = length - read;
+        let ret = ckb_read(fd, data[read..].as_mut_ptr() as *mut c_void, &mut len);
+        if ret != CKB_SUCCESS || len == 0 {
+            break;
+        }
+        read += len;
+    }
+    data.truncate(read);
+    data
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MockChildError {
+    /// File, error message.
+    Io(String, String),
+    /// File, error message.
+    Parse(String, String),
+    /// The capture has no such process.
+    UnknownProcess(u64),
+    /// Process, fd: the capture uses a fd the process did not inherit.
+    UninheritedFd(u64, u64),
+    /// Child, step, what the parent did instead.
+    Mismatch(String, usize, String),
+}
+impl std::fmt::Display for MockChildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(file, msg) => write!(f, "read {}: {}", file, msg),
+            Self::Parse(file, msg) => write!(f, "parse {}: {}", file, msg),
+            Self::UnknownProcess(pid) => write!(f, "process {} is not in the capture", pid),
+            Self::UninheritedFd(pid, fd) => {
+                write!(f, "process {} uses fd {} it did not inherit", pid, fd)
+            }
+            Self::Mismatch(child, step, msg) => write!(f, "{}, step {}: {}", child, step, msg),
+        }
+    }
+}
+impl std::error::Error for MockChildError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        capture::{CapturedProcess, PipeEvent},
+        ckb_pipe, ckb_spawn_cell, ckb_wait,
+        constants::CKB_ITEM_MISSING,
+        test_utils, RunningSetup, Simulation,
+    };
+    use std::sync::{Arc, Mutex};
+
+    /// Spawns `child` with a pipe to it as fd 0 and one from it as fd 1,
+    /// writes `request`, reads what comes back until the child closes its
+    /// end. Returns the exit code of the run, the child's when `wait` and 0
+    /// otherwise, the reply and the debug output.
+    fn talk(child: &MockChild, request: &'static [u8], wait: bool) -> (i8, Vec<u8>, Vec<String>) {
+        let code_hash = [1u8; 32];
+        let path = test_utils::temp_file(
+            &format!("mock_child_{}.json", faster_hex::hex_string(request)),
+            serde_json::to_string(child).unwrap().as_bytes(),
+        );
+        let setup = RunningSetup {
+            native_binaries: [(test_utils::cell_key(&code_hash, 1), path)].into(),
+            ..Default::default()
+        };
+        let sim = Simulation::new(Default::default(), setup);
+        let messages = Arc::new(Mutex::new(vec![]));
+        let sink = messages.clone();
+        sim.set_debug_sink(move |m| sink.lock().unwrap().push(m.to_string()));
+        let reply = Arc::new(Mutex::new(vec![]));
+        let replied = reply.clone();
+        let code = test_utils::with_timeout(move || {
+            sim.run_root("parent".to_string(), move |_, _| {
+                let (mut to_child, mut from_child) = ([0u64; 2], [0u64; 2]);
+                assert_eq!(ckb_pipe(to_child.as_mut_ptr()), CKB_SUCCESS);
+                assert_eq!(ckb_pipe(from_child.as_mut_ptr()), CKB_SUCCESS);
+                let inherited_fds = [to_child[0], from_child[1], 0];
+                let mut pid = 0;
+                let ret = ckb_spawn_cell(
+                    code_hash.as_ptr(),
+                    1,
+                    0,
+                    0,
+                    0,
+                    std::ptr::null(),
+                    inherited_fds.as_ptr(),
+                    &mut pid,
+                );
+                assert_eq!(ret, CKB_SUCCESS);
+                let mut len = request.len();
+                ckb_write(to_child[1], request.as_ptr() as *const c_void, &mut len);
+                *replied.lock().unwrap() = read_exact(from_child[0], 64);
+                let mut code = 0;
+                if wait {
+                    assert_eq!(ckb_wait(pid, &mut code), CKB_SUCCESS);
+                }
+                code
+            })
+        });
+        let reply = reply.lock().unwrap().clone();
+        let messages = messages.lock().unwrap().clone();
+        (code, reply, messages)
+    }
+
+    fn echo_child() -> MockChild {
+        serde_json::from_str(
+            r#"{
+                "steps": [
+                    { "read": { "fd": 0, "data": "0x0100????" } },
+                    { "write": { "fd": 1, "data": "0x0200" } },
+                    { "close": { "fd": 1 } }
+                ],
+                "exit_code": 5
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn matching_parents_get_the_scripted_replies() {
+        let (code, reply, messages) = talk(&echo_child(), &[1, 0, 7, 8], true);
+        assert_eq!((code, reply), (5, vec![2, 0]));
+        assert!(messages.is_empty(), "{:?}", messages);
+        let (code, _, _) = talk(&echo_child(), &[1, 0, 7, 9], false);
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    fn mismatching_parents_get_a_failing_child() {
+        let (code, reply, messages) = talk(&echo_child(), &[1, 1, 7, 8], true);
+        assert_eq!(code, SIMULATOR_MOCK_MISMATCH_EXIT_CODE);
+        assert!(reply.is_empty());
+        assert_eq!(messages.len(), 1);
+        let diff = "expected 0x0100????\n  got      0x01010708\n               ^^";
+        assert!(
+            messages[0].ends_with(&format!(
+                ", step 0: read from fd 2 does not match\n  {}",
+                diff
+            )),
+            "{}",
+            messages[0]
+        );
+    }
+
+    #[test]
+    fn mismatches_fail_the_run_when_the_parent_does_not_wait() {
+        let (code, _, messages) = talk(&echo_child(), &[1, 1, 7, 9], false);
+        assert_eq!(code, SIMULATOR_MOCK_MISMATCH_EXIT_CODE);
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn unloadable_children_fail_the_spawn_and_the_run() {
+        let code_hash = [1u8; 32];
+        let path = test_utils::temp_file("mock_child_broken.json", b"{ \"steps\": 1 }");
+        let setup = RunningSetup {
+            native_binaries: [(test_utils::cell_key(&code_hash, 1), path)].into(),
+            ..Default::default()
+        };
+        let sim = Simulation::new(Default::default(), setup);
+        let messages = Arc::new(Mutex::new(vec![]));
+        let sink = messages.clone();
+        sim.set_debug_sink(move |m| sink.lock().unwrap().push(m.to_string()));
+        let code = sim.run_root("parent".to_string(), move |_, _| {
+            let inherited_fds = [0u64];
+            let mut pid = 0;
+            let ret = ckb_spawn_cell(
+                code_hash.as_ptr(),
+                1,
+                0,
+                0,
+                0,
+                std::ptr::null(),
+                inherited_fds.as_ptr(),
+                &mut pid,
+            );
+            assert_eq!(ret, CKB_ITEM_MISSING);
+            0
+        });
+        assert_eq!(code, SIMULATOR_MOCK_MISMATCH_EXIT_CODE);
+        let messages = messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(
+            messages[0].contains("mock_child_broken.json"),
+            "{}",
+            messages[0]
+        );
+    }
+
+    #[test]
+    fn captures_replay_as_steps() {
+        let event = |pid, fd, op, data: &[u8]| PipeEvent {
+            pid,
+            fd,
+            op,
+            data: JsonBytes::from_vec(data.to_vec()),
+        };
+        let capture = Capture {
+            pipes: vec![],
+            processes: vec![CapturedProcess {
+                pid: 1,
+                inherited_fds: vec![2, 5],
+                exit_code: Some(3),
+            }],
+            events: vec![
+                event(0, 3, PipeOp::Write, &[1, 2]),
+                event(1, 2, PipeOp::Read, &[1]),
+                event(1, 2, PipeOp::Read, &[2]),
+                event(1, 5, PipeOp::Write, &[3]),
+                event(1, 5, PipeOp::Write, &[4]),
+                event(1, 5, PipeOp::Close, &[]),
+                event(0, 4, PipeOp::Read, &[3, 4]),
+            ],
+        };
+        let child = MockChild::from_capture(&capture, 1).unwrap();
+        assert_eq!(
+            child,
+            MockChild {
+                steps: vec![
+                    Step::Read {
+                        fd: 0,
+                        data: Pattern::exact(&[1, 2])
+                    },
+                    Step::Write {
+                        fd: 1,
+                        data: JsonBytes::from_vec(vec![3, 4])
+                    },
+                    Step::Close { fd: 1 },
+                ],
+                exit_code: 3,
+            }
+        );
+        assert_eq!(
+            MockChild::from_capture(&capture, 0),
+            Err(MockChildError::UnknownProcess(0))
+        );
+        let mut uninherited = capture.clone();
+        uninherited.processes[0].inherited_fds.pop();
+        assert_eq!(
+            MockChild::from_capture(&uninherited, 1),
+            Err(MockChildError::UninheritedFd(1, 5))
+        );
+    }
+
+    #[test]
+    fn patterns_parse_and_match() {
+        let pattern = Pattern::try_from("0x01??ff".to_string()).unwrap();
+        assert_eq!(pattern, Pattern(vec![Some(1), None, Some(0xff)]));
+        assert_eq!(pattern.to_string(), "0x01??ff");
+        assert!(pattern.matches(&[1, 9, 0xff]));
+        assert!(!pattern.matches(&[1, 9]));
+        assert!(Pattern::try_from("0x012".to_string()).is_err());
+        assert!(Pattern::try_from("0xzz".to_string()).is_err());
+        assert_eq!(
+            pattern.diff(&[2, 9]),
+            "expected 0x01??ff\n  got      0x0209\n             ^^  ^^"
+        );
+    }
+}
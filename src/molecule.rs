@@ -0,0 +1,923 @@
+//! Decodes molecule structures with `.mol` schemas, so witnesses, cell data
+//! and syscall buffers can be shown as tables instead of opaque bytes.
+//!
+//! Schemas are bound in the running setup, see `MoleculeBindings`. The types
+//! of ckb's `blockchain.mol` are always known, and `import blockchain;`
+//! falls back to them when the file is not next to the importing schema.
+use ckb_types::packed::CellOutput;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+const BLOCKCHAIN_MOL: &str = "
+array Uint32 [byte; 4];
+array Uint64 [byte; 8];
+array Uint128 [byte; 16];
+array Byte32 [byte; 32];
+array Uint256 [byte; 32];
+vector Bytes <byte>;
+option BytesOpt (Bytes);
+vector BytesOptVec <BytesOpt>;
+vector BytesVec <Bytes>;
+vector Byte32Vec <Byte32>;
+option ScriptOpt (Script);
+array ProposalShortId [byte; 10];
+vector ProposalShortIdVec <ProposalShortId>;
+vector CellDepVec <CellDep>;
+vector CellInputVec <CellInput>;
+vector CellOutputVec <CellOutput>;
+table Script { code_hash: Byte32, hash_type: byte, args: Bytes, }
+struct OutPoint { tx_hash: Byte32, index: Uint32, }
+struct CellInput { since: Uint64, previous_output: OutPoint, }
+table CellOutput { capacity: Uint64, lock: Script, type_: ScriptOpt, }
+struct CellDep { out_point: OutPoint, dep_type: byte, }
+table RawTransaction {
+    version: Uint32, cell_deps: CellDepVec, header_deps: Byte32Vec,
+    inputs: CellInputVec, outputs: CellOutputVec, outputs_data: BytesVec,
+}
+table Transaction { raw: RawTransaction, witnesses: BytesVec, }
+struct RawHeader {
+    version: Uint32, compact_target: Uint32, timestamp: Uint64, number: Uint64,
+    epoch: Uint64, parent_hash: Byte32, transactions_root: Byte32,
+    proposals_hash: Byte32, extra_hash: Byte32, dao: Byte32,
+}
+struct Header { raw: RawHeader, nonce: Uint128, }
+table WitnessArgs { lock: BytesOpt, input_type: BytesOpt, output_type: BytesOpt, }
+";
+
+/// Nesting limit of fixed size types, deeper ones are taken as recursive.
+const MAX_FIXED_DEPTH: usize = 64;
+// Recursive tables, vectors and unions nest 4 bytes a level, decoding stops
+// before the stack runs out.
+const MAX_DECODE_DEPTH: usize = 128;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SchemaError {
+    /// File, error message.
+    Io(String, String),
+    /// File, line, error message.
+    Parse(String, usize, String),
+    /// A type referenced but never defined, with the type using it.
+    UnknownType(String, String),
+    /// An array or struct containing itself, or a dynamic type.
+    InvalidFixedType(String),
+    /// An option of an option, which molecule forbids.
+    OptionOfOption(String),
+}
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(file, msg) => write!(f, "read {}: {}", file, msg),
+            Self::Parse(file, line, msg) => write!(f, "{}:{}: {}", file, line, msg),
+            Self::UnknownType(name, user) => write!(f, "unknown type {} used by {}", name, user),
+            Self::InvalidFixedType(name) => {
+                write!(f, "{} must have a fixed size and not contain itself", name)
+            }
+            Self::OptionOfOption(name) => write!(f, "{} is an option of an option", name),
+        }
+    }
+}
+impl std::error::Error for SchemaError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    /// Where decoding failed, like `WitnessArgs.lock`.
+    pub path: String,
+    pub message: String,
+}
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+impl std::error::Error for DecodeError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    Byte,
+    Array(String, usize),
+    Struct(Vec<(String, String)>),
+    /// A fixvec or dynvec, depending on whether the item has a fixed size.
+    Vector(String),
+    Table(Vec<(String, String)>),
+    Option(String),
+    /// Item types with their ids.
+    Union(Vec<(String, u32)>),
+}
+impl Type {
+    fn references(&self) -> Vec<&String> {
+        match self {
+            Self::Byte => vec![],
+            Self::Array(item, _) | Self::Vector(item) | Self::Option(item) => vec![item],
+            Self::Struct(fields) | Self::Table(fields) => fields.iter().map(|(_, t)| t).collect(),
+            Self::Union(items) => items.iter().map(|(t, _)| t).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    /// Byte arrays and vectors of bytes.
+    Bytes(Vec<u8>),
+    /// `Uint*` byte arrays, read as little endian.
+    Number(u128),
+    List(Vec<Value>),
+    /// Struct or table fields, with the type name.
+    Fields(String, Vec<(String, Value)>),
+    Option(Option<Box<Value>>),
+    /// Union item, with its type name.
+    Union(String, Box<Value>),
+}
+impl Value {
+    fn write(&self, out: &mut String, indent: usize) {
+        let pad = "  ".repeat(indent + 1);
+        match self {
+            Self::Bytes(data) => out.push_str(&format!("0x{}", faster_hex::hex_string(data))),
+            Self::Number(n) => out.push_str(&n.to_string()),
+            Self::List(items) if items.is_empty() => out.push_str("[]"),
+            Self::List(items) => {
+                out.push_str("[\n");
+                for item in items {
+                    out.push_str(&pad);
+                    item.write(out, indent + 1);
+                    out.push_str(",\n");
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push(']');
+            }
+            Self::Fields(name, fields) => {
+                out.push_str(name);
+                out.push_str(" {\n");
+                for (field, value) in fields {
+                    out.push_str(&format!("{}{}: ", pad, field));
+                    value.write(out, indent + 1);
+                    out.push_str(",\n");
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push('}');
+            }
+            Self::Option(None) => out.push_str("None"),
+            Self::Option(Some(value)) => {
+                out.push_str("Some(");
+                value.write(out, indent);
+                out.push(')');
+            }
+            Self::Union(name, value) => {
+                out.push_str(name);
+                out.push('(');
+                value.write(out, indent);
+                out.push(')');
+            }
+        }
+    }
+}
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut out = String::new();
+        self.write(&mut out, 0);
+        write!(f, "{}", out)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Schema {
+    pub types: HashMap<String, Type>,
+}
+impl Default for Schema {
+    /// The primitive `byte` and the types of `blockchain.mol`.
+    fn default() -> Self {
+        let mut schema = Self {
+            types: [("byte".to_string(), Type::Byte)].into(),
+        };
+        schema
+            .parse("blockchain.mol", BLOCKCHAIN_MOL, None, &mut vec![])
+            .expect("builtin schema");
+        schema
+    }
+}
+
+struct Token {
+    text: String,
+    line: usize,
+}
+
+fn tokenize(file: &str, content: &str) -> Result<Vec<Token>, SchemaError> {
+    let mut tokens = vec![];
+    let mut chars = content.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if last == '*' => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            last = c;
+                        }
+                        None => {
+                            return Err(SchemaError::Parse(
+                                file.to_string(),
+                                line,
+                                "unterminated comment".to_string(),
+                            ))
+                        }
+                    }
+                }
+            }
+            '{' | '}' | '[' | ']' | '<' | '>' | '(' | ')' | ';' | ':' | ',' => tokens.push(Token {
+                text: c.to_string(),
+                line,
+            }),
+            c if c.is_alphanumeric() || "_./-".contains(c) => {
+                let mut text = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || "_./-".contains(c)) {
+                        break;
+                    }
+                    text.push(c);
+                    chars.next();
+                }
+                tokens.push(Token { text, line });
+            }
+            c => {
+                return Err(SchemaError::Parse(
+                    file.to_string(),
+                    line,
+                    format!("unexpected character {:?}", c),
+                ))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    file: &'a str,
+    tokens: std::vec::IntoIter<Token>,
+    line: usize,
+}
+impl Parser<'_> {
+    fn error(&self, message: String) -> SchemaError {
+        SchemaError::Parse(self.file.to_string(), self.line, message)
+    }
+
+    fn next(&mut self) -> Result<String, SchemaError> {
+        let token = self
+            .tokens
+            .next()
+            .ok_or_else(|| self.error("unexpected end of file".to_string()))?;
+        self.line = token.line;
+        Ok(token.text)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.as_slice().first().map(|t| t.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), SchemaError> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(self.error(format!("expected {}, found {}", expected, token)));
+        }
+        Ok(())
+    }
+
+    fn number(&mut self) -> Result<u32, SchemaError> {
+        let token = self.next()?;
+        token
+            .parse()
+            .map_err(|_| self.error(format!("expected a number, found {}", token)))
+    }
+
+    /// `{ name: Type, ... }`
+    fn fields(&mut self) -> Result<Vec<(String, String)>, SchemaError> {
+        self.expect("{")?;
+        let mut fields = vec![];
+        while self.peek() != Some("}") {
+            let name = self.next()?;
+            self.expect(":")?;
+            fields.push((name, self.next()?));
+            if self.peek() == Some(",") {
+                self.next()?;
+            }
+        }
+        self.expect("}")?;
+        Ok(fields)
+    }
+
+    /// `{ Type, Type: id, ... }`, ids count on from the previous one.
+    fn union_items(&mut self) -> Result<Vec<(String, u32)>, SchemaError> {
+        self.expect("{")?;
+        let mut items = vec![];
+        let mut next_id = 0;
+        while self.peek() != Some("}") {
+            let name = self.next()?;
+            let mut id = next_id;
+            if self.peek() == Some(":") {
+                self.next()?;
+                id = self.number()?;
+            }
+            items.push((name, id));
+            next_id = id + 1;
+            if self.peek() == Some(",") {
+                self.next()?;
+            }
+        }
+        self.expect("}")?;
+        Ok(items)
+    }
+}
+
+impl Schema {
+    /// The default types plus the ones of every file in `files`.
+    pub fn load<P: AsRef<Path>>(files: &[P]) -> Result<Self, SchemaError> {
+        let mut schema = Self::default();
+        let mut loaded = vec![];
+        for file in files {
+            schema.load_file(file.as_ref(), &mut loaded)?;
+        }
+        schema.validate()?;
+        Ok(schema)
+    }
+
+    fn load_file(&mut self, path: &Path, loaded: &mut Vec<PathBuf>) -> Result<(), SchemaError> {
+        if loaded.iter().any(|p| p == path) {
+            return Ok(());
+        }
+        loaded.push(path.to_path_buf());
+        let name = path.display().to_string();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| SchemaError::Io(name.clone(), e.to_string()))?;
+        self.parse(&name, &content, path.parent(), loaded)
+    }
+
+    fn parse(
+        &mut self,
+        file: &str,
+        content: &str,
+        dir: Option<&Path>,
+        loaded: &mut Vec<PathBuf>,
+    ) -> Result<(), SchemaError> {
+        let mut parser = Parser {
+            file,
+            tokens: tokenize(file, content)?.into_iter(),
+            line: 1,
+        };
+        while parser.peek().is_some() {
+            let keyword = parser.next()?;
+            if keyword == "import" {
+                let import = parser.next()?;
+                parser.expect(";")?;
+                let path = dir
+                    .unwrap_or(Path::new("."))
+                    .join(format!("{}.mol", import));
+                // blockchain.mol is already in the default types.
+                if !path.exists() && import.ends_with("blockchain") {
+                    continue;
+                }
+                self.load_file(&path, loaded)?;
+                continue;
+            }
+            let name = parser.next()?;
+            let ty = match keyword.as_str() {
+                "array" => {
+                    parser.expect("[")?;
+                    let item = parser.next()?;
+                    parser.expect(";")?;
+                    let count = parser.number()?;
+                    parser.expect("]")?;
+                    parser.expect(";")?;
+                    Type::Array(item, count as usize)
+                }
+                "struct" => Type::Struct(parser.fields()?),
+                "table" => Type::Table(parser.fields()?),
+                "vector" => {
+                    parser.expect("<")?;
+                    let item = parser.next()?;
+                    parser.expect(">")?;
+                    parser.expect(";")?;
+                    Type::Vector(item)
+                }
+                "option" => {
+                    parser.expect("(")?;
+                    let item = parser.next()?;
+                    parser.expect(")")?;
+                    parser.expect(";")?;
+                    Type::Option(item)
+                }
+                "union" => Type::Union(parser.union_items()?),
+                _ => return Err(parser.error(format!("unknown keyword {}", keyword))),
+            };
+            self.types.insert(name, ty);
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), SchemaError> {
+        for (name, ty) in &self.types {
+            for reference in ty.references() {
+                if !self.types.contains_key(reference) {
+                    return Err(SchemaError::UnknownType(reference.clone(), name.clone()));
+                }
+            }
+            if matches!(ty, Type::Array(..) | Type::Struct(..)) && self.fixed_size(name).is_none() {
+                return Err(SchemaError::InvalidFixedType(name.clone()));
+            }
+            if let Type::Option(item) = ty {
+                if matches!(self.types.get(item), Some(Type::Option(_))) {
+                    return Err(SchemaError::OptionOfOption(name.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Size of `name` if it is a byte, an array or a struct.
+    pub fn fixed_size(&self, name: &str) -> Option<usize> {
+        self.fixed_size_at(name, 0)
+    }
+
+    fn fixed_size_at(&self, name: &str, depth: usize) -> Option<usize> {
+        if depth > MAX_FIXED_DEPTH {
+            return None;
+        }
+        match self.types.get(name)? {
+            Type::Byte => Some(1),
+            Type::Array(item, count) => self.fixed_size_at(item, depth + 1)?.checked_mul(*count),
+            Type::Struct(fields) => fields.iter().try_fold(0usize, |size, (_, ty)| {
+                size.checked_add(self.fixed_size_at(ty, depth + 1)?)
+            }),
+            _ => None,
+        }
+    }
+
+    /// Decodes and verifies `data` as type `name`.
+    pub fn decode(&self, name: &str, data: &[u8]) -> Result<Value, DecodeError> {
+        self.decode_at(name, data, name.to_string(), 0)
+    }
+
+    fn decode_at(
+        &self,
+        name: &str,
+        data: &[u8],
+        path: String,
+        depth: usize,
+    ) -> Result<Value, DecodeError> {
+        let error = |message: String| DecodeError {
+            path: path.clone(),
+            message,
+        };
+        if depth > MAX_DECODE_DEPTH {
+            return Err(error(format!("nested deeper than {}", MAX_DECODE_DEPTH)));
+        }
+        let ty = self
+            .types
+            .get(name)
+            .ok_or_else(|| error(format!("unknown type {}", name)))?;
+        if let Some(size) = self.fixed_size(name) {
+            if data.len() != size {
+                return Err(error(format!(
+                    "{} takes {} bytes, got {}",
+                    name,
+                    size,
+                    data.len()
+                )));
+            }
+        }
+        match ty {
+            Type::Byte => Ok(Value::Bytes(data.to_vec())),
+            Type::Array(item, _) if item == "byte" => match name.strip_prefix("Uint") {
+                Some(bits) if data.len() <= 16 && bits == (data.len() * 8).to_string() => {
+                    let mut buffer = [0u8; 16];
+                    buffer[..data.len()].copy_from_slice(data);
+                    Ok(Value::Number(u128::from_le_bytes(buffer)))
+                }
+                _ => Ok(Value::Bytes(data.to_vec())),
+            },
+            Type::Array(item, _) => {
+                let size = self.fixed_size(item).unwrap_or(1).max(1);
+                data.chunks(size)
+                    .enumerate()
+                    .map(|(i, chunk)| {
+                        self.decode_at(item, chunk, format!("{}[{}]", path, i), depth + 1)
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(Value::List)
+            }
+            Type::Struct(fields) => {
+                let mut offset = 0;
+                let mut values = vec![];
+                for (field, ty) in fields {
+                    let size = self.fixed_size(ty).unwrap_or_default();
+                    let value = self.decode_at(
+                        ty,
+                        &data[offset..offset + size],
+                        format!("{}.{}", path, field),
+                        depth + 1,
+                    )?;
+                    values.push((field.clone(), value));
+                    offset += size;
+                }
+                Ok(Value::Fields(name.to_string(), values))
+            }
+            Type::Vector(item) => match self.fixed_size(item) {
+                Some(size) => {
+                    let count = read_u32(data, 0)
+                        .ok_or_else(|| error("missing item count".to_string()))?
+                        as usize;
+                    let items = &data[4..];
+                    if Some(items.len()) != count.checked_mul(size) {
+                        return Err(error(format!(
+                            "{} items of {} bytes, got {} bytes",
+                            count,
+                            size,
+                            items.len()
+                        )));
+                    }
+                    if item == "byte" {
+                        return Ok(Value::Bytes(items.to_vec()));
+                    }
+                    items
+                        .chunks(size.max(1))
+                        .take(count)
+                        .enumerate()
+                        .map(|(i, chunk)| {
+                            self.decode_at(item, chunk, format!("{}[{}]", path, i), depth + 1)
+                        })
+                        .collect::<Result<_, _>>()
+                        .map(Value::List)
+                }
+                None => split_offsets(data)
+                    .map_err(error)?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, part)| {
+                        self.decode_at(item, part, format!("{}[{}]", path, i), depth + 1)
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(Value::List),
+            },
+            Type::Table(fields) => {
+                // Like molecule's compatible mode, fields appended by a
+                // newer schema are accepted and left out.
+                let parts = split_offsets(data).map_err(error)?;
+                if parts.len() < fields.len() {
+                    return Err(error(format!(
+                        "at least {} fields, got {}",
+                        fields.len(),
+                        parts.len()
+                    )));
+                }
+                let mut values = vec![];
+                for ((field, ty), part) in fields.iter().zip(parts) {
+                    let value =
+                        self.decode_at(ty, part, format!("{}.{}", path, field), depth + 1)?;
+                    values.push((field.clone(), value));
+                }
+                Ok(Value::Fields(name.to_string(), values))
+            }
+            Type::Option(_) if data.is_empty() => Ok(Value::Option(None)),
+            Type::Option(item) => Ok(Value::Option(Some(Box::new(self.decode_at(
+                item,
+                data,
+                path.clone(),
+                depth + 1,
+            )?)))),
+            Type::Union(items) => {
+                let id = read_u32(data, 0).ok_or_else(|| error("missing item id".to_string()))?;
+                let (item, _) = items
+                    .iter()
+                    .find(|(_, item_id)| *item_id == id)
+                    .ok_or_else(|| error(format!("unknown item id {}", id)))?;
+                let value =
+                    self.decode_at(item, &data[4..], format!("{}::{}", path, item), depth + 1)?;
+                Ok(Value::Union(item.clone(), Box::new(value)))
+            }
+        }
+    }
+
+    /// Decodes `data` for display, showing why and the raw bytes when it is
+    /// not a valid `name`.
+    pub fn render(&self, name: &str, data: &[u8]) -> String {
+        match self.decode(name, data) {
+            Ok(value) => value.to_string(),
+            Err(e) => format!("<invalid {}> 0x{}", e, faster_hex::hex_string(data)),
+        }
+    }
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Splits a table or dynvec into its items.
+pub(crate) fn split_offsets(data: &[u8]) -> Result<Vec<&[u8]>, String> {
+    let total = read_u32(data, 0).ok_or("missing total size")? as usize;
+    if total != data.len() {
+        return Err(format!("total size {}, got {} bytes", total, data.len()));
+    }
+    if total == 4 {
+        return Ok(vec![]);
+    }
+    let first = read_u32(data, 4).ok_or("missing offsets")? as usize;
+    if !first.is_multiple_of(4) || first < 8 || first > total {
+        return Err(format!("invalid first offset {}", first));
+    }
+    let mut offsets = vec![];
+    for i in 1..first / 4 {
+        offsets.push(read_u32(data, i * 4).ok_or("missing offsets")? as usize);
+    }
+    offsets.push(total);
+    let mut parts = vec![];
+    for window in offsets.windows(2) {
+        if window[0] > window[1] {
+            return Err(format!("offset {} after {}", window[0], window[1]));
+        }
+        parts.push(&data[window[0]..window[1]]);
+    }
+    Ok(parts)
+}
+
+type LoadedSchema = Result<Arc<Schema>, SchemaError>;
+
+lazy_static! {
+    static ref SCHEMAS: Mutex<HashMap<Vec<String>, LoadedSchema>> = Mutex::new(HashMap::new());
+}
+
+/// Molecule types of the data scripts read, by where the data comes from.
+/// Scripts' loads of bound data are decoded to the debug sink of their
+/// simulation, see `Simulation::set_debug_sink`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MoleculeBindings {
+    /// `.mol` files, imports are looked up next to the importing file.
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    /// Witness types, by witness index in the transaction.
+    #[serde(default)]
+    pub witnesses: HashMap<usize, String>,
+    /// Cell data types, by `0x` prefixed type script hash.
+    #[serde(default)]
+    pub cell_data: HashMap<String, String>,
+    /// Types of buffers returned by syscalls, by syscall name such as
+    /// `ckb_load_script`.
+    #[serde(default)]
+    pub syscalls: HashMap<String, String>,
+}
+impl MoleculeBindings {
+    pub fn is_empty(&self) -> bool {
+        self.witnesses.is_empty() && self.cell_data.is_empty() && self.syscalls.is_empty()
+    }
+
+    /// The bound schemas, parsed once per process.
+    pub fn schema(&self) -> Result<Arc<Schema>, SchemaError> {
+        let mut schemas = SCHEMAS.lock().expect("lock schemas");
+        schemas
+            .entry(self.schemas.clone())
+            .or_insert_with(|| Schema::load(&self.schemas).map(Arc::new))
+            .clone()
+    }
+
+    pub fn witness_type(&self, index: usize) -> Option<&str> {
+        self.witnesses.get(&index).map(|s| s.as_str())
+    }
+
+    pub fn cell_data_type(&self, output: &CellOutput) -> Option<&str> {
+        let hash = format!("{:#x}", output.type_().to_opt()?.calc_script_hash());
+        self.cell_data
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(&hash))
+            .map(|(_, name)| name.as_str())
+    }
+
+    pub fn syscall_type(&self, syscall: &str) -> Option<&str> {
+        self.syscalls.get(syscall).map(|s| s.as_str())
+    }
+
+    /// Renders `data` as type `name` with the bound schemas.
+    pub fn render(&self, name: &str, data: &[u8]) -> String {
+        match self.schema() {
+            Ok(schema) => schema.render(name, data),
+            Err(e) => format!("<schema error: {}> 0x{}", e, faster_hex::hex_string(data)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use ckb_types::{bytes::Bytes, packed::WitnessArgs, prelude::*};
+
+    fn table(fields: &[&[u8]]) -> Vec<u8> {
+        let header = 4 * (fields.len() + 1);
+        let total = header + fields.iter().map(|f| f.len()).sum::<usize>();
+        let mut data = (total as u32).to_le_bytes().to_vec();
+        let mut offset = header;
+        for field in fields {
+            data.extend_from_slice(&(offset as u32).to_le_bytes());
+            offset += field.len();
+        }
+        for field in fields {
+            data.extend_from_slice(field);
+        }
+        data
+    }
+
+    #[test]
+    fn parses_schemas_and_their_imports() {
+        test_utils::temp_file(
+            "molecule_common.mol",
+            b"import blockchain;\n/* ids */ array Id [byte; 2];\n",
+        );
+        let main = test_utils::temp_file(
+            "molecule_main.mol",
+            b"import molecule_common;\n\
+              // a comment\n\
+              struct Pair { id: Id, amount: Uint64, }\n\
+              union Action { Pair, Bytes: 5, Script }\n\
+              vector Pairs <Pair>;\n",
+        );
+        let schema = Schema::load(&[main]).unwrap();
+        assert_eq!(schema.types["Id"], Type::Array("byte".into(), 2));
+        assert_eq!(
+            schema.types["Action"],
+            Type::Union(vec![
+                ("Pair".into(), 0),
+                ("Bytes".into(), 5),
+                ("Script".into(), 6)
+            ])
+        );
+        assert_eq!(schema.fixed_size("Pair"), Some(10));
+        assert_eq!(schema.fixed_size("Pairs"), None);
+        assert_eq!(schema.fixed_size("Header"), Some(208));
+    }
+
+    #[test]
+    fn reports_invalid_schemas() {
+        let load = |name: &str, content: &str| {
+            let path = test_utils::temp_file(name, content.as_bytes());
+            Schema::load(std::slice::from_ref(&path))
+                .map(|_| ())
+                .map_err(|e| (path, e))
+        };
+        let (path, e) =
+            load("molecule_parse.mol", "array A [byte; 2];\nstruct B { a A }").unwrap_err();
+        assert_eq!(e, SchemaError::Parse(path, 2, "expected :, found A".into()));
+        let (path, e) = load("molecule_char.mol", "\n\nvector A <byte>?").unwrap_err();
+        assert_eq!(
+            e,
+            SchemaError::Parse(path, 3, "unexpected character '?'".into())
+        );
+        let (_, e) = load("molecule_unknown.mol", "vector A <Missing>;").unwrap_err();
+        assert_eq!(e, SchemaError::UnknownType("Missing".into(), "A".into()));
+        let (_, e) = load("molecule_fixed.mol", "struct A { b: Bytes }").unwrap_err();
+        assert_eq!(e, SchemaError::InvalidFixedType("A".into()));
+        let (_, e) = load("molecule_recursive.mol", "array A [A; 2];").unwrap_err();
+        assert_eq!(e, SchemaError::InvalidFixedType("A".into()));
+        let (_, e) = load("molecule_option.mol", "option A (Bytes);\noption B (A);").unwrap_err();
+        assert_eq!(e, SchemaError::OptionOfOption("B".into()));
+        let (_, e) = load("molecule_options.mol", "option A (B);\noption B (A);").unwrap_err();
+        assert!(matches!(e, SchemaError::OptionOfOption(_)));
+        assert!(matches!(
+            Schema::load(&["/nonexistent/a.mol"]),
+            Err(SchemaError::Io(..))
+        ));
+    }
+
+    #[test]
+    fn decodes_blockchain_types() {
+        let schema = Schema::default();
+        let witness = WitnessArgs::new_builder()
+            .lock(Some(Bytes::from(vec![1u8, 2])).pack())
+            .build();
+        assert_eq!(
+            schema.decode("WitnessArgs", witness.as_slice()),
+            Ok(Value::Fields(
+                "WitnessArgs".into(),
+                vec![
+                    (
+                        "lock".into(),
+                        Value::Option(Some(Box::new(Value::Bytes(vec![1, 2]))))
+                    ),
+                    ("input_type".into(), Value::Option(None)),
+                    ("output_type".into(), Value::Option(None)),
+                ]
+            ))
+        );
+        assert_eq!(
+            schema.decode("Uint64", &5u64.to_le_bytes()),
+            Ok(Value::Number(5))
+        );
+        assert_eq!(
+            schema.render("WitnessArgs", witness.as_slice()),
+            "WitnessArgs {\n  lock: Some(0x0102),\n  input_type: None,\n  output_type: None,\n}"
+        );
+    }
+
+    #[test]
+    fn tables_accept_fields_of_newer_schemas() {
+        let schema = Schema::default();
+        let none: &[u8] = &[];
+        let data = table(&[none, none, none, &[4, 0, 0, 0]]);
+        let value = schema.decode("WitnessArgs", &data).unwrap();
+        assert_eq!(
+            value,
+            schema.decode("WitnessArgs", &table(&[none; 3])).unwrap()
+        );
+        assert_eq!(
+            schema.decode("WitnessArgs", &table(&[none, none])),
+            Err(DecodeError {
+                path: "WitnessArgs".into(),
+                message: "at least 3 fields, got 2".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn reports_where_decoding_fails() {
+        let schema = Schema::default();
+        let bytes = [3u8, 0, 0, 0, 1, 2];
+        let data = table(&[&bytes[..], &[], &[]]);
+        assert_eq!(
+            schema.decode("WitnessArgs", &data),
+            Err(DecodeError {
+                path: "WitnessArgs.lock".into(),
+                message: "3 items of 1 bytes, got 2 bytes".into(),
+            })
+        );
+        assert_eq!(
+            schema.decode("OutPoint", &[0; 35]),
+            Err(DecodeError {
+                path: "OutPoint".into(),
+                message: "OutPoint takes 36 bytes, got 35".into(),
+            })
+        );
+        assert_eq!(
+            schema.decode("Bytes", &[1, 0, 0]).unwrap_err().message,
+            "missing item count"
+        );
+        assert_eq!(
+            schema.decode("Missing", &[]).unwrap_err().message,
+            "unknown type Missing"
+        );
+        assert_eq!(
+            schema.render("Uint32", &[1]),
+            "<invalid Uint32: Uint32 takes 4 bytes, got 1> 0x01"
+        );
+    }
+
+    #[test]
+    fn decodes_unions_by_id() {
+        let path =
+            test_utils::temp_file("molecule_union.mol", b"union Action { Uint32, Bytes: 5 }");
+        let schema = Schema::load(&[path]).unwrap();
+        assert_eq!(
+            schema.decode("Action", &[5, 0, 0, 0, 1, 0, 0, 0, 9]),
+            Ok(Value::Union(
+                "Bytes".into(),
+                Box::new(Value::Bytes(vec![9]))
+            ))
+        );
+        assert_eq!(
+            schema.decode("Action", &[1, 0, 0, 0]).unwrap_err(),
+            DecodeError {
+                path: "Action".into(),
+                message: "unknown item id 1".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn stops_decoding_recursive_types() {
+        let path = test_utils::temp_file("molecule_chain.mol", b"union Chain { Chain, Bytes }");
+        let schema = Schema::load(&[path]).unwrap();
+        let mut data = [0u8; 4].repeat(10_000);
+        data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            schema.decode("Chain", &data).unwrap_err().message,
+            format!("nested deeper than {}", MAX_DECODE_DEPTH)
+        );
+        let mut data = [0u8; 4].repeat(3);
+        data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(schema.decode("Chain", &data).is_ok());
+    }
+}
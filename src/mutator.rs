@@ -0,0 +1,356 @@
+//! Structured mutations of whole transactions for `FuzzHarness`, so fuzzed
+//! inputs stay well-formed enough to reach the script logic.
+//!
+//! Witnesses that are `WitnessArgs` are mutated field by field, tables and
+//! dynvecs item by item with their headers rebuilt, and byte vectors with
+//! their length prefix kept in sync. Inputs, outputs and witnesses are added
+//! and removed with their mock info and outputs data.
+use crate::{
+    molecule::{read_u32, split_offsets},
+    tx_loader::{decode_mock_tx, encode_mock_tx, pack_table},
+};
+use ckb_hash::blake2b_256;
+use ckb_mock_tx_types::MockTransaction;
+use ckb_types::{
+    bytes::Bytes,
+    packed::{BytesOpt, OutPoint, WitnessArgs},
+    prelude::*,
+};
+
+/// Bytes mutations favor, besides random ones.
+const INTERESTING_BYTES: [u8; 6] = [0x00, 0x01, 0x7f, 0x80, 0xfe, 0xff];
+/// Attempts to find a mutation fitting the fuzzer's size limit.
+const MUTATE_ATTEMPTS: usize = 8;
+
+/// A xorshift generator, mutations are reproducible from the fuzzer seed.
+pub struct Rng(u64);
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `0..n`, 0 when `n` is 0.
+    pub fn below(&mut self, n: usize) -> usize {
+        if n == 0 {
+            0
+        } else {
+            (self.next_u64() % n as u64) as usize
+        }
+    }
+
+    pub fn byte(&mut self) -> u8 {
+        self.next_u64() as u8
+    }
+}
+
+pub struct TxMutator {
+    /// Most inputs, outputs or witnesses, and items of molecule vectors,
+    /// mutations grow to.
+    pub max_items: usize,
+    /// How deep mutations descend into nested molecule structures.
+    pub max_depth: usize,
+}
+impl Default for TxMutator {
+    fn default() -> Self {
+        Self {
+            max_items: 16,
+            max_depth: 4,
+        }
+    }
+}
+impl TxMutator {
+    /// libFuzzer custom mutator: mutates the transaction encoded in
+    /// `data[..size]` in place and returns the new size. Inputs that do not
+    /// decode get plain byte mutations.
+    pub fn mutate_input(&self, data: &mut [u8], size: usize, max_size: usize, seed: u32) -> usize {
+        let mut rng = Rng::new(seed as u64);
+        let max_size = max_size.min(data.len());
+        for _ in 0..MUTATE_ATTEMPTS {
+            let mutated = match decode_mock_tx(&data[..size]) {
+                Ok(mut tx) => {
+                    self.mutate_tx(&mut tx, &mut rng);
+                    encode_mock_tx(&tx)
+                }
+                Err(_) => self.mutate_bytes(&data[..size], &mut rng),
+            };
+            if mutated.len() <= max_size {
+                data[..mutated.len()].copy_from_slice(&mutated);
+                return mutated.len();
+            }
+        }
+        size
+    }
+
+    /// Applies one random mutation to `tx`.
+    pub fn mutate_tx(&self, tx: &mut MockTransaction, rng: &mut Rng) {
+        let view = tx.tx.clone().into_view();
+        let mut witnesses: Vec<Bytes> =
+            view.witnesses().into_iter().map(|w| w.raw_data()).collect();
+        let mut outputs: Vec<_> = view.outputs_with_data_iter().collect();
+        let inputs = &mut tx.mock_info.inputs;
+
+        match rng.below(8) {
+            0..=2 if !witnesses.is_empty() => {
+                let i = rng.below(witnesses.len());
+                witnesses[i] = self.mutate_witness(&witnesses[i], rng).into();
+            }
+            0..=3 => {
+                if rng.below(2) == 0 && witnesses.len() < self.max_items {
+                    let witness = match witnesses.is_empty() {
+                        true => WitnessArgs::default().as_bytes(),
+                        false => witnesses[rng.below(witnesses.len())].clone(),
+                    };
+                    witnesses.insert(rng.below(witnesses.len() + 1), witness);
+                } else if !witnesses.is_empty() {
+                    witnesses.remove(rng.below(witnesses.len()));
+                }
+            }
+            4 if !outputs.is_empty() => {
+                let i = rng.below(outputs.len());
+                outputs[i].1 = self.mutate_molecule(&outputs[i].1, rng, 0).into();
+            }
+            4 | 5 if !inputs.is_empty() => {
+                let i = rng.below(inputs.len());
+                inputs[i].data = self.mutate_molecule(&inputs[i].data, rng, 0).into();
+            }
+            6 => {
+                if rng.below(2) == 0 && !outputs.is_empty() && outputs.len() < self.max_items {
+                    let output = outputs[rng.below(outputs.len())].clone();
+                    outputs.insert(rng.below(outputs.len() + 1), output);
+                } else if outputs.len() > 1 {
+                    outputs.remove(rng.below(outputs.len()));
+                }
+            }
+            _ => {
+                if rng.below(2) == 0 && !inputs.is_empty() && inputs.len() < self.max_items {
+                    let mut input = inputs[rng.below(inputs.len())].clone();
+                    // A fresh out point, so the input is not spent twice.
+                    let mut seed = input.input.as_slice().to_vec();
+                    seed.extend_from_slice(&rng.next_u64().to_le_bytes());
+                    let out_point = OutPoint::new(blake2b_256(&seed).pack(), 0);
+                    input.input = input.input.as_builder().previous_output(out_point).build();
+                    inputs.insert(rng.below(inputs.len() + 1), input);
+                } else if inputs.len() > 1 {
+                    inputs.remove(rng.below(inputs.len()));
+                }
+            }
+        }
+
+        let (outputs, outputs_data): (Vec<_>, Vec<_>) = outputs
+            .into_iter()
+            .map(|(output, data)| (output, data.pack()))
+            .unzip();
+        tx.tx = view
+            .as_advanced_builder()
+            .set_inputs(inputs.iter().map(|i| i.input.clone()).collect())
+            .set_outputs(outputs)
+            .set_outputs_data(outputs_data)
+            .set_witnesses(witnesses.into_iter().map(|w| w.pack()).collect())
+            .build()
+            .data();
+    }
+
+    /// Mutates one field of a `WitnessArgs`, or the witness as a molecule
+    /// structure when it is not one.
+    pub fn mutate_witness(&self, witness: &[u8], rng: &mut Rng) -> Vec<u8> {
+        let Ok(witness_args) = WitnessArgs::from_slice(witness) else {
+            return self.mutate_molecule(witness, rng, 0);
+        };
+        let field = rng.below(3);
+        let bytes = match field {
+            0 => witness_args.lock(),
+            1 => witness_args.input_type(),
+            _ => witness_args.output_type(),
+        };
+        // Toggles the option now and then, so scripts see it absent too.
+        let bytes: BytesOpt = match bytes.to_opt() {
+            Some(_) if rng.below(8) == 0 => None,
+            Some(bytes) => Some(self.mutate_molecule(&bytes.raw_data(), rng, 0)),
+            None => Some(self.mutate_bytes(&[], rng)),
+        }
+        .map(Bytes::from)
+        .pack();
+        let builder = witness_args.as_builder();
+        match field {
+            0 => builder.lock(bytes),
+            1 => builder.input_type(bytes),
+            _ => builder.output_type(bytes),
+        }
+        .build()
+        .as_slice()
+        .to_vec()
+    }
+
+    /// Mutates an item of a table or dynvec, or the content of a byte
+    /// vector, keeping headers consistent. Other data gets byte mutations.
+    pub fn mutate_molecule(&self, data: &[u8], rng: &mut Rng, depth: usize) -> Vec<u8> {
+        if depth < self.max_depth && rng.below(4) != 0 {
+            if let Ok(parts) = split_offsets(data) {
+                if !parts.is_empty() {
+                    let mut items: Vec<Vec<u8>> = parts.into_iter().map(|p| p.to_vec()).collect();
+                    match rng.below(8) {
+                        0 if items.len() < self.max_items => {
+                            let item = items[rng.below(items.len())].clone();
+                            items.insert(rng.below(items.len() + 1), item);
+                        }
+                        1 => {
+                            items.remove(rng.below(items.len()));
+                        }
+                        _ => {
+                            let i = rng.below(items.len());
+                            items[i] = self.mutate_molecule(&items[i], rng, depth + 1);
+                        }
+                    }
+                    let items: Vec<&[u8]> = items.iter().map(|i| i.as_slice()).collect();
+                    return pack_table(&items);
+                }
+            }
+            if read_u32(data, 0).map(|len| len as usize + 4) == Some(data.len()) {
+                let content = self.mutate_bytes(&data[4..], rng);
+                let mut mutated = (content.len() as u32).to_le_bytes().to_vec();
+                mutated.extend_from_slice(&content);
+                return mutated;
+            }
+        }
+        self.mutate_bytes(data, rng)
+    }
+
+    pub fn mutate_bytes(&self, data: &[u8], rng: &mut Rng) -> Vec<u8> {
+        let mut data = data.to_vec();
+        let pos = rng.below(data.len());
+        match rng.below(7) {
+            0 if !data.is_empty() => data[pos] ^= 1 << rng.below(8),
+            1 if !data.is_empty() => data[pos] = rng.byte(),
+            2 if !data.is_empty() => {
+                data[pos] = INTERESTING_BYTES[rng.below(INTERESTING_BYTES.len())]
+            }
+            3 if !data.is_empty() => {
+                data.remove(pos);
+            }
+            4 if !data.is_empty() => data.truncate(pos),
+            5 => {
+                for _ in 0..=rng.below(8) {
+                    data.push(rng.byte());
+                }
+            }
+            _ => data.insert(rng.below(data.len() + 1), rng.byte()),
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use ckb_types::packed::BytesVec;
+
+    #[test]
+    fn rng_is_reproducible() {
+        let (mut a, mut b) = (Rng::new(7), Rng::new(7));
+        let numbers: Vec<_> = (0..16).map(|_| a.next_u64()).collect();
+        assert_eq!(numbers, (0..16).map(|_| b.next_u64()).collect::<Vec<_>>());
+        assert_ne!(numbers[0], Rng::new(8).next_u64());
+        // Seed 0 does not get stuck at 0.
+        assert_ne!(Rng::new(0).next_u64(), 0);
+        assert_eq!(a.below(0), 0);
+        assert!((0..100).all(|_| a.below(3) < 3));
+    }
+
+    #[test]
+    fn mutated_transactions_stay_consistent() {
+        let mutator = TxMutator {
+            max_items: 3,
+            ..Default::default()
+        };
+        let mut tx = test_utils::sample_tx();
+        let mut rng = Rng::new(1);
+        let mut changed = 0;
+        for _ in 0..500 {
+            let before = encode_mock_tx(&tx);
+            mutator.mutate_tx(&mut tx, &mut rng);
+            let raw = tx.tx.raw();
+            assert_eq!(raw.inputs().len(), tx.mock_info.inputs.len());
+            for (input, mock) in raw.inputs().into_iter().zip(&tx.mock_info.inputs) {
+                assert_eq!(input.as_slice(), mock.input.as_slice());
+            }
+            assert!((1..=3).contains(&tx.mock_info.inputs.len()));
+            assert_eq!(raw.outputs().len(), raw.outputs_data().len());
+            assert!((1..=3).contains(&raw.outputs().len()));
+            assert!(tx.tx.witnesses().len() <= 3);
+            let encoded = encode_mock_tx(&tx);
+            assert_eq!(
+                test_utils::tx_json(&decode_mock_tx(&encoded).unwrap()),
+                test_utils::tx_json(&tx)
+            );
+            changed += (encoded != before) as usize;
+        }
+        assert!(changed > 400, "{} of 500 mutations changed the tx", changed);
+    }
+
+    #[test]
+    fn witness_args_are_mutated_field_by_field() {
+        let mutator = TxMutator::default();
+        let witness = WitnessArgs::new_builder()
+            .lock(Some(Bytes::from(vec![0u8; 65])).pack())
+            .build();
+        let mut rng = Rng::new(2);
+        for _ in 0..200 {
+            let mutated = mutator.mutate_witness(witness.as_slice(), &mut rng);
+            let mutated = WitnessArgs::from_slice(&mutated).unwrap();
+            let same = [
+                mutated.lock().as_slice() == witness.lock().as_slice(),
+                mutated.input_type().as_slice() == witness.input_type().as_slice(),
+                mutated.output_type().as_slice() == witness.output_type().as_slice(),
+            ];
+            assert!(same.iter().filter(|s| **s).count() >= 2, "{:?}", mutated);
+        }
+    }
+
+    #[test]
+    fn molecule_headers_are_rebuilt() {
+        let mutator = TxMutator::default();
+        let items: Vec<Bytes> = vec![vec![1u8, 2].into(), vec![3u8].into()];
+        let data = items.pack();
+        let mut rng = Rng::new(3);
+        let mut valid = 0;
+        for _ in 0..200 {
+            let mutated = mutator.mutate_molecule(data.as_slice(), &mut rng, 0);
+            valid += BytesVec::from_slice(&mutated).is_ok() as usize;
+        }
+        // Only the plain byte mutations, a quarter at each level, break it.
+        assert!(valid > 100, "{} of 200 mutations are valid", valid);
+        let bytes = mutator.mutate_molecule(&[2, 0, 0, 0, 1, 2], &mut Rng::new(5), 0);
+        assert_eq!(read_u32(&bytes, 0), Some(bytes.len() as u32 - 4));
+    }
+
+    #[test]
+    fn inputs_fit_the_fuzzer_buffer() {
+        let mutator = TxMutator::default();
+        let encoded = encode_mock_tx(&test_utils::sample_tx());
+        let mut data = encoded.clone();
+        let max_size = encoded.len() + 256;
+        data.resize(max_size, 0);
+        for seed in 0..50 {
+            let size = mutator.mutate_input(&mut data, encoded.len(), max_size, seed);
+            assert!(size <= max_size);
+            assert!(decode_mock_tx(&data[..size]).is_ok());
+            data[..encoded.len()].copy_from_slice(&encoded);
+        }
+        // No room to grow, the input is kept or shrunk.
+        let mut data = encoded.clone();
+        let size = mutator.mutate_input(&mut data, encoded.len(), encoded.len(), 1);
+        assert!(size <= encoded.len());
+        // Undecodable inputs get byte mutations.
+        let mut data = vec![0xaau8; 8];
+        let size = mutator.mutate_input(&mut data, 4, 8, 1);
+        assert!(size <= 8);
+    }
+}
@@ -0,0 +1,410 @@
+//! Checks a transaction and its running setup before anything runs, so
+//! mistakes surface as a report instead of a panic deep inside a contract.
+//!
+//! Every script group is resolved to the native binary or built-in script
+//! running it, and every `native_binaries` entry to the cell dep it loads
+//! code from. Spawn, exec and group keys are `0x{code_hash + hash_type +
+//! offset + length}`, 0xFF matching any hash type and a zero slice the whole
+//! cell, dlopen keys `0x{code_hash + hash_type}`. Keys of `ckb_spawn` and
+//! `ckb_exec` by index are `0x{blake2b(code)}`.
+use crate::{
+    system_scripts::builtin_script,
+    tx_verifier::resolve_cell_deps,
+    verify::{native_binary, native_binary_key, script_groups},
+    RunningSetup,
+};
+use ckb_mock_tx_types::{MockCellDep, MockTransaction};
+use ckb_types::{
+    core::{error::OutPointError, ScriptHashType},
+    packed::{Byte32, CellOutput, Script},
+    prelude::*,
+};
+use std::path::Path;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Binary {
+    Native(String),
+    Builtin(&'static str),
+    Missing,
+}
+impl std::fmt::Display for Binary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Native(path) => write!(f, "{}", path),
+            Self::Builtin(name) => write!(f, "built-in {}", name),
+            Self::Missing => write!(f, "<missing>"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GroupReport {
+    /// The group named like ckb does, `Inputs[0].Lock` for example.
+    pub source: String,
+    pub script_hash: Byte32,
+    pub key: String,
+    pub binary: Binary,
+    /// Index of the cell dep holding the script code, after dep group
+    /// expansion.
+    pub cell_dep: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TargetKind {
+    /// `ckb_spawn` or `ckb_exec` of a cell slice, 0 length for the rest of
+    /// the cell, or a script group.
+    Spawn { offset: u32, length: u32 },
+    /// `ckb_dlopen2`.
+    Dlopen,
+    /// `ckb_spawn` or `ckb_exec` by index, of the code hashing to the key.
+    /// The code may be a slice of a cell or a witness, only whole cells are
+    /// found.
+    Code,
+}
+
+#[derive(Clone, Debug)]
+pub struct TargetReport {
+    pub key: String,
+    pub kind: TargetKind,
+    pub code_hash: Byte32,
+    /// 0xFF for spawn keys matching any hash type, 0 for code keys.
+    pub hash_type: u8,
+    pub path: String,
+    pub cell_dep: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Issue {
+    /// `is_lock_script` and `is_output` are both set, outputs do not run
+    /// lock scripts.
+    LockScriptOutput,
+    /// The script of the setup does not exist, with its source.
+    MissingSetupScript(String),
+    UnresolvedCellDeps(OutPointError),
+    /// A group has neither a native binary nor a built-in script.
+    MissingNativeBinary(String),
+    /// No cell dep holds the code of a group or a target.
+    MissingCellDep(String, Byte32),
+    /// A mapped binary does not exist, by group source or key.
+    MissingPath(String, String),
+    /// A key is neither a spawn nor a dlopen key.
+    InvalidKey(String),
+    /// A spawn slice is past the end of its cell data.
+    OutOfBound(String, usize),
+}
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LockScriptOutput => {
+                write!(f, "setup sets both is_lock_script and is_output")
+            }
+            Self::MissingSetupScript(source) => {
+                write!(f, "setup script {} does not exist", source)
+            }
+            Self::UnresolvedCellDeps(e) => write!(f, "cell deps: {}", e),
+            Self::MissingNativeBinary(source) => {
+                write!(f, "{}: no native binary or built-in script", source)
+            }
+            Self::MissingCellDep(name, code_hash) => {
+                write!(f, "{}: no cell dep with code {:#x}", name, code_hash)
+            }
+            Self::MissingPath(name, path) => write!(f, "{}: {} does not exist", name, path),
+            Self::InvalidKey(key) => write!(f, "{}: not a native binary key", key),
+            Self::OutOfBound(key, data_len) => {
+                write!(f, "{}: slice out of cell data of {} bytes", key, data_len)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub groups: Vec<GroupReport>,
+    pub targets: Vec<TargetReport>,
+    pub issues: Vec<Issue>,
+}
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "script groups:")?;
+        for group in &self.groups {
+            writeln!(
+                f,
+                "  {} {:#x} -> {}",
+                group.source, group.script_hash, group.binary
+            )?;
+        }
+        writeln!(f, "native binaries:")?;
+        for target in &self.targets {
+            let cell_dep = match target.cell_dep {
+                Some(index) => format!("cell dep {}", index),
+                None => "no cell dep".to_string(),
+            };
+            let kind = match target.kind {
+                TargetKind::Spawn { offset, length } => {
+                    format!("spawn [{}, +{}]", offset, length)
+                }
+                TargetKind::Dlopen => "dlopen".to_string(),
+                TargetKind::Code => "code".to_string(),
+            };
+            writeln!(
+                f,
+                "  {} {:#x} {}, {} -> {}",
+                kind, target.code_hash, target.hash_type, cell_dep, target.path
+            )?;
+        }
+        if self.is_ok() {
+            write!(f, "no issues")
+        } else {
+            write!(f, "issues:")?;
+            for issue in &self.issues {
+                write!(f, "\n  {}", issue)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Whether `cell_dep` holds the code of `code_hash` and `hash_type`, any
+/// hash type for 0xFF.
+fn holds_code(cell_dep: &MockCellDep, code_hash: &Byte32, hash_type: u8) -> bool {
+    let by_type = || {
+        cell_dep
+            .output
+            .type_()
+            .to_opt()
+            .is_some_and(|t| &t.calc_script_hash() == code_hash)
+    };
+    let by_data = || &CellOutput::calc_data_hash(&cell_dep.data) == code_hash;
+    match hash_type {
+        0xFF => by_data() || by_type(),
+        t if t == ScriptHashType::Type as u8 => by_type(),
+        _ => by_data(),
+    }
+}
+
+fn find_code(cell_deps: &[&MockCellDep], code_hash: &Byte32, hash_type: u8) -> Option<usize> {
+    cell_deps
+        .iter()
+        .position(|dep| holds_code(dep, code_hash, hash_type))
+}
+
+fn setup_script(tx: &MockTransaction, setup: &RunningSetup) -> Option<Script> {
+    let cell = if setup.is_output {
+        tx.tx.raw().outputs().get(setup.script_index as usize)?
+    } else {
+        tx.mock_info
+            .inputs
+            .get(setup.script_index as usize)?
+            .output
+            .clone()
+    };
+    if setup.is_lock_script {
+        Some(cell.lock())
+    } else {
+        cell.type_().to_opt()
+    }
+}
+
+fn parse_key(key: &str) -> Option<(Byte32, u8, TargetKind)> {
+    let hex = key.strip_prefix("0x")?;
+    let mut buffer = vec![0u8; hex.len() / 2];
+    faster_hex::hex_decode(hex.as_bytes(), &mut buffer).ok()?;
+    let code_hash = Byte32::from_slice(buffer.get(..32)?).ok()?;
+    let kind = match buffer.len() {
+        32 => return Some((code_hash, ScriptHashType::Data as u8, TargetKind::Code)),
+        33 => TargetKind::Dlopen,
+        41 => TargetKind::Spawn {
+            offset: u32::from_be_bytes(buffer[33..37].try_into().unwrap()),
+            length: u32::from_be_bytes(buffer[37..41].try_into().unwrap()),
+        },
+        _ => return None,
+    };
+    Some((code_hash, buffer[32], kind))
+}
+
+/// Resolves every script group and native binary of `tx` without running
+/// anything.
+pub fn preflight(tx: &MockTransaction, setup: &RunningSetup) -> Report {
+    let mut report = Report::default();
+    if setup.is_lock_script && setup.is_output {
+        report.issues.push(Issue::LockScriptOutput);
+    }
+    if setup_script(tx, setup).is_none() {
+        let source = match (setup.is_output, setup.is_lock_script) {
+            (true, true) => format!("Outputs[{}].Lock", setup.script_index),
+            (true, false) => format!("Outputs[{}].Type", setup.script_index),
+            (false, true) => format!("Inputs[{}].Lock", setup.script_index),
+            (false, false) => format!("Inputs[{}].Type", setup.script_index),
+        };
+        report.issues.push(Issue::MissingSetupScript(source));
+    }
+    let cell_deps: Vec<&MockCellDep> = match resolve_cell_deps(tx) {
+        Ok(indices) => indices
+            .into_iter()
+            .map(|i| &tx.mock_info.cell_deps[i])
+            .collect(),
+        Err(e) => {
+            report.issues.push(Issue::UnresolvedCellDeps(e));
+            vec![]
+        }
+    };
+
+    for group in script_groups(tx) {
+        let source = group.source();
+        let key = native_binary_key(&group.script);
+        let builtin = builtin_script(&group.script).map(|(name, _)| name);
+        let binary = match (native_binary(setup, &group.script), builtin) {
+            (Some(path), _) => Binary::Native(path),
+            (None, Some(name)) => Binary::Builtin(name),
+            (None, None) => Binary::Missing,
+        };
+        match &binary {
+            Binary::Native(path) if !Path::new(path).exists() => {
+                report
+                    .issues
+                    .push(Issue::MissingPath(source.clone(), path.clone()));
+            }
+            Binary::Missing => report
+                .issues
+                .push(Issue::MissingNativeBinary(source.clone())),
+            _ => {}
+        }
+        let code_hash = group.script.code_hash();
+        let cell_dep = find_code(&cell_deps, &code_hash, group.script.hash_type().into());
+        // Type ID is part of ckb itself, it has no code cell.
+        if cell_dep.is_none() && builtin != Some("type_id") {
+            report
+                .issues
+                .push(Issue::MissingCellDep(source.clone(), code_hash));
+        }
+        report.groups.push(GroupReport {
+            source,
+            script_hash: group.script_hash(),
+            key,
+            binary,
+            cell_dep,
+        });
+    }
+
+    let mut keys: Vec<_> = setup.native_binaries.iter().collect();
+    keys.sort();
+    for (key, path) in keys {
+        let Some((code_hash, hash_type, kind)) = parse_key(key) else {
+            report.issues.push(Issue::InvalidKey(key.clone()));
+            continue;
+        };
+        if !Path::new(path).exists() {
+            report
+                .issues
+                .push(Issue::MissingPath(key.clone(), path.clone()));
+        }
+        let cell_dep = find_code(&cell_deps, &code_hash, hash_type);
+        match (cell_dep, &kind) {
+            (None, TargetKind::Code) => {}
+            (None, _) => report
+                .issues
+                .push(Issue::MissingCellDep(key.clone(), code_hash.clone())),
+            (Some(index), TargetKind::Spawn { offset, length }) => {
+                let data_len = cell_deps[index].data.len();
+                let end = *offset as usize + *length as usize;
+                if *offset as usize > data_len || end > data_len {
+                    report.issues.push(Issue::OutOfBound(key.clone(), data_len));
+                }
+            }
+            _ => {}
+        }
+        report.targets.push(TargetReport {
+            key: key.clone(),
+            kind,
+            code_hash,
+            hash_type,
+            path: path.clone(),
+            cell_dep,
+        });
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    /// `sample_tx` locked by the code of its cell dep, and a setup running
+    /// the lock with `argv_dylib` mapped like ckb-testtool does.
+    fn valid() -> (MockTransaction, RunningSetup) {
+        let code_hash = CellOutput::calc_data_hash(&[4]);
+        let lock = Script::new_builder()
+            .code_hash(code_hash.clone())
+            .hash_type(ScriptHashType::Data1.into())
+            .build();
+        let tx = test_utils::locked_tx(lock);
+        let key = test_utils::cell_key(&code_hash.as_slice().try_into().unwrap(), 0xFF);
+        let setup = RunningSetup {
+            is_lock_script: true,
+            native_binaries: [(key, test_utils::argv_dylib())].into(),
+            ..Default::default()
+        };
+        (tx, setup)
+    }
+
+    #[test]
+    fn valid_setups_have_no_issues() {
+        let (tx, setup) = valid();
+        let report = preflight(&tx, &setup);
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(
+            report.groups[0].binary,
+            Binary::Native(test_utils::argv_dylib())
+        );
+        assert_eq!(report.groups[0].cell_dep, Some(0));
+        assert_eq!(
+            report.targets[0].kind,
+            TargetKind::Spawn {
+                offset: 0,
+                length: 0
+            }
+        );
+        assert_eq!(report.targets[0].hash_type, 0xFF);
+    }
+
+    #[test]
+    fn mistakes_are_reported() {
+        let (tx, mut setup) = valid();
+        let code_hash = CellOutput::calc_data_hash(&[4]);
+        let mut slice = code_hash.as_slice().to_vec();
+        slice.push(ScriptHashType::Data1 as u8);
+        slice.extend_from_slice(&0u32.to_be_bytes());
+        slice.extend_from_slice(&2u32.to_be_bytes());
+        let slice = format!("0x{}", faster_hex::hex_string(&slice));
+        setup
+            .native_binaries
+            .insert(slice.clone(), "/missing.so".to_string());
+        setup
+            .native_binaries
+            .insert("0x01".to_string(), test_utils::argv_dylib());
+        setup.is_output = true;
+
+        let issues = preflight(&tx, &setup).issues;
+        assert!(issues.contains(&Issue::LockScriptOutput));
+        assert!(issues.contains(&Issue::MissingPath(
+            slice.clone(),
+            "/missing.so".to_string()
+        )));
+        assert!(issues.contains(&Issue::OutOfBound(slice, 1)));
+        assert!(issues.contains(&Issue::InvalidKey("0x01".to_string())));
+
+        let (tx, mut setup) = valid();
+        setup.native_binaries.clear();
+        assert_eq!(
+            preflight(&tx, &setup).issues,
+            vec![Issue::MissingNativeBinary("Inputs[0].Lock".to_string())]
+        );
+    }
+}
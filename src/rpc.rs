@@ -0,0 +1,547 @@
+//! Builds mock transactions from a CKB node, to reproduce failures of
+//! transactions seen on mainnet or testnet.
+//!
+//! Previous outputs and cell deps come from `get_transaction` of the
+//! transactions creating them, so spent cells resolve too, and headers from
+//! `get_header`. Only plain `http://` endpoints are supported, requests
+//! time out after `DEFAULT_TIMEOUT` unless set otherwise.
+use ckb_mock_tx_types::{MockCellDep, MockInfo, MockInput, MockTransaction, ReprMockTransaction};
+use ckb_types::{
+    bytes::Bytes,
+    core::{DepType, HeaderView},
+    packed::{self, Byte32, CellDep, CellOutput, OutPoint, OutPointVec},
+    prelude::*,
+    H256,
+};
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// Timeout of connecting to the node, and of each read and write.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RpcError {
+    /// The endpoint is not an `http://host[:port][/path]` URL.
+    Url(String),
+    /// The endpoint is an `https://` URL, TLS is not supported.
+    Https(String),
+    Io(String),
+    /// The server answered with a non-200 status line.
+    Http(String),
+    /// JSON-RPC error code and message.
+    Rpc(i64, String),
+    Json(String),
+    TransactionNotFound(H256),
+    HeaderNotFound(H256),
+    /// The out point points past the outputs of its transaction.
+    CellNotFound(OutPoint),
+    InvalidDepGroup(OutPoint),
+}
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Url(url) => write!(
+                f,
+                "unsupported url {}, expected http://host[:port][/path]",
+                url
+            ),
+            Self::Https(url) => write!(
+                f,
+                "unsupported url {}, https is not supported, use an http endpoint",
+                url
+            ),
+            Self::Io(msg) => write!(f, "rpc connection: {}", msg),
+            Self::Http(status) => write!(f, "rpc http status: {}", status),
+            Self::Rpc(code, msg) => write!(f, "rpc error {}: {}", code, msg),
+            Self::Json(msg) => write!(f, "rpc response: {}", msg),
+            Self::TransactionNotFound(hash) => write!(f, "transaction {:#x} not found", hash),
+            Self::HeaderNotFound(hash) => write!(f, "header {:#x} not found", hash),
+            Self::CellNotFound(out_point) => write!(
+                f,
+                "cell {:#x}:{} not found",
+                out_point.tx_hash(),
+                Unpack::<u32>::unpack(&out_point.index())
+            ),
+            Self::InvalidDepGroup(out_point) => write!(
+                f,
+                "cell {:#x}:{} is not a dep group",
+                out_point.tx_hash(),
+                Unpack::<u32>::unpack(&out_point.index())
+            ),
+        }
+    }
+}
+impl std::error::Error for RpcError {}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcErrorObject>,
+}
+
+#[derive(Deserialize)]
+struct RpcErrorObject {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct TransactionResponse {
+    transaction: Option<ckb_jsonrpc_types::TransactionView>,
+    tx_status: TxStatusResponse,
+}
+
+#[derive(Deserialize)]
+struct TxStatusResponse {
+    block_hash: Option<H256>,
+}
+
+/// A transaction and the block committing it, if any.
+#[derive(Clone)]
+struct CommittedTransaction {
+    transaction: packed::Transaction,
+    block_hash: Option<H256>,
+}
+
+pub struct RpcClient {
+    host: String,
+    port: u16,
+    path: String,
+    timeout: Duration,
+    transactions: RefCell<HashMap<H256, CommittedTransaction>>,
+}
+impl RpcClient {
+    pub fn new(url: &str) -> Result<Self, RpcError> {
+        if url.starts_with("https://") {
+            return Err(RpcError::Https(url.to_string()));
+        }
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| RpcError::Url(url.to_string()))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse().map_err(|_| RpcError::Url(url.to_string()))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(RpcError::Url(url.to_string()));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            timeout: DEFAULT_TIMEOUT,
+            transactions: RefCell::new(HashMap::new()),
+        })
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn connect(&self) -> Result<TcpStream, RpcError> {
+        let io = |e: std::io::Error| RpcError::Io(e.to_string());
+        let mut last_error = None;
+        for addr in (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(io)?
+        {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout)).map_err(io)?;
+                    stream.set_write_timeout(Some(self.timeout)).map_err(io)?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.map_or_else(|| RpcError::Io(format!("cannot resolve {}", self.host)), io))
+    }
+
+    fn post(&self, body: &[u8]) -> Result<Vec<u8>, RpcError> {
+        let io = |e: std::io::Error| RpcError::Io(e.to_string());
+        let mut stream = self.connect()?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            self.port,
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).map_err(io)?;
+        stream.write_all(body).map_err(io)?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response).map_err(io)?;
+
+        let header_end = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| RpcError::Io("truncated http response".to_string()))?;
+        let head = String::from_utf8_lossy(&response[..header_end]).to_string();
+        let body = response[header_end + 4..].to_vec();
+        let mut lines = head.lines();
+        let status = lines.next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(RpcError::Http(status.to_string()));
+        }
+        let chunked = lines.any(|line| {
+            let line = line.to_ascii_lowercase();
+            line.starts_with("transfer-encoding:") && line.contains("chunked")
+        });
+        if chunked {
+            decode_chunked(&body)
+        } else {
+            Ok(body)
+        }
+    }
+
+    /// Calls `method` and returns its result, `None` for a null result.
+    pub fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<Option<T>, RpcError> {
+        let request = serde_json::json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+        });
+        let body = self.post(request.to_string().as_bytes())?;
+        let response: RpcResponse<T> =
+            serde_json::from_slice(&body).map_err(|e| RpcError::Json(e.to_string()))?;
+        if let Some(error) = response.error {
+            return Err(RpcError::Rpc(error.code, error.message));
+        }
+        Ok(response.result)
+    }
+
+    fn get_transaction(&self, hash: &H256) -> Result<CommittedTransaction, RpcError> {
+        if let Some(tx) = self.transactions.borrow().get(hash) {
+            return Ok(tx.clone());
+        }
+        let response: TransactionResponse = self
+            .call("get_transaction", serde_json::json!([hash]))?
+            .ok_or_else(|| RpcError::TransactionNotFound(hash.clone()))?;
+        let tx = CommittedTransaction {
+            transaction: response
+                .transaction
+                .ok_or_else(|| RpcError::TransactionNotFound(hash.clone()))?
+                .inner
+                .into(),
+            block_hash: response.tx_status.block_hash,
+        };
+        self.transactions
+            .borrow_mut()
+            .insert(hash.clone(), tx.clone());
+        Ok(tx)
+    }
+
+    pub fn get_header(&self, hash: &H256) -> Result<HeaderView, RpcError> {
+        let header: ckb_jsonrpc_types::HeaderView = self
+            .call("get_header", serde_json::json!([hash]))?
+            .ok_or_else(|| RpcError::HeaderNotFound(hash.clone()))?;
+        Ok(header.into())
+    }
+
+    /// The cell at `out_point`, with the hash of the block committing it.
+    pub fn get_cell(
+        &self,
+        out_point: &OutPoint,
+    ) -> Result<(CellOutput, Bytes, Option<Byte32>), RpcError> {
+        let tx = self.get_transaction(&out_point.tx_hash().unpack())?;
+        let index: u32 = out_point.index().unpack();
+        let raw = tx.transaction.raw();
+        let output = raw
+            .outputs()
+            .get(index as usize)
+            .ok_or_else(|| RpcError::CellNotFound(out_point.clone()))?;
+        let data = raw
+            .outputs_data()
+            .get(index as usize)
+            .ok_or_else(|| RpcError::CellNotFound(out_point.clone()))?;
+        Ok((output, data.raw_data(), tx.block_hash.map(|h| h.pack())))
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, RpcError> {
+    let invalid = || RpcError::Io("invalid chunked encoding".to_string());
+    let mut decoded = vec![];
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(invalid)?;
+        let size = String::from_utf8_lossy(&body[..line_end]);
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16)
+            .map_err(|_| invalid())?;
+        if size == 0 {
+            return Ok(decoded);
+        }
+        let chunk = body
+            .get(line_end + 2..line_end + 2 + size)
+            .ok_or_else(invalid)?;
+        decoded.extend_from_slice(chunk);
+        body = body.get(line_end + 4 + size..).ok_or_else(invalid)?;
+    }
+}
+
+fn mock_cell_dep(client: &RpcClient, cell_dep: CellDep) -> Result<MockCellDep, RpcError> {
+    let (output, data, header) = client.get_cell(&cell_dep.out_point())?;
+    Ok(MockCellDep {
+        cell_dep,
+        output,
+        data,
+        header,
+    })
+}
+
+/// Resolves the inputs, cell deps and header deps of `tx`. Dep groups are
+/// listed followed by their member cells.
+pub fn fetch_mock_tx(
+    client: &RpcClient,
+    tx: packed::Transaction,
+) -> Result<ReprMockTransaction, RpcError> {
+    let raw = tx.raw();
+    let mut inputs = vec![];
+    for input in raw.inputs() {
+        let (output, data, header) = client.get_cell(&input.previous_output())?;
+        inputs.push(MockInput {
+            input,
+            output,
+            data,
+            header,
+        });
+    }
+
+    let mut cell_deps: Vec<MockCellDep> = vec![];
+    for cell_dep in raw.cell_deps() {
+        let group = mock_cell_dep(client, cell_dep.clone())?;
+        let members: Vec<OutPoint> = if cell_dep.dep_type() == DepType::DepGroup.into() {
+            OutPointVec::from_slice(&group.data)
+                .map_err(|_| RpcError::InvalidDepGroup(cell_dep.out_point()))?
+                .into_iter()
+                .collect()
+        } else {
+            vec![]
+        };
+        cell_deps.push(group);
+        for member in members {
+            if cell_deps
+                .iter()
+                .any(|dep| dep.cell_dep.out_point() == member)
+            {
+                continue;
+            }
+            let member_dep = CellDep::new_builder()
+                .out_point(member)
+                .dep_type(DepType::Code.into())
+                .build();
+            cell_deps.push(mock_cell_dep(client, member_dep)?);
+        }
+    }
+
+    let mut header_deps = vec![];
+    for hash in raw.header_deps() {
+        header_deps.push(client.get_header(&hash.unpack())?);
+    }
+
+    Ok(MockTransaction {
+        mock_info: MockInfo {
+            inputs,
+            cell_deps,
+            header_deps,
+            extensions: vec![],
+        },
+        tx,
+    }
+    .into())
+}
+
+/// Fetches the transaction `hash` and resolves it.
+pub fn fetch_mock_tx_by_hash(
+    client: &RpcClient,
+    hash: &H256,
+) -> Result<ReprMockTransaction, RpcError> {
+    let tx = client.get_transaction(hash)?;
+    fetch_mock_tx(client, tx.transaction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headers::MockHeader;
+    use ckb_types::core::TransactionView;
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+    };
+
+    /// The JSON body of an http request.
+    fn read_request(stream: &mut TcpStream) -> serde_json::Value {
+        let mut reader = BufReader::new(stream);
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// A node serving `get_transaction` of `txs`, committed in their block
+    /// if any, and `get_header` of `headers`, in chunked responses.
+    fn stub_node(txs: Vec<(TransactionView, Option<H256>)>, headers: Vec<HeaderView>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/rpc", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let request = read_request(&mut stream);
+                let hash = request["params"][0].as_str().unwrap().to_string();
+                let result = match request["method"].as_str().unwrap() {
+                    "get_transaction" => txs
+                        .iter()
+                        .find(|(tx, _)| format!("{:#x}", tx.hash()) == hash)
+                        .map(|(tx, block_hash)| {
+                            serde_json::json!({
+                                "transaction": ckb_jsonrpc_types::TransactionView::from(tx.clone()),
+                                "tx_status": { "status": "committed", "block_hash": block_hash },
+                            })
+                        }),
+                    "get_header" => headers
+                        .iter()
+                        .find(|h| format!("{:#x}", h.hash()) == hash)
+                        .map(|h| serde_json::json!(ckb_jsonrpc_types::HeaderView::from(h.clone()))),
+                    _ => None,
+                };
+                let body = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": result });
+                let body = body.to_string();
+                let (first, second) = body.split_at(body.len() / 2);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                    first.len(),
+                    first,
+                    second.len(),
+                    second
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn fetches_inputs_dep_groups_and_headers() {
+        let code = CellOutput::new_builder().capacity(100u64.pack()).build();
+        let previous = TransactionView::new_advanced_builder()
+            .output(code.clone())
+            .output_data(Bytes::from(vec![1u8]).pack())
+            .output(code.clone())
+            .output_data(Bytes::new().pack())
+            .output(code)
+            .output_data(Bytes::from(vec![2u8]).pack())
+            .build();
+        let member = OutPoint::new(previous.hash(), 2);
+        // Outputs data are part of the hash, the group comes in a second
+        // transaction.
+        let group = TransactionView::new_advanced_builder()
+            .output(CellOutput::new_builder().build())
+            .output_data(vec![member.clone()].pack().as_bytes().pack())
+            .build();
+        let header = MockHeader {
+            number: 7,
+            ..Default::default()
+        }
+        .build();
+        let tx = TransactionView::new_advanced_builder()
+            .input(packed::CellInput::new(OutPoint::new(previous.hash(), 0), 0))
+            .cell_dep(
+                CellDep::new_builder()
+                    .out_point(OutPoint::new(group.hash(), 0))
+                    .dep_type(DepType::DepGroup.into())
+                    .build(),
+            )
+            .header_dep(header.hash())
+            .build();
+        let block_hash = H256([5u8; 32]);
+        let url = stub_node(
+            vec![
+                (previous, Some(block_hash.clone())),
+                (group, None),
+                (tx.clone(), None),
+            ],
+            vec![header.clone()],
+        );
+
+        let client = RpcClient::new(&url).unwrap();
+        let mock_tx: MockTransaction = fetch_mock_tx_by_hash(&client, &tx.hash().unpack())
+            .unwrap()
+            .into();
+        assert_eq!(mock_tx.tx.as_slice(), tx.data().as_slice());
+        assert_eq!(mock_tx.mock_info.inputs[0].data, Bytes::from(vec![1u8]));
+        assert_eq!(mock_tx.mock_info.inputs[0].header, Some(block_hash.pack()));
+        let cell_deps = &mock_tx.mock_info.cell_deps;
+        assert_eq!(cell_deps.len(), 2);
+        assert_eq!(cell_deps[0].header, None);
+        assert_eq!(cell_deps[1].cell_dep.out_point(), member);
+        assert_eq!(cell_deps[1].data, Bytes::from(vec![2u8]));
+        assert_eq!(mock_tx.mock_info.header_deps, vec![header]);
+
+        let unknown = H256([9u8; 32]);
+        assert_eq!(
+            fetch_mock_tx_by_hash(&client, &unknown).map(|_| ()),
+            Err(RpcError::TransactionNotFound(unknown))
+        );
+    }
+
+    #[test]
+    fn unsupported_urls_are_rejected() {
+        assert!(matches!(
+            RpcClient::new("https://mainnet.ckb.dev/"),
+            Err(RpcError::Https(_))
+        ));
+        assert!(matches!(
+            RpcClient::new("ws://127.0.0.1:8114"),
+            Err(RpcError::Url(_))
+        ));
+        assert!(matches!(
+            RpcClient::new("http://127.0.0.1:port"),
+            Err(RpcError::Url(_))
+        ));
+    }
+
+    #[test]
+    fn silent_nodes_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let mut client = RpcClient::new(&url).unwrap();
+        client.set_timeout(Duration::from_millis(100));
+        // Connections are accepted by the backlog but never answered.
+        assert!(matches!(
+            client.get_header(&H256([1u8; 32])),
+            Err(RpcError::Io(_))
+        ));
+        drop(listener);
+    }
+}
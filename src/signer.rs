@@ -0,0 +1,351 @@
+//! Signs the lock groups of a mock transaction with test keys, producing
+//! the witnesses the built-in sighash-all and multisig-all locks (and the
+//! real ones on chain) accept.
+//!
+//! Groups are collected like `ckb_load_witness` with `SOURCE_GROUP_INPUT`
+//! sees them, and the message covers the same witnesses the scripts hash.
+use crate::{
+    system_scripts::{
+        blake160, sighash_all_message, MultisigConfig, BLAKE160_SIZE, MULTISIG_ALL_TYPE_HASH,
+        SECP256K1, SIGHASH_ALL_TYPE_HASH, SIGNATURE_SIZE,
+    },
+    verify::script_groups,
+};
+use ckb_mock_tx_types::MockTransaction;
+use ckb_types::{
+    bytes::Bytes,
+    core::ScriptHashType,
+    packed::{Script, WitnessArgs},
+    prelude::*,
+};
+use secp256k1::Message;
+pub use secp256k1::{PublicKey, SecretKey};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SignError {
+    /// No input is locked by the lock being signed.
+    NoLockGroup,
+    /// The witness at this index is neither empty nor a `WitnessArgs`.
+    InvalidWitness(usize),
+    /// The key at this index is not part of the multisig config.
+    UnknownKey(usize),
+    /// Number of keys given, and the threshold of the multisig config.
+    WrongKeyCount(usize, u8),
+}
+impl std::fmt::Display for SignError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoLockGroup => write!(f, "no input is locked by the signing lock"),
+            Self::InvalidWitness(i) => write!(f, "witness {} is not a WitnessArgs", i),
+            Self::UnknownKey(i) => write!(f, "key {} is not part of the multisig config", i),
+            Self::WrongKeyCount(keys, threshold) => {
+                write!(f, "{} keys given for a threshold of {}", keys, threshold)
+            }
+        }
+    }
+}
+impl std::error::Error for SignError {}
+
+pub fn pubkey_hash(key: &SecretKey) -> [u8; BLAKE160_SIZE] {
+    blake160(&PublicKey::from_secret_key(&SECP256K1, key).serialize())
+}
+
+/// The secp256k1_blake160_sighash_all lock of `key`.
+pub fn sighash_lock(key: &SecretKey) -> Script {
+    Script::new_builder()
+        .code_hash(SIGHASH_ALL_TYPE_HASH.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(Bytes::copy_from_slice(&pubkey_hash(key)).pack())
+        .build()
+}
+
+/// The secp256k1_blake160_multisig_all lock of `config`, optionally with a
+/// since requirement in its args.
+pub fn multisig_lock(config: &MultisigConfig, since: Option<u64>) -> Script {
+    let mut args = config.hash160().to_vec();
+    if let Some(since) = since {
+        args.extend_from_slice(&since.to_le_bytes());
+    }
+    Script::new_builder()
+        .code_hash(MULTISIG_ALL_TYPE_HASH.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(Bytes::from(args).pack())
+        .build()
+}
+
+/// Signs every input locked by the sighash-all lock of `key`.
+pub fn sign_sighash_all(mock_tx: &mut MockTransaction, key: &SecretKey) -> Result<(), SignError> {
+    let lock = sighash_lock(key);
+    sign_groups(
+        mock_tx,
+        |script| script == &lock,
+        &[0u8; SIGNATURE_SIZE],
+        |message| sign(message, key).to_vec(),
+    )
+}
+
+/// Signs every input locked by the multisig-all lock of `config`, with or
+/// without since, using exactly `threshold` keys of the config.
+pub fn sign_multisig_all(
+    mock_tx: &mut MockTransaction,
+    config: &MultisigConfig,
+    keys: &[SecretKey],
+) -> Result<(), SignError> {
+    if keys.len() != config.threshold as usize {
+        return Err(SignError::WrongKeyCount(keys.len(), config.threshold));
+    }
+    for (i, key) in keys.iter().enumerate() {
+        if !config.pubkey_hashes.contains(&pubkey_hash(key)) {
+            return Err(SignError::UnknownKey(i));
+        }
+    }
+    let hash = config.hash160();
+    let mut placeholder = config.to_bytes();
+    placeholder.resize(config.lock_size(), 0);
+    sign_groups(
+        mock_tx,
+        |script| {
+            script.code_hash() == MULTISIG_ALL_TYPE_HASH.pack()
+                && script.hash_type() == ScriptHashType::Type.into()
+                && script.args().raw_data().get(..BLAKE160_SIZE) == Some(&hash[..])
+        },
+        &placeholder,
+        |message| {
+            let mut lock = config.to_bytes();
+            keys.iter()
+                .for_each(|key| lock.extend_from_slice(&sign(message, key)));
+            lock
+        },
+    )
+}
+
+fn sign(message: &[u8; 32], key: &SecretKey) -> [u8; SIGNATURE_SIZE] {
+    let (recid, data) = SECP256K1
+        .sign_ecdsa_recoverable(&Message::from_digest(*message), key)
+        .serialize_compact();
+    let mut signature = [0u8; SIGNATURE_SIZE];
+    signature[..64].copy_from_slice(&data);
+    signature[64] = i32::from(recid) as u8;
+    signature
+}
+
+/// Fills the lock of the first witness of every lock group matching
+/// `matches`. The message is computed with `placeholder` as the lock, and
+/// `signer` turns it into the final lock.
+fn sign_groups<M, S>(
+    mock_tx: &mut MockTransaction,
+    matches: M,
+    placeholder: &[u8],
+    signer: S,
+) -> Result<(), SignError>
+where
+    M: Fn(&Script) -> bool,
+    S: Fn(&[u8; 32]) -> Vec<u8>,
+{
+    let groups: Vec<_> = script_groups(mock_tx)
+        .into_iter()
+        .filter(|g| g.is_lock_script && matches(&g.script))
+        .collect();
+    if groups.is_empty() {
+        return Err(SignError::NoLockGroup);
+    }
+
+    let tx_hash = mock_tx.tx.calc_tx_hash();
+    let inputs_len = mock_tx.tx.raw().inputs().len();
+    let mut witnesses: Vec<Bytes> = mock_tx
+        .tx
+        .witnesses()
+        .into_iter()
+        .map(|w| w.raw_data())
+        .collect();
+    for group in groups {
+        let first = group.input_indices[0];
+        if witnesses.len() <= first {
+            witnesses.resize(first + 1, Bytes::new());
+        }
+        let witness_args = if witnesses[first].is_empty() {
+            WitnessArgs::default()
+        } else {
+            WitnessArgs::from_slice(&witnesses[first])
+                .map_err(|_| SignError::InvalidWitness(first))?
+        };
+        let zeroed = witness_args
+            .clone()
+            .as_builder()
+            .lock(Some(Bytes::copy_from_slice(placeholder)).pack())
+            .build()
+            .as_bytes();
+
+        // Like the scripts, stop at the first group input without a witness.
+        let mut hashed: Vec<&[u8]> = vec![&zeroed];
+        hashed.extend(
+            group.input_indices[1..]
+                .iter()
+                .map_while(|i| witnesses.get(*i))
+                .map(|w| w.as_ref()),
+        );
+        hashed.extend(witnesses.iter().skip(inputs_len).map(|w| w.as_ref()));
+        let message = sighash_all_message(tx_hash.as_slice(), &hashed);
+
+        let lock = signer(&message);
+        witnesses[first] = witness_args
+            .as_builder()
+            .lock(Some(Bytes::from(lock)).pack())
+            .build()
+            .as_bytes();
+    }
+
+    mock_tx.tx = mock_tx
+        .tx
+        .clone()
+        .as_builder()
+        .witnesses(
+            witnesses
+                .into_iter()
+                .map(|w| w.pack())
+                .collect::<Vec<_>>()
+                .pack(),
+        )
+        .build();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        system_scripts::recover_blake160, test_utils, verify::verify_transaction, RunningSetup,
+    };
+    use ckb_types::packed::{CellInput, OutPoint};
+
+    fn key(byte: u8) -> SecretKey {
+        SecretKey::from_byte_array(&[byte; 32]).unwrap()
+    }
+
+    /// `locked_tx` with a second input under the same lock.
+    fn two_inputs_tx(lock: Script) -> MockTransaction {
+        let mut tx = test_utils::locked_tx(lock);
+        let mut input = tx.mock_info.inputs[0].clone();
+        input.input = CellInput::new(OutPoint::new([5u8; 32].pack(), 0), 0);
+        let raw = tx.tx.raw();
+        let inputs = raw.inputs().as_builder().push(input.input.clone()).build();
+        tx.tx = tx
+            .tx
+            .clone()
+            .as_builder()
+            .raw(raw.as_builder().inputs(inputs).build())
+            .build();
+        tx.mock_info.inputs.push(input);
+        tx
+    }
+
+    fn witness(tx: &MockTransaction, index: usize) -> WitnessArgs {
+        WitnessArgs::from_slice(&tx.tx.witnesses().get(index).unwrap().raw_data()).unwrap()
+    }
+
+    #[test]
+    fn pubkey_hash_is_the_lock_args() {
+        // The first key of ckb's dev chain and its well-known lock args.
+        let key = SecretKey::from_byte_array(&[
+            0xd0, 0x0c, 0x06, 0xbf, 0xd8, 0x00, 0xd2, 0x73, 0x97, 0x00, 0x2d, 0xca, 0x6f, 0xb0,
+            0x99, 0x3d, 0x5b, 0xa6, 0x39, 0x9b, 0x42, 0x38, 0xb2, 0xf2, 0x9e, 0xe9, 0xde, 0xb9,
+            0x75, 0x93, 0xd2, 0xbc,
+        ])
+        .unwrap();
+        assert_eq!(
+            faster_hex::hex_string(&pubkey_hash(&key)),
+            "c8328aabcd9b9e8e64fbc566c4385c3bdeb219d7"
+        );
+        assert_eq!(
+            sighash_lock(&key).args().raw_data().as_ref(),
+            pubkey_hash(&key)
+        );
+    }
+
+    #[test]
+    fn sighash_all_signs_the_first_witness_of_the_group() {
+        let key = key(1);
+        let mut tx = two_inputs_tx(sighash_lock(&key));
+        let input_type = Bytes::from(vec![7u8]);
+        let first = WitnessArgs::new_builder()
+            .input_type(Some(input_type.clone()).pack())
+            .build();
+        tx.tx = tx
+            .tx
+            .as_builder()
+            .witnesses(vec![first.as_bytes().pack()].pack())
+            .build();
+        sign_sighash_all(&mut tx, &key).unwrap();
+
+        let signed = witness(&tx, 0);
+        assert_eq!(
+            signed.input_type().to_opt().unwrap().raw_data(),
+            input_type,
+            "the other fields are kept"
+        );
+        let signature = signed.lock().to_opt().unwrap().raw_data();
+        assert_eq!(signature.len(), SIGNATURE_SIZE);
+        let zeroed = signed
+            .as_builder()
+            .lock(Some(Bytes::from(vec![0u8; SIGNATURE_SIZE])).pack())
+            .build()
+            .as_bytes();
+        let message = sighash_all_message(tx.tx.calc_tx_hash().as_slice(), &[&zeroed]);
+        assert_eq!(
+            recover_blake160(&message, &signature),
+            Ok(pubkey_hash(&key))
+        );
+        assert_eq!(verify_transaction(&tx, &RunningSetup::default()), Ok(()));
+    }
+
+    #[test]
+    fn signing_errors() {
+        let mut tx = test_utils::locked_tx(sighash_lock(&key(1)));
+        assert_eq!(
+            sign_sighash_all(&mut tx, &key(2)),
+            Err(SignError::NoLockGroup)
+        );
+        tx.tx = tx
+            .tx
+            .as_builder()
+            .witnesses(vec![Bytes::from(vec![1u8, 2]).pack()].pack())
+            .build();
+        assert_eq!(
+            sign_sighash_all(&mut tx, &key(1)),
+            Err(SignError::InvalidWitness(0))
+        );
+
+        let config = MultisigConfig {
+            require_first_n: 0,
+            threshold: 2,
+            pubkey_hashes: vec![pubkey_hash(&key(1)), pubkey_hash(&key(2))],
+        };
+        let mut tx = test_utils::locked_tx(multisig_lock(&config, None));
+        assert_eq!(
+            sign_multisig_all(&mut tx, &config, &[key(1)]),
+            Err(SignError::WrongKeyCount(1, 2))
+        );
+        assert_eq!(
+            sign_multisig_all(&mut tx, &config, &[key(1), key(3)]),
+            Err(SignError::UnknownKey(1))
+        );
+    }
+
+    #[test]
+    fn multisig_all_signs_locks_with_since() {
+        let keys = [key(1), key(2), key(3)];
+        let config = MultisigConfig {
+            require_first_n: 1,
+            threshold: 2,
+            pubkey_hashes: keys.iter().map(pubkey_hash).collect(),
+        };
+        let mut tx = two_inputs_tx(multisig_lock(&config, Some(0)));
+        sign_multisig_all(&mut tx, &config, &[keys[0], keys[2]]).unwrap();
+
+        let lock = witness(&tx, 0).lock().to_opt().unwrap().raw_data();
+        assert_eq!(lock.len(), config.lock_size());
+        assert_eq!(&lock[..config.to_bytes().len()], config.to_bytes());
+        assert_eq!(tx.tx.witnesses().len(), 1, "one witness per group");
+        assert_eq!(verify_transaction(&tx, &RunningSetup::default()), Ok(()));
+    }
+}
@@ -0,0 +1,257 @@
+//! Simulations isolated from each other inside one process.
+//!
+//! Each `Simulation` registers its own `SimContext` holding the transaction,
+//! the running setup, the debug sink, the syscall handler and the process
+//! table. Syscalls look the context up through the calling thread's
+//! simulation id, so independent simulations can run in parallel test
+//! threads.
+use crate::{
+    capture::Capture,
+    constants::SIMULATOR_MOCK_MISMATCH_EXIT_CODE,
+    crash::{self, CrashReport},
+    global_data::GlobalData,
+    simulator_context::SimContext,
+    syscalls::SyscallHandler,
+    tx_verifier::{precheck, TxVerifyError},
+    utils::{CkbNativeSimulator, ProcID, SimID},
+    RunningSetup,
+};
+use ckb_mock_tx_types::MockTransaction;
+use std::sync::Arc;
+
+pub struct Simulation {
+    id: SimID,
+}
+impl Simulation {
+    pub fn new(tx: MockTransaction, setup: RunningSetup) -> Self {
+        let ctx = SimContext::new(Arc::new(tx), Arc::new(setup));
+        let id = GlobalData::locked().set_tx(ctx);
+        Self { id }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id.clone().into()
+    }
+
+    /// Receives `ckb_debug` messages instead of stdout, and the buffers
+    /// decoded with `MoleculeBindings`, which are only shown here.
+    pub fn set_debug_sink<F: Fn(&str) + Send + Sync + 'static>(&self, sink: F) {
+        GlobalData::locked()
+            .get_tx_mut(&self.id)
+            .set_debug_sink(Some(Arc::new(sink)));
+    }
+
+    /// Consulted by every syscall of this simulation before the default
+    /// implementation, see `syscalls` module.
+    pub fn set_syscall_handler<H: SyscallHandler + 'static>(&self, handler: H) {
+        GlobalData::locked()
+            .get_tx_mut(&self.id)
+            .set_syscall_handler(Some(Arc::new(handler)));
+    }
+
+    /// Serves syscall number `n` of `syscalls::ckb_syscall`, given `a0` to
+    /// `a5` and returning `a0`, for custom VMs built on CKB-VM.
+    pub fn register_syscall<F: Fn([u64; 6]) -> i64 + Send + Sync + 'static>(
+        &self,
+        n: u64,
+        syscall: F,
+    ) {
+        GlobalData::locked()
+            .get_tx_mut(&self.id)
+            .register_syscall(n, Arc::new(syscall));
+    }
+
+    /// Pipe traffic recorded so far, when the setup has `pipe_capture`.
+    pub fn pipe_capture(&self) -> Capture {
+        GlobalData::locked().get_tx(&self.id).capture()
+    }
+
+    /// Crashes of the VMs of this simulation so far, in the order they
+    /// happened, see `crash` module.
+    pub fn crash_reports(&self) -> Vec<CrashReport> {
+        GlobalData::locked()
+            .get_tx(&self.id)
+            .crash_reports()
+            .to_vec()
+    }
+
+    /// Routes syscalls made by the current thread to this simulation, as if
+    /// they came from its root VM.
+    pub fn enter(&self) {
+        SimContext::update_ctx_id(self.id.clone(), Some(0.into()));
+    }
+
+    /// The checks enabled in the setup, see `tx_verifier::precheck`.
+    pub fn precheck(&self) -> Result<(), TxVerifyError> {
+        let (tx, setup) = {
+            let global_data = GlobalData::locked();
+            let sim_ctx = global_data.get_tx(&self.id);
+            (sim_ctx.transaction(), sim_ctx.setup())
+        };
+        precheck(&tx, &setup)
+    }
+
+    /// Runs a native simulator library as the root VM of this simulation and
+    /// returns its exit code, once the transaction passes `precheck`.
+    pub fn run_native(&self, path: &str, args: &[&str]) -> Result<i8, TxVerifyError> {
+        let args: Vec<&[u8]> = args.iter().map(|a| a.as_bytes()).collect();
+        self.run_native_bytes(path, &args)
+    }
+
+    /// `run_native` with arguments that need not be UTF-8, like the argv
+    /// of scripts on chain.
+    pub fn run_native_bytes(&self, path: &str, args: &[&[u8]]) -> Result<i8, TxVerifyError> {
+        self.precheck()?;
+        Ok(self.run_native_unchecked(path, args))
+    }
+
+    /// `run_native_bytes` for callers that already ran `precheck`.
+    pub(crate) fn run_native_unchecked(&self, path: &str, args: &[&[u8]]) -> i8 {
+        let sim = CkbNativeSimulator::new(&path.into());
+        let args = args.iter().map(|a| a.to_vec()).collect();
+        self.run_root(path.to_string(), move |sim_id, pid| {
+            sim.update_script_info(sim_id, pid);
+            sim.ckb_std_main(args)
+        })
+    }
+
+    /// Runs `func` as the root VM (process id 0) on a new thread.
+    pub(crate) fn run_root<F: Send + 'static + FnOnce(SimID, ProcID) -> i8>(
+        &self,
+        script: String,
+        func: F,
+    ) -> i8 {
+        let crash_handler = {
+            let mut global_data = GlobalData::locked();
+            let sim_ctx = global_data.get_tx_mut(&self.id);
+            sim_ctx.set_script(&0.into(), script);
+            sim_ctx.setup().crash_handler
+        };
+        let id = self.id.clone();
+        let code = std::thread::spawn(move || {
+            SimContext::update_ctx_id(id.clone(), Some(0.into()));
+            crash::run_protected(crash_handler, || func(id, 0.into()))
+        })
+        .join()
+        .expect("run root vm");
+
+        let mut global_data = GlobalData::locked();
+        let sim_ctx = global_data.get_tx_mut(&self.id);
+        sim_ctx.capture_exit(&0.into(), code);
+        if let Err(e) = sim_ctx.save_capture() {
+            eprintln!("[pipe capture] {}", e);
+        }
+        match sim_ctx.mock_child_failed() {
+            true => SIMULATOR_MOCK_MISMATCH_EXIT_CODE,
+            false => code,
+        }
+    }
+}
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        GlobalData::locked().remove_tx(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ckb_exec_cell, ckb_load_tx_hash, ckb_vm_version, test_utils, RunningType};
+    use ckb_types::{bytes::Bytes, core::error::TransactionError, prelude::*};
+    use std::sync::Barrier;
+
+    #[test]
+    fn concurrent_simulations_are_isolated() {
+        let barrier = Arc::new(Barrier::new(2));
+        let threads: Vec<_> = [1, 2]
+            .into_iter()
+            .map(|vm_version| {
+                let mut tx = test_utils::sample_tx();
+                tx.tx = tx
+                    .tx
+                    .as_builder()
+                    .witnesses([Bytes::from(vec![vm_version as u8])].pack())
+                    .build();
+                let tx_hash = tx.tx.calc_tx_hash();
+                let setup = RunningSetup {
+                    vm_version,
+                    ..Default::default()
+                };
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    let sim = Simulation::new(tx, setup);
+                    barrier.wait();
+                    sim.run_root("isolated".to_string(), move |_, _| {
+                        for _ in 0..100 {
+                            assert_eq!(ckb_vm_version(), vm_version);
+                            let mut hash = [0u8; 32];
+                            let mut len = 32;
+                            ckb_load_tx_hash(hash.as_mut_ptr().cast(), &mut len, 0);
+                            assert_eq!(hash, tx_hash.as_slice());
+                        }
+                        0
+                    })
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn run_native_checks_since_first() {
+        let mut tx = test_utils::sample_tx();
+        let input = tx.mock_info.inputs[0].input.clone();
+        let input = ckb_types::packed::CellInput::new(input.previous_output(), 1000);
+        tx.mock_info.inputs[0].input = input.clone();
+        let raw = tx.tx.raw().as_builder().inputs(vec![input].pack()).build();
+        tx.tx = tx.tx.as_builder().raw(raw).build();
+
+        let path = test_utils::argv_dylib();
+        let sim = Simulation::new(tx.clone(), RunningSetup::default());
+        assert_eq!(sim.run_native(&path, &[]), Ok(0));
+        let setup = RunningSetup {
+            check_since: true,
+            ..Default::default()
+        };
+        let sim = Simulation::new(tx, setup);
+        assert_eq!(
+            sim.run_native(&path, &[]),
+            Err(TxVerifyError::Transaction(TransactionError::Immature {
+                index: 0
+            }))
+        );
+    }
+
+    #[test]
+    fn exec_context_is_dropped_when_vm_ends() {
+        let code_hash = [2u8; 32];
+        let setup = RunningSetup {
+            vm_version: 1,
+            run_type: Some(RunningType::DynamicLib),
+            native_binaries: [(
+                test_utils::cell_key(&code_hash, 1),
+                test_utils::argv_dylib(),
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let sim = Simulation::new(Default::default(), setup);
+        let id = sim.id.clone();
+        let code = sim.run_root("exec".to_string(), move |_, _| {
+            let setup = GlobalData::locked().get_tx(&id).setup();
+            let references = Arc::strong_count(&setup);
+
+            let arg = c"ab";
+            let argv = [arg.as_ptr() as *const u8];
+            let code = ckb_exec_cell(code_hash.as_ptr(), 1, 0, 0, 1, argv.as_ptr());
+            assert_eq!(code, test_utils::argv_code(&[b"ab"]) as i32);
+
+            assert_eq!(SimContext::ctx_id(), id);
+            assert_eq!(Arc::strong_count(&setup), references);
+            0
+        });
+        assert_eq!(code, 0);
+    }
+}
@@ -1,8 +1,16 @@
 use crate::{
+    capture::{Capture, CaptureError, CapturedPipe, CapturedProcess, PipeEvent, PipeOp},
+    crash::{self, CrashReport},
     global_data::GlobalData,
+    syscalls::{CustomSyscall, SyscallHandler},
+    tx_verifier::resolve_cell_deps,
     utils::{Event, Fd, ProcID, SimID},
+    RunningSetup,
 };
-use std::{cell::RefCell, collections::HashMap, thread::JoinHandle};
+use ckb_jsonrpc_types::JsonBytes;
+use ckb_mock_tx_types::MockTransaction;
+use ckb_types::core::error::OutPointError;
+use std::{cell::RefCell, collections::HashMap, sync::Arc, thread::JoinHandle};
 
 thread_local! {
     static TX_CONTEXT_ID: RefCell<SimID> = RefCell::new(SimID::default());
@@ -11,6 +19,8 @@ thread_local! {
 
 const MAX_PROCESSES_COUNT: u64 = 16;
 
+pub type DebugSink = Arc<dyn Fn(&str) + Send + Sync>;
+
 #[derive(PartialEq, Eq, Clone)]
 pub enum ProcStatus {
     Default(ProcID),
@@ -129,6 +139,8 @@ struct ProcInfo {
 
     scheduler_event: Event,
     join_handle: Option<JoinHandle<i8>>,
+
+    script: Option<String>,
 }
 impl ProcInfo {
     fn set_pid(id: ProcID) {
@@ -150,6 +162,26 @@ pub struct SimContext {
     fds: HashMap<Fd, ProcID>,
 
     dbg_status_count: u64,
+
+    // None falls back to the ones given by environment variables.
+    tx: Option<Arc<MockTransaction>>,
+    setup: Option<Arc<RunningSetup>>,
+    debug_sink: Option<DebugSink>,
+    syscall_handler: Option<Arc<dyn SyscallHandler>>,
+    custom_syscalls: HashMap<u64, CustomSyscall>,
+    // Matching calls seen by every fault of the setup, by fault index.
+    fault_counts: HashMap<usize, u64>,
+    // Pipe traffic, recorded as it moves when the setup has a pipe capture
+    // file. Event data stays in plain vectors, `Bytes` made by a native
+    // simulator library point into it and would outlive it.
+    capture: Capture,
+    capture_events: Vec<(u64, u64, PipeOp, Vec<u8>)>,
+    // Indices into the mock cell deps, resolved on first use.
+    resolved_cell_deps: Option<Result<Arc<Vec<usize>>, OutPointError>>,
+    // A mock child failed, the run fails whatever its root VM returns.
+    mock_child_failed: bool,
+    // Crashes of the VMs of this simulation, see `crash` module.
+    crash_reports: Vec<CrashReport>,
 }
 impl Default for SimContext {
     fn default() -> Self {
@@ -163,10 +195,149 @@ impl Default for SimContext {
             fds: Default::default(),
 
             dbg_status_count: 0,
+
+            tx: None,
+            setup: None,
+            debug_sink: None,
+            syscall_handler: None,
+            custom_syscalls: Default::default(),
+            fault_counts: Default::default(),
+            capture: Default::default(),
+            capture_events: Default::default(),
+            resolved_cell_deps: None,
+            mock_child_failed: false,
+            crash_reports: Default::default(),
         }
     }
 }
 impl SimContext {
+    pub fn new(tx: Arc<MockTransaction>, setup: Arc<RunningSetup>) -> Self {
+        Self {
+            tx: Some(tx),
+            setup: Some(setup),
+            ..Default::default()
+        }
+    }
+    /// A fresh process table running on the same transaction, used by exec.
+    pub fn fork(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            setup: self.setup.clone(),
+            debug_sink: self.debug_sink.clone(),
+            syscall_handler: self.syscall_handler.clone(),
+            custom_syscalls: self.custom_syscalls.clone(),
+            // An exec continues the calls of the process it replaces.
+            fault_counts: self.fault_counts.clone(),
+            resolved_cell_deps: self.resolved_cell_deps.clone(),
+            ..Default::default()
+        }
+    }
+    pub fn transaction(&self) -> Arc<MockTransaction> {
+        self.tx
+            .clone()
+            .unwrap_or_else(|| crate::TRANSACTION.clone())
+    }
+    pub fn setup(&self) -> Arc<RunningSetup> {
+        self.setup.clone().unwrap_or_else(|| crate::SETUP.clone())
+    }
+    /// Cell deps with dep groups expanded, as indices into the mock cell
+    /// deps, see `tx_verifier::resolve_cell_deps`.
+    pub fn resolved_cell_deps(&mut self) -> Result<Arc<Vec<usize>>, OutPointError> {
+        if self.resolved_cell_deps.is_none() {
+            let resolved = resolve_cell_deps(&self.transaction()).map(Arc::new);
+            self.resolved_cell_deps = Some(resolved);
+        }
+        self.resolved_cell_deps.clone().expect("resolved cell deps")
+    }
+    pub fn debug_sink(&self) -> Option<DebugSink> {
+        self.debug_sink.clone()
+    }
+    pub fn set_debug_sink(&mut self, sink: Option<DebugSink>) {
+        self.debug_sink = sink;
+    }
+    pub fn syscall_handler(&self) -> Option<Arc<dyn SyscallHandler>> {
+        self.syscall_handler.clone()
+    }
+    pub fn set_syscall_handler(&mut self, handler: Option<Arc<dyn SyscallHandler>>) {
+        self.syscall_handler = handler;
+    }
+    pub fn custom_syscall(&self, n: u64) -> Option<CustomSyscall> {
+        self.custom_syscalls.get(&n).cloned()
+    }
+    pub fn register_syscall(&mut self, n: u64, syscall: CustomSyscall) {
+        self.custom_syscalls.insert(n, syscall);
+    }
+    /// Fails the run, see `mock_child` module.
+    pub fn fail_mock_child(&mut self) {
+        self.mock_child_failed = true;
+    }
+    pub fn mock_child_failed(&self) -> bool {
+        self.mock_child_failed
+    }
+    pub fn crash_reports(&self) -> &[CrashReport] {
+        &self.crash_reports
+    }
+    pub fn push_crash_report(&mut self, report: CrashReport) {
+        self.crash_reports.push(report);
+    }
+    /// Keeps what the run needs to know of `exec`, the context of an
+    /// exec'd VM that ended.
+    pub fn end_exec(&mut self, exec: SimContext) {
+        self.mock_child_failed |= exec.mock_child_failed;
+        self.crash_reports.extend(exec.crash_reports);
+    }
+    /// Counts a call matching the fault at `index`, returns the count.
+    pub fn count_fault(&mut self, index: usize) -> u64 {
+        let count = self.fault_counts.entry(index).or_default();
+        *count += 1;
+        *count
+    }
+    fn capturing(&self) -> bool {
+        self.setup().pipe_capture.is_some()
+    }
+    fn capture_event(&mut self, pid: &ProcID, fd: &Fd, op: PipeOp, data: &[u8]) {
+        if self.capturing() {
+            let (pid, fd) = (pid.clone().into(), fd.clone().into());
+            self.capture_events.push((pid, fd, op, data.to_vec()));
+        }
+    }
+    pub fn capture_exit(&mut self, pid: &ProcID, code: i8) {
+        if !self.capturing() {
+            return;
+        }
+        let pid = pid.clone().into();
+        if self.capture.process(pid).is_none() {
+            self.capture.processes.push(CapturedProcess {
+                pid,
+                ..Default::default()
+            });
+        }
+        let process = self.capture.processes.iter_mut().find(|p| p.pid == pid);
+        process.expect("captured process").exit_code = Some(code);
+    }
+    pub fn capture(&self) -> Capture {
+        let events = self
+            .capture_events
+            .iter()
+            .map(|(pid, fd, op, data)| PipeEvent {
+                pid: *pid,
+                fd: *fd,
+                op: op.clone(),
+                data: JsonBytes::from_vec(data.clone()),
+            });
+        Capture {
+            events: events.collect(),
+            ..self.capture.clone()
+        }
+    }
+    /// Writes the capture to the file of the setup, if any.
+    pub fn save_capture(&self) -> Result<(), CaptureError> {
+        match &self.setup().pipe_capture {
+            Some(path) => self.capture().save(path),
+            None => Ok(()),
+        }
+    }
+
     pub fn update_ctx_id(id: SimID, pid: Option<ProcID>) {
         TX_CONTEXT_ID.with(|f| *f.borrow_mut() = id);
         if let Some(pid) = pid {
@@ -184,6 +355,7 @@ impl SimContext {
     pub fn start_process<F: Send + 'static + FnOnce(SimID, ProcID) -> i8>(
         &mut self,
         fds: &[Fd],
+        script: String,
         func: F,
     ) -> ProcID {
         let parent_id = ProcInfo::id();
@@ -191,11 +363,19 @@ impl SimContext {
         let process = ProcInfo {
             parent_id: parent_id.clone(),
             inherited_fds: fds.to_vec(),
+            script: Some(script),
             ..Default::default()
         };
 
         self.processes.insert(id.clone(), process);
         let ctx_id = SimContext::ctx_id();
+        if self.capturing() {
+            self.capture.processes.push(CapturedProcess {
+                pid: id.clone().into(),
+                inherited_fds: fds.iter().map(|fd| fd.clone().into()).collect(),
+                exit_code: None,
+            });
+        }
 
         fds.iter().all(|fd| {
             self.move_pipe(fd, id.clone());
@@ -204,13 +384,15 @@ impl SimContext {
         self.process_status
             .push(ProcStatus::WaitSpawn(id.clone(), false));
 
+        let crash_handler = self.setup().crash_handler;
         let id2 = id.clone();
         let join_handle = std::thread::spawn(move || {
             SimContext::update_ctx_id(ctx_id.clone(), Some(id.clone()));
-            let code = func(ctx_id.clone(), id.clone());
+            let code = crash::run_protected(crash_handler, || func(ctx_id.clone(), id.clone()));
 
             let mut gd = GlobalData::locked();
             let cur_sim = gd.get_tx_mut(&SimContext::ctx_id());
+            cur_sim.capture_exit(&id, code);
             cur_sim.close_all(&id);
             cur_sim.process_io(None);
 
@@ -224,6 +406,12 @@ impl SimContext {
     pub fn pid() -> ProcID {
         ProcInfo::id()
     }
+    pub fn set_script(&mut self, id: &ProcID, script: String) {
+        self.process_mut(id).script = Some(script);
+    }
+    pub fn script(&self, id: &ProcID) -> Option<String> {
+        self.processes.get(id).and_then(|p| p.script.clone())
+    }
     pub fn inherited_fds(&self) -> Vec<Fd> {
         let process = self.process(&ProcInfo::id());
         process.inherited_fds.clone()
@@ -294,18 +482,23 @@ impl SimContext {
                     .expect("unknow error");
 
                 // Update Read Status
-                let (_, _rfd, rlen, rbuf) = self.process_status[*r_pos]
+                let (rpid, rfd, rlen, rbuf) = self.process_status[*r_pos]
                     .read_wait_mut()
                     .expect("Unknow error");
                 let copy_len = (*rlen).min(wbuf.len());
                 rbuf.extend_from_slice(&wbuf[..copy_len]);
                 *rlen -= copy_len;
+                let (rpid, rfd) = (rpid.clone(), rfd.clone());
 
                 // Update Write Status
-                let (_, _wfd, wbuf) = self.process_status[*w_pos]
+                let (wpid, wfd, wbuf) = self.process_status[*w_pos]
                     .write_wait_mut()
                     .expect("unknow error");
-                *wbuf = wbuf[copy_len..].to_vec();
+                let (wpid, wfd) = (wpid.clone(), wfd.clone());
+                let transferred = wbuf.drain(..copy_len).collect::<Vec<_>>();
+
+                self.capture_event(&wpid, &wfd, PipeOp::Write, &transferred);
+                self.capture_event(&rpid, &rfd, PipeOp::Read, &transferred);
             }
         });
 
@@ -384,10 +577,9 @@ impl SimContext {
                     let pid = self.fds.get(fd).expect("unknow error");
                     self.process(pid).scheduler_event.notify();
                 }
+                // Closing never blocks on chain, the closer goes on.
                 ProcStatus::CloseWait(pid, _) => {
-                    self.process(&self.process(pid).parent_id)
-                        .scheduler_event
-                        .notify();
+                    self.process(pid).scheduler_event.notify();
                 }
                 ProcStatus::Terminated(pid) => {
                     self.process(&self.process(pid).parent_id)
@@ -452,6 +644,13 @@ impl SimContext {
         self.fds.insert(fds.0.clone(), pid.clone());
         self.fds.insert(fds.1.clone(), pid.clone());
         self.fd_count = fds.2;
+        if self.capturing() {
+            self.capture.pipes.push(CapturedPipe {
+                pid: pid.into(),
+                read_fd: fds.0.clone().into(),
+                write_fd: fds.1.clone().into(),
+            });
+        }
 
         (fds.0, fds.1)
     }
@@ -459,6 +658,7 @@ impl SimContext {
         if !self.has_fd(&fd) {
             Err(())
         } else {
+            self.capture_event(&ProcInfo::id(), &fd, PipeOp::Close, &[]);
             self.process_status
                 .push(ProcStatus::CloseWait(ProcInfo::id(), fd.clone()));
             self.process_io(Some(&fd));
@@ -506,3 +706,49 @@ impl SimContext {
         self.fds.contains_key(&fd.other_fd())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ckb_pipe, ckb_spawn_cell, ckb_wait, constants::CKB_SUCCESS, test_utils, RunningSetup,
+        Simulation,
+    };
+
+    #[test]
+    fn child_closes_fd_while_parent_waits() {
+        let code_hash = [1u8; 32];
+        let child = test_utils::temp_file(
+            "close_child.json",
+            br#"{ "steps": [{ "close": { "fd": 0 } }], "exit_code": 3 }"#,
+        );
+        let setup = RunningSetup {
+            native_binaries: [(test_utils::cell_key(&code_hash, 1), child)].into(),
+            ..Default::default()
+        };
+        let sim = Simulation::new(Default::default(), setup);
+
+        let code = test_utils::with_timeout(move || {
+            sim.run_root("parent".to_string(), move |_, _| {
+                let mut fds = [0u64; 2];
+                assert_eq!(ckb_pipe(fds.as_mut_ptr()), CKB_SUCCESS);
+                let inherited_fds = [fds[1], 0];
+                let mut pid = 0;
+                let ret = ckb_spawn_cell(
+                    code_hash.as_ptr(),
+                    1,
+                    0,
+                    0,
+                    0,
+                    std::ptr::null(),
+                    inherited_fds.as_ptr(),
+                    &mut pid,
+                );
+                assert_eq!(ret, CKB_SUCCESS);
+                let mut code = 0;
+                assert_eq!(ckb_wait(pid, &mut code), CKB_SUCCESS);
+                code
+            })
+        });
+        assert_eq!(code, 3);
+    }
+}
@@ -0,0 +1,190 @@
+//! Checks inputs' `since` like ckb's `SinceVerifier`, against a tip header
+//! instead of the chain: absolute locks compare with the tip, relative ones
+//! with the header committing the input, looked up in header deps.
+//!
+//! The tip is `tip_header` of the running setup, or the latest header dep.
+//! Its timestamp stands for the median time of past blocks.
+use crate::RunningSetup;
+use ckb_mock_tx_types::MockTransaction;
+use ckb_types::{
+    core::{error::TransactionError, EpochNumberWithFraction, HeaderView},
+    prelude::*,
+};
+
+pub const SINCE_RELATIVE_FLAG: u64 = 0x8000_0000_0000_0000;
+pub const SINCE_METRIC_MASK: u64 = 0x6000_0000_0000_0000;
+pub const SINCE_BLOCK_NUMBER_FLAG: u64 = 0x0000_0000_0000_0000;
+pub const SINCE_EPOCH_FLAG: u64 = 0x2000_0000_0000_0000;
+pub const SINCE_TIMESTAMP_FLAG: u64 = 0x4000_0000_0000_0000;
+pub const SINCE_VALUE_MASK: u64 = 0x00ff_ffff_ffff_ffff;
+const SINCE_REMAIN_FLAGS_MASK: u64 = 0x1f00_0000_0000_0000;
+
+/// The header since is checked at.
+pub fn tip_header(tx: &MockTransaction, setup: &RunningSetup) -> HeaderView {
+    match &setup.tip_header {
+        Some(header) => header.clone().into(),
+        None => tx
+            .mock_info
+            .header_deps
+            .iter()
+            .max_by_key(|h| h.number())
+            .cloned()
+            .unwrap_or_else(|| crate::headers::MockHeader::default().build()),
+    }
+}
+
+/// Returns whether `since` is satisfied from a base block, the tip for
+/// absolute locks or the input's block for relative ones.
+fn satisfied(
+    index: usize,
+    since: u64,
+    base: &HeaderView,
+    tip: &HeaderView,
+) -> Result<bool, TransactionError> {
+    let value = since & SINCE_VALUE_MASK;
+    let relative = since & SINCE_RELATIVE_FLAG != 0;
+    match since & SINCE_METRIC_MASK {
+        SINCE_BLOCK_NUMBER_FLAG => Ok(if relative {
+            base.number().saturating_add(value) <= tip.number()
+        } else {
+            value <= tip.number()
+        }),
+        SINCE_EPOCH_FLAG => {
+            let epoch = EpochNumberWithFraction::from_full_value(value);
+            if !epoch.is_well_formed_increment() {
+                return Err(TransactionError::InvalidSince { index });
+            }
+            let required = epoch.normalize().to_rational();
+            Ok(if relative {
+                base.epoch().to_rational() + required <= tip.epoch().to_rational()
+            } else {
+                required <= tip.epoch().to_rational()
+            })
+        }
+        SINCE_TIMESTAMP_FLAG => Ok(if relative {
+            base.timestamp().saturating_add(value) <= tip.timestamp()
+        } else {
+            value <= tip.timestamp()
+        }),
+        _ => Err(TransactionError::InvalidSince { index }),
+    }
+}
+
+/// Checks the since of every input, reporting the first violation.
+pub fn verify_since(tx: &MockTransaction, tip: &HeaderView) -> Result<(), TransactionError> {
+    for (index, input) in tx.tx.raw().inputs().into_iter().enumerate() {
+        let since: u64 = input.since().unpack();
+        if since == 0 {
+            continue;
+        }
+        if since & SINCE_REMAIN_FLAGS_MASK != 0 {
+            return Err(TransactionError::InvalidSince { index });
+        }
+        let base = if since & SINCE_RELATIVE_FLAG != 0 {
+            // Like ckb, an input without a known block is immature.
+            let header = tx
+                .mock_info
+                .inputs
+                .get(index)
+                .and_then(|i| i.header.as_ref())
+                .and_then(|hash| tx.mock_info.header_deps.iter().find(|h| &h.hash() == hash));
+            match header {
+                Some(header) => header.clone(),
+                None => return Err(TransactionError::Immature { index }),
+            }
+        } else {
+            tip.clone()
+        };
+        if !satisfied(index, since, &base, tip)? {
+            return Err(TransactionError::Immature { index });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{headers::MockHeader, test_utils};
+    use ckb_types::packed::CellInput;
+
+    /// `sample_tx` whose input, committed in block 5 at epoch 0 5/1000 and
+    /// timestamp 0, has `since`.
+    fn since_tx(since: u64) -> MockTransaction {
+        let mut tx = test_utils::sample_tx();
+        let input = tx.mock_info.inputs[0].input.clone();
+        let input = CellInput::new(input.previous_output(), since);
+        tx.mock_info.inputs[0].input = input.clone();
+        let raw = tx.tx.raw().as_builder().inputs(vec![input].pack()).build();
+        tx.tx = tx.tx.as_builder().raw(raw).build();
+        tx
+    }
+
+    fn tip(number: u64, timestamp: u64) -> HeaderView {
+        MockHeader {
+            number,
+            epoch: EpochNumberWithFraction::new(0, number, 1000),
+            timestamp,
+            ..Default::default()
+        }
+        .build()
+    }
+
+    fn check(since: u64, tip: &HeaderView) -> Result<(), TransactionError> {
+        verify_since(&since_tx(since), tip)
+    }
+
+    #[test]
+    fn absolute_since_compares_with_the_tip() {
+        let tip = tip(10, 20_000);
+        let immature = Err(TransactionError::Immature { index: 0 });
+        assert_eq!(check(SINCE_BLOCK_NUMBER_FLAG | 10, &tip), Ok(()));
+        assert_eq!(check(SINCE_BLOCK_NUMBER_FLAG | 11, &tip), immature);
+        assert_eq!(check(SINCE_TIMESTAMP_FLAG | 20_000, &tip), Ok(()));
+        assert_eq!(check(SINCE_TIMESTAMP_FLAG | 20_001, &tip), immature);
+        let epoch = |index| EpochNumberWithFraction::new(0, index, 1000).full_value();
+        assert_eq!(check(SINCE_EPOCH_FLAG | epoch(10), &tip), Ok(()));
+        assert_eq!(check(SINCE_EPOCH_FLAG | epoch(11), &tip), immature);
+    }
+
+    #[test]
+    fn relative_since_counts_from_the_input_block() {
+        let tip = tip(10, 20_000);
+        let immature = Err(TransactionError::Immature { index: 0 });
+        let relative = |since| SINCE_RELATIVE_FLAG | since;
+        assert_eq!(check(relative(SINCE_BLOCK_NUMBER_FLAG | 5), &tip), Ok(()));
+        assert_eq!(check(relative(SINCE_BLOCK_NUMBER_FLAG | 6), &tip), immature);
+        assert_eq!(check(relative(SINCE_TIMESTAMP_FLAG | 20_000), &tip), Ok(()));
+
+        let mut tx = since_tx(relative(SINCE_BLOCK_NUMBER_FLAG | 1));
+        tx.mock_info.header_deps.clear();
+        assert_eq!(
+            verify_since(&tx, &tip),
+            immature,
+            "the input block is unknown"
+        );
+    }
+
+    #[test]
+    fn malformed_since_is_invalid() {
+        let tip = tip(10, 0);
+        let invalid = Err(TransactionError::InvalidSince { index: 0 });
+        assert_eq!(check(0x0100_0000_0000_0000 | 1, &tip), invalid);
+        assert_eq!(check(SINCE_METRIC_MASK | 1, &tip), invalid);
+        // An index past the epoch length.
+        let epoch = (1000 << 40) | (1001 << 24);
+        assert_eq!(check(SINCE_EPOCH_FLAG | epoch, &tip), invalid);
+    }
+
+    #[test]
+    fn tip_defaults_to_the_latest_header_dep() {
+        let tx = since_tx(0);
+        let setup = RunningSetup::default();
+        assert_eq!(tip_header(&tx, &setup).number(), 5);
+        let setup = RunningSetup {
+            tip_header: Some(tip(42, 0).into()),
+            ..Default::default()
+        };
+        assert_eq!(tip_header(&tx, &setup).number(), 42);
+    }
+}
@@ -1,11 +1,15 @@
 use crate::{
     constants::{
-        CKB_INVALID_FD, CKB_MAX_FDS_CREATED, CKB_MAX_VMS_SPAWNED, CKB_OTHER_END_CLOSED,
-        CKB_SUCCESS, CKB_WAIT_FAILURE,
+        CKB_INVALID_FD, CKB_ITEM_MISSING, CKB_MAX_FDS_CREATED, CKB_MAX_VMS_SPAWNED,
+        CKB_OTHER_END_CLOSED, CKB_SUCCESS, CKB_WAIT_FAILURE,
     },
     get_cur_tx, get_cur_tx_mut,
     global_data::GlobalData,
+    load_syscall,
+    mock_child::{self, MockChild},
     simulator_context::SimContext,
+    syscall_args, syscalls,
+    syscalls::SyscallResult,
     utils,
     utils::{Fd, ProcID},
 };
@@ -36,6 +40,55 @@ pub extern "C" fn ckb_spawn_cell(
     argv: *const *const u8,
     inherited_fds: *const u64,
     pid: *mut u64,
+) -> c_int {
+    let args = syscall_args!(hash_type, offset, length, argc);
+    syscalls::handle("ckb_spawn_cell", &args, |_| {
+        let code_hash = utils::to_array(code_hash, 32);
+        let sim_path = utils::get_simulator_path(code_hash, hash_type, offset, length)
+            .unwrap_or_else(|| {
+                let key = utils::simulator_key(code_hash, hash_type, offset, length);
+                crate::missing_native_binary(format!("ckb_spawn_cell of {}", key))
+            });
+        spawn_native(sim_path, argc, argv as *const *const i8, inherited_fds, pid)
+    })
+    .ret as c_int
+}
+
+/// Spawns the code `place` and `bounds` select in the cell at `index` and
+/// `source`, see `locate_code`.
+#[no_mangle]
+pub extern "C" fn ckb_spawn(
+    index: u64,
+    source: u64,
+    place: u64,
+    bounds: u64,
+    spawn_args: *const SpawnArgs,
+) -> c_int {
+    let args = syscall_args!(index, source, place, bounds);
+    syscalls::handle("ckb_spawn", &args, |_| {
+        let spawn_args = unsafe { &*({ spawn_args }) };
+        match crate::locate_code(index, source, place, bounds) {
+            Ok(sim_path) => spawn_native(
+                sim_path,
+                spawn_args.argc as i32,
+                spawn_args.argv,
+                spawn_args.inherited_fds,
+                spawn_args.process_id,
+            ),
+            Err(code) => code,
+        }
+    })
+    .ret as c_int
+}
+
+/// Starts the native binary at `sim_path` as a child process, or the mock
+/// child for `.json` paths.
+fn spawn_native(
+    sim_path: String,
+    argc: i32,
+    argv: *const *const i8,
+    inherited_fds: *const u64,
+    pid: *mut u64,
 ) -> c_int {
     // check fd:
     let inherited_fds = get_fds(inherited_fds);
@@ -48,12 +101,27 @@ pub extern "C" fn ckb_spawn_cell(
         return CKB_MAX_VMS_SPAWNED;
     }
 
-    let ckb_sim = utils::CkbNativeSimulator::new_by_hash(code_hash, hash_type, offset, length);
-    let args = utils::to_vec_args(argc, argv as *const *const i8);
-    let new_id = get_cur_tx_mut!().start_process(&inherited_fds, move |sim_id, pid| {
-        ckb_sim.update_script_info(sim_id, pid);
-        ckb_sim.ckb_std_main(args)
-    });
+    let args = utils::to_vec_args(argc, argv);
+    let new_id = if sim_path.ends_with(".json") {
+        let child = match MockChild::load(&sim_path) {
+            Ok(child) => child,
+            Err(e) => {
+                mock_child::fail_run(&e.to_string());
+                return CKB_ITEM_MISSING;
+            }
+        };
+        let name = sim_path.clone();
+        get_cur_tx_mut!().start_process(&inherited_fds, sim_path, move |_, _| {
+            child.run_spawned(&name)
+        })
+    } else {
+        let ckb_sim = utils::CkbNativeSimulator::new(&sim_path.into());
+        let script = ckb_sim.path();
+        get_cur_tx_mut!().start_process(&inherited_fds, script, move |sim_id, pid| {
+            ckb_sim.update_script_info(sim_id, pid);
+            ckb_sim.ckb_std_main(args)
+        })
+    };
 
     let event = get_cur_tx!().get_event();
     event.wait();
@@ -64,112 +132,170 @@ pub extern "C" fn ckb_spawn_cell(
 
 #[no_mangle]
 pub extern "C" fn ckb_wait(pid: u64, code: *mut i8) -> c_int {
-    let pid: ProcID = pid.into();
-    if !get_cur_tx!().has_proc(&pid) {
-        return CKB_WAIT_FAILURE;
-    }
-    let join_handle = get_cur_tx_mut!().exit(&pid);
+    syscalls::handle("ckb_wait", &syscall_args!(pid), |_| {
+        let pid: ProcID = pid.into();
+        if !get_cur_tx!().has_proc(&pid) {
+            return CKB_WAIT_FAILURE;
+        }
+        let join_handle = get_cur_tx_mut!().exit(&pid);
 
-    let c = if let Some(j) = join_handle {
-        j.join().unwrap()
-    } else {
-        return CKB_WAIT_FAILURE;
-    };
-    unsafe { *({ code }) = c };
-    CKB_SUCCESS
+        let c = if let Some(j) = join_handle {
+            j.join().unwrap()
+        } else {
+            return CKB_WAIT_FAILURE;
+        };
+        unsafe { *({ code }) = c };
+        CKB_SUCCESS
+    })
+    .ret as c_int
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_process_id() -> u64 {
-    SimContext::pid().into()
+    syscalls::handle("ckb_process_id", &[], |_| SyscallResult {
+        ret: u64::from(SimContext::pid()) as i64,
+        data: None,
+    })
+    .ret as u64
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_pipe(fds: *mut u64) -> c_int {
-    if get_cur_tx!().len_pipe() >= MAX_FDS {
-        return CKB_MAX_FDS_CREATED;
-    }
+    syscalls::handle("ckb_pipe", &[], |_| {
+        if get_cur_tx!().len_pipe() >= MAX_FDS {
+            return CKB_MAX_FDS_CREATED;
+        }
 
-    let out = get_cur_tx_mut!().new_pipe();
-    copy_fds(&[out.0, out.1], fds);
-    CKB_SUCCESS
+        let out = get_cur_tx_mut!().new_pipe();
+        copy_fds(&[out.0, out.1], fds);
+        CKB_SUCCESS
+    })
+    .ret as c_int
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_read(fd: u64, buf: *mut c_void, length: *mut usize) -> c_int {
-    let fd: Fd = fd.into();
+    let requested = utils::to_usize(length);
+    let args = {
+        let length = requested;
+        syscall_args!(fd, length)
+    };
+    let result = syscalls::handle("ckb_read", &args, |fault| {
+        let fd: Fd = fd.into();
 
-    // Check
-    if let Err(e) = CheckSpawn::Read.check(&fd) {
-        return e;
-    }
+        // Check
+        if let Err(e) = CheckSpawn::Read.check(&fd) {
+            return e.into();
+        }
 
-    // wait read
-    let event = get_cur_tx_mut!().wait_read(fd.clone(), unsafe { *({ length }) });
-    event.wait();
+        let mut read_length = requested;
+        if let Some(max) = fault.as_ref().and_then(|f| f.length) {
+            read_length = read_length.min(max as usize);
+        }
+        let data = match fault.and_then(|f| f.data) {
+            // Served instead of the pipe, which is left untouched.
+            Some(data) => {
+                let data = data.into_bytes();
+                data.slice(..data.len().min(read_length))
+            }
+            None => {
+                // wait read
+                let event = get_cur_tx_mut!().wait_read(fd.clone(), read_length);
+                event.wait();
 
-    let data = get_cur_tx_mut!().read_cache(&fd);
+                get_cur_tx_mut!().read_cache(&fd).into()
+            }
+        };
+        SyscallResult::data(data)
+    });
 
-    if !data.is_empty() {
-        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), buf as *mut u8, data.len()) };
-    }
-    unsafe {
-        *({ length }) = data.len();
+    if let Some(data) = &result.data {
+        let data = &data[..data.len().min(requested)];
+        if !data.is_empty() {
+            unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), buf as *mut u8, data.len()) };
+        }
+        unsafe {
+            *({ length }) = data.len();
+        }
     }
 
-    CKB_SUCCESS
+    result.ret as c_int
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_write(fd: u64, buf: *const c_void, length: *mut usize) -> c_int {
-    let fd: Fd = fd.into();
-
-    if let Err(e) = CheckSpawn::Write.check(&fd) {
-        return e;
-    }
-
-    let buf = unsafe {
+    let args = {
         let length = utils::to_usize(length);
-        std::slice::from_raw_parts(buf as *const u8, length)
-    }
-    .to_vec();
-    let event = get_cur_tx_mut!().wait_write(fd, &buf);
-    event.wait();
+        syscall_args!(fd, length)
+    };
+    syscalls::handle("ckb_write", &args, |fault| {
+        let fd: Fd = fd.into();
 
-    CKB_SUCCESS
+        if let Err(e) = CheckSpawn::Write.check(&fd) {
+            return e;
+        }
+
+        // A short write reports the bytes written, like ckb does.
+        if let Some(max) = fault.and_then(|f| f.length) {
+            unsafe { *({ length }) = utils::to_usize(length).min(max as usize) };
+        }
+        let buf = unsafe {
+            let length = utils::to_usize(length);
+            std::slice::from_raw_parts(buf as *const u8, length)
+        }
+        .to_vec();
+        let event = get_cur_tx_mut!().wait_write(fd.clone(), &buf);
+        event.wait();
+        CKB_SUCCESS
+    })
+    .ret as c_int
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_inherited_fds(fds: *mut u64, length: *mut usize) -> c_int {
-    let out_fds = get_cur_tx!().inherited_fds();
-    let len = out_fds.len().min(utils::to_usize(length));
+    syscalls::handle("ckb_inherited_fds", &[], |_| {
+        let out_fds = get_cur_tx!().inherited_fds();
+        let len = out_fds.len().min(utils::to_usize(length));
 
-    copy_fds(&out_fds[0..len], fds);
-    unsafe { *({ length }) = len };
-    CKB_SUCCESS
+        copy_fds(&out_fds[0..len], fds);
+        unsafe { *({ length }) = len };
+        CKB_SUCCESS
+    })
+    .ret as c_int
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_close(fd: u64) -> c_int {
-    let fd = fd.into();
-    let event = get_cur_tx_mut!().close_pipe(fd);
-    if let Ok(event) = event {
-        event.wait();
-        CKB_SUCCESS
-    } else {
-        CKB_INVALID_FD
-    }
+    syscalls::handle("ckb_close", &syscall_args!(fd), |_| {
+        let event = get_cur_tx_mut!().close_pipe(fd.into());
+        if let Ok(event) = event {
+            event.wait();
+            CKB_SUCCESS
+        } else {
+            CKB_INVALID_FD
+        }
+    })
+    .ret as c_int
 }
 
 #[no_mangle]
 pub extern "C" fn ckb_load_block_extension(
-    _addr: *mut c_void,
-    _len: *mut u64,
-    _offset: usize,
-    _index: usize,
-    _source: usize,
+    addr: *mut c_void,
+    len: *mut u64,
+    offset: usize,
+    index: usize,
+    source: usize,
 ) -> c_int {
-    panic!("unsupport");
+    // Only a syscall handler can serve block extensions.
+    let args = syscall_args!(index, source, offset);
+    load_syscall(
+        "ckb_load_block_extension",
+        &args,
+        addr,
+        len,
+        offset as u64,
+        || panic!("unsupport"),
+    )
 }
 
 fn copy_fds(in_fd: &[Fd], out_fd: *mut u64) {
@@ -0,0 +1,425 @@
+//! Syscall interception: a `SyscallHandler` registered on a simulation is
+//! consulted by every exported syscall before its default, mock transaction
+//! backed implementation, and sees every result.
+//!
+//! `ckb_syscall` dispatches raw syscalls by number to the exported ones, and
+//! to custom syscalls registered with `Simulation::register_syscall`. Other
+//! numbers return `SIMULATOR_UNSUPPORTED_SYSCALL`.
+//!
+//! ```ignore
+//! struct ExtraCellDep(Bytes);
+//! impl SyscallHandler for ExtraCellDep {
+//!     fn intercept(&self, call: &SyscallCall) -> Interception {
+//!         if call.name == "ckb_load_cell_data" && call.arg("index") == Some(9) {
+//!             return Interception::Override(SyscallResult::data(self.0.clone()));
+//!         }
+//!         Interception::PassThrough
+//!     }
+//! }
+//! simulation.set_syscall_handler(ExtraCellDep(data));
+//! ```
+use crate::{
+    constants::{
+        SIMULATOR_UNSUPPORTED_SYSCALL, SYS_CLOSE, SYS_CURRENT_CYCLES, SYS_DEBUG, SYS_EXEC,
+        SYS_EXIT, SYS_INHERITED_FDS, SYS_LOAD_BLOCK_EXTENSION, SYS_LOAD_CELL,
+        SYS_LOAD_CELL_BY_FIELD, SYS_LOAD_CELL_DATA, SYS_LOAD_CELL_DATA_AS_CODE, SYS_LOAD_HEADER,
+        SYS_LOAD_HEADER_BY_FIELD, SYS_LOAD_INPUT, SYS_LOAD_INPUT_BY_FIELD, SYS_LOAD_SCRIPT,
+        SYS_LOAD_SCRIPT_HASH, SYS_LOAD_TRANSACTION, SYS_LOAD_TX_HASH, SYS_LOAD_WITNESS, SYS_PIPE,
+        SYS_PROCESS_ID, SYS_READ, SYS_SPAWN, SYS_VM_VERSION, SYS_WAIT, SYS_WRITE,
+    },
+    faults::Fault,
+    get_cur_tx,
+    global_data::GlobalData,
+    spawn, SimContext,
+};
+use ckb_types::bytes::Bytes;
+use std::{
+    os::raw::{c_char, c_int, c_void},
+    sync::Arc,
+};
+
+/// A syscall as the script made it, with its arguments named like in
+/// `faults::Fault::args`.
+#[derive(Clone, Debug)]
+pub struct SyscallCall<'a> {
+    pub name: &'a str,
+    pub args: &'a [(&'a str, u64)],
+    pub pid: u64,
+}
+impl SyscallCall<'_> {
+    pub fn arg(&self, name: &str) -> Option<u64> {
+        self.args.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+    }
+}
+
+/// What a syscall returns to the script.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyscallResult {
+    /// The return code, or the value of `ckb_vm_version`,
+    /// `ckb_current_cycles` and `ckb_process_id`.
+    pub ret: i64,
+    /// The whole data of load syscalls, the bytes `ckb_read` returns.
+    /// Ignored by other syscalls.
+    pub data: Option<Bytes>,
+}
+impl SyscallResult {
+    /// A successful load or read of `data`.
+    pub fn data(data: Bytes) -> Self {
+        Self {
+            ret: 0,
+            data: Some(data),
+        }
+    }
+}
+impl From<c_int> for SyscallResult {
+    fn from(code: c_int) -> Self {
+        Self {
+            ret: code as i64,
+            data: None,
+        }
+    }
+}
+
+pub enum Interception {
+    /// Runs the default implementation.
+    PassThrough,
+    /// Returns this instead of running the default implementation.
+    Override(SyscallResult),
+}
+
+pub trait SyscallHandler: Send + Sync {
+    /// Called before the default implementation.
+    fn intercept(&self, _call: &SyscallCall) -> Interception {
+        Interception::PassThrough
+    }
+
+    /// Called with the result the script gets, overridden or not.
+    fn observe(&self, _call: &SyscallCall, _result: &SyscallResult) {}
+}
+
+/// Runs a syscall: the handler of the current simulation first, then the
+/// faults of the setup, then `default` with the fault altering its result.
+pub(crate) fn handle<R: Into<SyscallResult>>(
+    name: &str,
+    args: &[(&str, u64)],
+    default: impl FnOnce(Option<Fault>) -> R,
+) -> SyscallResult {
+    let handler = get_cur_tx!().syscall_handler();
+    let call = SyscallCall {
+        name,
+        args,
+        pid: SimContext::pid().into(),
+    };
+    let intercepted = match &handler {
+        Some(handler) => handler.intercept(&call),
+        None => Interception::PassThrough,
+    };
+    let result = match intercepted {
+        Interception::Override(result) => result,
+        Interception::PassThrough => match crate::faults::inject(name, args) {
+            Ok(fault) => default(fault).into(),
+            Err(code) => code.into(),
+        },
+    };
+    if let Some(handler) = &handler {
+        handler.observe(&call, &result);
+    }
+    result
+}
+
+/// Names syscall arguments after their variables, for `SyscallCall::args`.
+#[macro_export]
+macro_rules! syscall_args {
+    ($($arg:ident),*) => {
+        [$((stringify!($arg), $arg as u64)),*]
+    };
+}
+
+/// A custom syscall registered on a simulation, given `a0` to `a5` and
+/// returning `a0`.
+pub type CustomSyscall = Arc<dyn Fn([u64; 6]) -> i64 + Send + Sync>;
+
+/// Raw syscalls by number, like the `ecall` of CKB-VM. Numbers registered
+/// with `Simulation::register_syscall` take precedence over the `SYS_*`
+/// ones. Unknown numbers and `SYS_LOAD_CELL_DATA_AS_CODE` go through the
+/// syscall handler as `ckb_syscall` and return
+/// `SIMULATOR_UNSUPPORTED_SYSCALL` unless it overrides them.
+#[no_mangle]
+pub extern "C" fn ckb_syscall(n: u64, a0: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64) -> i64 {
+    let args = syscall_args!(n, a0, a1, a2, a3, a4, a5);
+    let custom = get_cur_tx!().custom_syscall(n);
+    if let Some(syscall) = custom {
+        return handle("ckb_syscall", &args, |_| SyscallResult {
+            ret: syscall([a0, a1, a2, a3, a4, a5]),
+            data: None,
+        })
+        .ret;
+    }
+
+    let ptr = a0 as *mut c_void;
+    let len = a1 as *mut u64;
+    match n {
+        SYS_EXIT => crate::ckb_exit(a0 as i8) as i64,
+        SYS_VM_VERSION => crate::ckb_vm_version() as i64,
+        SYS_CURRENT_CYCLES => crate::ckb_current_cycles() as i64,
+        SYS_LOAD_TRANSACTION => crate::ckb_load_transaction(ptr, len, a2) as i64,
+        SYS_LOAD_SCRIPT => crate::ckb_load_script(ptr, len, a2) as i64,
+        SYS_LOAD_TX_HASH => crate::ckb_load_tx_hash(ptr, len, a2) as i64,
+        SYS_LOAD_SCRIPT_HASH => crate::ckb_load_script_hash(ptr, len, a2) as i64,
+        SYS_LOAD_CELL => crate::ckb_load_cell(ptr, len, a2, a3, a4) as i64,
+        SYS_LOAD_HEADER => crate::ckb_load_header(ptr, len, a2, a3, a4) as i64,
+        SYS_LOAD_INPUT => crate::ckb_load_input(ptr, len, a2, a3, a4) as i64,
+        SYS_LOAD_WITNESS => crate::ckb_load_witness(ptr, len, a2, a3, a4) as i64,
+        SYS_LOAD_CELL_BY_FIELD => crate::ckb_load_cell_by_field(ptr, len, a2, a3, a4, a5) as i64,
+        SYS_LOAD_HEADER_BY_FIELD => {
+            crate::ckb_load_header_by_field(ptr, len, a2, a3, a4, a5) as i64
+        }
+        SYS_LOAD_INPUT_BY_FIELD => crate::ckb_load_input_by_field(ptr, len, a2, a3, a4, a5) as i64,
+        SYS_LOAD_CELL_DATA => crate::ckb_load_cell_data(ptr, len, a2, a3, a4) as i64,
+        SYS_LOAD_BLOCK_EXTENSION => {
+            spawn::ckb_load_block_extension(ptr, len, a2 as usize, a3 as usize, a4 as usize) as i64
+        }
+        SYS_DEBUG => {
+            crate::ckb_debug(a0 as *const c_char);
+            0
+        }
+        SYS_EXEC => crate::ckb_exec(a0, a1, a2, a3, a4 as i32, a5 as *const *const u8) as i64,
+        SYS_SPAWN => spawn::ckb_spawn(a0, a1, a2, a3, a4 as *const spawn::SpawnArgs) as i64,
+        SYS_WAIT => spawn::ckb_wait(a0, a1 as *mut i8) as i64,
+        SYS_PROCESS_ID => spawn::ckb_process_id() as i64,
+        SYS_PIPE => spawn::ckb_pipe(a0 as *mut u64) as i64,
+        SYS_WRITE => spawn::ckb_write(a0, a1 as *const c_void, a2 as *mut usize) as i64,
+        SYS_READ => spawn::ckb_read(a0, a1 as *mut c_void, a2 as *mut usize) as i64,
+        SYS_INHERITED_FDS => spawn::ckb_inherited_fds(a0 as *mut u64, a1 as *mut usize) as i64,
+        SYS_CLOSE => spawn::ckb_close(a0) as i64,
+        // Native code cannot be loaded from cell data, use ckb_dlopen2.
+        SYS_LOAD_CELL_DATA_AS_CODE => {
+            handle("ckb_syscall", &args, |_| SIMULATOR_UNSUPPORTED_SYSCALL).ret
+        }
+        _ => handle("ckb_syscall", &args, |_| SIMULATOR_UNSUPPORTED_SYSCALL).ret,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ckb_load_witness, ckb_vm_version,
+        constants::{CKB_SUCCESS, SOURCE_INPUT},
+        test_utils, RunningSetup, Simulation,
+    };
+    use std::os::raw::c_char;
+    use std::sync::Mutex;
+
+    /// Name, arguments, pid and result of an observed call.
+    type Observed = (String, Vec<(String, u64)>, u64, SyscallResult);
+
+    /// Overrides `ckb_load_witness` at `index` with `data`, records every
+    /// call and result.
+    #[derive(Default)]
+    struct Recorder {
+        index: u64,
+        data: Bytes,
+        calls: Mutex<Vec<Observed>>,
+    }
+    impl SyscallHandler for Arc<Recorder> {
+        fn intercept(&self, call: &SyscallCall) -> Interception {
+            if call.name == "ckb_load_witness" && call.arg("index") == Some(self.index) {
+                return Interception::Override(SyscallResult::data(self.data.clone()));
+            }
+            Interception::PassThrough
+        }
+
+        fn observe(&self, call: &SyscallCall, result: &SyscallResult) {
+            let args = call.args.iter().map(|(n, v)| (n.to_string(), *v)).collect();
+            self.calls.lock().unwrap().push((
+                call.name.to_string(),
+                args,
+                call.pid,
+                result.clone(),
+            ));
+        }
+    }
+
+    fn load_witness(index: u64) -> Result<Vec<u8>, c_int> {
+        let mut data = [0u8; 16];
+        let mut len = data.len() as u64;
+        match ckb_load_witness(data.as_mut_ptr().cast(), &mut len, 0, index, SOURCE_INPUT) {
+            CKB_SUCCESS => Ok(data[..len.min(16) as usize].to_vec()),
+            code => Err(code),
+        }
+    }
+
+    /// Runs `f` as the root script of `sample_tx` under `setup`, with
+    /// `recorder` as the syscall handler.
+    fn run_recorded(setup: RunningSetup, recorder: &Arc<Recorder>, f: fn() -> i8) -> i8 {
+        let sim = Simulation::new(test_utils::sample_tx(), setup);
+        sim.set_syscall_handler(recorder.clone());
+        sim.run_root("handler".to_string(), move |_, _| f())
+    }
+
+    #[test]
+    fn handlers_override_and_observe_syscalls() {
+        let recorder = Arc::new(Recorder {
+            index: 1,
+            data: Bytes::from(vec![5u8, 6]),
+            ..Default::default()
+        });
+        let setup = RunningSetup {
+            vm_version: 2,
+            ..Default::default()
+        };
+        let code = run_recorded(setup, &recorder, || {
+            assert_eq!(load_witness(0), Ok(vec![9]));
+            // Witness 1 is not in the transaction, the handler serves it.
+            assert_eq!(load_witness(1), Ok(vec![5, 6]));
+            ckb_vm_version() as i8
+        });
+        assert_eq!(code, 2);
+        let calls = recorder.calls.lock().unwrap();
+        let args = |index| {
+            vec![
+                ("index".to_string(), index),
+                ("source".to_string(), SOURCE_INPUT),
+                ("offset".to_string(), 0),
+            ]
+        };
+        assert_eq!(
+            *calls,
+            vec![
+                (
+                    "ckb_load_witness".to_string(),
+                    args(0),
+                    0,
+                    SyscallResult::data(Bytes::from(vec![9u8]))
+                ),
+                (
+                    "ckb_load_witness".to_string(),
+                    args(1),
+                    0,
+                    SyscallResult::data(Bytes::from(vec![5u8, 6]))
+                ),
+                ("ckb_vm_version".to_string(), vec![], 0, 2.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn handlers_see_faults_and_take_precedence() {
+        let recorder = Arc::new(Recorder {
+            index: 0,
+            data: Bytes::from(vec![1u8]),
+            ..Default::default()
+        });
+        let fault = |index| Fault {
+            syscall: "ckb_load_witness".to_string(),
+            args: [("index".to_string(), index)].into(),
+            return_code: Some(3),
+            ..Default::default()
+        };
+        let setup = RunningSetup {
+            faults: vec![fault(0), fault(2)],
+            ..Default::default()
+        };
+        run_recorded(setup, &recorder, || {
+            assert_eq!(load_witness(0), Ok(vec![1]));
+            assert_eq!(load_witness(2), Err(3));
+            0
+        });
+        let results: Vec<_> = recorder
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, _, _, result)| result.clone())
+            .collect();
+        assert_eq!(
+            results,
+            vec![SyscallResult::data(Bytes::from(vec![1u8])), 3.into()]
+        );
+    }
+
+    #[test]
+    fn raw_syscalls_dispatch_by_number() {
+        let recorder = Arc::new(Recorder {
+            index: u64::MAX,
+            ..Default::default()
+        });
+        run_recorded(RunningSetup::default(), &recorder, || {
+            let mut data = [0u8; 4];
+            let mut len = data.len() as u64;
+            let (ptr, len_ptr) = (data.as_mut_ptr() as u64, &mut len as *mut u64 as u64);
+            let ret = ckb_syscall(SYS_LOAD_WITNESS, ptr, len_ptr, 0, 0, SOURCE_INPUT, 0);
+            assert_eq!((ret, len, data[0]), (CKB_SUCCESS as i64, 1, 9));
+            assert_eq!(ckb_syscall(SYS_PROCESS_ID, 0, 0, 0, 0, 0, 0), 0);
+            let message = c"raw".as_ptr() as *const c_char as u64;
+            assert_eq!(ckb_syscall(SYS_DEBUG, message, 0, 0, 0, 0, 0), 0);
+            let unsupported = SIMULATOR_UNSUPPORTED_SYSCALL as i64;
+            assert_eq!(ckb_syscall(9999, 1, 2, 3, 4, 5, 6), unsupported);
+            let as_code = ckb_syscall(SYS_LOAD_CELL_DATA_AS_CODE, 0, 0, 0, 0, 0, 0);
+            assert_eq!(as_code, unsupported);
+            0
+        });
+        let calls = recorder.calls.lock().unwrap();
+        let names: Vec<_> = calls.iter().map(|(name, ..)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "ckb_load_witness",
+                "ckb_process_id",
+                "ckb_debug",
+                "ckb_syscall",
+                "ckb_syscall"
+            ]
+        );
+        let (_, args, _, result) = &calls[3];
+        assert_eq!(args[0], ("n".to_string(), 9999));
+        assert_eq!(args[6], ("a5".to_string(), 6));
+        assert_eq!(*result, SIMULATOR_UNSUPPORTED_SYSCALL.into());
+    }
+
+    #[test]
+    fn registered_syscalls_take_precedence() {
+        let sim = Simulation::new(test_utils::sample_tx(), RunningSetup::default());
+        sim.register_syscall(9000, |args| args.iter().sum::<u64>() as i64);
+        sim.register_syscall(SYS_PROCESS_ID, |_| 42);
+        let code = sim.run_root("custom".to_string(), |_, _| {
+            assert_eq!(ckb_syscall(9000, 1, 2, 3, 4, 5, 6), 21);
+            assert_eq!(ckb_syscall(SYS_PROCESS_ID, 0, 0, 0, 0, 0, 0), 42);
+            assert_eq!(
+                ckb_syscall(9001, 0, 0, 0, 0, 0, 0),
+                SIMULATOR_UNSUPPORTED_SYSCALL as i64
+            );
+            0
+        });
+        assert_eq!(code, 0);
+        // Registered on one simulation only.
+        let other = Simulation::new(test_utils::sample_tx(), RunningSetup::default());
+        other.run_root("other".to_string(), |_, _| {
+            assert_eq!(
+                ckb_syscall(9000, 0, 0, 0, 0, 0, 0),
+                SIMULATOR_UNSUPPORTED_SYSCALL as i64
+            );
+            0
+        });
+    }
+
+    #[test]
+    fn handlers_can_serve_unknown_numbers() {
+        struct Serve;
+        impl SyscallHandler for Serve {
+            fn intercept(&self, call: &SyscallCall) -> Interception {
+                match call.arg("n") {
+                    Some(9999) => Interception::Override(7.into()),
+                    _ => Interception::PassThrough,
+                }
+            }
+        }
+        let sim = Simulation::new(test_utils::sample_tx(), RunningSetup::default());
+        sim.set_syscall_handler(Serve);
+        sim.run_root("served".to_string(), |_, _| {
+            assert_eq!(ckb_syscall(9999, 0, 0, 0, 0, 0, 0), 7);
+            0
+        });
+    }
+}
@@ -0,0 +1,537 @@
+//! Native implementations of the CKB system scripts, selected by their
+//! well-known code hashes (`hash_type = type`). They follow the behavior and
+//! error codes of ckb-system-scripts and the Type ID script built into ckb.
+//!
+//! They run like any native binary: inside a simulation, reading the
+//! transaction through the exported syscalls.
+use crate::constants::{
+    CELL_FIELD_CAPACITY, CELL_FIELD_TYPE_HASH, CKB_INDEX_OUT_OF_BOUND, CKB_ITEM_MISSING,
+    CKB_SUCCESS, INPUT_FIELD_SINCE, SOURCE_GROUP_INPUT, SOURCE_GROUP_OUTPUT, SOURCE_INPUT,
+    SOURCE_OUTPUT,
+};
+use crate::since::{SINCE_EPOCH_FLAG, SINCE_METRIC_MASK, SINCE_VALUE_MASK};
+use ckb_hash::{blake2b_256, new_blake2b};
+use ckb_types::{
+    bytes::Bytes,
+    core::{EpochNumberWithFraction, ScriptHashType},
+    h256,
+    packed::{self, Script, WitnessArgs},
+    prelude::*,
+    H256,
+};
+use secp256k1::{
+    ecdsa::{RecoverableSignature, RecoveryId},
+    All, Message, Secp256k1,
+};
+use std::ffi::{c_int, c_void};
+
+pub const SIGHASH_ALL_TYPE_HASH: H256 =
+    h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8");
+pub const MULTISIG_ALL_TYPE_HASH: H256 =
+    h256!("0x5c5069eb0857efc65e1bca0c07df34c31663b3622fd3876c876320fc9634e2a8");
+pub const TYPE_ID_CODE_HASH: H256 =
+    h256!("0x00000000000000000000000000000000000000000000000000545950455f4944");
+
+pub const SIGNATURE_SIZE: usize = 65;
+pub const BLAKE160_SIZE: usize = 20;
+pub const MULTISIG_HEADER_SIZE: usize = 4;
+
+// secp256k1_blake160_sighash_all and secp256k1_blake160_multisig_all
+const ERROR_ARGUMENTS_LEN: i8 = -1;
+const ERROR_ENCODING: i8 = -2;
+const ERROR_SYSCALL: i8 = -3;
+const ERROR_SECP_RECOVER_PUBKEY: i8 = -11;
+const ERROR_SECP_PARSE_SIGNATURE: i8 = -14;
+const ERROR_WITNESS_SIZE: i8 = -22;
+const ERROR_INCORRECT_SINCE_FLAGS: i8 = -23;
+const ERROR_INCORRECT_SINCE_VALUE: i8 = -24;
+const ERROR_PUBKEY_BLAKE160_HASH: i8 = -31;
+const ERROR_INVALID_RESERVE_FIELD: i8 = -41;
+const ERROR_INVALID_PUBKEYS_CNT: i8 = -42;
+const ERROR_INVALID_THRESHOLD: i8 = -43;
+const ERROR_INVALID_REQUIRE_FIRST_N: i8 = -44;
+const ERROR_MULTSIG_SCRIPT_HASH: i8 = -51;
+const ERROR_VERIFICATION: i8 = -52;
+
+// Type ID
+const ERROR_TYPE_ID_ARGS: i8 = -1;
+const ERROR_TYPE_ID_TOO_MANY_CELLS: i8 = -2;
+const ERROR_TYPE_ID_INVALID_INPUT_HASH: i8 = -3;
+
+lazy_static! {
+    pub(crate) static ref SECP256K1: Secp256k1<All> = Secp256k1::new();
+}
+
+pub type BuiltinScript = fn() -> i8;
+
+/// Looks up the built-in implementation of a system script.
+pub fn builtin_script(script: &Script) -> Option<(&'static str, BuiltinScript)> {
+    if script.hash_type() != ScriptHashType::Type.into() {
+        return None;
+    }
+    let code_hash: H256 = script.code_hash().unpack();
+    if code_hash == SIGHASH_ALL_TYPE_HASH {
+        Some(("secp256k1_blake160_sighash_all", sighash_all))
+    } else if code_hash == MULTISIG_ALL_TYPE_HASH {
+        Some(("secp256k1_blake160_multisig_all", multisig_all))
+    } else if code_hash == TYPE_ID_CODE_HASH {
+        Some(("type_id", type_id))
+    } else if code_hash == crate::dao::DAO_TYPE_HASH {
+        Some(("dao", crate::dao::dao))
+    } else {
+        None
+    }
+}
+
+pub fn blake160(data: &[u8]) -> [u8; BLAKE160_SIZE] {
+    let mut buf = [0u8; BLAKE160_SIZE];
+    buf.copy_from_slice(&blake2b_256(data)[..BLAKE160_SIZE]);
+    buf
+}
+
+/// The sighash-all message: the transaction hash, then every witness as its
+/// u64 length followed by its content. `witnesses[0]` is the first witness of
+/// the group with its signature zeroed, followed by the rest of the group and
+/// the witnesses not covered by any input.
+pub fn sighash_all_message(tx_hash: &[u8], witnesses: &[&[u8]]) -> [u8; 32] {
+    let mut blake2b = new_blake2b();
+    blake2b.update(tx_hash);
+    for witness in witnesses {
+        blake2b.update(&(witness.len() as u64).to_le_bytes());
+        blake2b.update(witness);
+    }
+    let mut message = [0u8; 32];
+    blake2b.finalize(&mut message);
+    message
+}
+
+/// Replaces `lock[start..]` of a `WitnessArgs` with zeros, as signers do
+/// before hashing.
+pub fn zero_witness_lock(witness_args: &WitnessArgs, start: usize) -> WitnessArgs {
+    let mut lock = witness_args
+        .lock()
+        .to_opt()
+        .map(|l| l.raw_data().to_vec())
+        .unwrap_or_default();
+    let start = start.min(lock.len());
+    lock[start..].iter_mut().for_each(|b| *b = 0);
+    witness_args
+        .clone()
+        .as_builder()
+        .lock(Some(Bytes::from(lock)).pack())
+        .build()
+}
+
+pub fn recover_blake160(message: &[u8; 32], signature: &[u8]) -> Result<[u8; BLAKE160_SIZE], i8> {
+    if signature.len() != SIGNATURE_SIZE {
+        return Err(ERROR_WITNESS_SIZE);
+    }
+    let recid =
+        RecoveryId::try_from(signature[64] as i32).map_err(|_| ERROR_SECP_PARSE_SIGNATURE)?;
+    let signature = RecoverableSignature::from_compact(&signature[..64], recid)
+        .map_err(|_| ERROR_SECP_PARSE_SIGNATURE)?;
+    let pubkey = SECP256K1
+        .recover_ecdsa(&Message::from_digest(*message), &signature)
+        .map_err(|_| ERROR_SECP_RECOVER_PUBKEY)?;
+    Ok(blake160(&pubkey.serialize()))
+}
+
+/// Loads a whole syscall result, whatever its size.
+pub(crate) fn load<F: Fn(*mut c_void, *mut u64, u64) -> c_int>(f: F) -> Result<Vec<u8>, c_int> {
+    let mut empty = [0u8; 0];
+    let mut len = 0u64;
+    let code = f(empty.as_mut_ptr() as *mut c_void, &mut len, 0);
+    if code != CKB_SUCCESS {
+        return Err(code);
+    }
+    let mut buf = vec![0u8; len as usize];
+    let code = f(buf.as_mut_ptr() as *mut c_void, &mut len, 0);
+    if code != CKB_SUCCESS {
+        return Err(code);
+    }
+    Ok(buf)
+}
+
+fn load_script() -> Result<Script, i8> {
+    let script = load(|ptr, len, offset| crate::ckb_load_script(ptr, len, offset))
+        .map_err(|_| ERROR_SYSCALL)?;
+    Script::from_slice(&script).map_err(|_| ERROR_ENCODING)
+}
+
+fn load_transaction() -> Result<packed::Transaction, i8> {
+    let tx = load(|ptr, len, offset| crate::ckb_load_transaction(ptr, len, offset))
+        .map_err(|_| ERROR_SYSCALL)?;
+    packed::Transaction::from_slice(&tx).map_err(|_| ERROR_ENCODING)
+}
+
+pub(crate) fn load_witness(index: u64, source: u64) -> Result<Option<Vec<u8>>, i8> {
+    match load(|ptr, len, offset| crate::ckb_load_witness(ptr, len, offset, index, source)) {
+        Ok(witness) => Ok(Some(witness)),
+        Err(CKB_INDEX_OUT_OF_BOUND) => Ok(None),
+        Err(_) => Err(ERROR_SYSCALL),
+    }
+}
+
+pub(crate) fn count_cells(source: u64) -> Result<usize, i8> {
+    let mut count = 0;
+    loop {
+        match load(|ptr, len, offset| {
+            crate::ckb_load_cell_by_field(ptr, len, offset, count, source, CELL_FIELD_CAPACITY)
+        }) {
+            Ok(_) => count += 1,
+            Err(CKB_INDEX_OUT_OF_BOUND) => return Ok(count as usize),
+            Err(_) => return Err(ERROR_SYSCALL),
+        }
+    }
+}
+
+/// Hashes the group's witnesses for sighash-all, keeping `lock[..keep]` of
+/// the first one and zeroing the rest of it. Returns the message and the
+/// original lock.
+fn group_message(keep: usize) -> Result<([u8; 32], Bytes), i8> {
+    let tx = load_transaction()?;
+    let tx_hash = tx.calc_tx_hash();
+
+    let first = load_witness(0, SOURCE_GROUP_INPUT)?.ok_or(ERROR_ENCODING)?;
+    let witness_args = WitnessArgs::from_slice(&first).map_err(|_| ERROR_ENCODING)?;
+    let lock: Bytes = witness_args
+        .lock()
+        .to_opt()
+        .ok_or(ERROR_ENCODING)?
+        .raw_data();
+    let zeroed = zero_witness_lock(&witness_args, keep);
+
+    let mut witnesses = vec![zeroed.as_slice().to_vec()];
+    let mut i = 1;
+    while let Some(witness) = load_witness(i, SOURCE_GROUP_INPUT)? {
+        witnesses.push(witness);
+        i += 1;
+    }
+    let inputs_len = tx.raw().inputs().len();
+    for witness in tx.witnesses().into_iter().skip(inputs_len) {
+        witnesses.push(witness.raw_data().to_vec());
+    }
+    let witnesses: Vec<&[u8]> = witnesses.iter().map(|w| w.as_slice()).collect();
+    Ok((sighash_all_message(tx_hash.as_slice(), &witnesses), lock))
+}
+
+pub(crate) fn exit_code(result: Result<(), i8>) -> i8 {
+    match result {
+        Ok(()) => 0,
+        Err(code) => code,
+    }
+}
+
+fn sighash_all() -> i8 {
+    exit_code((|| {
+        let args = load_script()?.args().raw_data();
+        if args.len() != BLAKE160_SIZE {
+            return Err(ERROR_ARGUMENTS_LEN);
+        }
+        let (message, lock) = group_message(0)?;
+        if lock.len() != SIGNATURE_SIZE {
+            return Err(ERROR_WITNESS_SIZE);
+        }
+        if recover_blake160(&message, &lock)? != args[..] {
+            return Err(ERROR_PUBKEY_BLAKE160_HASH);
+        }
+        Ok(())
+    })())
+}
+
+/// Parsed `S | R | M | N | PubKeyHash1 | ... | PubKeyHashN` multisig script.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultisigConfig {
+    pub require_first_n: u8,
+    pub threshold: u8,
+    pub pubkey_hashes: Vec<[u8; BLAKE160_SIZE]>,
+}
+impl MultisigConfig {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![
+            0,
+            self.require_first_n,
+            self.threshold,
+            self.pubkey_hashes.len() as u8,
+        ];
+        self.pubkey_hashes
+            .iter()
+            .for_each(|h| buf.extend_from_slice(h));
+        buf
+    }
+
+    /// The lock args of the multisig script, without since.
+    pub fn hash160(&self) -> [u8; BLAKE160_SIZE] {
+        blake160(&self.to_bytes())
+    }
+
+    pub fn from_lock(lock: &[u8]) -> Result<Self, i8> {
+        if lock.len() < MULTISIG_HEADER_SIZE {
+            return Err(ERROR_WITNESS_SIZE);
+        }
+        let (reserved, require_first_n, threshold, pubkeys_cnt) =
+            (lock[0], lock[1], lock[2], lock[3] as usize);
+        if reserved != 0 {
+            return Err(ERROR_INVALID_RESERVE_FIELD);
+        }
+        if pubkeys_cnt == 0 {
+            return Err(ERROR_INVALID_PUBKEYS_CNT);
+        }
+        if threshold as usize > pubkeys_cnt || threshold == 0 {
+            return Err(ERROR_INVALID_THRESHOLD);
+        }
+        if require_first_n > threshold {
+            return Err(ERROR_INVALID_REQUIRE_FIRST_N);
+        }
+        let size = MULTISIG_HEADER_SIZE
+            + BLAKE160_SIZE * pubkeys_cnt
+            + SIGNATURE_SIZE * threshold as usize;
+        if lock.len() != size {
+            return Err(ERROR_WITNESS_SIZE);
+        }
+        let pubkey_hashes = lock[MULTISIG_HEADER_SIZE..]
+            .chunks(BLAKE160_SIZE)
+            .take(pubkeys_cnt)
+            .map(|c| c.try_into().expect("blake160"))
+            .collect();
+        Ok(Self {
+            require_first_n,
+            threshold,
+            pubkey_hashes,
+        })
+    }
+
+    pub fn lock_size(&self) -> usize {
+        MULTISIG_HEADER_SIZE
+            + BLAKE160_SIZE * self.pubkey_hashes.len()
+            + SIGNATURE_SIZE * self.threshold as usize
+    }
+}
+
+fn since_ge(input_since: u64, since: u64) -> bool {
+    if since & SINCE_METRIC_MASK == SINCE_EPOCH_FLAG {
+        let a = EpochNumberWithFraction::from_full_value(input_since & SINCE_VALUE_MASK);
+        let b = EpochNumberWithFraction::from_full_value(since & SINCE_VALUE_MASK);
+        a.to_rational() >= b.to_rational()
+    } else {
+        input_since & SINCE_VALUE_MASK >= since & SINCE_VALUE_MASK
+    }
+}
+
+fn check_multisig_since(since: u64) -> Result<(), i8> {
+    let mut i = 0;
+    loop {
+        match load(|ptr, len, offset| {
+            crate::ckb_load_input_by_field(
+                ptr,
+                len,
+                offset,
+                i,
+                SOURCE_GROUP_INPUT,
+                INPUT_FIELD_SINCE,
+            )
+        }) {
+            Ok(data) => {
+                let input_since = u64::from_le_bytes(data.try_into().map_err(|_| ERROR_ENCODING)?);
+                if input_since >> 56 != since >> 56 {
+                    return Err(ERROR_INCORRECT_SINCE_FLAGS);
+                }
+                if !since_ge(input_since, since) {
+                    return Err(ERROR_INCORRECT_SINCE_VALUE);
+                }
+            }
+            Err(CKB_INDEX_OUT_OF_BOUND) => return Ok(()),
+            Err(_) => return Err(ERROR_SYSCALL),
+        }
+        i += 1;
+    }
+}
+
+fn multisig_all() -> i8 {
+    exit_code((|| {
+        let args = load_script()?.args().raw_data();
+        if args.len() != BLAKE160_SIZE && args.len() != BLAKE160_SIZE + 8 {
+            return Err(ERROR_ARGUMENTS_LEN);
+        }
+        if args.len() == BLAKE160_SIZE + 8 {
+            let since = u64::from_le_bytes(args[BLAKE160_SIZE..].try_into().expect("since"));
+            check_multisig_since(since)?;
+        }
+
+        let first = load_witness(0, SOURCE_GROUP_INPUT)?.ok_or(ERROR_ENCODING)?;
+        let lock = WitnessArgs::from_slice(&first)
+            .map_err(|_| ERROR_ENCODING)?
+            .lock()
+            .to_opt()
+            .ok_or(ERROR_ENCODING)?
+            .raw_data();
+        let config = MultisigConfig::from_lock(&lock)?;
+        let signatures_start = config.lock_size() - SIGNATURE_SIZE * config.threshold as usize;
+        if config.hash160() != args[..BLAKE160_SIZE] {
+            return Err(ERROR_MULTSIG_SCRIPT_HASH);
+        }
+
+        let (message, _) = group_message(signatures_start)?;
+        let mut used = vec![false; config.pubkey_hashes.len()];
+        for signature in lock[signatures_start..].chunks(SIGNATURE_SIZE) {
+            let pubkey_hash = recover_blake160(&message, signature)?;
+            let matched = config
+                .pubkey_hashes
+                .iter()
+                .enumerate()
+                .position(|(j, h)| !used[j] && h == &pubkey_hash)
+                .ok_or(ERROR_VERIFICATION)?;
+            used[matched] = true;
+        }
+        if used[..config.require_first_n as usize].iter().any(|u| !u) {
+            return Err(ERROR_VERIFICATION);
+        }
+        Ok(())
+    })())
+}
+
+fn type_id() -> i8 {
+    exit_code((|| {
+        let script = load_script()?;
+        if script.args().raw_data().len() != 32 {
+            return Err(ERROR_TYPE_ID_ARGS);
+        }
+        let inputs = count_cells(SOURCE_GROUP_INPUT)?;
+        let outputs = count_cells(SOURCE_GROUP_OUTPUT)?;
+        if inputs > 1 || outputs > 1 {
+            return Err(ERROR_TYPE_ID_TOO_MANY_CELLS);
+        }
+        if inputs == 1 || outputs == 0 {
+            return Ok(());
+        }
+
+        // A new Type ID is the hash of the first input and the output index.
+        let first_input =
+            load(|ptr, len, offset| crate::ckb_load_input(ptr, len, offset, 0, SOURCE_INPUT))
+                .map_err(|_| ERROR_SYSCALL)?;
+        let script_hash = script.calc_script_hash();
+        let mut index = 0;
+        let output_index = loop {
+            match load(|ptr, len, offset| {
+                crate::ckb_load_cell_by_field(
+                    ptr,
+                    len,
+                    offset,
+                    index,
+                    SOURCE_OUTPUT,
+                    CELL_FIELD_TYPE_HASH,
+                )
+            }) {
+                Ok(hash) if hash == script_hash.as_slice() => break index,
+                Ok(_) | Err(CKB_ITEM_MISSING) => index += 1,
+                Err(_) => return Err(ERROR_SYSCALL),
+            }
+        };
+        let mut blake2b = new_blake2b();
+        blake2b.update(&first_input);
+        blake2b.update(&output_index.to_le_bytes());
+        let mut type_id = [0u8; 32];
+        blake2b.finalize(&mut type_id);
+        if script.args().raw_data()[..] != type_id[..] {
+            return Err(ERROR_TYPE_ID_INVALID_INPUT_HASH);
+        }
+        Ok(())
+    })())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        signer, test_utils,
+        verify::{run_group, script_groups, verify_transaction, VerifyError},
+        RunningSetup,
+    };
+    use ckb_mock_tx_types::MockTransaction;
+    use ckb_types::packed::CellOutput;
+
+    fn key(byte: u8) -> signer::SecretKey {
+        signer::SecretKey::from_byte_array(&[byte; 32]).unwrap()
+    }
+
+    #[test]
+    fn sighash_all_accepts_signed_groups_only() {
+        let key = key(1);
+        let mut tx = test_utils::locked_tx(signer::sighash_lock(&key));
+        let setup = RunningSetup::default();
+        signer::sign_sighash_all(&mut tx, &key).unwrap();
+        assert_eq!(verify_transaction(&tx, &setup), Ok(()));
+
+        // Another output changes the message.
+        let output = tx.tx.raw().outputs().get(0).unwrap();
+        let raw = tx
+            .tx
+            .raw()
+            .as_builder()
+            .outputs(vec![output.clone(), output].pack());
+        tx.tx = tx.tx.clone().as_builder().raw(raw.build()).build();
+        let lock_hash = signer::sighash_lock(&key).calc_script_hash();
+        assert_eq!(
+            verify_transaction(&tx, &setup),
+            Err(VerifyError::ScriptFailed(
+                "Inputs[0].Lock".to_string(),
+                lock_hash,
+                ERROR_PUBKEY_BLAKE160_HASH
+            ))
+        );
+    }
+
+    #[test]
+    fn multisig_all_checks_threshold_signatures() {
+        let keys = [key(1), key(2), key(3)];
+        let config = MultisigConfig {
+            require_first_n: 0,
+            threshold: 2,
+            pubkey_hashes: keys.iter().map(signer::pubkey_hash).collect(),
+        };
+        let mut tx = test_utils::locked_tx(signer::multisig_lock(&config, None));
+        signer::sign_multisig_all(&mut tx, &config, &keys[1..]).unwrap();
+        assert_eq!(verify_transaction(&tx, &RunningSetup::default()), Ok(()));
+
+        let lock = config.to_bytes();
+        assert_eq!(
+            MultisigConfig::from_lock(&lock),
+            Err(ERROR_WITNESS_SIZE),
+            "signatures are missing"
+        );
+        let mut lock = config.to_bytes();
+        lock[2] = 4;
+        assert_eq!(
+            MultisigConfig::from_lock(&lock),
+            Err(ERROR_INVALID_THRESHOLD)
+        );
+    }
+
+    #[test]
+    fn type_id_checks_new_ids() {
+        let mut tx = test_utils::sample_tx();
+        let first_input = tx.tx.raw().inputs().get(0).unwrap();
+        let mut blake2b = new_blake2b();
+        blake2b.update(first_input.as_slice());
+        blake2b.update(&0u64.to_le_bytes());
+        let mut id = [0u8; 32];
+        blake2b.finalize(&mut id);
+
+        let run = |tx: &mut MockTransaction, args: [u8; 32]| {
+            let type_id = Script::new_builder()
+                .code_hash(TYPE_ID_CODE_HASH.pack())
+                .hash_type(ScriptHashType::Type.into())
+                .args(Bytes::copy_from_slice(&args).pack())
+                .build();
+            let output: CellOutput = tx.tx.raw().outputs().get(0).unwrap();
+            let output = output.as_builder().type_(Some(type_id).pack()).build();
+            let raw = tx.tx.raw().as_builder().outputs(vec![output].pack());
+            tx.tx = tx.tx.clone().as_builder().raw(raw.build()).build();
+            let group = script_groups(tx).pop().unwrap();
+            assert!(group.is_output);
+            run_group(tx, &RunningSetup::default(), &group).unwrap()
+        };
+        assert_eq!(run(&mut tx, id), 0);
+        assert_eq!(run(&mut tx, [0u8; 32]), ERROR_TYPE_ID_INVALID_INPUT_HASH);
+    }
+}
@@ -0,0 +1,210 @@
+//! Helpers shared by the unit tests.
+use ckb_mock_tx_types::{MockCellDep, MockInfo, MockInput, MockTransaction, ReprMockTransaction};
+use ckb_types::{
+    bytes::Bytes,
+    core::{EpochNumberWithFraction, HeaderView, ScriptHashType, TransactionView},
+    packed::{CellDep, CellInput, CellOutput, OutPoint, Script},
+    prelude::*,
+};
+use std::{
+    path::PathBuf,
+    process::Command,
+    sync::{mpsc, OnceLock},
+    time::Duration,
+};
+
+/// A path in the temp dir unique to this test binary.
+pub fn temp_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ckb-x64-simulator-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("create temp dir");
+    dir.join(name)
+}
+
+/// Writes `content` to a temp file named `name`, returns its path.
+pub fn temp_file(name: &str, content: &[u8]) -> String {
+    let path = temp_path(name);
+    std::fs::write(&path, content).expect("write temp file");
+    path.display().to_string()
+}
+
+/// The `native_binaries` key of a spawn or exec of the whole cell.
+pub fn cell_key(code_hash: &[u8; 32], hash_type: u8) -> String {
+    let mut key = code_hash.to_vec();
+    key.push(hash_type);
+    key.extend_from_slice(&[0u8; 8]);
+    format!("0x{}", faster_hex::hex_string(&key))
+}
+
+/// Runs `f` on a new thread, failing when it does not return in time, so a
+/// scheduler deadlock fails the test instead of hanging it.
+pub fn with_timeout<T: Send + 'static, F: FnOnce() -> T + Send + 'static>(f: F) -> T {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || sender.send(f()));
+    receiver
+        .recv_timeout(Duration::from_secs(30))
+        .expect("finishes in time")
+}
+
+/// Runs `f` in a process of its own, running only `test` of this test
+/// binary, which must be the test calling it. Returns how the process
+/// exited, for code ending the whole process.
+pub fn exit_status_of<F: FnOnce()>(test: &str, f: F) -> std::process::ExitStatus {
+    const CHILD_ENV: &str = "CKB_X64_SIMULATOR_TEST_CHILD";
+    if std::env::var(CHILD_ENV).as_deref() == Ok(test) {
+        f();
+        std::process::exit(0);
+    }
+    Command::new(std::env::current_exe().expect("test binary"))
+        .args([test, "--exact", "--test-threads=1"])
+        .env(CHILD_ENV, test)
+        .stdout(std::process::Stdio::null())
+        .status()
+        .expect("run test process")
+}
+
+/// A transaction with an input, a cell dep, a header dep, an output and an
+/// extension, all of them carrying distinct data.
+pub fn sample_tx() -> MockTransaction {
+    let lock = Script::new_builder()
+        .code_hash([1u8; 32].pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(Bytes::from(vec![2u8; 20]).pack())
+        .build();
+    let output = CellOutput::new_builder()
+        .capacity(1000u64.pack())
+        .lock(lock)
+        .build();
+    let input = CellInput::new(OutPoint::new([3u8; 32].pack(), 0), 0);
+    let cell_dep = CellDep::new_builder()
+        .out_point(OutPoint::new([4u8; 32].pack(), 1))
+        .build();
+    let header = HeaderView::new_advanced_builder()
+        .number(5u64.pack())
+        .epoch(EpochNumberWithFraction::new(0, 5, 1000).pack())
+        .build();
+    let tx = TransactionView::new_advanced_builder()
+        .input(input.clone())
+        .cell_dep(cell_dep.clone())
+        .header_dep(header.hash())
+        .output(output.clone())
+        .output_data(Bytes::from(vec![8u8]).pack())
+        .witness(Bytes::from(vec![9u8]).pack())
+        .build();
+    MockTransaction {
+        mock_info: MockInfo {
+            inputs: vec![MockInput {
+                input,
+                output: output.clone(),
+                data: Bytes::from(vec![1, 2, 3]),
+                header: Some(header.hash()),
+            }],
+            cell_deps: vec![MockCellDep {
+                cell_dep,
+                output,
+                data: Bytes::from(vec![4]),
+                header: None,
+            }],
+            header_deps: vec![header],
+            extensions: vec![([6u8; 32].pack(), Bytes::from(vec![7]))],
+        },
+        tx: tx.data(),
+    }
+}
+
+/// `mock_tx` as JSON, to compare transactions.
+pub fn tx_json(mock_tx: &MockTransaction) -> serde_json::Value {
+    serde_json::to_value(ReprMockTransaction::from(mock_tx.clone())).expect("serialize tx")
+}
+
+/// Builds the C `source` as a native simulator library named `name`.
+fn build_dylib(name: &str, source: &str) -> String {
+    let source = temp_file(&format!("{}.c", name), source.as_bytes());
+    let path = temp_path(&format!("lib{}.so", name)).display().to_string();
+    let status = Command::new("cc")
+        .args(["-shared", "-fPIC", "-o", &path, &source])
+        .status()
+        .expect("run cc");
+    assert!(status.success(), "build {}", path);
+    path
+}
+
+/// A native simulator library whose `__ckb_std_main` returns `argv_code`
+/// of its argv, built once per test binary.
+pub fn argv_dylib() -> String {
+    static PATH: OnceLock<String> = OnceLock::new();
+    PATH.get_or_init(|| {
+        build_dylib(
+            "argv_dylib",
+            r#"
+#include <stdint.h>
+void __set_script_info(void* ptr, uint64_t tx_ctx_id, uint64_t pid) {}
+int8_t __ckb_std_main(int argc, char** argv) {
+  uint8_t code = (uint8_t)argc;
+  for (int i = 0; i < argc; i++) {
+    for (char* c = argv[i]; *c; c++) {
+      code = (uint8_t)(code * 31 + (uint8_t)*c);
+    }
+  }
+  return (int8_t)code;
+}
+"#,
+        )
+    })
+    .clone()
+}
+
+/// A native simulator library whose `__ckb_std_main` aborts.
+pub fn abort_dylib() -> String {
+    static PATH: OnceLock<String> = OnceLock::new();
+    PATH.get_or_init(|| {
+        build_dylib(
+            "abort_dylib",
+            r#"
+#include <stdint.h>
+#include <stdlib.h>
+void __set_script_info(void* ptr, uint64_t tx_ctx_id, uint64_t pid) {}
+int8_t __ckb_std_main(int argc, char** argv) {
+  abort();
+}
+"#,
+        )
+    })
+    .clone()
+}
+
+/// A native simulator library whose `__ckb_std_main` returns the exit code
+/// of crashed VMs without crashing.
+pub fn crash_code_dylib() -> String {
+    static PATH: OnceLock<String> = OnceLock::new();
+    PATH.get_or_init(|| {
+        build_dylib(
+            "crash_code_dylib",
+            r#"
+#include <stdint.h>
+void __set_script_info(void* ptr, uint64_t tx_ctx_id, uint64_t pid) {}
+int8_t __ckb_std_main(int argc, char** argv) {
+  return -128;
+}
+"#,
+        )
+    })
+    .clone()
+}
+
+/// Exit code of `argv_dylib` run with `args`.
+pub fn argv_code(args: &[&[u8]]) -> i8 {
+    let mut code = args.len() as u8;
+    for byte in args.iter().flat_map(|a| a.iter()) {
+        code = code.wrapping_mul(31).wrapping_add(*byte);
+    }
+    code as i8
+}
+
+/// `sample_tx` with its input locked by `lock` and no witnesses.
+pub fn locked_tx(lock: Script) -> MockTransaction {
+    let mut tx = sample_tx();
+    let input = &mut tx.mock_info.inputs[0];
+    input.output = input.output.clone().as_builder().lock(lock).build();
+    tx.tx = tx.tx.as_builder().witnesses(Default::default()).build();
+    tx
+}
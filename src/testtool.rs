@@ -0,0 +1,234 @@
+//! Runs transactions built with ckb-testtool's `Context` natively and in
+//! process, behind the `ckb-testtool` feature.
+//!
+//! Contracts find their simulators the way `native-simulators` crates are
+//! named: contract `my-contract` in a contracts dir is simulated by
+//! `libmy_contract_sim.so` in the same dir. ckb-testtool's own
+//! `native-simulator` feature should stay off, it links a second copy of the
+//! syscalls.
+use crate::{
+    utils,
+    verify::{native_binary_key, script_groups, verify_transaction, VerifyError},
+    RunningSetup, RunningType,
+};
+use ckb_mock_tx_types::MockTransaction;
+use ckb_testtool::context::Context;
+use ckb_types::{
+    core::{ScriptHashType, TransactionView},
+    packed::{Byte32, CellOutput, Script},
+    prelude::*,
+};
+use std::{collections::HashMap, env, path::PathBuf};
+
+pub struct NativeSimulators {
+    pub contracts_dirs: Vec<PathBuf>,
+    /// Simulators set by hand, by data hash of the contract binary.
+    pub binaries: HashMap<Byte32, PathBuf>,
+}
+impl Default for NativeSimulators {
+    /// Searches `$TOP/build/$MODE`, like `Context::default()`.
+    fn default() -> Self {
+        let mut contracts_dir = env::var("TOP").map(PathBuf::from).unwrap_or_default();
+        contracts_dir.push("build");
+        if !contracts_dir.exists() {
+            contracts_dir.pop();
+            contracts_dir.push("../build");
+        }
+        let contracts_dir = contracts_dir.join(env::var("MODE").unwrap_or("release".to_string()));
+        Self {
+            contracts_dirs: vec![contracts_dir],
+            binaries: HashMap::new(),
+        }
+    }
+}
+impl NativeSimulators {
+    pub fn add_contract_dir(&mut self, path: &str) {
+        self.contracts_dirs.push(path.into());
+    }
+
+    pub fn set_simulator(&mut self, code_hash: Byte32, path: &str) {
+        self.binaries.insert(code_hash, path.into());
+    }
+
+    /// File name of the simulator of contract `name`.
+    pub fn simulator_name(name: &str) -> String {
+        format!(
+            "lib{}_sim.{}",
+            name.replace('-', "_"),
+            env::consts::DLL_EXTENSION
+        )
+    }
+
+    /// Every known simulator, by data hash of the contract binary.
+    pub fn resolve(&self) -> HashMap<Byte32, PathBuf> {
+        let mut binaries = HashMap::new();
+        for dir in &self.contracts_dirs {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                    continue;
+                };
+                let sim_path = dir.join(Self::simulator_name(name));
+                if !path.is_file() || !sim_path.is_file() {
+                    continue;
+                }
+                if let Ok(data) = std::fs::read(&path) {
+                    binaries
+                        .entry(CellOutput::calc_data_hash(&data))
+                        .or_insert(sim_path);
+                }
+            }
+        }
+        binaries.extend(self.binaries.clone());
+        binaries
+    }
+}
+
+/// Data hash of the code a script runs, looking type scripts up in the
+/// cells deployed in `context`.
+fn data_hash(context: &Context, script: &Script) -> Option<Byte32> {
+    if script.hash_type() == ScriptHashType::Type.into() {
+        let out_point = context.cells_by_type_hash.get(&script.code_hash())?;
+        let (_, data) = context.cells.get(out_point)?;
+        Some(CellOutput::calc_data_hash(data))
+    } else {
+        Some(script.code_hash())
+    }
+}
+
+/// The running setup of `tx`, mapping every script group and every
+/// deployed contract with a simulator to it.
+pub fn running_setup(
+    context: &Context,
+    tx: &MockTransaction,
+    simulators: &NativeSimulators,
+) -> RunningSetup {
+    let binaries = simulators.resolve();
+    let mut native_binaries = HashMap::new();
+    for (output, data) in context.cells.values() {
+        let Some(path) = binaries.get(&CellOutput::calc_data_hash(data)) else {
+            continue;
+        };
+        let path = path.display().to_string();
+        // Spawn and exec keys, matching any hash type and the whole cell.
+        let mut hashes = vec![CellOutput::calc_data_hash(data)];
+        if let Some(type_script) = output.type_().to_opt() {
+            hashes.push(type_script.calc_script_hash());
+        }
+        for hash in hashes {
+            native_binaries.insert(
+                utils::simulator_key(hash.as_slice(), 0xFF, 0, 0),
+                path.clone(),
+            );
+        }
+    }
+    for group in script_groups(tx) {
+        if let Some(path) = data_hash(context, &group.script).and_then(|h| binaries.get(&h)) {
+            native_binaries.insert(native_binary_key(&group.script), path.display().to_string());
+        }
+    }
+    RunningSetup {
+        is_lock_script: false,
+        is_output: false,
+        script_index: 0,
+        vm_version: 2,
+        native_binaries,
+        run_type: Some(RunningType::DynamicLib),
+        ..Default::default()
+    }
+}
+
+/// Runs every script group of `tx` natively, with the cells and headers of
+/// `context`. Groups without a simulator must be built-in system scripts.
+pub fn verify_tx_native(
+    context: &Context,
+    tx: &TransactionView,
+    simulators: &NativeSimulators,
+) -> Result<(), VerifyError> {
+    let mock_tx: MockTransaction = context
+        .dump_tx(tx)
+        .map_err(|e| VerifyError::Dump(e.to_string()))?
+        .into();
+    let setup = running_setup(context, &mock_tx, simulators);
+    verify_transaction(&mock_tx, &setup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use ckb_types::{
+        bytes::Bytes,
+        core::TransactionBuilder,
+        packed::{CellInput, CellOutput},
+    };
+
+    /// A contracts dir with contract `name` simulated by `argv_dylib`.
+    fn contracts_dir(name: &str, code: &[u8]) -> PathBuf {
+        let dir = test_utils::temp_path(&format!("contracts-{}", name));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(name), code).unwrap();
+        std::fs::copy(
+            test_utils::argv_dylib(),
+            dir.join(NativeSimulators::simulator_name(name)),
+        )
+        .unwrap();
+        dir
+    }
+
+    fn spending_tx(context: &mut Context, lock: Script) -> TransactionView {
+        let input = context.create_cell(
+            CellOutput::new_builder()
+                .capacity(1000u64.pack())
+                .lock(lock.clone())
+                .build(),
+            Bytes::new(),
+        );
+        let tx = TransactionBuilder::default()
+            .input(CellInput::new(input, 0))
+            .output(CellOutput::new_builder().lock(lock).build())
+            .output_data(Bytes::new().pack())
+            .build();
+        context.complete_tx(tx)
+    }
+
+    #[test]
+    fn groups_run_their_simulators() {
+        let code = b"my contract";
+        let dir = contracts_dir("my-contract", code);
+        let mut simulators = NativeSimulators::default();
+        simulators.add_contract_dir(dir.to_str().unwrap());
+
+        let mut context = Context::default();
+        let out_point = context.deploy_cell(Bytes::from_static(code));
+        for hash_type in [ScriptHashType::Type, ScriptHashType::Data1] {
+            let lock = context
+                .build_script_with_hash_type(&out_point, hash_type, Bytes::new())
+                .unwrap();
+            let tx = spending_tx(&mut context, lock);
+            assert_eq!(verify_tx_native(&context, &tx, &simulators), Ok(()));
+        }
+    }
+
+    #[test]
+    fn groups_without_simulator_are_reported() {
+        let mut context = Context::default();
+        let out_point = context.deploy_cell(Bytes::from_static(b"no simulator"));
+        let lock = context.build_script(&out_point, Bytes::new()).unwrap();
+        let tx = spending_tx(&mut context, lock.clone());
+        let simulators = NativeSimulators {
+            contracts_dirs: vec![],
+            binaries: HashMap::new(),
+        };
+        assert_eq!(
+            verify_tx_native(&context, &tx, &simulators),
+            Err(VerifyError::MissingNativeBinary(
+                "Inputs[0].Lock".to_string(),
+                lock.calc_script_hash()
+            ))
+        );
+    }
+}
@@ -0,0 +1,500 @@
+//! Loads the mock transaction to simulate from the formats we commonly get:
+//!
+//! * `ReprMockTransaction` JSON, as dumped by ckb-debugger and ckb-testtool;
+//! * ckb-cli / ckb-sdk transaction JSON (a bare `Transaction`, a
+//!   `TransactionView`, or an object with a `transaction` field), combined with
+//!   separately dumped cell data in `ReprMockInfo` JSON;
+//! * molecule encoded `MockTransaction` bytes, in the schema of this crate
+//!   documented on `encode_mock_tx`;
+//! * any of the above compressed with gzip.
+//!
+//! The source itself can be a file path, `-` for stdin, or inline JSON.
+use crate::molecule::split_offsets;
+use ckb_jsonrpc_types as json_types;
+use ckb_mock_tx_types::{
+    MockCellDep, MockInfo, MockInput, MockTransaction, ReprMockInfo, ReprMockTransaction,
+};
+use ckb_types::{
+    bytes::Bytes,
+    core::HeaderView,
+    molecule::{pack_number, NUMBER_SIZE},
+    packed::{self, Byte32, Byte32Opt},
+    prelude::*,
+};
+use serde::de::DeserializeOwned;
+use std::io::Read;
+use std::path::PathBuf;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+pub const TX_FILE_ENV: &str = "CKB_TX_FILE";
+pub const TX_MOCK_INFO_ENV: &str = "CKB_TX_MOCK_INFO";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TxSource {
+    File(PathBuf),
+    Stdin,
+    Inline(String),
+}
+impl From<&str> for TxSource {
+    fn from(value: &str) -> Self {
+        if value == "-" {
+            Self::Stdin
+        } else if value.trim_start().starts_with('{') {
+            Self::Inline(value.to_string())
+        } else {
+            Self::File(value.into())
+        }
+    }
+}
+impl std::fmt::Display for TxSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Stdin => write!(f, "<stdin>"),
+            Self::Inline(_) => write!(f, "<inline json>"),
+        }
+    }
+}
+impl TxSource {
+    pub fn read(&self) -> Result<Vec<u8>, LoadError> {
+        let io_error = |e: std::io::Error| LoadError::Io(self.to_string(), e.to_string());
+        match self {
+            Self::File(path) => std::fs::read(path).map_err(io_error),
+            Self::Stdin => {
+                let mut buf = Vec::new();
+                std::io::stdin().read_to_end(&mut buf).map_err(io_error)?;
+                Ok(buf)
+            }
+            Self::Inline(content) => Ok(content.as_bytes().to_vec()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// Source, error message.
+    Io(String, String),
+    /// Path of the offending field, error message.
+    Json(String, String),
+    /// Path of the offending field, error message.
+    Molecule(String, String),
+    Gzip(String),
+    /// A plain transaction was given without the cell data it spends.
+    MissingMockInfo,
+    UnknownFormat,
+}
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(source, e) => write!(f, "cannot read {}: {}", source, e),
+            Self::Json(field, e) => write!(f, "invalid json at `{}`: {}", field, e),
+            Self::Molecule(field, e) => write!(f, "invalid molecule at `{}`: {}", field, e),
+            Self::Gzip(e) => write!(f, "invalid gzip data: {}", e),
+            Self::MissingMockInfo => write!(
+                f,
+                "transaction has no mock info, provide cell data via {}",
+                TX_MOCK_INFO_ENV
+            ),
+            Self::UnknownFormat => write!(f, "unknown transaction format"),
+        }
+    }
+}
+impl std::error::Error for LoadError {}
+
+/// Loads the transaction named by `CKB_TX_FILE`, using `CKB_TX_MOCK_INFO` as
+/// cell data when it only contains a plain transaction.
+pub fn load_from_env() -> Result<MockTransaction, LoadError> {
+    let source = std::env::var(TX_FILE_ENV)
+        .map_err(|e| LoadError::Io(TX_FILE_ENV.to_string(), e.to_string()))?;
+    let mock_info = match std::env::var(TX_MOCK_INFO_ENV) {
+        Ok(info) => Some(TxSource::from(info.as_str()).read()?),
+        Err(_) => None,
+    };
+    load_mock_tx(&source.as_str().into(), mock_info.as_deref())
+}
+
+pub fn load_mock_tx(
+    source: &TxSource,
+    mock_info: Option<&[u8]>,
+) -> Result<MockTransaction, LoadError> {
+    parse_mock_tx(&source.read()?, mock_info)
+}
+
+/// Detects the format of `content` and parses it. `mock_info` is only used
+/// when `content` is a plain transaction.
+pub fn parse_mock_tx(
+    content: &[u8],
+    mock_info: Option<&[u8]>,
+) -> Result<MockTransaction, LoadError> {
+    // Binary transactions start with their size, which can be any byte.
+    let content = match is_molecule(content) {
+        true => content.to_vec(),
+        false => gunzip(content)?,
+    };
+    if !is_molecule(&content) && is_json(&content) {
+        parse_json_tx(&content, mock_info)
+    } else {
+        decode_mock_tx(&content)
+    }
+}
+
+fn is_molecule(content: &[u8]) -> bool {
+    split_offsets(content).is_ok()
+}
+
+fn gunzip(content: &[u8]) -> Result<Vec<u8>, LoadError> {
+    if !content.starts_with(&GZIP_MAGIC) {
+        return Ok(content.to_vec());
+    }
+    let mut buf = Vec::new();
+    flate2::read::MultiGzDecoder::new(content)
+        .read_to_end(&mut buf)
+        .map_err(|e| LoadError::Gzip(e.to_string()))?;
+    Ok(buf)
+}
+
+fn is_json(content: &[u8]) -> bool {
+    content
+        .iter()
+        .find(|c| !c.is_ascii_whitespace())
+        .map(|c| *c == b'{')
+        .unwrap_or(false)
+}
+
+fn from_json_value<T: DeserializeOwned>(
+    prefix: &str,
+    value: serde_json::Value,
+) -> Result<T, LoadError> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let path = e.path().to_string();
+        let field = match (prefix.is_empty(), path.as_str()) {
+            (true, _) => path.clone(),
+            (false, ".") => prefix.to_string(),
+            (false, _) => format!("{}.{}", prefix, path),
+        };
+        LoadError::Json(field, e.into_inner().to_string())
+    })
+}
+
+fn parse_json_tx(content: &[u8], mock_info: Option<&[u8]>) -> Result<MockTransaction, LoadError> {
+    let value: serde_json::Value = serde_json::from_slice(content)
+        .map_err(|e| LoadError::Json(".".to_string(), e.to_string()))?;
+    let object = value.as_object().ok_or(LoadError::UnknownFormat)?;
+
+    if object.contains_key("mock_info") {
+        let repr: ReprMockTransaction = from_json_value("", value)?;
+        return Ok(repr.into());
+    }
+
+    // ckb-cli tx files and get_transaction responses wrap the transaction.
+    let (prefix, mut tx) = match object.get("transaction") {
+        Some(tx) => ("transaction", tx.clone()),
+        None => ("", value.clone()),
+    };
+    if let Some(tx) = tx.as_object_mut() {
+        // A TransactionView carries its hash next to the transaction fields.
+        tx.remove("hash");
+    }
+    let tx: json_types::Transaction = from_json_value(prefix, tx)?;
+    let mock_info = mock_info.ok_or(LoadError::MissingMockInfo)?;
+    let mock_info = gunzip(mock_info)?;
+    let mock_info_value: serde_json::Value = serde_json::from_slice(&mock_info)
+        .map_err(|e| LoadError::Json("mock_info".to_string(), e.to_string()))?;
+    let mock_info: ReprMockInfo = from_json_value("mock_info", mock_info_value)?;
+    Ok(ReprMockTransaction { mock_info, tx }.into())
+}
+
+/// Encodes `mock_tx` in the binary format of this crate, which is not one
+/// of ckb. It is molecule, with these types on top of blockchain.mol:
+///
+/// ```text
+/// table MockCellDep { cell_dep: CellDep, output: CellOutput, data: Bytes, header: Byte32Opt }
+/// table MockInput { input: CellInput, output: CellOutput, data: Bytes, header: Byte32Opt }
+/// table Extension { hash: Byte32, data: Bytes }
+/// vector MockCellDepVec <MockCellDep>;
+/// vector MockInputVec <MockInput>;
+/// vector ExtensionVec <Extension>;
+/// table MockInfo {
+///     inputs: MockInputVec,
+///     cell_deps: MockCellDepVec,
+///     header_deps: HeaderVec,
+///     extensions: ExtensionVec,
+/// }
+/// table MockTransaction { mock_info: MockInfo, tx: Transaction }
+/// ```
+///
+/// The fields mirror `ReprMockTransaction`, which stays the format to share
+/// transactions with other tools.
+pub fn encode_mock_tx(mock_tx: &MockTransaction) -> Vec<u8> {
+    let info = &mock_tx.mock_info;
+    let header = |header: &Option<Byte32>| Byte32Opt::new_builder().set(header.clone()).build();
+    let inputs: Vec<Vec<u8>> = info
+        .inputs
+        .iter()
+        .map(|input| {
+            pack_table(&[
+                input.input.as_slice(),
+                input.output.as_slice(),
+                input.data.pack().as_slice(),
+                header(&input.header).as_slice(),
+            ])
+        })
+        .collect();
+    let cell_deps: Vec<Vec<u8>> = info
+        .cell_deps
+        .iter()
+        .map(|dep| {
+            pack_table(&[
+                dep.cell_dep.as_slice(),
+                dep.output.as_slice(),
+                dep.data.pack().as_slice(),
+                header(&dep.header).as_slice(),
+            ])
+        })
+        .collect();
+    let header_deps = packed::HeaderVec::new_builder()
+        .set(info.header_deps.iter().map(|h| h.data()).collect())
+        .build();
+    let extensions: Vec<Vec<u8>> = info
+        .extensions
+        .iter()
+        .map(|(hash, data)| pack_table(&[hash.as_slice(), data.pack().as_slice()]))
+        .collect();
+
+    let mock_info = pack_table(&[
+        &pack_table(&as_slices(&inputs)),
+        &pack_table(&as_slices(&cell_deps)),
+        header_deps.as_slice(),
+        &pack_table(&as_slices(&extensions)),
+    ]);
+    pack_table(&[&mock_info, mock_tx.tx.as_slice()])
+}
+
+/// Decodes the binary format of `encode_mock_tx`.
+pub fn decode_mock_tx(data: &[u8]) -> Result<MockTransaction, LoadError> {
+    let fields = unpack_table(data, 2, "")?;
+    let info_fields = unpack_table(fields[0], 4, "mock_info")?;
+
+    let mut inputs = vec![];
+    for (i, item) in unpack_table(info_fields[0], usize::MAX, "mock_info.inputs")?
+        .into_iter()
+        .enumerate()
+    {
+        let field = format!("mock_info.inputs[{}]", i);
+        let item = unpack_table(item, 4, &field)?;
+        inputs.push(MockInput {
+            input: from_molecule(item[0], &field, "input")?,
+            output: from_molecule(item[1], &field, "output")?,
+            data: from_molecule::<packed::Bytes>(item[2], &field, "data")?.unpack(),
+            header: from_molecule::<Byte32Opt>(item[3], &field, "header")?.to_opt(),
+        });
+    }
+    let mut cell_deps = vec![];
+    for (i, item) in unpack_table(info_fields[1], usize::MAX, "mock_info.cell_deps")?
+        .into_iter()
+        .enumerate()
+    {
+        let field = format!("mock_info.cell_deps[{}]", i);
+        let item = unpack_table(item, 4, &field)?;
+        cell_deps.push(MockCellDep {
+            cell_dep: from_molecule(item[0], &field, "cell_dep")?,
+            output: from_molecule(item[1], &field, "output")?,
+            data: from_molecule::<packed::Bytes>(item[2], &field, "data")?.unpack(),
+            header: from_molecule::<Byte32Opt>(item[3], &field, "header")?.to_opt(),
+        });
+    }
+    let header_deps: packed::HeaderVec = from_molecule(info_fields[2], "mock_info", "header_deps")?;
+    let mut extensions = vec![];
+    for (i, item) in unpack_table(info_fields[3], usize::MAX, "mock_info.extensions")?
+        .into_iter()
+        .enumerate()
+    {
+        let field = format!("mock_info.extensions[{}]", i);
+        let item = unpack_table(item, 2, &field)?;
+        let hash: Byte32 = from_molecule(item[0], &field, "hash")?;
+        let data: Bytes = from_molecule::<packed::Bytes>(item[1], &field, "data")?.unpack();
+        extensions.push((hash, data));
+    }
+
+    Ok(MockTransaction {
+        mock_info: MockInfo {
+            inputs,
+            cell_deps,
+            header_deps: header_deps
+                .into_iter()
+                .map(|h| h.into_view())
+                .collect::<Vec<HeaderView>>(),
+            extensions,
+        },
+        tx: from_molecule(fields[1], "", "tx")?,
+    })
+}
+
+fn from_molecule<T: Entity>(data: &[u8], parent: &str, name: &str) -> Result<T, LoadError> {
+    T::from_slice(data).map_err(|e| {
+        let field = if parent.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", parent, name)
+        };
+        LoadError::Molecule(field, e.to_string())
+    })
+}
+
+fn as_slices(items: &[Vec<u8>]) -> Vec<&[u8]> {
+    items.iter().map(|i| i.as_slice()).collect()
+}
+
+/// Tables and dynvecs share the same layout: full size, item offsets, items.
+pub(crate) fn pack_table(items: &[&[u8]]) -> Vec<u8> {
+    let header_size = NUMBER_SIZE * (items.len() + 1);
+    let total_size = header_size + items.iter().map(|i| i.len()).sum::<usize>();
+    let mut buf = Vec::with_capacity(total_size);
+    buf.extend_from_slice(&pack_number(total_size as u32));
+    let mut offset = header_size;
+    for item in items {
+        buf.extend_from_slice(&pack_number(offset as u32));
+        offset += item.len();
+    }
+    for item in items {
+        buf.extend_from_slice(item);
+    }
+    buf
+}
+
+/// Splits a table (or a dynvec when `count` is `usize::MAX`) into its items.
+fn unpack_table<'a>(data: &'a [u8], count: usize, field: &str) -> Result<Vec<&'a [u8]>, LoadError> {
+    let error = |e: String| {
+        let field = if field.is_empty() { "." } else { field };
+        LoadError::Molecule(field.to_string(), e)
+    };
+    let items = split_offsets(data).map_err(error)?;
+    if count != usize::MAX && items.len() < count {
+        return Err(error(format!(
+            "at least {} fields, got {}",
+            count,
+            items.len()
+        )));
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{sample_tx, tx_json};
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let tx = sample_tx();
+        let json = serde_json::to_vec(&tx_json(&tx)).unwrap();
+        assert_eq!(tx_json(&parse_mock_tx(&json, None).unwrap()), tx_json(&tx));
+        let gzipped = gzip(&json);
+        assert_eq!(
+            tx_json(&parse_mock_tx(&gzipped, None).unwrap()),
+            tx_json(&tx)
+        );
+    }
+
+    #[test]
+    fn binary_roundtrip() {
+        let tx = sample_tx();
+        let encoded = encode_mock_tx(&tx);
+        assert_eq!(tx_json(&decode_mock_tx(&encoded).unwrap()), tx_json(&tx));
+        assert_eq!(
+            tx_json(&parse_mock_tx(&encoded, None).unwrap()),
+            tx_json(&tx)
+        );
+        let gzipped = gzip(&encoded);
+        assert_eq!(
+            tx_json(&parse_mock_tx(&gzipped, None).unwrap()),
+            tx_json(&tx)
+        );
+    }
+
+    /// `sample_tx` with a witness padding its binary encoding to `len`
+    /// bytes.
+    fn encoded_with_len(len: usize) -> Vec<u8> {
+        let mut tx = sample_tx();
+        let size = encode_mock_tx(&tx).len();
+        let witness = Bytes::from(vec![0u8; 1 + len - size]);
+        tx.tx = tx
+            .tx
+            .as_builder()
+            .witnesses(vec![witness.pack()].pack())
+            .build();
+        encode_mock_tx(&tx)
+    }
+
+    #[test]
+    fn binary_sizes_are_not_taken_for_other_formats() {
+        // Total sizes starting like JSON, then like gzip.
+        for len in [0x47b, 0x8b1f] {
+            let encoded = encoded_with_len(len);
+            assert_eq!(encoded.len(), len);
+            let tx = decode_mock_tx(&encoded).unwrap();
+            assert_eq!(
+                tx_json(&parse_mock_tx(&encoded, None).unwrap()),
+                tx_json(&tx)
+            );
+            assert_eq!(
+                tx_json(&parse_mock_tx(&gzip(&encoded), None).unwrap()),
+                tx_json(&tx)
+            );
+        }
+    }
+
+    #[test]
+    fn plain_transaction_with_mock_info() {
+        let json = tx_json(&sample_tx());
+        let tx = serde_json::json!({ "transaction": json["tx"].clone() });
+        let tx = serde_json::to_vec(&tx).unwrap();
+        let mock_info = serde_json::to_vec(&json["mock_info"]).unwrap();
+
+        let loaded = parse_mock_tx(&tx, Some(&mock_info)).unwrap();
+        assert_eq!(tx_json(&loaded), json);
+        assert!(matches!(
+            parse_mock_tx(&tx, None),
+            Err(LoadError::MissingMockInfo)
+        ));
+    }
+
+    #[test]
+    fn errors_name_the_offending_field() {
+        let mut json = tx_json(&sample_tx());
+        json["mock_info"]["inputs"][0]["output"]["capacity"] = "many".into();
+        let content = serde_json::to_vec(&json).unwrap();
+        match parse_mock_tx(&content, None) {
+            Err(LoadError::Json(field, _)) => {
+                assert_eq!(field, "mock_info.inputs[0].output.capacity")
+            }
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("invalid capacity is accepted"),
+        }
+
+        let mut encoded = encode_mock_tx(&sample_tx());
+        encoded.truncate(encoded.len() - 1);
+        assert!(matches!(
+            decode_mock_tx(&encoded),
+            Err(LoadError::Molecule(field, _)) if field == "."
+        ));
+    }
+
+    #[test]
+    fn sources() {
+        assert_eq!(TxSource::from("-"), TxSource::Stdin);
+        assert_eq!(
+            TxSource::from(" {\"tx\": {}}"),
+            TxSource::Inline(" {\"tx\": {}}".to_string())
+        );
+        assert_eq!(TxSource::from("tx.json"), TxSource::File("tx.json".into()));
+    }
+}
@@ -0,0 +1,380 @@
+//! Non-script checks of a mock transaction, mirroring ckb's transaction
+//! verifiers, so malformed transactions are rejected with ckb's errors
+//! before any native code runs into them.
+use crate::{dao::DAO_TYPE_HASH, RunningSetup};
+use ckb_mock_tx_types::MockTransaction;
+use ckb_types::{
+    core::{
+        error::{OutPointError, TransactionError, TransactionErrorSource},
+        Capacity, DepType, ScriptHashType,
+    },
+    packed::{CellOutput, OutPoint, OutPointVec},
+    prelude::*,
+};
+use std::collections::HashSet;
+
+/// Block size limit of mainnet, transactions must fit in a block.
+pub const MAX_BLOCK_BYTES: u64 = 597_000;
+/// Most cells dep groups of a transaction may expand to.
+pub const MAX_DEP_EXPANSION_LIMIT: usize = 2048;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TxVerifyError {
+    Transaction(TransactionError),
+    /// Inputs, cell deps or header deps missing from the mock info.
+    OutPoint(OutPointError),
+}
+impl std::fmt::Display for TxVerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transaction(e) => write!(f, "{}", e),
+            Self::OutPoint(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for TxVerifyError {}
+impl From<TransactionError> for TxVerifyError {
+    fn from(e: TransactionError) -> Self {
+        Self::Transaction(e)
+    }
+}
+impl From<OutPointError> for TxVerifyError {
+    fn from(e: OutPointError) -> Self {
+        Self::OutPoint(e)
+    }
+}
+
+fn capacity_overflow() -> TransactionError {
+    TransactionError::Internal {
+        description: "capacity overflow".to_string(),
+    }
+}
+
+fn verify_empty(tx: &MockTransaction) -> Result<(), TxVerifyError> {
+    let raw = tx.tx.raw();
+    if raw.inputs().is_empty() {
+        return Err(TransactionError::Empty {
+            inner: TransactionErrorSource::Inputs,
+        }
+        .into());
+    }
+    if raw.outputs().is_empty() {
+        return Err(TransactionError::Empty {
+            inner: TransactionErrorSource::Outputs,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn verify_size(tx: &MockTransaction) -> Result<(), TxVerifyError> {
+    // A transaction takes 4 more bytes as an offset in its block.
+    let actual = tx.tx.as_slice().len() as u64 + 4;
+    if actual > MAX_BLOCK_BYTES {
+        return Err(TransactionError::ExceededMaximumBlockBytes {
+            limit: MAX_BLOCK_BYTES,
+            actual,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn verify_outputs_data(tx: &MockTransaction) -> Result<(), TxVerifyError> {
+    let outputs_len = tx.tx.raw().outputs().len();
+    let outputs_data_len = tx.tx.raw().outputs_data().len();
+    if outputs_len != outputs_data_len {
+        return Err(TransactionError::OutputsDataLengthMismatch {
+            outputs_len,
+            outputs_data_len,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn verify_duplicate_deps(tx: &MockTransaction) -> Result<(), TxVerifyError> {
+    let mut seen_cells = HashSet::new();
+    for cell_dep in tx.tx.raw().cell_deps() {
+        if !seen_cells.insert(cell_dep.clone()) {
+            return Err(TransactionError::DuplicateCellDeps {
+                out_point: cell_dep.out_point(),
+            }
+            .into());
+        }
+    }
+    let mut seen_headers = HashSet::new();
+    for hash in tx.tx.raw().header_deps() {
+        if !seen_headers.insert(hash.clone()) {
+            return Err(TransactionError::DuplicateHeaderDeps { hash }.into());
+        }
+    }
+    Ok(())
+}
+
+/// Every input must be resolved by the mock input at the same index, and
+/// spent only once.
+fn verify_inputs(tx: &MockTransaction) -> Result<(), TxVerifyError> {
+    let mut seen = HashSet::new();
+    for (i, input) in tx.tx.raw().inputs().into_iter().enumerate() {
+        let out_point = input.previous_output();
+        if !seen.insert(out_point.clone()) {
+            return Err(OutPointError::Dead(out_point).into());
+        }
+        match tx.mock_info.inputs.get(i) {
+            Some(mock_input) if mock_input.input.previous_output() == out_point => {}
+            _ => return Err(OutPointError::Unknown(out_point).into()),
+        }
+    }
+    Ok(())
+}
+
+fn find_cell_dep(tx: &MockTransaction, out_point: &OutPoint) -> Option<usize> {
+    tx.mock_info
+        .cell_deps
+        .iter()
+        .position(|dep| &dep.cell_dep.out_point() == out_point)
+}
+
+/// Resolves cell deps like ckb: dep groups are replaced by their member
+/// cells, so `SOURCE_CELL_DEP` indices match the chain. Returns indices into
+/// `mock_info.cell_deps`, where members are looked up by out point.
+pub fn resolve_cell_deps(tx: &MockTransaction) -> Result<Vec<usize>, OutPointError> {
+    let mut resolved = vec![];
+    let mut expanded = 0;
+    for cell_dep in tx.tx.raw().cell_deps() {
+        let out_point = cell_dep.out_point();
+        let index =
+            find_cell_dep(tx, &out_point).ok_or(OutPointError::Unknown(out_point.clone()))?;
+        if cell_dep.dep_type() != DepType::DepGroup.into() {
+            resolved.push(index);
+            continue;
+        }
+        let members = OutPointVec::from_slice(&tx.mock_info.cell_deps[index].data)
+            .map_err(|_| OutPointError::InvalidDepGroup(out_point.clone()))?;
+        expanded += members.len();
+        if expanded > MAX_DEP_EXPANSION_LIMIT {
+            return Err(OutPointError::OverMaxDepExpansionLimit);
+        }
+        for member in members {
+            resolved.push(find_cell_dep(tx, &member).ok_or(OutPointError::Unknown(member))?);
+        }
+    }
+    Ok(resolved)
+}
+
+/// Cell deps must be in the mock info, and dep groups must expand to cells
+/// that are too.
+fn verify_cell_deps(tx: &MockTransaction) -> Result<(), TxVerifyError> {
+    resolve_cell_deps(tx)?;
+    for hash in tx.tx.raw().header_deps() {
+        if tx.mock_info.header_deps.iter().all(|h| h.hash() != hash) {
+            return Err(OutPointError::InvalidHeader(hash).into());
+        }
+    }
+    Ok(())
+}
+
+fn is_dao_withdrawing(output: &CellOutput, data: &[u8]) -> bool {
+    let is_dao = output.type_().to_opt().is_some_and(|t| {
+        t.code_hash() == DAO_TYPE_HASH.pack() && t.hash_type() == ScriptHashType::Type.into()
+    });
+    is_dao && data.len() == 8 && data != [0u8; 8]
+}
+
+/// Outputs must hold their occupied capacity and, unless DAO withdrawing
+/// inputs add interest, not exceed the inputs.
+fn verify_capacity(tx: &MockTransaction) -> Result<(), TxVerifyError> {
+    let dao_withdraw = tx
+        .mock_info
+        .inputs
+        .iter()
+        .any(|input| is_dao_withdrawing(&input.output, &input.data));
+    if !dao_withdraw {
+        let mut inputs_sum = Capacity::zero();
+        for input in &tx.mock_info.inputs {
+            inputs_sum = inputs_sum
+                .safe_add(Unpack::<Capacity>::unpack(&input.output.capacity()))
+                .map_err(|_| capacity_overflow())?;
+        }
+        let mut outputs_sum = Capacity::zero();
+        for output in tx.tx.raw().outputs() {
+            outputs_sum = outputs_sum
+                .safe_add(Unpack::<Capacity>::unpack(&output.capacity()))
+                .map_err(|_| capacity_overflow())?;
+        }
+        if inputs_sum < outputs_sum {
+            return Err(TransactionError::OutputsSumOverflow {
+                inputs_sum,
+                outputs_sum,
+            }
+            .into());
+        }
+    }
+
+    let raw = tx.tx.raw();
+    for (index, (output, data)) in raw
+        .outputs()
+        .into_iter()
+        .zip(raw.outputs_data())
+        .enumerate()
+    {
+        let data_capacity = Capacity::bytes(data.len()).map_err(|_| capacity_overflow())?;
+        let occupied_capacity = output
+            .occupied_capacity(data_capacity)
+            .map_err(|_| capacity_overflow())?;
+        let capacity: Capacity = output.capacity().unpack();
+        if occupied_capacity > capacity {
+            return Err(TransactionError::InsufficientCellCapacity {
+                inner: TransactionErrorSource::Outputs,
+                index,
+                occupied_capacity,
+                capacity,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Runs every check, reporting the first problem.
+pub fn verify_structure(tx: &MockTransaction) -> Result<(), TxVerifyError> {
+    verify_empty(tx)?;
+    verify_size(tx)?;
+    verify_outputs_data(tx)?;
+    verify_duplicate_deps(tx)?;
+    verify_inputs(tx)?;
+    verify_cell_deps(tx)?;
+    verify_capacity(tx)
+}
+
+/// The checks enabled in `setup`, for a transaction about to run.
+pub fn precheck(tx: &MockTransaction, setup: &RunningSetup) -> Result<(), TxVerifyError> {
+    if setup.check_structure {
+        verify_structure(tx)?;
+    }
+    if setup.check_since {
+        crate::since::verify_since(tx, &crate::since::tip_header(tx, setup))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use ckb_types::{core::TransactionView, packed::CellDep};
+
+    /// `sample_tx` with enough capacity in its input and output.
+    fn funded_tx(input: u64, output: u64) -> MockTransaction {
+        let mut tx = test_utils::sample_tx();
+        let cell = &mut tx.mock_info.inputs[0].output;
+        *cell = cell.clone().as_builder().capacity(input.pack()).build();
+        let output = tx
+            .tx
+            .raw()
+            .outputs()
+            .get(0)
+            .unwrap()
+            .as_builder()
+            .capacity(output.pack())
+            .build();
+        let raw = tx
+            .tx
+            .raw()
+            .as_builder()
+            .outputs(vec![output].pack())
+            .build();
+        tx.tx = tx.tx.as_builder().raw(raw).build();
+        tx
+    }
+
+    fn with_tx(
+        mut tx: MockTransaction,
+        f: impl FnOnce(TransactionView) -> TransactionView,
+    ) -> MockTransaction {
+        tx.tx = f(tx.tx.into_view()).data();
+        tx
+    }
+
+    #[test]
+    fn well_formed_transactions_pass() {
+        let tx = funded_tx(10_000_000_000, 10_000_000_000);
+        assert_eq!(verify_structure(&tx), Ok(()));
+        let setup = RunningSetup {
+            check_structure: true,
+            ..Default::default()
+        };
+        assert_eq!(precheck(&tx, &setup), Ok(()));
+    }
+
+    #[test]
+    fn capacity_is_checked() {
+        let tx = test_utils::sample_tx();
+        assert!(matches!(
+            verify_structure(&tx),
+            Err(TxVerifyError::Transaction(
+                TransactionError::InsufficientCellCapacity { index: 0, .. }
+            ))
+        ));
+        let tx = funded_tx(10_000_000_000, 10_000_000_001);
+        assert!(matches!(
+            verify_structure(&tx),
+            Err(TxVerifyError::Transaction(
+                TransactionError::OutputsSumOverflow { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn malformed_transactions_fail_like_ckb() {
+        let tx = funded_tx(10_000_000_000, 10_000_000_000);
+        let no_data = with_tx(tx.clone(), |view| {
+            view.as_advanced_builder().set_outputs_data(vec![]).build()
+        });
+        assert_eq!(
+            verify_structure(&no_data),
+            Err(TransactionError::OutputsDataLengthMismatch {
+                outputs_len: 1,
+                outputs_data_len: 0
+            }
+            .into())
+        );
+
+        let cell_dep: CellDep = tx.tx.raw().cell_deps().get(0).unwrap();
+        let duplicate = with_tx(tx.clone(), |view| {
+            view.as_advanced_builder()
+                .cell_dep(cell_dep.clone())
+                .build()
+        });
+        assert_eq!(
+            verify_structure(&duplicate),
+            Err(TransactionError::DuplicateCellDeps {
+                out_point: cell_dep.out_point()
+            }
+            .into())
+        );
+
+        let mut unknown = tx.clone();
+        unknown.mock_info.cell_deps.clear();
+        assert_eq!(
+            verify_structure(&unknown),
+            Err(OutPointError::Unknown(cell_dep.out_point()).into())
+        );
+
+        let mut unresolved = tx;
+        let other = OutPoint::new([9u8; 32].pack(), 0);
+        unresolved.mock_info.inputs[0].input = ckb_types::packed::CellInput::new(other, 0);
+        let out_point = unresolved
+            .tx
+            .raw()
+            .inputs()
+            .get(0)
+            .unwrap()
+            .previous_output();
+        assert_eq!(
+            verify_structure(&unresolved),
+            Err(OutPointError::Unknown(out_point).into())
+        );
+    }
+}
@@ -1,5 +1,6 @@
-use crate::global_data::GlobalData;
+use crate::{constants::MAX_ARGV_SIZE, global_data::GlobalData, simulator_context::SimContext};
 use std::{
+    collections::HashMap,
     ffi::{c_int, c_void},
     path::PathBuf,
     sync::{Arc, Condvar, Mutex},
@@ -10,16 +11,24 @@ pub fn get_simulator_path(
     hash_type: u8,
     offset: u32,
     length: u32,
+) -> Option<String> {
+    let setup = crate::get_cur_tx!().setup();
+    simulator_path(&setup.native_binaries, code_hash, hash_type, offset, length)
+}
+
+/// Looks the key of the code up in `native_binaries`, then the same key
+/// with the wildcard hash type 0xFF.
+pub fn simulator_path(
+    native_binaries: &HashMap<String, String>,
+    code_hash: &[u8],
+    hash_type: u8,
+    offset: u32,
+    length: u32,
 ) -> Option<String> {
     let mut filename = None;
     for ht in [hash_type, 0xFF] {
-        let mut buffer = vec![];
-        buffer.extend_from_slice(code_hash);
-        buffer.push(ht);
-        buffer.extend_from_slice(&offset.to_be_bytes()[..]);
-        buffer.extend_from_slice(&length.to_be_bytes()[..]);
-        let key = format!("0x{}", faster_hex::hex_string(&buffer));
-        filename = crate::SETUP.native_binaries.get(&key);
+        let key = simulator_key(code_hash, ht, offset, length);
+        filename = native_binaries.get(&key);
         if filename.is_some() {
             break;
         }
@@ -27,35 +36,42 @@ pub fn get_simulator_path(
     filename.cloned()
 }
 
+/// `0x{code_hash + hash_type + offset.to_be_bytes() + length.to_be_bytes()}`
+pub fn simulator_key(code_hash: &[u8], hash_type: u8, offset: u32, length: u32) -> String {
+    let mut buffer = vec![];
+    buffer.extend_from_slice(code_hash);
+    buffer.push(hash_type);
+    buffer.extend_from_slice(&offset.to_be_bytes()[..]);
+    buffer.extend_from_slice(&length.to_be_bytes()[..]);
+    format!("0x{}", faster_hex::hex_string(&buffer))
+}
+
 pub struct CkbNativeSimulator {
     lib: libloading::Library,
+    path: String,
 }
 impl CkbNativeSimulator {
-    pub fn new_by_hash(code_hash: *const u8, hash_type: u8, offset: u32, length: u32) -> Self {
-        let sim_path = get_simulator_path(
-            unsafe { std::slice::from_raw_parts(code_hash, 32) },
-            hash_type,
-            offset,
-            length,
-        );
-        let sim_path = sim_path.expect("cannot locate native binary for ckb_spawn syscall!");
-        Self::new(&sim_path.into())
-    }
-    fn new(path: &PathBuf) -> Self {
+    pub fn new(path: &PathBuf) -> Self {
         unsafe {
             let lib = libloading::Library::new(path).expect("Load library");
-            Self { lib }
+            Self {
+                lib,
+                path: path.display().to_string(),
+            }
         }
     }
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
 
-    pub fn ckb_std_main(self, args: Vec<String>) -> i8 {
+    pub fn ckb_std_main(self, args: Vec<Vec<u8>>) -> i8 {
         type CkbMainFunc<'a> =
             libloading::Symbol<'a, unsafe extern "C" fn(argc: i32, argv: *const *const i8) -> i8>;
 
         let argc = args.len() as u64;
         let mut argv: Vec<*const i8> = Vec::with_capacity(argc as usize + 1);
         for s in args {
-            let c_string = std::ffi::CString::new(s.clone()).expect("CString::new failed");
+            let c_string = std::ffi::CString::new(s).expect("CString::new failed");
             argv.push(c_string.into_raw());
         }
         argv.push(std::ptr::null_mut());
@@ -85,14 +101,21 @@ impl CkbNativeSimulator {
     }
 }
 
-pub fn to_vec_args(argc: c_int, argv: *const *const i8) -> Vec<String> {
+/// The argv of exec and spawn as byte strings, they need not be UTF-8.
+/// Panics like CKB-VM fails when they overflow its stack.
+pub fn to_vec_args(argc: c_int, argv: *const *const i8) -> Vec<Vec<u8>> {
     let mut args = Vec::with_capacity(argc as usize);
     for i in 0..argc {
         let c_str = unsafe { std::ffi::CStr::from_ptr(*argv.add(i as usize)) };
-        let str_slice = c_str
-            .to_str()
-            .expect("Failed to convert C string to Rust string");
-        args.push(str_slice.to_owned());
+        args.push(c_str.to_bytes().to_vec());
+    }
+    // argc, the argv pointers with the null one, and the strings.
+    let size = 8 * (args.len() + 2) + args.iter().map(|a| a.len() + 1).sum::<usize>();
+    if size > MAX_ARGV_SIZE {
+        panic!(
+            "argv of {} bytes exceeds the VM stack of {} bytes",
+            size, MAX_ARGV_SIZE
+        );
     }
     args
 }
@@ -167,7 +190,7 @@ impl Fd {
         Fd(self.0 ^ 0x1)
     }
     pub fn is_read(&self) -> bool {
-        self.0 % 2 == 0
+        self.0.is_multiple_of(2)
     }
 }
 
@@ -219,3 +242,81 @@ impl ProcID {
         id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ckb_spawn_cell, ckb_wait, constants::CKB_SUCCESS, test_utils, RunningSetup, Simulation,
+    };
+    use std::ffi::CString;
+
+    fn c_args(args: &[&[u8]]) -> Vec<CString> {
+        args.iter().map(|a| CString::new(*a).unwrap()).collect()
+    }
+
+    #[test]
+    fn argv_fits_the_vm_stack() {
+        // argc, 2 argv pointers with the null one and 2 nul terminators.
+        let overhead = 8 * 4 + 2;
+        let long = vec![b'a'; MAX_ARGV_SIZE - overhead - 1];
+        let args = c_args(&[&long, b"b"]);
+        let argv: Vec<_> = args.iter().map(|a| a.as_ptr()).collect();
+        let parsed = to_vec_args(2, argv.as_ptr());
+        assert_eq!(parsed, vec![long, b"b".to_vec()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the VM stack")]
+    fn argv_overflowing_the_vm_stack_fails() {
+        let long = vec![b'a'; MAX_ARGV_SIZE - 8 * 4 - 2];
+        let args = c_args(&[&long, b"b"]);
+        let argv: Vec<_> = args.iter().map(|a| a.as_ptr()).collect();
+        to_vec_args(2, argv.as_ptr());
+    }
+
+    #[test]
+    fn native_scripts_get_non_utf8_argv() {
+        let args: [&[u8]; 2] = [b"\xff\xfe", b"a\x80"];
+        let sim = Simulation::new(test_utils::sample_tx(), RunningSetup::default());
+        let code = sim.run_native_bytes(&test_utils::argv_dylib(), &args);
+        assert_eq!(code, Ok(test_utils::argv_code(&args)));
+    }
+
+    #[test]
+    fn spawned_scripts_get_non_utf8_argv() {
+        let code_hash = [1u8; 32];
+        let setup = RunningSetup {
+            native_binaries: [(
+                test_utils::cell_key(&code_hash, 1),
+                test_utils::argv_dylib(),
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let sim = Simulation::new(Default::default(), setup);
+        let code = test_utils::with_timeout(move || {
+            sim.run_root("spawner".to_string(), move |_, _| {
+                let args = c_args(&[b"\xff", b"\xc3("]);
+                let argv: Vec<_> = args.iter().map(|a| a.as_ptr() as *const u8).collect();
+                let inherited_fds = [0u64];
+                let mut pid = 0;
+                let ret = ckb_spawn_cell(
+                    code_hash.as_ptr(),
+                    1,
+                    0,
+                    0,
+                    2,
+                    argv.as_ptr(),
+                    inherited_fds.as_ptr(),
+                    &mut pid,
+                );
+                assert_eq!(ret, CKB_SUCCESS);
+                let mut code = 0;
+                assert_eq!(ckb_wait(pid, &mut code), CKB_SUCCESS);
+                code
+            })
+        });
+        assert_eq!(code, test_utils::argv_code(&[b"\xff", b"\xc3("]));
+    }
+}
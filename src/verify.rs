@@ -0,0 +1,258 @@
+//! Whole-transaction verification: every script group of a mock transaction
+//! runs natively, each one in its own simulation.
+//!
+//! A group runs the native binary mapped in `native_binaries` to its script
+//! like the whole cell is for spawn and exec, with the key
+//! `0x{code_hash + hash_type + offset + length}` where offset and length are
+//! zero and the hash type 0xFF matches any, or the built-in implementation
+//! of a system script.
+use crate::{
+    system_scripts,
+    tx_verifier::{precheck, TxVerifyError},
+    utils, RunningSetup, Simulation,
+};
+use ckb_mock_tx_types::MockTransaction;
+use ckb_types::{
+    packed::{Byte32, Script},
+    prelude::*,
+};
+
+#[derive(Clone, Debug)]
+pub struct ScriptGroup {
+    pub script: Script,
+    pub is_lock_script: bool,
+    /// Whether `script_index` points to an output, for type scripts that
+    /// only appear in outputs.
+    pub is_output: bool,
+    pub script_index: u64,
+    pub input_indices: Vec<usize>,
+    pub output_indices: Vec<usize>,
+}
+impl ScriptGroup {
+    pub fn script_hash(&self) -> Byte32 {
+        self.script.calc_script_hash()
+    }
+
+    /// The setup running this group, based on `setup`.
+    pub fn setup(&self, setup: &RunningSetup) -> RunningSetup {
+        RunningSetup {
+            is_lock_script: self.is_lock_script,
+            is_output: self.is_output,
+            script_index: self.script_index,
+            ..setup.clone()
+        }
+    }
+
+    /// Names the script like ckb does in transaction script errors.
+    pub fn source(&self) -> String {
+        match (self.is_output, self.is_lock_script) {
+            (true, _) => format!("Outputs[{}].Type", self.script_index),
+            (false, true) => format!("Inputs[{}].Lock", self.script_index),
+            (false, false) => format!("Inputs[{}].Type", self.script_index),
+        }
+    }
+}
+
+/// Lists script groups the way ckb does: lock groups, then type groups, each
+/// in order of first appearance.
+pub fn script_groups(tx: &MockTransaction) -> Vec<ScriptGroup> {
+    let mut groups: Vec<ScriptGroup> = vec![];
+    let mut add = |script: Script, is_lock_script: bool, is_output: bool, index: usize| {
+        if groups
+            .iter()
+            .any(|g| g.is_lock_script == is_lock_script && g.script == script)
+        {
+            return;
+        }
+        let (input_indices, output_indices) = crate::group_indices(tx, &script, is_lock_script);
+        groups.push(ScriptGroup {
+            script,
+            is_lock_script,
+            is_output,
+            script_index: index as u64,
+            input_indices,
+            output_indices,
+        });
+    };
+
+    for (i, input) in tx.mock_info.inputs.iter().enumerate() {
+        add(input.output.lock(), true, false, i);
+    }
+    for (i, input) in tx.mock_info.inputs.iter().enumerate() {
+        if let Some(t) = input.output.type_().to_opt() {
+            add(t, false, false, i);
+        }
+    }
+    for (i, output) in tx.tx.raw().outputs().into_iter().enumerate() {
+        if let Some(t) = output.type_().to_opt() {
+            add(t, false, true, i);
+        }
+    }
+    groups
+}
+
+/// Key of a script's native binary in `native_binaries`.
+pub fn native_binary_key(script: &Script) -> String {
+    utils::simulator_key(
+        script.code_hash().as_slice(),
+        script.hash_type().into(),
+        0,
+        0,
+    )
+}
+
+/// The native binary of a script, mapped to its hash type or any.
+pub fn native_binary(setup: &RunningSetup, script: &Script) -> Option<String> {
+    let code_hash = script.code_hash();
+    let hash_type = script.hash_type().into();
+    utils::simulator_path(
+        &setup.native_binaries,
+        code_hash.as_slice(),
+        hash_type,
+        0,
+        0,
+    )
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// No native binary or built-in script for the group, with its source.
+    MissingNativeBinary(String, Byte32),
+    /// The group exited with a non-zero code, with its source.
+    ScriptFailed(String, Byte32, i8),
+    /// The transaction fails a check enabled in the setup, before any
+    /// script runs.
+    Invalid(TxVerifyError),
+    /// ckb-testtool cannot dump the transaction from its context.
+    Dump(String),
+}
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingNativeBinary(source, hash) => {
+                write!(f, "{}: no native binary for script {:#x}", source, hash)
+            }
+            Self::ScriptFailed(source, hash, code) => write!(
+                f,
+                "{}: ValidationFailure(script {:#x}, error code {})",
+                source, hash, code
+            ),
+            Self::Invalid(e) => write!(f, "{}", e),
+            Self::Dump(e) => write!(f, "dump tx: {}", e),
+        }
+    }
+}
+impl std::error::Error for VerifyError {}
+
+/// Runs one script group and returns its exit code. The transaction is not
+/// prechecked, `verify_transaction` checks it once for every group.
+pub fn run_group(
+    tx: &MockTransaction,
+    setup: &RunningSetup,
+    group: &ScriptGroup,
+) -> Result<i8, VerifyError> {
+    let sim = Simulation::new(tx.clone(), group.setup(setup));
+    run_group_in(&sim, setup, group)
+}
+
+/// `run_group` in `sim`, a simulation of the group, for callers looking at
+/// it once the group ends.
+pub(crate) fn run_group_in(
+    sim: &Simulation,
+    setup: &RunningSetup,
+    group: &ScriptGroup,
+) -> Result<i8, VerifyError> {
+    if let Some(path) = native_binary(setup, &group.script) {
+        return Ok(sim.run_native_unchecked(&path, &[]));
+    }
+    match system_scripts::builtin_script(&group.script) {
+        Some((name, script)) => Ok(sim.run_root(name.to_string(), move |_, _| script())),
+        None => Err(VerifyError::MissingNativeBinary(
+            group.source(),
+            group.script_hash(),
+        )),
+    }
+}
+
+/// Runs every script group of `tx`, stopping at the first failure.
+pub fn verify_transaction(tx: &MockTransaction, setup: &RunningSetup) -> Result<(), VerifyError> {
+    precheck(tx, setup).map_err(VerifyError::Invalid)?;
+    for group in script_groups(tx) {
+        let code = run_group(tx, setup, &group)?;
+        if code != 0 {
+            return Err(VerifyError::ScriptFailed(
+                group.source(),
+                group.script_hash(),
+                code,
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn lock_code_hash(tx: &MockTransaction) -> [u8; 32] {
+        let code_hash = tx.mock_info.inputs[0].output.lock().code_hash();
+        code_hash.as_slice().try_into().unwrap()
+    }
+
+    #[test]
+    fn runs_groups_mapped_like_ckb_testtool() {
+        let tx = test_utils::sample_tx();
+        // ckb-testtool maps data hashes with the wildcard hash type.
+        let key = test_utils::cell_key(&lock_code_hash(&tx), 0xFF);
+        let setup = RunningSetup {
+            native_binaries: [(key, test_utils::argv_dylib())].into(),
+            ..Default::default()
+        };
+        assert_eq!(verify_transaction(&tx, &setup), Ok(()));
+    }
+
+    #[test]
+    fn runs_groups_mapped_to_their_hash_type() {
+        let tx = test_utils::sample_tx();
+        let lock = tx.mock_info.inputs[0].output.lock();
+        let setup = RunningSetup {
+            native_binaries: [(native_binary_key(&lock), test_utils::argv_dylib())].into(),
+            ..Default::default()
+        };
+        let groups = script_groups(&tx);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(run_group(&tx, &setup, &groups[0]), Ok(0));
+
+        let other_hash_type = test_utils::cell_key(&lock_code_hash(&tx), 0);
+        let setup = RunningSetup {
+            native_binaries: [(other_hash_type, test_utils::argv_dylib())].into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            verify_transaction(&tx, &setup),
+            Err(VerifyError::MissingNativeBinary(
+                "Inputs[0].Lock".to_string(),
+                lock.calc_script_hash()
+            ))
+        );
+    }
+
+    #[test]
+    fn transactions_are_prechecked_before_the_groups() {
+        // The sample output has less capacity than it occupies.
+        let tx = test_utils::sample_tx();
+        let lock = tx.mock_info.inputs[0].output.lock();
+        let setup = RunningSetup {
+            native_binaries: [(native_binary_key(&lock), test_utils::argv_dylib())].into(),
+            check_structure: true,
+            ..Default::default()
+        };
+        let groups = script_groups(&tx);
+        assert_eq!(run_group(&tx, &setup, &groups[0]), Ok(0));
+        assert!(matches!(
+            verify_transaction(&tx, &setup),
+            Err(VerifyError::Invalid(_))
+        ));
+    }
+}